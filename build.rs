@@ -1,3 +1,58 @@
 fn main() {
     embuild::espidf::sysenv::output();
+
+    // checksums of the embedded web assets, checked again on the device at boot
+    for (envar, path) in [("INDEX_HTML_CRC32", "src/restful-server-index.html")] {
+        println!("cargo:rerun-if-changed={}", path);
+        let contents = std::fs::read(path).unwrap();
+        println!("cargo:rustc-env={}={}", envar, crc32(&contents));
+    }
+
+    // served as is to browsers that take gzip, which is nearly all of them
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+    let html = std::fs::read("src/restful-server-index.html").unwrap();
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    std::io::Write::write_all(&mut encoder, &html).unwrap();
+    let gzipped = encoder.finish().unwrap();
+    println!("cargo:rustc-env=INDEX_HTML_GZ_CRC32={}", crc32(&gzipped));
+    std::fs::write(out_dir.join("index.html.gz"), gzipped).unwrap();
+
+    // what went into this build, for /version.json
+    println!("cargo:rerun-if-changed=.git/HEAD");
+    println!("cargo:rerun-if-changed=.git/index");
+    let git_hash = std::process::Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|o| o.status.success())
+        .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    let dirty = std::process::Command::new("git")
+        .args(["status", "--porcelain", "--untracked-files=no"])
+        .output()
+        .map_or(false, |o| o.status.success() && !o.stdout.is_empty());
+    println!("cargo:rustc-env=BUILD_GIT_HASH={}{}", git_hash, if dirty { "-dirty" } else { "" });
+    let build_secs = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs();
+    println!("cargo:rustc-env=BUILD_UNIX_SECS={}", build_secs);
+    let mut features: Vec<String> = std::env::vars()
+        .filter_map(|(k, _)| k.strip_prefix("CARGO_FEATURE_").map(|f| f.to_lowercase().replace('_', "-")))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=BUILD_FEATURES={}", features.join(","));
+    for envar in ["MCU", "ESP_IDF_VERSION"] {
+        println!("cargo:rerun-if-env-changed={}", envar);
+        println!("cargo:rustc-env=BUILD_{}={}", envar, std::env::var(envar).unwrap_or_else(|_| "unknown".to_string()));
+    }
+}
+
+// must match integrity::crc32 in the firmware
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for b in bytes {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
 }
@@ -0,0 +1,193 @@
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+
+use anyhow::{anyhow, bail};
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+// the firmware's own types for what set.json takes and status.json gives, so the two can't drift apart
+#[path = "../../src/api.rs"]
+#[allow(dead_code)]
+mod api;
+use api::{FanSpeed, HeatPumpMode, UnitSetting, UnitStatus};
+
+// these have to match the firmware
+const MDNS_SERVICE: &str = "_eteq-mheatpump._tcp.local.";
+const HTTP_PORT: u16 = 8923;
+
+const BROWSE_TIME: Duration = Duration::from_secs(3);
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+const USAGE: &str = "usage: heatpumpctl [--location LOCATION | --host HOST[:PORT]] (list | status | set [--temp C] [--mode MODE] [--fan SPEED] [--power on|off])";
+
+#[derive(Debug)]
+struct Controller {
+    hostname: String,
+    address: String,  // host:port
+    location: Option<String>,
+    version: Option<String>,
+}
+
+fn discover() -> anyhow::Result<Vec<Controller>> {
+    let mdns = ServiceDaemon::new()?;
+    let receiver = mdns.browse(MDNS_SERVICE)?;
+    let mut controllers: Vec<Controller> = Vec::new();
+
+    let start = Instant::now();
+    while let Some(remaining) = BROWSE_TIME.checked_sub(start.elapsed()) {
+        match receiver.recv_timeout(remaining) {
+            Ok(ServiceEvent::ServiceResolved(info)) => {
+                let Some(ip) = info.get_addresses().iter().next() else { continue; };
+                if controllers.iter().any(|c| c.hostname == info.get_hostname()) {
+                    continue;
+                }
+                controllers.push(Controller {
+                    hostname: info.get_hostname().to_string(),
+                    address: format!("{}:{}", ip, info.get_port()),
+                    location: info.get_property_val_str("location").filter(|l| !l.is_empty()).map(String::from),
+                    version: info.get_property_val_str("version").map(String::from),
+                });
+            }
+            Ok(_) => {}
+            Err(_) => { break; }
+        }
+    }
+    mdns.shutdown()?;
+    controllers.sort_by(|a, b| a.location.cmp(&b.location).then(a.hostname.cmp(&b.hostname)));
+    Ok(controllers)
+}
+
+/// A bare-bones HTTP/1.0 request, which is all the controller needs.  Returns the status code and body
+fn http_request(address: &str, method: &str, path: &str, body: Option<&str>) -> anyhow::Result<(u16, String)> {
+    let mut stream = TcpStream::connect(address)?;
+    stream.set_read_timeout(Some(HTTP_TIMEOUT))?;
+    stream.set_write_timeout(Some(HTTP_TIMEOUT))?;
+
+    let body = body.unwrap_or("");
+    write!(stream, "{} {} HTTP/1.0\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+           method, path, address, body.len(), body)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let (head, body) = response.split_once("\r\n\r\n").ok_or(anyhow!("malformed response from {}", address))?;
+    let code = head.split_whitespace().nth(1).and_then(|c| c.parse().ok())
+        .ok_or(anyhow!("malformed status line from {}", address))?;
+    Ok((code, body.to_string()))
+}
+
+/// A mode or fan speed the way set.json reads it, by name in any case
+fn parse_name<T: DeserializeOwned>(name: &str) -> anyhow::Result<T> {
+    Ok(serde_json::from_value(serde_json::Value::String(name.to_string()))?)
+}
+
+/// Turns the set arguments into a set.json request, leaving out anything not given
+fn setting_from_args(args: &[String]) -> anyhow::Result<UnitSetting> {
+    let mut setting = UnitSetting::new();
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args.next().ok_or(anyhow!("{} needs a value", flag))?;
+        match flag.as_str() {
+            "--temp" => { setting.desired_temperature_c = Some(value.parse::<f32>()?); }
+            "--mode" => { setting.mode = Some(parse_name::<HeatPumpMode>(value)?); }
+            "--fan" => { setting.fan_speed = Some(parse_name::<FanSpeed>(value)?); }
+            "--power" => {
+                setting.poweron = match value.as_str() {
+                    "on" => Some(true),
+                    "off" => Some(false),
+                    _ => bail!("--power must be on or off"),
+                };
+            }
+            _ => bail!("unknown option {}\n{}", flag, USAGE),
+        }
+    }
+    if setting.is_empty() {
+        bail!("nothing to set\n{}", USAGE);
+    }
+    Ok(setting)
+}
+
+fn print_status(status: &UnitStatus) {
+    if !status.connected {
+        println!("not connected to the heat pump");
+        return;
+    }
+    println!("power:    {}", if status.poweron { "on" } else { "off" });
+    println!("mode:     {:?}", status.mode);
+    println!("setpoint: {} C", status.desired_temperature_c);
+    if status.room_temperature_c > -999.0 {
+        println!("room:     {} C", status.room_temperature_c);
+    }
+    println!("fan:      {:?}", status.fan_speed);
+    println!("vane:     {:?}, {:?}", status.vane, status.widevane);
+}
+
+#[derive(Deserialize)]
+struct ErrorBody {
+    error: String,
+}
+
+/// The body of a response, as T if it succeeded.  A failure is turned into an error with the controller's message
+fn response_body<T: DeserializeOwned>((code, body): (u16, String)) -> anyhow::Result<T> {
+    if !(200..300).contains(&code) {
+        match serde_json::from_str::<ErrorBody>(&body) {
+            Ok(e) => bail!("controller responded with status {}: {}", code, e.error),
+            Err(_) => bail!("controller responded with status {}: {}", code, body),
+        }
+    }
+    Ok(serde_json::from_str(&body)?)
+}
+
+fn main() -> anyhow::Result<()> {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+
+    let mut location = None;
+    let mut host = None;
+    while args.len() >= 2 && args[0].starts_with("--") {
+        let value = args.remove(1);
+        match args.remove(0).as_str() {
+            "--location" => { location = Some(value); }
+            "--host" => { host = Some(if value.contains(':') { value } else { format!("{}:{}", value, HTTP_PORT) }); }
+            other => bail!("unknown option {}\n{}", other, USAGE),
+        }
+    }
+    let command = args.first().cloned().ok_or(anyhow!(USAGE))?;
+
+    if command == "list" {
+        for c in discover()? {
+            println!("{:<40} {:<22} {:<20} {}", c.hostname, c.address, c.location.as_deref().unwrap_or("-"),
+                     c.version.as_deref().unwrap_or("-"));
+        }
+        return Ok(());
+    }
+
+    // everything else needs exactly one controller
+    let address = match host {
+        Some(h) => h,
+        None => {
+            let controllers = discover()?;
+            let mut matching = controllers.iter()
+                .filter(|c| location.is_none() || c.location == location);
+            match (matching.next(), matching.next()) {
+                (Some(c), None) => c.address.clone(),
+                (None, _) => bail!("no controller found{}", location.map_or(String::new(), |l| format!(" at location {:?}", l))),
+                (Some(_), Some(_)) => bail!("more than one controller found, pick one with --location or --host"),
+            }
+        }
+    };
+
+    match command.as_str() {
+        "status" => {
+            print_status(&response_body::<UnitStatus>(http_request(&address, "GET", "/status.json", None)?)?);
+        }
+        "set" => {
+            let setting = serde_json::to_string(&setting_from_args(&args[1..])?)?;
+            // the reply is the whole setting, of which only the heat pump's part was given
+            let accepted = response_body::<UnitSetting>(http_request(&address, "POST", "/set.json", Some(&setting))?)?;
+            println!("set {}", serde_json::to_string(&accepted)?);
+        }
+        _ => bail!("unknown command {}\n{}", command, USAGE),
+    }
+    Ok(())
+}
@@ -0,0 +1,22 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use host_tests::packet::{Packet, PacketParser, PACKET_SIZE_MAX};
+
+// whatever comes in on the bus, the parser and from_bytes must not panic, and anything the parser accepts has to go
+// back out as the same packet
+fuzz_target!(|data: &[u8]| {
+    let _ = Packet::from_bytes_unchecked(data);
+
+    let mut parser = PacketParser::new();
+    for b in data {
+        if let Some(Ok(packet)) = parser.feed(*b) {
+            let mut buf = [0u8; PACKET_SIZE_MAX];
+            let bytes = packet.to_bytes(&mut buf);
+            let again = Packet::from_bytes(bytes).expect("a parsed packet round trips");
+            assert_eq!(again.packet_type, packet.packet_type);
+            assert_eq!(again.data, packet.data);
+        }
+    }
+});
@@ -0,0 +1,18 @@
+//! The parts of the firmware that don't need the esp32, built from the same source files so the tests exercise
+//! exactly what's flashed.  Anything included here has to stay free of esp-idf imports
+
+// the firmware makes things with new() throughout rather than Default
+#![allow(clippy::new_without_default)]
+
+#[path = "../../src/packet.rs"]
+pub mod packet;
+#[path = "../../src/transport.rs"]
+pub mod transport;
+#[path = "../../src/clock.rs"]
+pub mod clock;
+#[path = "../../src/history.rs"]
+pub mod history;
+#[path = "../../src/adherence.rs"]
+pub mod adherence;
+#[path = "../../src/schedule.rs"]
+pub mod schedule;
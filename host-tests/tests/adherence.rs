@@ -0,0 +1,67 @@
+use std::collections::VecDeque;
+
+use host_tests::adherence::{Adherence, DayAdherence};
+use host_tests::history::{History, Sample, SAMPLE_INTERVAL};
+
+// 2024-03-10 00:00:00 UTC
+const MIDNIGHT: u64 = 1_710_028_800;
+
+fn sample(unix_secs: u64, room_c: f32, setpoint_c: Option<f32>) -> Sample {
+    Sample { unix_secs: Some(unix_secs), room_c: Some(room_c), setpoint_c, heating: setpoint_c.is_some() }
+}
+
+#[test]
+fn days_are_totalled_from_the_history() {
+    let step = SAMPLE_INTERVAL.as_secs();
+    let mut history = History::new();
+    // the last hour of the 9th in band, then on the 10th an hour in band, an hour out of it, and an hour off
+    for i in 0..12 {
+        history.push(sample(MIDNIGHT - 3600 + i * step, 20.5, Some(21.0)));
+    }
+    for i in 0..12 {
+        history.push(sample(MIDNIGHT + i * step, 21.0, Some(21.0)));
+        history.push(sample(MIDNIGHT + 3600 + i * step, 18.0, Some(21.0)));
+        history.push(sample(MIDNIGHT + 7200 + i * step, 15.0, None));
+    }
+    // and some from before the clock was set, which can't be put on a day
+    history.push(Sample { unix_secs: None, room_c: Some(21.0), setpoint_c: Some(21.0), heating: true });
+
+    let mut adherence = Adherence::new();
+    adherence.update(&history);
+    let days: Vec<_> = adherence.days.iter().map(|d| (d.date.as_str(), d.total_secs, d.percent)).collect();
+    assert_eq!(days, vec![("2024-03-09", 3600.0, Some(100.0)), ("2024-03-10", 7200.0, Some(50.0))]);
+
+    // totalling up again doesn't count anything twice
+    adherence.update(&history);
+    assert_eq!(adherence.days.len(), 2);
+    assert_eq!(adherence.days[1].total_secs, 7200.0);
+}
+
+#[test]
+fn days_saved_before_a_reboot_are_added_to() {
+    let step = SAMPLE_INTERVAL.as_secs();
+    let mut adherence = Adherence::new();
+    let saved = DayAdherence { date: "2024-03-10".to_string(), in_band_secs: 3600.0, total_secs: 3600.0, percent: Some(100.0) };
+    let older = DayAdherence { date: "2024-03-08".to_string(), in_band_secs: 0.0, total_secs: 600.0, percent: Some(0.0) };
+    adherence.restore(VecDeque::from(vec![older, saved]));
+
+    let mut history = History::new();
+    for i in 0..12 {
+        history.push(sample(MIDNIGHT + 7200 + i * step, 25.0, Some(21.0)));
+    }
+    adherence.update(&history);
+    assert_eq!(adherence.days.len(), 2);
+    assert_eq!(adherence.days[0].date, "2024-03-08");
+    assert_eq!(adherence.days[1].total_secs, 7200.0);
+    assert_eq!(adherence.days[1].percent, Some(50.0));
+}
+
+#[test]
+fn the_history_is_capped() {
+    let mut history = History::new();
+    for i in 0..1000 {
+        history.push(sample(MIDNIGHT + i * 300, 21.0, Some(21.0)));
+    }
+    assert!(history.samples.len() < 1000);
+    assert_eq!(history.samples.back().unwrap().unix_secs, Some(MIDNIGHT + 999 * 300));
+}
@@ -0,0 +1,79 @@
+use proptest::prelude::*;
+
+use host_tests::packet::{checksum, Packet, CONNECT_BYTES, PACKET_SIZE_MAX};
+
+// what the checksum is meant to be, worked out without any wrapping arithmetic
+fn reference(bytes: &[u8]) -> u8 {
+    let sum: u32 = bytes.iter().map(|b| *b as u32).sum();
+    ((0xfc + 256 * 256 - sum) % 256) as u8
+}
+
+// packets as the controller and the units send them, checksums included
+const REAL_PACKETS: &[&[u8]] = &[
+    // the connection string, and a unit's answer to it
+    &CONNECT_BYTES,
+    &[0xfc, 0x7a, 0x01, 0x30, 0x01, 0x00, 0x54],
+    // info requests for the settings, room temperature, operating status and standby
+    &[0xfc, 0x42, 0x01, 0x30, 0x10, 0x02, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x7b],
+    &[0xfc, 0x42, 0x01, 0x30, 0x10, 0x03, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x7a],
+    &[0xfc, 0x42, 0x01, 0x30, 0x10, 0x06, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x77],
+    &[0xfc, 0x42, 0x01, 0x30, 0x10, 0x09, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x74],
+    // a unit acknowledging a setting
+    &[0xfc, 0x61, 0x01, 0x30, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x5e],
+];
+
+#[test]
+fn real_packets() {
+    for packet in REAL_PACKETS {
+        let (body, sum) = packet.split_at(packet.len() - 1);
+        assert_eq!(checksum(body), sum[0], "{:02x?}", packet);
+        assert!(Packet::from_bytes(packet).unwrap().check_checksum());
+    }
+}
+
+#[test]
+fn empty() {
+    assert_eq!(checksum(&[]), 0xfc);
+}
+
+#[test]
+fn every_byte_and_pair() {
+    for a in 0..=255u8 {
+        assert_eq!(checksum(&[a]), reference(&[a]));
+        for b in 0..=255u8 {
+            assert_eq!(checksum(&[a, b]), reference(&[a, b]));
+        }
+    }
+}
+
+#[test]
+fn sync_byte_then_every_byte_and_pair() {
+    // every real packet starts with 0xfc, which on its own takes the sum most of the way round
+    for a in 0..=255u8 {
+        for b in 0..=255u8 {
+            assert_eq!(checksum(&[0xfc, a, b]), reference(&[0xfc, a, b]));
+        }
+    }
+}
+
+#[test]
+fn wraps_many_times_over() {
+    // the biggest packet there is, all 0xff, goes round 21 times
+    let bytes = [0xffu8; PACKET_SIZE_MAX - 1];
+    assert_eq!(checksum(&bytes), reference(&bytes));
+    assert_eq!(checksum(&bytes), 0xfcu8.wrapping_add(PACKET_SIZE_MAX as u8 - 1));
+}
+
+proptest! {
+    #[test]
+    fn matches_the_reference(bytes in prop::collection::vec(any::<u8>(), 0..PACKET_SIZE_MAX)) {
+        prop_assert_eq!(checksum(&bytes), reference(&bytes));
+    }
+
+    #[test]
+    fn whole_packet_sums_to_the_sync_byte(bytes in prop::collection::vec(any::<u8>(), 0..PACKET_SIZE_MAX)) {
+        // which is what a unit checking the packet relies on
+        let total = bytes.iter().fold(checksum(&bytes), |sum, b| sum.wrapping_add(*b));
+        prop_assert_eq!(total, 0xfc);
+    }
+}
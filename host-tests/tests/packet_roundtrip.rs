@@ -0,0 +1,102 @@
+use proptest::prelude::*;
+
+use host_tests::packet::{Packet, PacketData, PacketError, PacketParser, PACKET_DATA_MAX, PACKET_SIZE_MAX};
+
+fn packet_from(packet_type: u8, h2: u8, h3: u8, data: &[u8]) -> Packet {
+    let mut packet = Packet::new();
+    packet.packet_type = packet_type;
+    packet.h2 = h2;
+    packet.h3 = h3;
+    packet.data = PacketData::from_slice(data).unwrap();
+    packet.set_checksum();
+    packet
+}
+
+fn packet_error(e: &anyhow::Error) -> &PacketError {
+    e.downcast_ref::<PacketError>().expect("a PacketError")
+}
+
+proptest! {
+    #[test]
+    fn bytes_round_trip(packet_type: u8, h2: u8, h3: u8, data in prop::collection::vec(any::<u8>(), 0..=PACKET_DATA_MAX)) {
+        let packet = packet_from(packet_type, h2, h3, &data);
+        let mut buf = [0u8; PACKET_SIZE_MAX];
+        let bytes = packet.to_bytes(&mut buf);
+        prop_assert_eq!(bytes.len(), packet.packet_size());
+
+        let parsed = Packet::from_bytes(bytes).unwrap();
+        prop_assert_eq!(parsed.packet_type, packet_type);
+        prop_assert_eq!(parsed.h2, h2);
+        prop_assert_eq!(parsed.h3, h3);
+        prop_assert_eq!(&parsed.data[..], &data[..]);
+        prop_assert_eq!(parsed.checksum, packet.checksum);
+    }
+
+    #[test]
+    fn parser_finds_packet_after_noise(noise in prop::collection::vec(any::<u8>().prop_filter("not sync", |b| *b != 0xfc), 0..32),
+                                       packet_type: u8, data in prop::collection::vec(any::<u8>(), 0..=PACKET_DATA_MAX)) {
+        let packet = packet_from(packet_type, 0x01, 0x30, &data);
+        let mut buf = [0u8; PACKET_SIZE_MAX];
+        let mut stream = noise.clone();
+        stream.extend_from_slice(packet.to_bytes(&mut buf));
+
+        let mut parser = PacketParser::new();
+        let results: Vec<_> = stream.iter().filter_map(|b| parser.feed(*b)).collect();
+        prop_assert_eq!(results.len(), 1);
+        let parsed = results.into_iter().next().unwrap().unwrap();
+        prop_assert_eq!(parsed.packet_type, packet_type);
+        prop_assert_eq!(&parsed.data[..], &data[..]);
+        prop_assert_eq!(parser.skipped(), noise.len());
+        prop_assert!(!parser.in_packet());
+    }
+
+    #[test]
+    fn one_changed_byte_fails_the_checksum(packet_type: u8, data in prop::collection::vec(any::<u8>(), 1..=PACKET_DATA_MAX),
+                                           index: prop::sample::Index, delta in 1u8..=255) {
+        let packet = packet_from(packet_type, 0x01, 0x30, &data);
+        let mut buf = [0u8; PACKET_SIZE_MAX];
+        let mut bytes = packet.to_bytes(&mut buf).to_vec();
+        // anything but the sync and length bytes, which fail differently
+        let positions: Vec<usize> = (1..bytes.len()).filter(|i| *i != 4).collect();
+        let i = positions[index.index(positions.len())];
+        bytes[i] = bytes[i].wrapping_add(delta);
+
+        let e = Packet::from_bytes(&bytes).unwrap_err();
+        prop_assert!(matches!(packet_error(&e), PacketError::BadChecksum));
+    }
+
+    #[test]
+    fn from_bytes_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..64)) {
+        if let Ok(packet) = Packet::from_bytes_unchecked(&bytes) {
+            prop_assert!(packet.data.len() <= PACKET_DATA_MAX);
+            prop_assert!(packet.packet_size() <= bytes.len());
+        }
+    }
+
+    #[test]
+    fn parser_never_panics_or_overreads(bytes in prop::collection::vec(any::<u8>(), 0..256)) {
+        let mut parser = PacketParser::new();
+        let mut since_packet = 0;
+        for b in bytes {
+            prop_assert!(parser.wanted() >= 1);
+            since_packet += 1;
+            if parser.feed(b).is_some() {
+                // a packet never takes more bytes than the biggest one there is
+                prop_assert!(since_packet <= PACKET_SIZE_MAX);
+                since_packet = 0;
+            } else if !parser.in_packet() {
+                since_packet = 0;
+            }
+        }
+    }
+}
+
+#[test]
+fn impossible_length_gives_up_at_the_header() {
+    let mut parser = PacketParser::new();
+    let header = [0xfc, 0x62, 0x01, 0x30, PACKET_DATA_MAX as u8 + 1];
+    let results: Vec<_> = header.iter().filter_map(|b| parser.feed(*b)).collect();
+    assert_eq!(results.len(), 1);
+    assert!(matches!(packet_error(results[0].as_ref().unwrap_err()), PacketError::TooLong));
+    assert!(!parser.in_packet());
+}
@@ -0,0 +1,102 @@
+use host_tests::history::{History, Sample, SAMPLE_INTERVAL};
+use host_tests::schedule::{ComfortPeriod, Schedule, ScheduleConfig};
+
+// 2024-03-11 00:00:00 UTC, a Monday
+const MONDAY: u64 = 1_710_115_200;
+const HOUR: u64 = 3600;
+
+fn schedule(periods: Vec<ComfortPeriod>) -> Schedule {
+    let mut schedule = Schedule::new();
+    schedule.set_config(ScheduleConfig { enabled: true, periods, setback_c: 16.0, ..ScheduleConfig::new() });
+    schedule
+}
+
+fn period(days: Vec<u8>, start: &str, end: &str, temperature_c: f32) -> ComfortPeriod {
+    ComfortPeriod { days, start: start.to_string(), end: end.to_string(), temperature_c }
+}
+
+#[test]
+fn periods_on_their_days() {
+    let mut schedule = schedule(vec![period(vec![0, 1, 2, 3, 4], "07:00", "09:00", 21.0)]);
+    assert_eq!(schedule.target(MONDAY + 6*HOUR, false, None, None), Some(16.0));
+    assert_eq!(schedule.target(MONDAY + 7*HOUR, false, None, None), Some(21.0));
+    assert_eq!(schedule.target(MONDAY + 9*HOUR, false, None, None), Some(16.0));
+    // Saturday
+    assert_eq!(schedule.target(MONDAY + 5*24*HOUR + 8*HOUR, false, None, None), Some(16.0));
+}
+
+#[test]
+fn a_period_can_run_past_midnight() {
+    // only on Sunday, until Monday morning
+    let mut schedule = schedule(vec![period(vec![6], "22:00", "02:00", 19.0)]);
+    assert_eq!(schedule.target(MONDAY + HOUR, false, None, None), Some(19.0));
+    assert_eq!(schedule.target(MONDAY + 3*HOUR, false, None, None), Some(16.0));
+    assert_eq!(schedule.target(MONDAY - HOUR, false, None, None), Some(19.0));
+}
+
+#[test]
+fn evaluate_only_returns_changes() {
+    let mut schedule = schedule(vec![period(vec![], "07:00", "09:00", 21.0)]);
+    assert_eq!(schedule.evaluate(MONDAY + 6*HOUR, false, None, None), Some(16.0));
+    assert_eq!(schedule.evaluate(MONDAY + 6*HOUR + 60, false, None, None), None);
+    assert_eq!(schedule.evaluate(MONDAY + 7*HOUR, false, None, None), Some(21.0));
+}
+
+#[test]
+fn preheats_by_the_warmup_rate_and_keeps_going() {
+    let mut schedule = schedule(vec![period(vec![], "07:00", "09:00", 21.0)]);
+    // 3 degrees to go at 0.05 a minute is an hour
+    assert_eq!(schedule.target(MONDAY + 5*HOUR + 50*60, true, Some(18.0), Some(0.05)), Some(16.0));
+    assert_eq!(schedule.target(MONDAY + 6*HOUR, true, Some(18.0), Some(0.05)), Some(21.0));
+    assert!(schedule.preheat.is_some());
+    // the room warming up doesn't end it before the period starts
+    assert_eq!(schedule.target(MONDAY + 6*HOUR + 40*60, true, Some(20.5), Some(0.05)), Some(21.0));
+    assert_eq!(schedule.target(MONDAY + 7*HOUR, true, Some(21.0), Some(0.05)), Some(21.0));
+    assert!(schedule.preheat.is_none());
+}
+
+#[test]
+fn no_preheat_without_heating_or_a_rate() {
+    let mut schedule = schedule(vec![period(vec![], "07:00", "09:00", 21.0)]);
+    assert_eq!(schedule.target(MONDAY + 6*HOUR + 30*60, false, Some(18.0), Some(0.05)), Some(16.0));
+    assert_eq!(schedule.target(MONDAY + 6*HOUR + 30*60, true, Some(18.0), None), Some(16.0));
+    assert_eq!(schedule.target(MONDAY + 6*HOUR + 30*60, true, None, Some(0.05)), Some(16.0));
+}
+
+#[test]
+fn preheat_is_capped() {
+    let mut schedule = schedule(vec![period(vec![], "07:00", "09:00", 21.0)]);
+    schedule.config.max_preheat_mins = 30;
+    assert_eq!(schedule.target(MONDAY + 6*HOUR, true, Some(10.0), Some(0.01)), Some(16.0));
+    assert_eq!(schedule.target(MONDAY + 6*HOUR + 30*60, true, Some(10.0), Some(0.01)), Some(21.0));
+}
+
+#[test]
+fn bad_times_are_refused() {
+    let mut config = ScheduleConfig::new();
+    config.periods = vec![period(vec![], "7am", "09:00", 21.0)];
+    assert!(config.validate().is_err());
+    config.periods = vec![period(vec![7], "07:00", "09:00", 21.0)];
+    assert!(config.validate().is_err());
+    config.periods = vec![period(vec![], "07:00", "07:00", 21.0)];
+    assert!(config.validate().is_err());
+    config.periods = vec![period(vec![], "23:30", "00:30", 21.0)];
+    assert!(config.validate().is_ok());
+}
+
+#[test]
+fn warmup_rate_from_the_history() {
+    let step = SAMPLE_INTERVAL.as_secs();
+    let mut history = History::new();
+    assert_eq!(history.warmup_c_per_min(), None);
+    // a degree every 20 minutes up to the setpoint, then holding at the setpoint, which doesn't count
+    for i in 0..17 {
+        let room_c = 17.0 + (i as f32 * step as f32 / 60.0) / 20.0;
+        history.push(Sample { unix_secs: Some(MONDAY + i * step), room_c: Some(room_c), setpoint_c: Some(21.0), heating: true });
+    }
+    for i in 17..24 {
+        history.push(Sample { unix_secs: Some(MONDAY + i * step), room_c: Some(21.0), setpoint_c: Some(21.0), heating: true });
+    }
+    let rate = history.warmup_c_per_min().unwrap();
+    assert!((rate - 0.05).abs() < 0.001, "{}", rate);
+}
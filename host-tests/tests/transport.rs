@@ -0,0 +1,244 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use host_tests::packet::{Packet, PacketData, PacketError, CONNECT_BYTES, PACKET_SIZE_MAX};
+use host_tests::transport::{read_reply, Reply, Transport};
+
+// much shorter than the firmware's, so the tests don't take long
+const WAIT: Duration = Duration::from_millis(200);
+const BYTE_GAP: Duration = Duration::from_millis(20);
+const READ_LIMIT: Duration = Duration::from_millis(300);
+
+/// One part of a reply: how long after the request (or the part before) it comes, and its bytes
+struct Chunk {
+    after: Duration,
+    bytes: Vec<u8>,
+}
+
+/// Stands in for the heat pump: each write is answered with the next scripted reply, its bytes turning up in real
+/// time as the chunks say
+struct MockUnit {
+    replies: Mutex<VecDeque<Vec<Chunk>>>,
+    incoming: Mutex<VecDeque<(Instant, u8)>>,
+    written: Mutex<Vec<Vec<u8>>>,
+}
+
+impl MockUnit {
+    fn new(replies: Vec<Vec<Chunk>>) -> Self {
+        Self {
+            replies: Mutex::new(replies.into()),
+            incoming: Mutex::new(VecDeque::new()),
+            written: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl Transport for MockUnit {
+    fn write(&self, bytes: &[u8]) -> anyhow::Result<usize> {
+        self.written.lock().unwrap().push(bytes.to_vec());
+        if let Some(reply) = self.replies.lock().unwrap().pop_front() {
+            let mut at = Instant::now();
+            let mut incoming = self.incoming.lock().unwrap();
+            for chunk in reply {
+                at += chunk.after;
+                incoming.extend(chunk.bytes.iter().map(|b| (at, *b)));
+            }
+        }
+        Ok(bytes.len())
+    }
+
+    fn read(&self, buf: &mut [u8], timeout: Duration) -> anyhow::Result<usize> {
+        let deadline = Instant::now() + timeout;
+        let next = self.incoming.lock().unwrap().front().map(|(at, _)| *at);
+        match next {
+            Some(at) if at <= deadline => { std::thread::sleep(at.saturating_duration_since(Instant::now())); }
+            _ => {
+                std::thread::sleep(timeout);
+                return Ok(0);
+            }
+        }
+        // like the uart driver, hand over whatever has come in, up to the buffer's size
+        let now = Instant::now();
+        let mut incoming = self.incoming.lock().unwrap();
+        let mut n = 0;
+        while n < buf.len() && incoming.front().is_some_and(|(at, _)| *at <= now) {
+            buf[n] = incoming.pop_front().unwrap().1;
+            n += 1;
+        }
+        Ok(n)
+    }
+
+    fn discard_input(&self) -> anyhow::Result<()> {
+        let now = Instant::now();
+        self.incoming.lock().unwrap().retain(|(at, _)| *at > now);
+        Ok(())
+    }
+}
+
+fn packet_bytes(packet_type: u8, data: &[u8]) -> Vec<u8> {
+    let mut packet = Packet::new();
+    packet.packet_type = packet_type;
+    packet.data = PacketData::from_slice(data).unwrap();
+    packet.set_checksum();
+    let mut buf = [0u8; PACKET_SIZE_MAX];
+    packet.to_bytes(&mut buf).to_vec()
+}
+
+fn at_once(bytes: Vec<u8>) -> Vec<Chunk> {
+    vec![Chunk { after: Duration::ZERO, bytes }]
+}
+
+fn info_request(info_type: u8) -> Vec<u8> {
+    let mut data = [0u8; 16];
+    data[0] = info_type;
+    packet_bytes(0x42, &data)
+}
+
+fn status_reply(info_type: u8) -> Vec<u8> {
+    let mut data = [0u8; 16];
+    data[0] = info_type;
+    packet_bytes(0x62, &data)
+}
+
+fn request(unit: &MockUnit, bytes: &[u8]) -> Reply {
+    unit.write(bytes).unwrap();
+    read_reply(unit, WAIT, BYTE_GAP, READ_LIMIT).unwrap()
+}
+
+fn packet_error(reply: &Reply) -> &PacketError {
+    reply.packet.as_ref().unwrap_err().downcast_ref::<PacketError>().expect("a PacketError")
+}
+
+#[test]
+fn connect_string_is_a_good_packet() {
+    let packet = Packet::from_bytes(&CONNECT_BYTES).unwrap();
+    assert_eq!(packet.packet_type, 0x5a);
+}
+
+#[test]
+fn connect() {
+    let unit = MockUnit::new(vec![at_once(packet_bytes(0x7a, &[0x00]))]);
+    let reply = request(&unit, &CONNECT_BYTES);
+    assert_eq!(unit.written.lock().unwrap()[0], CONNECT_BYTES);
+    assert_eq!(reply.packet.unwrap().unwrap().packet_type, 0x7a);
+    assert_eq!(reply.skipped, 0);
+    assert!(reply.latency.is_some());
+}
+
+#[test]
+fn connect_without_an_answer() {
+    let unit = MockUnit::new(vec![]);
+    let started = Instant::now();
+    let reply = request(&unit, &CONNECT_BYTES);
+    assert!(reply.packet.unwrap().is_none());
+    assert!(reply.bytes.is_empty());
+    assert!(reply.latency.is_none());
+    assert!(started.elapsed() >= WAIT);
+}
+
+#[test]
+fn poll_each_status_type() {
+    let types = [0x02, 0x03, 0x06, 0x09];
+    let unit = MockUnit::new(types.iter().map(|t| at_once(status_reply(*t))).collect());
+    for t in types {
+        unit.discard_input().unwrap();
+        let packet = request(&unit, &info_request(t)).packet.unwrap().unwrap();
+        assert_eq!(packet.packet_type, 0x62);
+        assert_eq!(packet.data[0], t);
+    }
+    assert_eq!(unit.written.lock().unwrap().len(), types.len());
+}
+
+#[test]
+fn poll_skips_noise_before_the_reply() {
+    let mut bytes = vec![0x00, 0x13, 0x37];
+    bytes.extend(status_reply(0x03));
+    let unit = MockUnit::new(vec![at_once(bytes.clone())]);
+    let reply = request(&unit, &info_request(0x03));
+    assert_eq!(reply.skipped, 3);
+    assert_eq!(reply.bytes, bytes);
+    assert_eq!(reply.packet.unwrap().unwrap().data[0], 0x03);
+}
+
+#[test]
+fn what_follows_a_packet_is_left_for_next_time() {
+    let mut bytes = status_reply(0x02);
+    bytes.extend(status_reply(0x03));
+    let unit = MockUnit::new(vec![at_once(bytes)]);
+    assert_eq!(request(&unit, &info_request(0x02)).packet.unwrap().unwrap().data[0], 0x02);
+    let reply = read_reply(&unit, WAIT, BYTE_GAP, READ_LIMIT).unwrap();
+    assert_eq!(reply.packet.unwrap().unwrap().data[0], 0x03);
+}
+
+#[test]
+fn truncated_packet() {
+    let bytes = status_reply(0x02);
+    let unit = MockUnit::new(vec![at_once(bytes[..10].to_vec())]);
+    let reply = request(&unit, &info_request(0x02));
+    assert!(matches!(packet_error(&reply), PacketError::TooShort));
+    assert_eq!(reply.bytes.len(), 10);
+}
+
+#[test]
+fn pause_longer_than_the_byte_gap_cuts_a_packet_short() {
+    let bytes = status_reply(0x02);
+    let unit = MockUnit::new(vec![vec![
+        Chunk { after: Duration::ZERO, bytes: bytes[..8].to_vec() },
+        Chunk { after: BYTE_GAP * 3, bytes: bytes[8..].to_vec() },
+    ]]);
+    let reply = request(&unit, &info_request(0x02));
+    assert!(matches!(packet_error(&reply), PacketError::TooShort));
+}
+
+#[test]
+fn pause_shorter_than_the_byte_gap_is_fine() {
+    let bytes = status_reply(0x02);
+    let unit = MockUnit::new(vec![vec![
+        Chunk { after: Duration::ZERO, bytes: bytes[..8].to_vec() },
+        Chunk { after: BYTE_GAP / 4, bytes: bytes[8..].to_vec() },
+    ]]);
+    let reply = request(&unit, &info_request(0x02));
+    assert_eq!(reply.packet.unwrap().unwrap().data[0], 0x02);
+}
+
+#[test]
+fn only_noise() {
+    let unit = MockUnit::new(vec![at_once(vec![0x00; 12])]);
+    let reply = request(&unit, &info_request(0x02));
+    assert!(matches!(packet_error(&reply), PacketError::NoSync));
+    assert_eq!(reply.skipped, 0);
+}
+
+#[test]
+fn bad_checksum() {
+    let mut bytes = status_reply(0x02);
+    *bytes.last_mut().unwrap() ^= 0xff;
+    let unit = MockUnit::new(vec![at_once(bytes)]);
+    let reply = request(&unit, &info_request(0x02));
+    assert!(matches!(packet_error(&reply), PacketError::BadChecksum));
+}
+
+#[test]
+fn delayed_reply_within_the_wait() {
+    let delay = WAIT / 2;
+    let unit = MockUnit::new(vec![vec![Chunk { after: delay, bytes: status_reply(0x06) }]]);
+    let reply = request(&unit, &info_request(0x06));
+    assert!(reply.latency.unwrap() >= delay);
+    assert_eq!(reply.packet.unwrap().unwrap().data[0], 0x06);
+}
+
+#[test]
+fn reply_later_than_the_wait_is_thrown_away_before_the_next_poll() {
+    let unit = MockUnit::new(vec![
+        vec![Chunk { after: WAIT * 2, bytes: status_reply(0x02) }],
+        at_once(status_reply(0x03)),
+    ]);
+    assert!(request(&unit, &info_request(0x02)).packet.unwrap().is_none());
+
+    // by the next poll the late one has come in, and has to be cleared out first like request_status does
+    std::thread::sleep(WAIT * 2);
+    unit.discard_input().unwrap();
+    let packet = request(&unit, &info_request(0x03)).packet.unwrap().unwrap();
+    assert_eq!(packet.data[0], 0x03);
+}
@@ -0,0 +1,120 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+use crate::history::{History, SAMPLE_INTERVAL};
+
+const DAYS_KEPT: usize = 14;
+// the days are saved to NVS this often so the periodic reboot doesn't wipe them
+const SAVE_INTERVAL: Duration = Duration::from_secs(10*60);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AdherenceConfig {
+    pub band_c: f32,  // how far from the setpoint still counts as on target
+    pub utc_offset_minutes: i32,  // so days start at local midnight
+}
+impl AdherenceConfig {
+    pub fn new() -> Self {
+        Self {
+            band_c: 1.0,
+            utc_offset_minutes: 0,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.band_c.is_finite() || self.band_c <= 0.0 {
+            return Err("band_c must be a positive number".to_string());
+        }
+        if self.utc_offset_minutes.abs() > 14*60 {
+            return Err("utc_offset_minutes must be within +/- 14 hours".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DayAdherence {
+    pub date: String,
+    pub in_band_secs: f64,
+    pub total_secs: f64,  // only time when the heat pump was on in a mode with a setpoint
+    pub percent: Option<f32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Adherence {
+    // Per day, how much of the time the room was within band_c of the setpoint, totalled up from the history
+    pub config: AdherenceConfig,
+    pub days: VecDeque<DayAdherence>,
+    #[serde(skip)]
+    restored: Vec<DayAdherence>,  // as saved before this boot, since the history only goes back to it
+    #[serde(skip)]
+    pub last_save: Option<Instant>,
+}
+
+impl Adherence {
+    pub fn new() -> Self {
+        Self {
+            config: AdherenceConfig::new(),
+            days: VecDeque::new(),
+            restored: Vec::new(),
+            last_save: None,
+        }
+    }
+
+    /// Picks up the days saved before a reboot.  The history will add to whichever of them it overlaps
+    pub fn restore(&mut self, days: VecDeque<DayAdherence>) {
+        self.restored = days.iter().cloned().collect();
+        self.days = days;
+    }
+
+    /// Re-totals every day the history covers.  Samples without a setpoint to compare to (e.g. off, or no room
+    /// reading) don't count either way, and neither do ones from before the clock was set, since there's no
+    /// telling what day they're from
+    pub fn update(&mut self, history: &History) {
+        let mut totals: Vec<(String, f64, f64)> = Vec::new();
+        let sample_secs = SAMPLE_INTERVAL.as_secs_f64();
+        for sample in &history.samples {
+            let (unix_secs, room_c, setpoint_c) = match (sample.unix_secs, sample.room_c, sample.setpoint_c) {
+                (Some(u), Some(r), Some(s)) => (u, r, s),
+                _ => { continue; }
+            };
+            let date = self.local_date(unix_secs);
+            if totals.last().map_or(true, |(d, _, _)| *d != date) {
+                totals.push((date, 0.0, 0.0));
+            }
+            let (_, in_band_secs, total_secs) = totals.last_mut().unwrap();
+            *total_secs += sample_secs;
+            if (room_c - setpoint_c).abs() <= self.config.band_c {
+                *in_band_secs += sample_secs;
+            }
+        }
+
+        for (date, in_band_secs, total_secs) in totals {
+            let (before_in_band, before_total) = self.restored.iter().find(|d| d.date == date)
+                                                     .map_or((0.0, 0.0), |d| (d.in_band_secs, d.total_secs));
+            if self.days.back().map_or(true, |d| d.date < date) {
+                self.days.push_back(DayAdherence { date: date.clone(), in_band_secs: 0.0, total_secs: 0.0, percent: None });
+                if self.days.len() > DAYS_KEPT {
+                    self.days.pop_front();
+                }
+            }
+            if let Some(day) = self.days.iter_mut().find(|d| d.date == date) {
+                day.in_band_secs = before_in_band + in_band_secs;
+                day.total_secs = before_total + total_secs;
+                day.percent = Some((100.0 * day.in_band_secs / day.total_secs) as f32);
+            }
+        }
+    }
+
+    fn local_date(&self, unix_secs: u64) -> String {
+        let local_secs = (unix_secs as i64 + self.config.utc_offset_minutes as i64 * 60).max(0) as u64;
+        let (year, month, day, _, _, _) = clock::civil_from_unix(local_secs);
+        format!("{:04}-{:02}-{:02}", year, month, day)
+    }
+
+    pub fn needs_save(&self) -> bool {
+        !self.days.is_empty() && self.last_save.map_or(true, |t| t.elapsed() >= SAVE_INTERVAL)
+    }
+}
@@ -0,0 +1,170 @@
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::http_client;
+
+const PUSHOVER_URL: &str = "https://api.pushover.net/1/messages.json";
+const ALERT_TITLE: &str = "Heat pump controller";
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertService {
+    Ntfy,  // url is the full topic url, e.g. https://ntfy.sh/my-topic
+    Pushover,  // token is the application token and user_key the user/group key
+    Webhook,  // url gets a json body with title and message
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AlertConfig {
+    pub enabled: bool,
+    pub service: AlertService,
+    pub url: String,
+    // secrets are stored separately in NVS and never serialized so they can't leak through status.json
+    #[serde(default, skip_serializing)]
+    pub token: Option<String>,
+    #[serde(default, skip_serializing)]
+    pub user_key: Option<String>,
+    pub disconnect_minutes: u64,
+    pub wifi_storm_count: usize,
+    pub wifi_storm_window_secs: u64,
+}
+impl AlertConfig {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            service: AlertService::Ntfy,
+            url: String::new(),
+            token: None,
+            user_key: None,
+            disconnect_minutes: 10,
+            wifi_storm_count: 5,
+            wifi_storm_window_secs: 3600,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled && self.service != AlertService::Pushover &&
+           !(self.url.starts_with("https://") || self.url.starts_with("http://")) {
+            return Err("url must start with https:// or http://".to_string());
+        }
+        if self.wifi_storm_count == 0 {
+            return Err("wifi_storm_count must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Alerts {
+    // Decides when something is worth a push notification, making sure each problem is only sent once
+    pub config: AlertConfig,
+    pub last_alert: Option<String>,
+    pub last_send_error: Option<String>,
+    #[serde(skip)]
+    pub error_alerted: bool,
+    #[serde(skip)]
+    pub disconnected_since: Option<Instant>,
+    #[serde(skip)]
+    pub disconnect_alerted: bool,
+    #[serde(skip)]
+    pub wifi_reconnects: Vec<Instant>,
+}
+
+impl Alerts {
+    pub fn new() -> Self {
+        Self {
+            config: AlertConfig::new(),
+            last_alert: None,
+            last_send_error: None,
+            error_alerted: false,
+            disconnected_since: None,
+            disconnect_alerted: false,
+            wifi_reconnects: Vec::new(),
+        }
+    }
+
+    pub fn record_wifi_reconnect(&mut self) {
+        self.wifi_reconnects.push(Instant::now());
+    }
+
+    /// Returns the messages that should be sent given the current heat pump state
+    pub fn check(&mut self, connected: bool, error_data: &Option<Vec<u8>>) -> Vec<String> {
+        let mut messages = Vec::new();
+
+        match error_data {
+            Some(data) if !self.error_alerted => {
+                messages.push(format!("Heat pump is reporting an error: {:02x?}", data));
+                self.error_alerted = true;
+            }
+            None => { self.error_alerted = false; }
+            _ => {}
+        }
+
+        if connected {
+            self.disconnected_since = None;
+            self.disconnect_alerted = false;
+        } else {
+            let since = *self.disconnected_since.get_or_insert_with(Instant::now);
+            if !self.disconnect_alerted && since.elapsed() >= Duration::from_secs(self.config.disconnect_minutes * 60) {
+                messages.push(format!("Heat pump has been disconnected for more than {} minutes", self.config.disconnect_minutes));
+                self.disconnect_alerted = true;
+            }
+        }
+
+        let window = Duration::from_secs(self.config.wifi_storm_window_secs);
+        self.wifi_reconnects.retain(|t| t.elapsed() < window);
+        if self.wifi_reconnects.len() >= self.config.wifi_storm_count {
+            messages.push(format!("WiFi has reconnected {} times in the last {} minutes",
+                                  self.wifi_reconnects.len(), window.as_secs() / 60));
+            // start counting afresh so the same storm doesn't alert every loop
+            self.wifi_reconnects.clear();
+        }
+
+        if !self.config.enabled {
+            messages.clear();
+        }
+        messages
+    }
+
+    pub fn record(&mut self, message: &str, result: anyhow::Result<u16>) {
+        self.last_alert = Some(message.to_string());
+        self.last_send_error = match result {
+            Ok(code) if (200..300).contains(&code) => None,
+            Ok(code) => Some(format!("alert service responded with status {}", code)),
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(e) = &self.last_send_error {
+            info!("sending alert {:?} failed: {}", message, e);
+        }
+    }
+}
+
+pub fn send(config: &AlertConfig, message: &str) -> anyhow::Result<u16> {
+    info!("sending alert: {}", message);
+    match config.service {
+        AlertService::Ntfy => {
+            let auth = config.token.as_ref().map(|t| format!("Bearer {}", t));
+            let mut headers = vec![("Title", ALERT_TITLE)];
+            if let Some(a) = &auth {
+                headers.push(("Authorization", a.as_str()));
+            }
+            http_client::post(&config.url, "text/plain", &headers, message)
+        }
+        AlertService::Pushover => {
+            let body = json!({
+                "token": config.token,
+                "user": config.user_key,
+                "title": ALERT_TITLE,
+                "message": message,
+            });
+            http_client::post_json(PUSHOVER_URL, &[], &body.to_string())
+        }
+        AlertService::Webhook => {
+            let body = json!({"title": ALERT_TITLE, "message": message});
+            http_client::post_json(&config.url, &[], &body.to_string())
+        }
+    }
+}
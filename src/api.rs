@@ -0,0 +1,138 @@
+//! The heat pump's side of the HTTP API, as set.json takes it and status.json shows it.  heatpumpctl builds this same
+//! file for the host, so it has to stay free of esp-idf imports
+
+use serde::{Deserialize, Serialize};
+use strum::IntoEnumIterator;
+use strum_macros::{EnumIter, FromRepr};
+
+macro_rules! deserialize_by_name_or_number {
+    ($t:ty, $what:expr) => {
+        impl<'de> Deserialize<'de> for $t {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                deserialize_enum(deserializer, $what, <$t>::from_repr)
+            }
+        }
+    };
+}
+
+#[derive(Clone, Copy, PartialEq, FromRepr, Debug, Serialize, EnumIter)]
+pub enum HeatPumpMode {
+    Off = 0,
+    Heat = 1,
+    Dry = 2,
+    Cool = 3,
+    Fan = 7,
+    Auto = 8,
+}
+
+#[derive(Clone, Copy, PartialEq, FromRepr, Debug, Serialize, EnumIter)]
+pub enum FanSpeed {
+    Auto = 0,
+    Quiet = 1,
+    Low = 2,
+    Med = 3,
+    High = 5,
+    VeryHigh = 6,
+}
+
+#[derive(Clone, Copy, PartialEq, FromRepr, Debug, Serialize, EnumIter)]
+pub enum VaneDirection {
+    Auto = 0,
+    Horizontal=1,
+    MidHorizontal=2,
+    Midpoint=3,
+    MidVertical=4,
+    Vertical=5,
+    Swing=7,
+}
+
+#[derive(Clone, Copy, PartialEq, FromRepr, Debug, Serialize, EnumIter)]
+pub enum WideVaneDirection {
+    FarLeft=1,
+    Left=2,
+    Mid=3,
+    Right=4,
+    FarRight=5,
+    Split=8,
+    Swing=0x0c,
+    // ISee=0x80, //not really clear what's going on here, for now we just ignore this bit
+    Unknown=999,
+}
+
+// These four are serialized by name, as status.json shows them, but read back from the name in any case ("heat" as well
+// as "Heat", "very_high" for VeryHigh) or from the number on the wire, so hand-written requests are harder to get wrong
+deserialize_by_name_or_number!(HeatPumpMode, "mode");
+deserialize_by_name_or_number!(FanSpeed, "fan speed");
+deserialize_by_name_or_number!(VaneDirection, "vane direction");
+deserialize_by_name_or_number!(WideVaneDirection, "widevane direction");
+
+/// The names of an enum's values, as they're serialized
+pub fn enum_names<T: IntoEnumIterator + Serialize>() -> Vec<serde_json::Value> {
+    T::iter().map(|v| serde_json::to_value(v).unwrap()).collect()
+}
+
+fn deserialize_enum<'de, D, T>(deserializer: D, what: &str, from_number: fn(usize) -> Option<T>) -> Result<T, D::Error>
+where D: serde::Deserializer<'de>, T: IntoEnumIterator + Serialize {
+    fn normalize(name: &str) -> String {
+        name.chars().filter(|c| !matches!(c, '_' | '-' | ' ')).collect::<String>().to_ascii_lowercase()
+    }
+    let value = serde_json::Value::deserialize(deserializer)?;
+    let found = match &value {
+        serde_json::Value::String(name) => T::iter().find(|v| {
+            serde_json::to_value(v).ok().as_ref().and_then(|n| n.as_str()).map(normalize) == Some(normalize(name))
+        }),
+        serde_json::Value::Number(n) => n.as_u64().and_then(|n| from_number(n as usize)),
+        _ => None,
+    };
+    found.ok_or_else(|| {
+        let names: Vec<String> = enum_names::<T>().iter().filter_map(|n| n.as_str().map(|n| n.to_string())).collect();
+        serde::de::Error::custom(format!("{} is not a {}, expected one of {} (or its number)", value, what, names.join(", ")))
+    })
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnitSetting {
+    // The part of a set.json request that goes to the heat pump.  Anything left out isn't changed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poweron: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mode: Option<HeatPumpMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub desired_temperature_c: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fan_speed: Option<FanSpeed>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub vane: Option<VaneDirection>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub widevane: Option<WideVaneDirection>,
+}
+impl UnitSetting {
+    pub fn new() -> Self {
+        Self {
+            poweron: None,
+            mode: None,
+            desired_temperature_c: None,
+            fan_speed: None,
+            vane: None,
+            widevane: None,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.poweron.is_none() && self.mode.is_none() && self.desired_temperature_c.is_none() &&
+        self.fan_speed.is_none() && self.vane.is_none() && self.widevane.is_none()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnitStatus {
+    // The heat pump's part of status.json.  Until connected the rest is only the defaults, not the unit's
+    pub connected: bool,
+    pub poweron: bool,
+    pub mode: HeatPumpMode,
+    pub desired_temperature_c: f32,
+    pub fan_speed: FanSpeed,
+    pub vane: VaneDirection,
+    pub widevane: WideVaneDirection,
+    pub room_temperature_c: f32,  // -999 without a reading
+}
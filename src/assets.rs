@@ -0,0 +1,109 @@
+use std::ffi::CString;
+use std::io::Write;
+
+use log::info;
+use serde::Serialize;
+
+use esp_idf_svc::sys::{self, esp, EspError};
+
+// the "assets" SPIFFS partition in partitions.csv is mounted here
+pub const MOUNT_POINT: &str = "/assets";
+const PARTITION_LABEL: &str = "assets";
+// SPIFFS names are at most 32 bytes, and that includes the mount point
+const NAME_MAX_LEN: usize = 24;
+// the partition is 128K, so this leaves room for a couple of others
+pub const ASSET_MAX_LEN: usize = 96 * 1024;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AssetFile {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Mounts the assets partition, formatting it the first time
+pub fn mount() -> Result<(), EspError> {
+    let base_path = CString::new(MOUNT_POINT).unwrap();
+    let label = CString::new(PARTITION_LABEL).unwrap();
+    let conf = sys::esp_vfs_spiffs_conf_t {
+        base_path: base_path.as_ptr(),
+        partition_label: label.as_ptr(),
+        max_files: 4,
+        format_if_mount_failed: true,
+    };
+    // the strings are copied, so they can go once this returns
+    esp!(unsafe { sys::esp_vfs_spiffs_register(&conf) })?;
+    info!("Mounted the assets partition at {}", MOUNT_POINT);
+    Ok(())
+}
+
+/// Plain names only, so nothing can be written outside the mount point
+pub fn valid_name(name: &str) -> bool {
+    !name.is_empty() && name.len() <= NAME_MAX_LEN && !name.starts_with('.') &&
+        name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+}
+
+pub fn content_type(name: &str) -> &'static str {
+    match name.rsplit('.').next() {
+        Some("html") => "text/html",
+        Some("js") => "text/javascript",
+        Some("css") => "text/css",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+fn path(name: &str) -> String {
+    format!("{}/{}", MOUNT_POINT, name)
+}
+
+/// The contents of an uploaded asset, or None if there isn't one by that name (or the partition isn't mounted)
+pub fn read(name: &str) -> Option<Vec<u8>> {
+    std::fs::read(path(name)).ok()
+}
+
+pub fn list() -> Vec<AssetFile> {
+    let mut files: Vec<AssetFile> = match std::fs::read_dir(MOUNT_POINT) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| AssetFile {
+            name: e.file_name().to_string_lossy().into_owned(),
+            size: e.metadata().map_or(0, |m| m.len()),
+        }).collect(),
+        Err(_) => Vec::new(),
+    };
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    files
+}
+
+/// Writes an asset from chunks as they come in.  It's written to a temporary file and only renamed into place once
+/// it's all there, so a dropped upload leaves the old one being served
+pub struct AssetWriter {
+    name: String,
+    file: std::fs::File,
+}
+
+impl AssetWriter {
+    pub fn create(name: &str) -> std::io::Result<Self> {
+        Ok(Self {
+            name: name.to_string(),
+            file: std::fs::File::create(path(".upload"))?,
+        })
+    }
+
+    pub fn write(&mut self, chunk: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(chunk)
+    }
+
+    pub fn finish(self) -> std::io::Result<()> {
+        drop(self.file);
+        // SPIFFS rename won't replace an existing file
+        let _ = std::fs::remove_file(path(&self.name));
+        std::fs::rename(path(".upload"), path(&self.name))
+    }
+}
+
+/// Removes an asset, so the one built into the firmware is served again if there is one
+pub fn remove(name: &str) -> std::io::Result<()> {
+    std::fs::remove_file(path(name))
+}
@@ -0,0 +1,111 @@
+use std::collections::VecDeque;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::StatusPacketType;
+
+const TRANSACTIONS_KEPT: usize = 50;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TraceFrame {
+    pub ms_since_start: u64,
+    pub hex: String,
+    pub decode: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Transaction {
+    pub seq: u64,
+    pub tx: TraceFrame,
+    pub rx: Option<TraceFrame>,  // None if nothing came back
+}
+
+#[derive(Debug, Serialize)]
+pub struct BusTrace {
+    // The last few request/response pairs on the CN105 bus, for debugging and documenting the protocol
+    pub transactions: VecDeque<Transaction>,
+    #[serde(skip)]
+    next_seq: u64,
+    #[serde(skip)]
+    start: Instant,
+}
+
+impl BusTrace {
+    pub fn new() -> Self {
+        Self {
+            transactions: VecDeque::new(),
+            next_seq: 0,
+            start: Instant::now(),
+        }
+    }
+
+    fn frame(&self, bytes: &[u8]) -> TraceFrame {
+        TraceFrame {
+            ms_since_start: self.start.elapsed().as_millis() as u64,
+            hex: bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "),
+            decode: decode(bytes),
+        }
+    }
+
+    pub fn record_tx(&mut self, bytes: &[u8]) {
+        let tx = self.frame(bytes);
+        if self.transactions.len() >= TRANSACTIONS_KEPT {
+            self.transactions.pop_front();
+        }
+        self.transactions.push_back(Transaction { seq: self.next_seq, tx, rx: None });
+        self.next_seq += 1;
+    }
+
+    /// Attaches what was read to the last thing sent. Reading nothing leaves it as a timeout
+    pub fn record_rx(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        let rx = self.frame(bytes);
+        if let Some(last) = self.transactions.back_mut() {
+            if last.rx.is_none() {
+                last.rx = Some(rx);
+            }
+        }
+    }
+
+    /// The trace as a Mermaid sequence diagram
+    pub fn to_mermaid(&self) -> String {
+        let mut out = String::from("sequenceDiagram\n    participant C as Controller\n    participant H as Heat pump\n");
+        for t in self.transactions.iter() {
+            out += &format!("    C->>H: {} [{}]\n", t.tx.decode, t.tx.hex);
+            match &t.rx {
+                Some(rx) => { out += &format!("    H-->>C: {} [{}] (+{} ms)\n", rx.decode, rx.hex, 
+                                              rx.ms_since_start.saturating_sub(t.tx.ms_since_start)); }
+                None => { out += "    Note over H: no response\n"; }
+            }
+        }
+        out
+    }
+}
+
+/// A short description of a raw packet, e.g. "info request (RoomTemperature)"
+pub fn decode(bytes: &[u8]) -> String {
+    if bytes.len() < 6 || bytes[0] != 0xfc {
+        return "not a packet".to_string();
+    }
+    let name = match bytes[1] {
+        0x5a => "connect",
+        0x7a => "connect ack",
+        0x41 => "set",
+        0x61 => "set ack",
+        0x42 => "info request",
+        0x62 => "info response",
+        _ => "unknown type",
+    };
+    match (bytes[1], bytes.get(5)) {
+        (0x42, Some(subtype)) | (0x62, Some(subtype)) => {
+            match StatusPacketType::from_repr(*subtype as usize) {
+                Some(t) => format!("{} ({:?})", name, t),
+                None => format!("{} (unknown 0x{:02x})", name, subtype),
+            }
+        }
+        _ => name.to_string(),
+    }
+}
@@ -0,0 +1,69 @@
+use std::time::{Duration, Instant};
+
+// changes shorter than this are contact bounce
+const DEBOUNCE: Duration = Duration::from_millis(30);
+// a second press this soon after letting go of the first makes a double press
+const DOUBLE_PRESS_WINDOW: Duration = Duration::from_millis(400);
+// held longer than this it isn't a press at all, e.g. it's stuck or someone's leaning on it
+const PRESS_MAX_HOLD: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Press {
+    Single,
+    Double,
+}
+
+/// Turns readings of a button's pin, taken every few ms, into single and double presses.  A single press is only
+/// known to be one once the double press window has passed
+pub struct PressDetector {
+    raw_down: bool,
+    raw_since: Instant,
+    down: bool,  // the debounced state
+    pressed_at: Instant,
+    released_at: Instant,
+    presses: u8,
+}
+
+impl PressDetector {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            raw_down: false,
+            raw_since: now,
+            down: false,
+            pressed_at: now,
+            released_at: now,
+            presses: 0,
+        }
+    }
+
+    pub fn update(&mut self, down: bool) -> Option<Press> {
+        let now = Instant::now();
+        if down != self.raw_down {
+            self.raw_down = down;
+            self.raw_since = now;
+        }
+
+        if self.raw_down != self.down && now - self.raw_since >= DEBOUNCE {
+            self.down = self.raw_down;
+            if self.down {
+                self.pressed_at = now;
+            } else if now - self.pressed_at <= PRESS_MAX_HOLD {
+                self.presses += 1;
+                self.released_at = now;
+                if self.presses >= 2 {
+                    self.presses = 0;
+                    return Some(Press::Double);
+                }
+            } else {
+                self.presses = 0;
+            }
+        }
+
+        if !self.down && self.presses == 1 && now - self.released_at >= DOUBLE_PRESS_WINDOW {
+            self.presses = 0;
+            return Some(Press::Single);
+        }
+        None
+    }
+}
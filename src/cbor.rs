@@ -0,0 +1,86 @@
+use serde_json::Value;
+
+/// Encodes a JSON value as CBOR (RFC 8949).  The responses are all built as serde_json Values, so this is all that's
+/// needed, rather than another serde format in the image
+pub fn to_vec(value: &Value) -> Vec<u8> {
+    let mut out = Vec::new();
+    write_value(&mut out, value);
+    out
+}
+
+/// Whether an Accept header asks for CBOR
+pub fn accepted(accept: Option<&str>) -> bool {
+    accept.map_or(false, |header| {
+        header.split(',').any(|media_type| {
+            let mut parts = media_type.split(';');
+            let name = parts.next().unwrap_or("").trim();
+            let refused = parts.any(|p| matches!(p.trim(), "q=0" | "q=0.0" | "q=0.00" | "q=0.000"));
+            name == "application/cbor" && !refused
+        })
+    })
+}
+
+// the initial byte and the argument after it, in as few bytes as will hold it
+fn write_head(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    match n {
+        0..=23 => out.push(major | n as u8),
+        24..=0xff => out.extend_from_slice(&[major | 24, n as u8]),
+        0x100..=0xffff => {
+            out.push(major | 25);
+            out.extend_from_slice(&(n as u16).to_be_bytes());
+        }
+        0x1_0000..=0xffff_ffff => {
+            out.push(major | 26);
+            out.extend_from_slice(&(n as u32).to_be_bytes());
+        }
+        _ => {
+            out.push(major | 27);
+            out.extend_from_slice(&n.to_be_bytes());
+        }
+    }
+}
+
+fn write_value(out: &mut Vec<u8>, value: &Value) {
+    match value {
+        Value::Null => out.push(0xf6),
+        Value::Bool(false) => out.push(0xf4),
+        Value::Bool(true) => out.push(0xf5),
+        Value::Number(n) => {
+            if let Some(u) = n.as_u64() {
+                write_head(out, 0, u);
+            } else if let Some(i) = n.as_i64() {
+                // negative, as as_u64 would have had it otherwise
+                write_head(out, 1, (-1 - i) as u64);
+            } else {
+                let f = n.as_f64().unwrap_or(f64::NAN);
+                // most of the floats here started out as f32s, so take half the space
+                if (f as f32) as f64 == f {
+                    out.push(0xfa);
+                    out.extend_from_slice(&(f as f32).to_be_bytes());
+                } else {
+                    out.push(0xfb);
+                    out.extend_from_slice(&f.to_be_bytes());
+                }
+            }
+        }
+        Value::String(s) => {
+            write_head(out, 3, s.len() as u64);
+            out.extend_from_slice(s.as_bytes());
+        }
+        Value::Array(items) => {
+            write_head(out, 4, items.len() as u64);
+            for item in items {
+                write_value(out, item);
+            }
+        }
+        Value::Object(map) => {
+            write_head(out, 5, map.len() as u64);
+            for (key, item) in map {
+                write_head(out, 3, key.len() as u64);
+                out.extend_from_slice(key.as_bytes());
+                write_value(out, item);
+            }
+        }
+    }
+}
@@ -0,0 +1,47 @@
+#![allow(dead_code)]
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// anything before this means SNTP hasn't set the clock yet (the esp starts at the epoch)
+const EARLIEST_VALID_UNIX_SECS: u64 = 1_700_000_000;
+
+pub const DEFAULT_NTP_SERVER: &str = "pool.ntp.org";
+
+/// Seconds since the unix epoch, or None if the clock hasn't been set yet
+pub fn unix_secs() -> Option<u64> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.as_secs();
+    if secs < EARLIEST_VALID_UNIX_SECS {
+        None
+    } else {
+        Some(secs)
+    }
+}
+
+/// The (year, month, day, hour, minute, second) in UTC for a unix time
+pub fn civil_from_unix(unix_secs: u64) -> (i64, u32, u32, u32, u32, u32) {
+    // from Howard Hinnant's civil_from_days: http://howardhinnant.github.io/date_algorithms.html
+    let days = (unix_secs / 86400) as i64;
+    let secs_of_day = (unix_secs % 86400) as u32;
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = (if mp < 10 { mp + 3 } else { mp - 9 }) as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    (year, month, day, secs_of_day / 3600, (secs_of_day / 60) % 60, secs_of_day % 60)
+}
+
+pub fn iso8601(unix_secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = civil_from_unix(unix_secs);
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, hour, minute, second)
+}
+
+/// The current time as an ISO8601 UTC string, or None if the clock hasn't been set yet
+pub fn iso8601_now() -> Option<String> {
+    unix_secs().map(iso8601)
+}
@@ -0,0 +1,99 @@
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::http_client;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CloudPushConfig {
+    pub enabled: bool,
+    pub url: String,
+    // the token is stored separately in NVS and never serialized so it can't leak through status.json
+    #[serde(default, skip_serializing)]
+    pub token: Option<String>,
+    pub interval_secs: u64,
+}
+impl CloudPushConfig {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            url: String::new(),
+            token: None,
+            interval_secs: 60,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled && !(self.url.starts_with("https://") || self.url.starts_with("http://")) {
+            return Err("url must start with https:// or http://".to_string());
+        }
+        if self.interval_secs < 10 {
+            return Err("interval_secs must be at least 10".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CloudPush {
+    // Periodically POSTs the status to a user-run relay, so the controller can be seen from outside the LAN
+    pub config: CloudPushConfig,
+    pub last_status_code: Option<u16>,
+    pub last_error: Option<String>,
+    pub consecutive_failures: u32,
+    #[serde(skip)]
+    pub last_push: Option<Instant>,
+}
+
+impl CloudPush {
+    pub fn new() -> Self {
+        Self {
+            config: CloudPushConfig::new(),
+            last_status_code: None,
+            last_error: None,
+            consecutive_failures: 0,
+            last_push: None,
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.config.enabled &&
+            self.last_push.map_or(true, |t| t.elapsed() >= Duration::from_secs(self.config.interval_secs))
+    }
+
+    /// Records the outcome of a push. Errors are kept rather than returned since a relay being down
+    /// shouldn't take the controller down with it.
+    pub fn record(&mut self, result: anyhow::Result<u16>) {
+        self.last_push = Some(Instant::now());
+        match result {
+            Ok(code) => {
+                self.last_status_code = Some(code);
+                if (200..300).contains(&code) {
+                    self.last_error = None;
+                    self.consecutive_failures = 0;
+                } else {
+                    self.last_error = Some(format!("relay responded with status {}", code));
+                    self.consecutive_failures += 1;
+                }
+            }
+            Err(e) => {
+                self.last_error = Some(e.to_string());
+                self.consecutive_failures += 1;
+            }
+        }
+        if let Some(e) = &self.last_error {
+            info!("status push to {} failed ({} in a row): {}", self.config.url, self.consecutive_failures, e);
+        }
+    }
+}
+
+/// POSTs the status body to the relay, with the token as a bearer token if there is one
+pub fn send(config: &CloudPushConfig, body: &str) -> anyhow::Result<u16> {
+    let auth = config.token.as_ref().map(|t| format!("Bearer {}", t));
+    let mut headers = Vec::new();
+    if let Some(a) = &auth {
+        headers.push(("Authorization", a.as_str()));
+    }
+    http_client::post_json(&config.url, &headers, body)
+}
@@ -0,0 +1,316 @@
+use std::collections::BTreeMap;
+use std::ffi::CString;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use esp_idf_svc::nvs;
+use esp_idf_svc::sys::{self, esp, EspError};
+
+use crate::nvs_get_string;
+
+// the periodic reboot, which can be changed at runtime (0 to turn it off)
+pub const REBOOT_PERIOD_DEFAULT_MINS: u32 = 90;
+pub const LED_DEFAULT_BRIGHTNESS: u8 = 20;
+// how long after a mode change to tolerate odd status values while the unit transitions
+pub const MODE_SETTLE_DEFAULT_SECS: u32 = 10;
+pub const STATUS_POLL_DEFAULT_MS: u64 = 1000;
+// a UI slider sends a set.json for each step it's dragged through, and at 2400 baud each one takes the bus for a while,
+// so sets this close together are merged and sent as one
+pub const SET_DEBOUNCE_DEFAULT_MS: u64 = 300;
+// set.json requests a minute, for everyone together and for any one address.  0 turns either off
+pub const SET_RATE_DEFAULT_PER_MIN: u32 = 60;
+pub const SET_CLIENT_RATE_DEFAULT_PER_MIN: u32 = 30;
+
+// the keys these used to be kept under, one each, before there was a config
+const LEGACY_KEYS: [&str; 4] = ["led_brightness", "controller_loc", "reboot_period", "mode_settle"];
+
+pub const EXPORT_VERSION: u32 = 1;
+// an export is a good deal bigger than anything else that gets POSTed
+pub const IMPORT_MAX_LEN: usize = 8192;
+// the settings that go in an export, stored as JSON.  Left out are the secrets (tokens, keys and the wifi networks,
+// whose passwords are kept alongside them), and what's recorded rather than set, like the crash log and meter count
+const EXPORTED_JSON_KEYS: [&str; 24] = ["config", "presets", "sniffer", "wifi_radio", "static_ip", "setpoint_limits", "thermostat",
+    "schedule", "temp_smoothing", "pid", "cloud_push", "coordinator", "follow", "bus_watchdog", "alerts", "adherence_cfg",
+    "rate_model", "energy_meter", "capabilities", "ui_language", "reboot_window", "mdns", "watchdog", "ota"];
+// and the ones stored as plain strings
+const EXPORTED_STR_KEYS: [&str; 3] = ["ntp_server", "syslog_server", "wifi_country"];
+// the keys kept under a different name from the set.json field that sets them
+const RENAMED_KEYS: [(&str, &str); 2] = [("temp_smoothing", "room_temperature_smoothing"), ("adherence_cfg", "adherence")];
+// and the ones that aren't set through set.json at all, so are checked on their own
+const NOT_SETTING_KEYS: [&str; 3] = ["config", "presets", "rate_model"];
+
+/// The controller's own settings, kept together as one JSON value in NVS under "config"
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ControllerConfig {
+    pub led_brightness: u8,
+    pub location: Option<String>,
+    pub reboot_period_mins: u32,  // 0 means never
+    pub mode_settle_secs: u32,
+    pub status_poll_ms: u64,
+    pub restore_on_boot: bool,  // send the last applied setting again once connected, for units without auto restart
+    pub set_debounce_ms: u64,
+    pub set_rate_per_min: u32,
+    pub set_client_rate_per_min: u32,
+}
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            led_brightness: LED_DEFAULT_BRIGHTNESS,
+            location: None,
+            reboot_period_mins: REBOOT_PERIOD_DEFAULT_MINS,
+            mode_settle_secs: MODE_SETTLE_DEFAULT_SECS,
+            status_poll_ms: STATUS_POLL_DEFAULT_MS,
+            restore_on_boot: false,
+            set_debounce_ms: SET_DEBOUNCE_DEFAULT_MS,
+            set_rate_per_min: SET_RATE_DEFAULT_PER_MIN,
+            set_client_rate_per_min: SET_CLIENT_RATE_DEFAULT_PER_MIN,
+        }
+    }
+}
+
+impl ControllerConfig {
+    /// Reads the config, moving the settings over from their old separate keys the first time
+    pub fn load(nvs_settings: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<Self> {
+        if let Some(json) = nvs_get_string(nvs_settings, "config")? {
+            return match serde_json::from_str(&json) {
+                Ok(config) => Ok(config),
+                Err(e) => {
+                    info!("Stored config could not be read, using the defaults: {}", e);
+                    Ok(Self::default())
+                }
+            };
+        }
+
+        let defaults = Self::default();
+        let config = Self {
+            led_brightness: nvs_settings.get_u8("led_brightness")?.unwrap_or(defaults.led_brightness),
+            location: nvs_get_string(nvs_settings, "controller_loc")?,
+            reboot_period_mins: nvs_settings.get_u32("reboot_period")?.unwrap_or(defaults.reboot_period_mins),
+            mode_settle_secs: nvs_settings.get_u32("mode_settle")?.unwrap_or(defaults.mode_settle_secs),
+            status_poll_ms: defaults.status_poll_ms,
+            restore_on_boot: defaults.restore_on_boot,
+            set_debounce_ms: defaults.set_debounce_ms,
+            set_rate_per_min: defaults.set_rate_per_min,
+            set_client_rate_per_min: defaults.set_client_rate_per_min,
+        };
+        config.save(nvs_settings)?;
+        for key in LEGACY_KEYS {
+            nvs_settings.remove(key)?;
+        }
+        info!("Moved the controller settings into the config: {:?}", config);
+        Ok(config)
+    }
+
+    pub fn save(&self, nvs_settings: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<()> {
+        nvs_settings.set_str("config", &serde_json::to_string(self)?)?;
+        Ok(())
+    }
+
+    pub fn apply(&mut self, update: &ConfigUpdate) {
+        if let Some(led_brightness) = update.led_brightness { self.led_brightness = led_brightness; }
+        if let Some(location) = &update.location { self.location = Some(location.clone()); }
+        if let Some(mins) = update.reboot_period_mins { self.reboot_period_mins = mins; }
+        if let Some(secs) = update.mode_settle_secs { self.mode_settle_secs = secs; }
+        if let Some(ms) = update.status_poll_ms { self.status_poll_ms = ms; }
+        if let Some(restore) = update.restore_on_boot { self.restore_on_boot = restore; }
+        if let Some(ms) = update.set_debounce_ms { self.set_debounce_ms = ms; }
+        if let Some(rate) = update.set_rate_per_min { self.set_rate_per_min = rate; }
+        if let Some(rate) = update.set_client_rate_per_min { self.set_client_rate_per_min = rate; }
+    }
+
+    /// The whole config as an update, for checking it with the same limits
+    pub fn as_update(&self) -> ConfigUpdate {
+        ConfigUpdate {
+            led_brightness: Some(self.led_brightness),
+            location: self.location.clone(),
+            reboot_period_mins: Some(self.reboot_period_mins),
+            mode_settle_secs: Some(self.mode_settle_secs),
+            status_poll_ms: Some(self.status_poll_ms),
+            restore_on_boot: Some(self.restore_on_boot),
+            set_debounce_ms: Some(self.set_debounce_ms),
+            set_rate_per_min: Some(self.set_rate_per_min),
+            set_client_rate_per_min: Some(self.set_client_rate_per_min),
+        }
+    }
+}
+
+/// A change to some of the config, as POSTed to /config.json.  Anything left out stays as it is
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigUpdate {
+    pub led_brightness: Option<u8>,
+    pub location: Option<String>,
+    pub reboot_period_mins: Option<u32>,
+    pub mode_settle_secs: Option<u32>,
+    pub status_poll_ms: Option<u64>,
+    pub restore_on_boot: Option<bool>,
+    pub set_debounce_ms: Option<u64>,
+    pub set_rate_per_min: Option<u32>,
+    pub set_client_rate_per_min: Option<u32>,
+}
+impl ConfigUpdate {
+    pub fn is_empty(&self) -> bool {
+        self.led_brightness.is_none() && self.location.is_none() && self.reboot_period_mins.is_none()
+            && self.mode_settle_secs.is_none() && self.status_poll_ms.is_none() && self.restore_on_boot.is_none()
+            && self.set_debounce_ms.is_none() && self.set_rate_per_min.is_none() && self.set_client_rate_per_min.is_none()
+    }
+
+    /// Folds a later update in on top of this one
+    pub fn merge(&mut self, later: ConfigUpdate) {
+        if later.led_brightness.is_some() { self.led_brightness = later.led_brightness; }
+        if later.location.is_some() { self.location = later.location; }
+        if later.reboot_period_mins.is_some() { self.reboot_period_mins = later.reboot_period_mins; }
+        if later.mode_settle_secs.is_some() { self.mode_settle_secs = later.mode_settle_secs; }
+        if later.status_poll_ms.is_some() { self.status_poll_ms = later.status_poll_ms; }
+        if later.restore_on_boot.is_some() { self.restore_on_boot = later.restore_on_boot; }
+        if later.set_debounce_ms.is_some() { self.set_debounce_ms = later.set_debounce_ms; }
+        if later.set_rate_per_min.is_some() { self.set_rate_per_min = later.set_rate_per_min; }
+        if later.set_client_rate_per_min.is_some() { self.set_client_rate_per_min = later.set_client_rate_per_min; }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(mins) = self.reboot_period_mins {
+            if mins != 0 && mins < 10 {
+                return Err("reboot_period_mins must be 0 (off) or at least 10".to_string());
+            }
+        }
+        if let Some(secs) = self.mode_settle_secs {
+            if secs > 600 {
+                return Err("mode_settle_secs must be at most 600".to_string());
+            }
+        }
+        if let Some(ms) = self.status_poll_ms {
+            // much faster than a second and the unit doesn't keep up; much slower and the status isn't much use
+            if !(1000..=60000).contains(&ms) {
+                return Err("status_poll_ms must be between 1000 and 60000".to_string());
+            }
+        }
+        if let Some(ms) = self.set_debounce_ms {
+            // longer and the UI feels like it isn't listening
+            if ms > 5000 {
+                return Err("set_debounce_ms must be at most 5000".to_string());
+            }
+        }
+        if let Some(location) = &self.location {
+            if location.len() > 64 {
+                return Err("location must be at most 64 characters".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Everything that's been set on a controller, for moving it over to another one
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigExport {
+    pub version: u32,
+    pub wifi_channel: Option<u8>,
+    pub settings: BTreeMap<String, serde_json::Value>,
+}
+
+impl ConfigExport {
+    pub fn read(nvs_settings: &nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<Self> {
+        let mut settings = BTreeMap::new();
+        for key in EXPORTED_JSON_KEYS {
+            if let Some(json) = nvs_get_string(nvs_settings, key)? {
+                match serde_json::from_str(&json) {
+                    Ok(value) => { settings.insert(key.to_string(), value); }
+                    Err(e) => { info!("Leaving {} out of the export, as it could not be read: {}", key, e); }
+                }
+            }
+        }
+        for key in EXPORTED_STR_KEYS {
+            if let Some(value) = nvs_get_string(nvs_settings, key)? {
+                settings.insert(key.to_string(), serde_json::Value::String(value));
+            }
+        }
+        Ok(Self {
+            version: EXPORT_VERSION,
+            wifi_channel: nvs_settings.get_u8("wifi_channel")?,
+            settings,
+        })
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.version != EXPORT_VERSION {
+            return Err(format!("version must be {}", EXPORT_VERSION));
+        }
+        if let Some(channel) = self.wifi_channel {
+            if !(1..=13).contains(&channel) {
+                return Err("wifi_channel must be between 1 and 13".to_string());
+            }
+        }
+        for (key, value) in &self.settings {
+            if EXPORTED_STR_KEYS.contains(&key.as_str()) {
+                if !value.is_string() {
+                    return Err(format!("{} must be a string", key));
+                }
+            } else if !EXPORTED_JSON_KEYS.contains(&key.as_str()) {
+                return Err(format!("{} is not a setting that can be imported", key));
+            }
+        }
+        if let Some(config) = self.settings.get("config") {
+            let config = serde_json::from_value::<ControllerConfig>(config.clone()).map_err(|e| format!("config: {}", e))?;
+            config.as_update().validate().map_err(|msg| format!("config: {}", msg))?;
+        }
+        Ok(())
+    }
+
+    /// The settings as they'd be POSTed to set.json, for checking them the same way.  Leaves out the config, presets
+    /// and rate model, which aren't set through it
+    pub fn setting_json(&self) -> serde_json::Value {
+        let mut setting = serde_json::Map::new();
+        for (key, value) in &self.settings {
+            if NOT_SETTING_KEYS.contains(&key.as_str()) {
+                continue;
+            }
+            let field = RENAMED_KEYS.iter().find(|(stored, _)| stored == key).map_or(key.as_str(), |(_, field)| field);
+            setting.insert(field.to_string(), value.clone());
+        }
+        serde_json::Value::Object(setting)
+    }
+
+    /// The key a set.json field is kept under, to name it in errors the way the export does
+    pub fn stored_key(field: &str) -> &str {
+        RENAMED_KEYS.iter().find(|(_, f)| *f == field).map_or(field, |(stored, _)| stored)
+    }
+
+    /// Stores every setting in the export, leaving the ones it doesn't have as they are.  They're only read on boot
+    pub fn write(&self, nvs_settings: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<()> {
+        if let Some(channel) = self.wifi_channel {
+            nvs_settings.set_u8("wifi_channel", channel)?;
+        }
+        for (key, value) in &self.settings {
+            match value {
+                serde_json::Value::String(s) if EXPORTED_STR_KEYS.contains(&key.as_str()) => { nvs_settings.set_str(key, s)?; }
+                _ => { nvs_settings.set_str(key, &value.to_string())?; }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Forgets everything that's been set, including the networks the wifi driver keeps for itself, and has the next boot
+/// go to AP mode for provisioning.  The caller restarts
+pub fn factory_reset(nvs_settings: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<()> {
+    erase_namespace("settings")?;
+    erase_namespace("nvs.net80211")?;
+    nvs_settings.set_u8("wifi_force_ap", 1)?;
+    info!("Erased all the settings");
+    Ok(())
+}
+
+/// Erases every key in an NVS namespace.  The EspNvs wrapper only removes keys one at a time, by name
+pub fn erase_namespace(namespace: &str) -> Result<(), EspError> {
+    let name = CString::new(namespace).unwrap();
+    let mut handle: sys::nvs_handle_t = 0;
+    unsafe {
+        esp!(sys::nvs_open(name.as_ptr(), sys::nvs_open_mode_t_NVS_READWRITE, &mut handle))?;
+        let result = esp!(sys::nvs_erase_all(handle)).and_then(|_| esp!(sys::nvs_commit(handle)));
+        sys::nvs_close(handle);
+        result
+    }
+}
@@ -0,0 +1,174 @@
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::http_client;
+use crate::Peer;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct CoordinatorConfig {
+    pub enabled: bool,
+    pub poll_secs: u64,  // how often each unit's status is fetched
+}
+impl CoordinatorConfig {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            poll_secs: 60,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.poll_secs < 10 {
+            return Err("poll_secs must be at least 10".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Unit {
+    // another controller as seen by this one, with its status.json as of the last poll
+    pub hostname: String,
+    pub url: String,
+    pub location: Option<String>,
+    pub status: Option<serde_json::Value>,
+    pub last_error: Option<String>,
+    pub last_set_status_code: Option<u16>,
+    #[serde(skip)]
+    pub updated: Option<Instant>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnitSetRequest {
+    pub target: String,  // a hostname, a controller_location, or "all"
+    pub setting: serde_json::Value,  // passed on as-is, so each unit validates it for itself
+}
+
+#[derive(Debug, Serialize)]
+pub struct Coordinator {
+    // Lets one controller report on and set all the others found via mDNS, so a dashboard only needs one address
+    pub config: CoordinatorConfig,
+    pub units: Vec<Unit>,
+    #[serde(skip)]
+    pub pending_sets: Vec<(String, String)>,  // (hostname, body) waiting to be sent from the outbound task
+    #[serde(skip)]
+    next_poll: usize,
+}
+
+impl Coordinator {
+    pub fn new() -> Self {
+        Self {
+            config: CoordinatorConfig::new(),
+            units: Vec::new(),
+            pending_sets: Vec::new(),
+            next_poll: 0,
+        }
+    }
+
+    /// Brings the unit list in line with the latest mDNS browse, keeping what's known about units still there
+    pub fn update_units(&mut self, peers: &[Peer]) {
+        let mut units = Vec::new();
+        for peer in peers {
+            let (Some(hostname), Some(address)) = (&peer.hostname, peer.addresses.first()) else { continue; };
+            let url = format!("http://{}:{}", address, peer.port);
+            let mut unit = self.units.iter().find(|u| &u.hostname == hostname).cloned().unwrap_or(Unit {
+                hostname: hostname.clone(),
+                url: url.clone(),
+                location: None,
+                status: None,
+                last_error: None,
+                last_set_status_code: None,
+                updated: None,
+            });
+            unit.url = url;
+            unit.location = peer.location.clone().filter(|l| !l.is_empty());
+            units.push(unit);
+        }
+        self.units = units;
+    }
+
+    /// Whether the target of a set request means this unit
+    pub fn matches(unit_hostname: &str, unit_location: Option<&str>, target: &str) -> bool {
+        target == "all" || target == unit_hostname || unit_location == Some(target)
+    }
+
+    /// Queues a setting for every unit the target matches, returning their hostnames
+    pub fn queue_set(&mut self, request: &UnitSetRequest) -> Vec<String> {
+        let body = request.setting.to_string();
+        let matched: Vec<String> = self.units.iter()
+            .filter(|u| Self::matches(&u.hostname, u.location.as_deref(), &request.target))
+            .map(|u| u.hostname.clone())
+            .collect();
+        for hostname in matched.iter() {
+            self.pending_sets.push((hostname.clone(), body.clone()));
+        }
+        matched
+    }
+
+    /// The next unit whose status is due to be fetched, if any.  Only one is done per pass so the outbound task
+    /// never waits on more than one slow unit at a time
+    pub fn next_poll_url(&mut self) -> Option<(String, String)> {
+        if !self.config.enabled || self.units.is_empty() {
+            return None;
+        }
+        let poll_interval = Duration::from_secs(self.config.poll_secs);
+        for _ in 0..self.units.len() {
+            self.next_poll = (self.next_poll + 1) % self.units.len();
+            let unit = &self.units[self.next_poll];
+            if unit.updated.map_or(true, |t| t.elapsed() >= poll_interval) {
+                return Some((unit.hostname.clone(), format!("{}/status.json", unit.url)));
+            }
+        }
+        None
+    }
+
+    /// The url and body of the next queued set, if any
+    pub fn next_set(&mut self) -> Option<(String, String, String)> {
+        while !self.pending_sets.is_empty() {
+            let (hostname, body) = self.pending_sets.remove(0);
+            if let Some(unit) = self.units.iter().find(|u| u.hostname == hostname) {
+                return Some((hostname, format!("{}/set.json", unit.url), body));
+            }
+        }
+        None
+    }
+
+    pub fn record_poll(&mut self, hostname: &str, result: anyhow::Result<(u16, String)>) {
+        let Some(unit) = self.units.iter_mut().find(|u| u.hostname == hostname) else { return; };
+        unit.updated = Some(Instant::now());
+        let parsed = result.and_then(|(code, body)| {
+            if (200..300).contains(&code) {
+                Ok(serde_json::from_str::<serde_json::Value>(&body)?)
+            } else {
+                Err(anyhow::anyhow!("responded with status {}", code))
+            }
+        });
+        match parsed {
+            Ok(status) => {
+                unit.status = Some(status);
+                unit.last_error = None;
+            }
+            Err(e) => {
+                info!("could not get the status of {}: {}", hostname, e);
+                unit.last_error = Some(e.to_string());
+            }
+        }
+    }
+
+    pub fn record_set(&mut self, hostname: &str, result: anyhow::Result<u16>) {
+        let Some(unit) = self.units.iter_mut().find(|u| u.hostname == hostname) else { return; };
+        match result {
+            Ok(code) => {
+                unit.last_set_status_code = Some(code);
+                // fetch it again soon so the change shows up
+                unit.updated = None;
+            }
+            Err(e) => {
+                info!("could not send setting to {}: {}", hostname, e);
+                unit.last_error = Some(e.to_string());
+            }
+        }
+    }
+}
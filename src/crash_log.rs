@@ -0,0 +1,176 @@
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use esp_idf_hal::reset;
+use esp_idf_svc::nvs;
+use esp_idf_svc::sys;
+
+use crate::clock;
+
+const ENTRIES_KEPT: usize = 10;
+// in recover mode, this many trips in a row with the loop making no progress at all means it's stuck for good
+const STUCK_TRIPS_BEFORE_RESET: u32 = 3;
+
+// bumped from the TWDT interrupt, and by the main loop each time around, so they can't be behind a mutex
+static TWDT_TRIPS: AtomicU32 = AtomicU32::new(0);
+static LOOP_HEARTBEAT: AtomicU32 = AtomicU32::new(0);
+
+// A panic on a TWDT trip resets with the same reason as any other panic, so the interrupt also leaves this in memory
+// that survives the reset.  It's garbage after a power cycle, hence the magic value
+const TRIP_MARKER_MAGIC: u32 = 0x5744_5431;
+#[link_section = ".rtc_noinit"]
+static TRIP_MARKER: AtomicU32 = AtomicU32::new(0);
+
+/// Called by ESP-IDF from the TWDT interrupt whenever a watched task hasn't fed it in time
+#[no_mangle]
+pub extern "C" fn esp_task_wdt_isr_user_handler() {
+    TWDT_TRIPS.fetch_add(1, Ordering::Relaxed);
+    TRIP_MARKER.store(TRIP_MARKER_MAGIC, Ordering::Relaxed);
+}
+
+/// For when a trip has been recorded some other way, so it's not also taken as the cause of the next reset
+pub fn clear_trip_marker() {
+    TRIP_MARKER.store(0, Ordering::Relaxed);
+}
+
+/// Whether the last reset came straight after a TWDT trip that nothing else recorded, clearing it for next time
+pub fn tripped_before_reset() -> bool {
+    TRIP_MARKER.swap(0, Ordering::Relaxed) == TRIP_MARKER_MAGIC
+}
+
+pub fn twdt_trips() -> u32 {
+    TWDT_TRIPS.load(Ordering::Relaxed)
+}
+
+pub fn heartbeat() {
+    LOOP_HEARTBEAT.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The name of the FreeRTOS task this is called from
+pub fn current_task_name() -> String {
+    let name = unsafe { sys::pcTaskGetName(std::ptr::null_mut()) };
+    if name.is_null() {
+        return "unknown".to_string();
+    }
+    unsafe { CStr::from_ptr(name) }.to_string_lossy().into_owned()
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WatchdogConfig {
+    pub panic: bool,  // panic (and so reset) on a trip, rather than logging it and trying to recover
+    pub timeout_secs: u64,
+}
+impl WatchdogConfig {
+    pub fn new() -> Self {
+        Self {
+            panic: true,
+            timeout_secs: 10,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        // the loop can legitimately block for the http client's 5 s timeout
+        if self.timeout_secs < 8 || self.timeout_secs > 120 {
+            return Err("timeout_secs must be between 8 and 120".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CrashEntry {
+    pub boot_count: u32,
+    pub secs_since_boot: Option<f32>,  // None if it was only found out about after the reset
+    pub time: Option<String>,
+    pub task: String,
+    pub action: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CrashLog {
+    // Every watchdog trip and what was done about it, kept in NVS so the ones that ended in a reset are still there
+    pub entries: VecDeque<CrashEntry>,
+    #[serde(skip)]
+    dirty: bool,
+}
+
+impl CrashLog {
+    pub fn new() -> Self {
+        Self {
+            entries: VecDeque::new(),
+            dirty: false,
+        }
+    }
+
+    pub fn load(json: &str) -> Result<Self, serde_json::Error> {
+        Ok(Self {
+            entries: serde_json::from_str(json)?,
+            dirty: false,
+        })
+    }
+
+    pub fn record(&mut self, boot_count: u32, boot_instant: Option<Instant>, task: &str, action: &str) {
+        info!("watchdog trip in task {}: {}", task, action);
+        if self.entries.len() >= ENTRIES_KEPT {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(CrashEntry {
+            boot_count,
+            secs_since_boot: boot_instant.map(|t| t.elapsed().as_secs_f32()),
+            time: boot_instant.and_then(|_| clock::iso8601_now()),
+            task: task.to_string(),
+            action: action.to_string(),
+        });
+        self.dirty = true;
+    }
+
+    /// The entries to store, if any have been added since last time
+    pub fn take_dirty_json(&mut self) -> Option<String> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        Some(serde_json::to_string(&self.entries).unwrap())
+    }
+}
+
+/// For the recover mode: logs every trip, and resets if the loop has stopped going around altogether.  Trips the loop
+/// comes back from are left to it to deal with, since it owns the subsystems to restart
+pub fn supervise(crash_log: Arc<Mutex<CrashLog>>, mut nvs_settings: nvs::EspNvs<nvs::NvsDefault>, task: String,
+                 boot_count: u32, boot_instant: Instant) -> ! {
+    let mut seen_trips = twdt_trips();
+    let mut heartbeat_at_trip = LOOP_HEARTBEAT.load(Ordering::Relaxed);
+    let mut stuck_trips = 0;
+    loop {
+        std::thread::sleep(Duration::from_secs(1));
+        let trips = twdt_trips();
+        if trips == seen_trips {
+            continue;
+        }
+        seen_trips = trips;
+        clear_trip_marker();
+
+        let heartbeat = LOOP_HEARTBEAT.load(Ordering::Relaxed);
+        stuck_trips = if heartbeat == heartbeat_at_trip { stuck_trips + 1 } else { 1 };
+        heartbeat_at_trip = heartbeat;
+
+        let mut log = crash_log.lock().unwrap();
+        if stuck_trips >= STUCK_TRIPS_BEFORE_RESET {
+            log.record(boot_count, Some(boot_instant), &task, "reset, loop stuck");
+            if let Some(json) = log.take_dirty_json() {
+                if let Err(e) = nvs_settings.set_str("crash_log", &json) {
+                    info!("Could not save the crash log before resetting: {}", e);
+                }
+            }
+            std::thread::sleep(Duration::from_millis(100));
+            reset::restart();
+        }
+        log.record(boot_count, Some(boot_instant), &task, "logged");
+    }
+}
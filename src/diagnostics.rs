@@ -0,0 +1,47 @@
+use std::ffi::CString;
+
+use serde::Serialize;
+
+use esp_idf_svc::sys;
+
+// the tasks worth watching in this firmware.  Ones that don't exist on a given chip or config are just left out
+const WATCHED_TASKS: &[&str] = &["main", "httpd", "IDLE0", "IDLE1", "Tmr Svc", "sys_evt", "tiT", "wifi", "esp_timer", "mdns"];
+
+#[derive(Debug, Serialize)]
+pub struct TaskStack {
+    pub name: String,
+    pub stack_high_water_bytes: u32,  // the least free stack the task has ever had
+}
+
+#[derive(Debug, Serialize)]
+pub struct Diagnostics {
+    pub free_heap_bytes: u32,
+    pub min_free_heap_bytes: u32,
+    pub largest_free_block_bytes: usize,
+    pub tasks: Vec<TaskStack>,
+}
+
+/// Snapshot of heap and task stack usage, for spotting slow leaks over long uptimes
+pub fn collect() -> Diagnostics {
+    let tasks = WATCHED_TASKS.iter().filter_map(|name| {
+        let cname = CString::new(*name).unwrap();
+        let handle = unsafe { sys::xTaskGetHandle(cname.as_ptr()) };
+        if handle.is_null() {
+            None
+        } else {
+            Some(TaskStack {
+                name: name.to_string(),
+                stack_high_water_bytes: unsafe { sys::uxTaskGetStackHighWaterMark(handle) },
+            })
+        }
+    }).collect();
+
+    unsafe {
+        Diagnostics {
+            free_heap_bytes: sys::esp_get_free_heap_size(),
+            min_free_heap_bytes: sys::esp_get_minimum_free_heap_size(),
+            largest_free_block_bytes: sys::heap_caps_get_largest_free_block(sys::MALLOC_CAP_8BIT),
+            tasks,
+        }
+    }
+}
@@ -0,0 +1,72 @@
+use serde::{Deserialize, Serialize};
+
+// the running total is written to NVS every this many pulses, so a reboot loses at most this many
+const SAVE_EVERY_PULSES: u64 = 100;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EnergyMeterConfig {
+    pub pulses_per_kwh: u32,  // printed on the meter, often as imp/kWh
+}
+impl EnergyMeterConfig {
+    pub fn new() -> Self {
+        Self {
+            pulses_per_kwh: 1000,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.pulses_per_kwh == 0 {
+            return Err("pulses_per_kwh must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct EnergyMeter {
+    // Energy measured by an S0/pulse output meter on the supply to the heat pump
+    pub config: EnergyMeterConfig,
+    pub present: bool,  // whether the firmware was built with the pulse input
+    pub total_pulses: u64,
+    pub kwh: f64,
+    #[serde(skip)]
+    pub saved_pulses: u64,
+}
+
+impl EnergyMeter {
+    pub fn new() -> Self {
+        Self {
+            config: EnergyMeterConfig::new(),
+            present: cfg!(feature="pulsemeter"),
+            total_pulses: 0,
+            kwh: 0.0,
+            saved_pulses: 0,
+        }
+    }
+
+    /// Starts from a total saved before the last reboot
+    pub fn restore(&mut self, total_pulses: u64) {
+        self.total_pulses = total_pulses;
+        self.saved_pulses = total_pulses;
+        self.update_kwh();
+    }
+
+    pub fn add_pulses(&mut self, pulses: u64) {
+        self.total_pulses += pulses;
+        self.update_kwh();
+    }
+
+    pub fn set_config(&mut self, config: EnergyMeterConfig) {
+        self.config = config;
+        self.update_kwh();
+    }
+
+    /// Whether enough has been counted since the last save that the total should be written out
+    pub fn needs_save(&self) -> bool {
+        self.total_pulses - self.saved_pulses >= SAVE_EVERY_PULSES
+    }
+
+    fn update_kwh(&mut self) {
+        self.kwh = self.total_pulses as f64 / self.config.pulses_per_kwh as f64;
+    }
+}
@@ -0,0 +1,189 @@
+#![allow(dead_code)]
+
+use std::fmt;
+
+use serde::Serialize;
+use serde_json::error::Category;
+
+use embedded_svc::io::ReadExactError;
+use esp_idf_hal::io::EspIOError;
+use esp_idf_hal::sys::EspError;
+
+use crate::packet::PacketError;
+
+/// The catalog of error codes.  The numbers are stable, and show up both in API error responses (as "code") and in
+/// the logs (as e.g. "E200"), so a bug report can say exactly which error it was.  New codes get new numbers; old
+/// ones are never reused for something else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum ErrorCode {
+    // 1xx: the request itself
+    BadJson = 100,
+    RequestTooBig = 101,
+    InvalidSetting = 102,
+    NotFound = 103,
+    Unauthorized = 104,
+    Busy = 105,
+    TimedOut = 106,
+    BadPacketHex = 107,
+    RateLimited = 108,
+    Disabled = 109,
+    // 2xx: the heat pump and the bus to it
+    HeatPumpUnavailable = 200,
+    BadStatusPacket = 201,
+    PacketTooShort = 202,
+    PacketNoSync = 203,
+    PacketLengthMismatch = 204,
+    PacketBadChecksum = 205,
+    PacketTooLong = 206,
+    // 3xx: websockets
+    WsSessionMissing = 300,
+    WsUnexpectedFrame = 301,
+    // 4xx: the platform underneath
+    EspIdf = 400,
+    Io = 401,
+    CorruptAsset = 402,
+}
+
+impl ErrorCode {
+    pub fn code(&self) -> u16 {
+        *self as u16
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "E{} {:?}", self.code(), self)
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(self.code())
+    }
+}
+
+pub struct FirmwareError {
+    pub code: ErrorCode,
+    pub message: String,
+    pub field: Option<String>,  // the request field that was wrong, if it was down to one
+    pub value: Option<serde_json::Value>,  // and what was given for it
+}
+
+impl FirmwareError {
+    pub fn new(code: ErrorCode, message: impl fmt::Display) -> Self {
+        Self { code, message: message.to_string(), field: None, value: None }
+    }
+
+    pub fn with_field(mut self, field: &str) -> Self {
+        self.field = Some(field.to_string());
+        self
+    }
+
+    pub fn with_value(mut self, value: impl Serialize) -> Self {
+        self.value = serde_json::to_value(value).ok();
+        self
+    }
+
+    /// For a request body that didn't deserialize.  serde_json doesn't say which field was wrong, so for a value that
+    /// isn't right (e.g. a mode that isn't one of the names) it's taken to be the last key before where it gave up
+    pub fn from_json_error(body: &[u8], e: &serde_json::Error) -> Self {
+        let field = match e.classify() {
+            Category::Data => json_error_field(body, e),
+            _ => None,
+        };
+        Self { field, ..Self::new(ErrorCode::BadJson, format!("JSON error: {}", e)) }
+    }
+
+    /// The body for an API error response.  "field" is always there, null if the error isn't down to one field
+    pub fn to_json(&self) -> serde_json::Value {
+        let mut jval = serde_json::json!({
+            "error": self.message,
+            "code": self.code,
+            "code_name": format!("{:?}", self.code),
+            "field": self.field,
+        });
+        if let Some(value) = &self.value {
+            jval["value"] = value.clone();
+        }
+        jval
+    }
+}
+
+fn json_error_field(body: &[u8], e: &serde_json::Error) -> Option<String> {
+    let message = e.to_string();
+    // these name the field themselves
+    for prefix in ["missing field `", "unknown field `", "duplicate field `"] {
+        if let Some(rest) = message.strip_prefix(prefix) {
+            return rest.split('`').next().map(|f| f.to_string());
+        }
+    }
+    // from_value errors have no position
+    if e.line() == 0 {
+        return None;
+    }
+    // line and column are 1-based, and the column counts bytes
+    let line_start: usize = body.split(|&b| b == b'\n').take(e.line() - 1).map(|line| line.len() + 1).sum();
+    let end = (line_start + e.column()).min(body.len());
+    let before = std::str::from_utf8(&body[..end]).ok()?;
+    for (colon, _) in before.rmatch_indices(':') {
+        if let Some(key) = before[..colon].trim_end().strip_suffix('"') {
+            if let Some(quote) = key.rfind('"') {
+                return Some(key[quote + 1..].to_string());
+            }
+        }
+    }
+    None
+}
+
+impl fmt::Display for FirmwareError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+// handler errors get logged with Debug, and those should have the code in them too
+impl fmt::Debug for FirmwareError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl std::error::Error for FirmwareError {}
+
+impl From<EspError> for FirmwareError {
+    fn from(e: EspError) -> Self {
+        Self::new(ErrorCode::EspIdf, e)
+    }
+}
+
+impl From<EspIOError> for FirmwareError {
+    fn from(e: EspIOError) -> Self {
+        Self::new(ErrorCode::Io, e)
+    }
+}
+
+impl<E: fmt::Debug> From<ReadExactError<E>> for FirmwareError {
+    fn from(e: ReadExactError<E>) -> Self {
+        Self::new(ErrorCode::Io, format!("{:?}", e))
+    }
+}
+
+// here rather than in packet.rs, so that builds on its own for the host tests
+impl From<&PacketError> for ErrorCode {
+    fn from(e: &PacketError) -> Self {
+        match e {
+            PacketError::TooShort => ErrorCode::PacketTooShort,
+            PacketError::NoSync => ErrorCode::PacketNoSync,
+            PacketError::LengthMismatch => ErrorCode::PacketLengthMismatch,
+            PacketError::BadChecksum => ErrorCode::PacketBadChecksum,
+            PacketError::TooLong => ErrorCode::PacketTooLong,
+        }
+    }
+}
+
+impl From<PacketError> for FirmwareError {
+    fn from(e: PacketError) -> Self {
+        Self::new(ErrorCode::from(&e), e.message())
+    }
+}
@@ -0,0 +1,64 @@
+use log::info;
+
+use esp_idf_hal as hal;
+use hal::gpio::{InputPin, OutputPin};
+use hal::peripheral::Peripheral;
+use hal::prelude::*;
+use hal::spi;
+
+use esp_idf_svc::{
+    eth::{BlockingEth, EspEth, EthDriver, SpiEth, SpiEthChipset},
+    eventloop::EspSystemEventLoop,
+};
+
+use crate::startup::StartupPhase;
+use crate::CONNECT_TIMEOUT;
+
+const W5500_SPI_CLOCK_MHZ: u32 = 20;
+
+pub type Ethernet<'a> = BlockingEth<EspEth<'a, SpiEth<spi::SpiDriver<'a>>>>;
+
+/// Brings up a W5500 SPI ethernet module with DHCP, in place of the wifi.  The esp32c6 has no internal ethernet MAC,
+/// so an RMII PHY isn't an option on it; the W5500 works on any board with a free SPI bus.
+pub fn setup_ethernet<'a>(spi: impl Peripheral<P = impl spi::SpiAnyPins> + 'a,
+                          sclk: impl Peripheral<P = impl OutputPin> + 'a,
+                          mosi: impl Peripheral<P = impl OutputPin> + 'a,
+                          miso: impl Peripheral<P = impl InputPin + OutputPin> + 'a,
+                          cs: impl Peripheral<P = impl OutputPin> + 'a,
+                          int: impl Peripheral<P = impl InputPin> + 'a,
+                          rst: impl Peripheral<P = impl OutputPin> + 'a,
+                          on_phase: &mut dyn FnMut(StartupPhase) -> anyhow::Result<()>) -> anyhow::Result<(Ethernet<'a>, Option<[u8; 6]>)> {
+    let sys_loop = EspSystemEventLoop::take()?;
+
+    // the W5500 doesn't come with a MAC address of its own, so use the one the esp reserves for ethernet
+    let mut mac = [0u8; 6];
+    hal::sys::esp!(unsafe { hal::sys::esp_read_mac(mac.as_mut_ptr(), hal::sys::esp_mac_type_t_ESP_MAC_ETH) })?;
+
+    let spi_driver = spi::SpiDriver::new(spi, sclk, mosi, Some(miso), &spi::SpiDriverConfig::new().dma(spi::Dma::Auto(4096)))?;
+    let mut eth = BlockingEth::wrap(
+        EspEth::wrap(EthDriver::new_spi(
+            spi_driver,
+            int,
+            Some(cs),
+            Some(rst),
+            SpiEthChipset::W5500,
+            W5500_SPI_CLOCK_MHZ.MHz().into(),
+            Some(&mac),
+            None,
+            sys_loop.clone(),
+        )?)?,
+        sys_loop,
+    )?;
+
+    on_phase(StartupPhase::WifiConnect)?;
+    eth.start()?;
+    info!("Ethernet started, waiting for DHCP");
+
+    on_phase(StartupPhase::Ip)?;
+    eth.ip_wait_while(|| eth.eth().netif().is_up().map(|s| !s), Some(CONNECT_TIMEOUT))?;
+
+    let ip = eth.eth().netif().get_ip_info()?;
+    info!("Ethernet DHCP info: {:?}", ip);
+
+    Ok((eth, Some(mac)))
+}
@@ -0,0 +1,128 @@
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::api::UnitStatus;
+use crate::{HeatPumpSetting, Peer};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FollowConfig {
+    pub enabled: bool,
+    pub leader: String,  // the leader's hostname or controller_location
+    pub follow_power: bool,
+    pub follow_mode: bool,
+    pub follow_setpoint: bool,
+    pub poll_secs: u64,
+}
+impl FollowConfig {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            leader: String::new(),
+            follow_power: true,
+            follow_mode: true,
+            follow_setpoint: true,
+            poll_secs: 30,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled && self.leader.is_empty() {
+            return Err("leader must be set to follow one".to_string());
+        }
+        if self.poll_secs < 10 {
+            return Err("poll_secs must be at least 10".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Follower {
+    // Mirrors another controller's changes, e.g. for two units serving one open-plan space.  Only changes on the
+    // leader are copied, so a local adjustment sticks until the leader is changed again
+    pub config: FollowConfig,
+    pub leader_url: Option<String>,
+    pub last_leader_state: Option<UnitStatus>,
+    pub last_error: Option<String>,
+    #[serde(skip)]
+    pub last_poll: Option<Instant>,
+}
+
+impl Follower {
+    pub fn new() -> Self {
+        Self {
+            config: FollowConfig::new(),
+            leader_url: None,
+            last_leader_state: None,
+            last_error: None,
+            last_poll: None,
+        }
+    }
+
+    pub fn set_config(&mut self, config: FollowConfig) {
+        // a different leader (or following afresh) shouldn't count as a change to copy
+        self.last_leader_state = None;
+        self.config = config;
+    }
+
+    /// Finds the leader's address among the peers found via mDNS
+    pub fn update_leader(&mut self, peers: &[Peer]) {
+        let leader = &self.config.leader;
+        self.leader_url = peers.iter()
+            .find(|p| p.hostname.as_ref() == Some(leader) || p.location.as_ref() == Some(leader))
+            .and_then(|p| p.addresses.first().map(|a| format!("http://{}:{}", a, p.port)));
+    }
+
+    /// The leader's status url if it's time to poll it
+    pub fn poll_url(&self) -> Option<String> {
+        if !self.config.enabled || self.last_poll.map_or(false, |t| t.elapsed() < Duration::from_secs(self.config.poll_secs)) {
+            return None;
+        }
+        self.leader_url.as_ref().map(|u| format!("{}/status.json", u))
+    }
+
+    /// Records the leader's status, returning the setting to copy if it changed since last time
+    pub fn record_poll(&mut self, result: anyhow::Result<(u16, String)>) -> Option<HeatPumpSetting> {
+        self.last_poll = Some(Instant::now());
+        let parsed = result.and_then(|(code, body)| {
+            if !(200..300).contains(&code) {
+                anyhow::bail!("leader responded with status {}", code);
+            }
+            // a leader that isn't connected to its own unit has nothing worth copying
+            let status = serde_json::from_str::<UnitStatus>(&body)?;
+            if !status.connected {
+                anyhow::bail!("leader is not connected to its heat pump");
+            }
+            Ok(status)
+        });
+        let leader_state = match parsed {
+            Ok(s) => s,
+            Err(e) => {
+                self.last_error = Some(e.to_string());
+                return None;
+            }
+        };
+        self.last_error = None;
+
+        let previous = self.last_leader_state.replace(leader_state.clone());
+        let previous = previous?;  // the first look at the leader is just a baseline
+        let mut setting = HeatPumpSetting::new();
+        if self.config.follow_power && previous.poweron != leader_state.poweron {
+            setting.poweron = Some(leader_state.poweron);
+        }
+        if self.config.follow_mode && previous.mode != leader_state.mode {
+            setting.mode = Some(leader_state.mode);
+        }
+        if self.config.follow_setpoint && previous.desired_temperature_c != leader_state.desired_temperature_c {
+            setting.desired_temperature_c = Some(leader_state.desired_temperature_c);
+        }
+        if setting.requires_packet() {
+            info!("leader {} changed from {:?} to {:?}, following", self.config.leader, previous, leader_state);
+            Some(setting)
+        } else {
+            None
+        }
+    }
+}
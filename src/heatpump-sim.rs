@@ -0,0 +1,239 @@
+// Pretends to be a heat pump on the CN105 port, for trying out the controller firmware without one.  Flash this to a
+// second ESP and cross its TX/RX pins over to the controller's.
+
+use log::info;
+use paste::paste;
+
+use std::time::{Duration, Instant};
+
+use esp_idf_hal as hal;
+
+use hal::prelude::*;
+use hal::gpio::AnyIOPin;
+use hal::uart;
+use hal::rmt;
+
+mod ws2812b;
+use ws2812b::{Ws2812B, Rgb};
+
+mod packet;
+use packet::{Packet, PacketParser};
+
+mod error;
+
+const LOOP_MIN_LENGTH:Duration = Duration::from_millis(2);
+// how long a quiet line means a packet is never going to be finished
+const PACKET_BYTE_GAP:Duration = Duration::from_millis(50);
+// the room moves toward the setpoint this fast while the unit is running
+const ROOM_DEGREES_PER_MIN: f32 = 0.5;
+const LED_BRIGHTNESS: u8 = 20;
+
+macro_rules! pin_from_envar {
+    ($ppins:expr, $evname:tt) => {
+        paste! {
+            $ppins.[<gpio env!($evname)>]
+        }
+    };
+}
+
+#[derive(Debug)]
+struct SimulatedUnit {
+    pub connected: bool,
+    pub poweron: bool,
+    pub mode: u8,
+    pub desired_temperature_c: f32,
+    pub fan_speed: u8,
+    pub vane: u8,
+    pub widevane: u8,
+    pub room_temperature_c: f32,
+    pub operating: u8,
+    last_update: Instant,
+}
+
+impl SimulatedUnit {
+    fn new() -> Self {
+        Self {
+            connected: false,
+            poweron: false,
+            mode: 1,  // heat
+            desired_temperature_c: 21.0,
+            fan_speed: 0,  // auto
+            vane: 0,  // auto
+            widevane: 3,  // middle
+            room_temperature_c: 18.0,
+            operating: 0,
+            last_update: Instant::now(),
+        }
+    }
+
+    /// Moves the room temperature along, as if the unit had been heating or cooling since last time
+    fn update(&mut self) {
+        let minutes = self.last_update.elapsed().as_secs_f32() / 60.0;
+        self.last_update = Instant::now();
+
+        let heating = self.poweron && self.mode == 1 && self.room_temperature_c < self.desired_temperature_c;
+        let cooling = self.poweron && (self.mode == 2 || self.mode == 3) && self.room_temperature_c > self.desired_temperature_c;
+        let step = ROOM_DEGREES_PER_MIN * minutes;
+        if heating {
+            self.room_temperature_c = (self.room_temperature_c + step).min(self.desired_temperature_c);
+        } else if cooling {
+            self.room_temperature_c = (self.room_temperature_c - step).max(self.desired_temperature_c);
+        }
+        self.operating = (heating || cooling) as u8;
+    }
+
+    /// The reply to a packet from the controller, if it's one a unit answers
+    fn respond(&mut self, request: &Packet) -> Option<Packet> {
+        match request.packet_type {
+            0x5a => {
+                info!("Controller connected");
+                self.connected = true;
+                Some(reply(0x7a, &[0]))
+            }
+            0x42 if self.connected => {
+                self.update();
+                self.status(request.data.first().copied().unwrap_or(0))
+            }
+            0x41 if self.connected && request.data.first() == Some(&1) => {
+                self.update();
+                self.apply_set(request);
+                Some(reply(0x61, &[0; 16]))
+            }
+            _ => {
+                info!("Not answering packet {:?}", request);
+                None
+            }
+        }
+    }
+
+    fn status(&self, info_type: u8) -> Option<Packet> {
+        let mut data = [0u8; 16];
+        data[0] = info_type;
+        match info_type {
+            2 => {
+                data[3] = self.poweron as u8;
+                data[4] = self.mode;
+                data[5] = (self.desired_temperature_c as u8).saturating_sub(10);
+                data[6] = self.fan_speed;
+                data[7] = self.vane;
+                data[10] = self.widevane;
+                data[11] = (self.desired_temperature_c * 2.0) as u8 + 128;
+            }
+            3 => {
+                data[3] = (self.room_temperature_c as u8).saturating_sub(10);
+                data[6] = (self.room_temperature_c * 2.0) as u8 + 128;
+            }
+            4 => {
+                data[4] = 0x80;  // no error
+            }
+            5 | 9 => {}
+            6 => {
+                data[4] = self.operating;
+            }
+            _ => {
+                // a real unit doesn't answer the types it doesn't know, which is what the status probe looks for
+                return None;
+            }
+        }
+        Some(reply(0x62, &data))
+    }
+
+    fn apply_set(&mut self, request: &Packet) {
+        let data = &request.data;
+        if data.len() < 16 {
+            info!("Set packet too short, ignoring it: {:?}", request);
+            return;
+        }
+        if data[1] & 1 != 0 { self.poweron = data[3] != 0; }
+        if data[1] & (1 << 1) != 0 { self.mode = data[4]; }
+        if data[1] & (1 << 2) != 0 { self.desired_temperature_c = (data[14] as f32 - 128.0) / 2.0; }
+        if data[1] & (1 << 3) != 0 { self.fan_speed = data[6]; }
+        if data[1] & (1 << 4) != 0 { self.vane = data[7]; }
+        if data[2] & 1 != 0 { self.widevane = data[13]; }
+        info!("Settings now {:?}", self);
+    }
+}
+
+fn reply(packet_type: u8, data: &[u8]) -> Packet {
+    let mut packet = Packet::new_type_size(packet_type, data.len());
+    packet.data.copy_from_slice(data);
+    packet.set_checksum();
+    packet
+}
+
+fn main() -> anyhow::Result<()> {
+    esp_idf_svc::sys::link_patches();
+    esp_idf_svc::log::EspLogger::initialize_default();
+
+    let peripherals = Peripherals::take().unwrap();
+    let pins = peripherals.pins;
+
+    #[cfg(feature="ws2182onboard")]
+    let rmtconfig = rmt::config::TransmitConfig::new().clock_divider(1);
+    #[cfg(feature="ws2182onboard")]
+    let mut npx = Ws2812B::new(rmt::TxRmtDriver::new(peripherals.rmt.channel0, pin_from_envar!(pins, "LED_PIN_NUM"), &rmtconfig)?);
+
+    // the same settings the controller uses
+    let uart_config = uart::config::Config::default()
+        .baudrate(Hertz(2400))
+        .data_bits(uart::config::DataBits::DataBits8)
+        .parity_even()
+        .stop_bits(uart::config::StopBits::STOP1)
+        .flow_control(uart::config::FlowControl::None);
+
+    let uart: uart::UartDriver = uart::UartDriver::new(
+        peripherals.uart1,
+        pin_from_envar!(pins, "TX_PIN_NUM"),
+        pin_from_envar!(pins, "RX_PIN_NUM"),
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        &uart_config
+    ).unwrap();
+
+    let mut unit = SimulatedUnit::new();
+    let mut parser = PacketParser::new();
+    let mut rbuf = [0u8; 32];
+    let gap_ticks: u32 = hal::delay::TickType::from(PACKET_BYTE_GAP).into();
+
+    info!("Simulated heat pump ready");
+
+    loop {
+        let loopstart = Instant::now();
+
+        // magenta until the controller has connected, then green, and red while the room is being heated or cooled
+        #[cfg(feature="ws2182onboard")]
+        if !unit.connected {
+            npx.set(Rgb::new(LED_BRIGHTNESS, 0, LED_BRIGHTNESS))?;
+        } else if unit.operating != 0 {
+            npx.set(Rgb::new(LED_BRIGHTNESS, 0, 0))?;
+        } else {
+            npx.set(Rgb::new(0, LED_BRIGHTNESS, 0))?;
+        }
+
+        let wanted = parser.wanted().min(rbuf.len());
+        let nread = uart.read(&mut rbuf[..wanted], gap_ticks)?;
+        if nread == 0 && parser.in_packet() {
+            info!("Gave up on a partial packet");
+            parser = PacketParser::new();
+        }
+        for b in &rbuf[..nread] {
+            match parser.feed(*b) {
+                Some(Ok(request)) => {
+                    if let Some(response) = unit.respond(&request) {
+                        let mut txbuf = [0u8; packet::PACKET_SIZE_MAX];
+                        uart.write(response.to_bytes(&mut txbuf))?;
+                    }
+                }
+                Some(Err(e)) => {
+                    info!("Bad packet from the controller: {}", e);
+                }
+                None => {}
+            }
+        }
+
+        let loopelapsed = loopstart.elapsed();
+        if loopelapsed < LOOP_MIN_LENGTH {
+            std::thread::sleep(LOOP_MIN_LENGTH - loopelapsed);
+        }
+    }
+}
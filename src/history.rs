@@ -0,0 +1,77 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+pub const SAMPLE_INTERVAL: Duration = Duration::from_secs(5*60);
+// how far below the setpoint the room has to be for the heat pump to count as warming it up, rather than holding it
+const WARMUP_MARGIN_C: f32 = 0.5;
+// and how long it has to have been seen doing that for the rate to mean anything, since the room temperature only
+// comes in half-degree steps
+const WARMUP_MIN_SECS: u64 = 30*60;
+// a day and a bit, so the whole of yesterday is still here just after local midnight to be totalled up.  At 5
+// minutes apart this is about 10kB
+const SAMPLES_KEPT: usize = 26*12;
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Sample {
+    pub unix_secs: Option<u64>,  // None until the clock is set
+    pub room_c: Option<f32>,  // None without a reading from the heat pump
+    pub setpoint_c: Option<f32>,  // None when the heat pump is off, or in a mode without one
+    pub heating: bool,  // on and in heat mode
+}
+
+#[derive(Debug, Serialize)]
+pub struct History {
+    // The room temperature and setpoint every SAMPLE_INTERVAL, which adherence and the pre-heat lookahead are
+    // worked out from.  Only kept in memory, so it starts afresh on boot
+    pub samples: VecDeque<Sample>,
+    #[serde(skip)]
+    last_sample: Option<Instant>,
+}
+
+impl History {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::new(),
+            last_sample: None,
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.last_sample.map_or(true, |t| t.elapsed() >= SAMPLE_INTERVAL)
+    }
+
+    pub fn push(&mut self, sample: Sample) {
+        self.last_sample = Some(Instant::now());
+        if self.samples.len() >= SAMPLES_KEPT {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(sample);
+    }
+
+    /// How fast the room warmed up, in degrees per minute, over every stretch of the history where it was heating
+    /// towards a setpoint well above it.  None until there's been enough of that to go on
+    pub fn warmup_c_per_min(&self) -> Option<f32> {
+        let mut warmed_c = 0.0;
+        let mut secs = 0;
+        for (before, after) in self.samples.iter().zip(self.samples.iter().skip(1)) {
+            let (before_c, after_c, setpoint_c) = match (before.room_c, after.room_c, before.setpoint_c) {
+                (Some(b), Some(a), Some(s)) if before.heating && after.heating => (b, a, s),
+                _ => { continue; }
+            };
+            if setpoint_c - before_c <= WARMUP_MARGIN_C {
+                continue;
+            }
+            warmed_c += after_c - before_c;
+            secs += match (before.unix_secs, after.unix_secs) {
+                (Some(b), Some(a)) if a > b => a - b,
+                _ => SAMPLE_INTERVAL.as_secs(),
+            };
+        }
+        if secs < WARMUP_MIN_SECS || warmed_c <= 0.0 {
+            return None;
+        }
+        Some(warmed_c / (secs as f32 / 60.0))
+    }
+}
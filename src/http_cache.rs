@@ -0,0 +1,28 @@
+use crate::integrity::crc32;
+
+/// A strong ETag for a response body
+pub fn etag(body: &[u8]) -> String {
+    format!("\"{:08x}\"", crc32(body))
+}
+
+/// Whether an If-None-Match header already has this ETag, so a 304 will do
+pub fn not_modified(if_none_match: Option<&str>, etag: &str) -> bool {
+    match if_none_match {
+        // weak comparison, as If-None-Match is meant to use
+        Some(header) => header.split(',').map(|t| t.trim()).any(|t| t == "*" || t.trim_start_matches("W/") == etag),
+        None => false,
+    }
+}
+
+/// Whether an Accept-Encoding header allows gzip
+pub fn accepts_gzip(accept_encoding: Option<&str>) -> bool {
+    accept_encoding.map_or(false, |header| {
+        header.split(',').any(|coding| {
+            let mut parts = coding.split(';');
+            let name = parts.next().unwrap_or("").trim();
+            // "gzip;q=0" is a refusal
+            let refused = parts.any(|p| matches!(p.trim(), "q=0" | "q=0.0" | "q=0.00" | "q=0.000"));
+            (name == "gzip" || name == "*") && !refused
+        })
+    })
+}
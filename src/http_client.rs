@@ -0,0 +1,59 @@
+use std::time::Duration;
+
+use embedded_svc::http::client::Client;
+use embedded_svc::http::Status;
+use embedded_svc::io::{Read, Write};
+
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+
+// the outbound task makes these one after another, so one host that doesn't answer shouldn't hold the rest up for long
+const HTTP_CLIENT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// POSTs a body with the given content type to an http or https url, returning the response status code.
+pub fn post(url: &str, content_type: &str, headers: &[(&str, &str)], body: &str) -> anyhow::Result<u16> {
+    let connection = EspHttpConnection::new(&Configuration {
+        timeout: Some(HTTP_CLIENT_TIMEOUT),
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    let mut client = Client::wrap(connection);
+
+    let content_length = body.len().to_string();
+    let mut all_headers = vec![("Content-Type", content_type), ("Content-Length", content_length.as_str())];
+    all_headers.extend_from_slice(headers);
+
+    let mut request = client.post(url, &all_headers)?;
+    request.write_all(body.as_bytes())?;
+    request.flush()?;
+    let response = request.submit()?;
+
+    Ok(response.status())
+}
+
+pub fn post_json(url: &str, headers: &[(&str, &str)], body: &str) -> anyhow::Result<u16> {
+    post(url, "application/json", headers, body)
+}
+
+/// GETs an http url, returning the status code and the body
+pub fn get(url: &str) -> anyhow::Result<(u16, String)> {
+    let connection = EspHttpConnection::new(&Configuration {
+        timeout: Some(HTTP_CLIENT_TIMEOUT),
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    let mut client = Client::wrap(connection);
+
+    let mut response = client.get(url)?.submit()?;
+    let status = response.status();
+    let mut body = Vec::new();
+    let mut buf = [0u8; 512];
+    loop {
+        let n = response.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&buf[..n]);
+    }
+
+    Ok((status, String::from_utf8(body)?))
+}
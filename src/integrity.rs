@@ -0,0 +1,104 @@
+use std::ffi::CStr;
+use std::sync::OnceLock;
+
+use log::info;
+use serde::Serialize;
+
+use esp_idf_svc::sys;
+
+use crate::INDEX_HTML;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AssetCheck {
+    pub name: &'static str,
+    pub expected_crc32: u32,  // computed from the source file at build time
+    pub actual_crc32: u32,
+    pub ok: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FirmwareCheck {
+    pub partition: Option<String>,
+    pub ota_state: String,  // "factory" when running from a factory partition, which has no OTA state
+}
+
+#[derive(Debug, Serialize)]
+pub struct Integrity {
+    // What was found checking the firmware, embedded assets and stored config at boot
+    pub assets: Vec<AssetCheck>,
+    pub firmware: FirmwareCheck,
+    pub config_errors: Vec<String>,  // NVS settings that were there but could not be read back
+}
+
+impl Integrity {
+    pub fn new() -> Self {
+        Self {
+            assets: asset_checks().to_vec(),
+            firmware: check_firmware(),
+            config_errors: Vec::new(),
+        }
+    }
+
+    pub fn record_config_error(&mut self, key: &str, error: &serde_json::Error) {
+        self.config_errors.push(format!("{}: {}", key, error));
+    }
+
+    pub fn ok(&self) -> bool {
+        self.assets.iter().all(|a| a.ok) && self.config_errors.is_empty()
+    }
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffffffffu32;
+    for b in bytes {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// The embedded assets are only checked once, since they can't change while running
+fn asset_checks() -> &'static [AssetCheck] {
+    static CHECKS: OnceLock<Vec<AssetCheck>> = OnceLock::new();
+    CHECKS.get_or_init(|| {
+        [("index.html", env!("INDEX_HTML_CRC32"), INDEX_HTML)].iter().map(|(name, expected, contents)| {
+            let expected_crc32 = expected.parse().unwrap();
+            let actual_crc32 = crc32(contents.as_bytes());
+            if expected_crc32 != actual_crc32 {
+                info!("embedded {} is corrupt: crc32 {:08x}, expected {:08x}", name, actual_crc32, expected_crc32);
+            }
+            AssetCheck { name, expected_crc32, actual_crc32, ok: expected_crc32 == actual_crc32 }
+        }).collect()
+    })
+}
+
+/// Whether the named embedded asset can be served
+pub fn asset_ok(name: &str) -> bool {
+    asset_checks().iter().any(|a| a.name == name && a.ok)
+}
+
+fn check_firmware() -> FirmwareCheck {
+    let running = unsafe { sys::esp_ota_get_running_partition() };
+    if running.is_null() {
+        return FirmwareCheck { partition: None, ota_state: "unknown".to_string() };
+    }
+    let partition = unsafe { CStr::from_ptr((*running).label.as_ptr()) }.to_string_lossy().into_owned();
+
+    let mut state: sys::esp_ota_img_states_t = 0;
+    let ota_state = match unsafe { sys::esp_ota_get_state_partition(running, &mut state) } as u32 {
+        sys::ESP_OK => match state {
+            sys::esp_ota_img_states_t_ESP_OTA_IMG_NEW => "new",
+            sys::esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY => "pending_verify",
+            sys::esp_ota_img_states_t_ESP_OTA_IMG_VALID => "valid",
+            sys::esp_ota_img_states_t_ESP_OTA_IMG_INVALID => "invalid",
+            sys::esp_ota_img_states_t_ESP_OTA_IMG_ABORTED => "aborted",
+            _ => "undefined",
+        },
+        sys::ESP_ERR_NOT_SUPPORTED => "factory",
+        _ => "unknown",
+    }.to_string();
+
+    FirmwareCheck { partition: Some(partition), ota_state }
+}
@@ -0,0 +1,45 @@
+use serde::Serialize;
+
+// what each part counts for in the overall score.  Parts that don't apply (e.g. wifi when on ethernet) are left
+// out and the rest reweighted
+const WIFI_WEIGHT: f32 = 0.4;
+const BUS_WEIGHT: f32 = 0.4;
+const LATENCY_WEIGHT: f32 = 0.2;
+
+// RSSI at or above the first is perfect, at or below the second is useless
+const RSSI_BEST_DBM: f32 = -50.0;
+const RSSI_WORST_DBM: f32 = -90.0;
+
+// heat pump response times, likewise
+const LATENCY_BEST_MS: f32 = 100.0;
+const LATENCY_WORST_MS: f32 = 1000.0;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkQuality {
+    // One 0-100 number for dashboards, with the parts it was made from
+    pub score: u8,
+    pub wifi: Option<u8>,
+    pub bus: Option<u8>,
+    pub latency: Option<u8>,
+}
+
+fn scale(value: f32, worst: f32, best: f32) -> u8 {
+    (100.0 * (value - worst) / (best - worst)).clamp(0.0, 100.0).round() as u8
+}
+
+/// Combines the wifi signal, the fraction of recent heat pump reads that were good, and how quickly the heat pump
+/// has been answering.  None for a part means there's nothing to go on for it
+pub fn compute(rssi: Option<i8>, bus_success_rate: Option<f32>, latency_ms: Option<f32>) -> LinkQuality {
+    let wifi = rssi.map(|r| scale(r as f32, RSSI_WORST_DBM, RSSI_BEST_DBM));
+    let bus = bus_success_rate.map(|r| scale(r, 0.0, 1.0));
+    let latency = latency_ms.map(|l| scale(l, LATENCY_WORST_MS, LATENCY_BEST_MS));
+
+    let parts = [(wifi, WIFI_WEIGHT), (bus, BUS_WEIGHT), (latency, LATENCY_WEIGHT)];
+    let (sum, weights) = parts.iter().fold((0.0, 0.0), |(sum, weights), (part, weight)| match part {
+        Some(p) => (sum + *p as f32 * weight, weights + weight),
+        None => (sum, weights),
+    });
+    let score = if weights > 0.0 { (sum / weights).round() as u8 } else { 0 };
+
+    LinkQuality { score, wifi, bus, latency }
+}
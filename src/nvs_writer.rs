@@ -0,0 +1,52 @@
+use std::sync::mpsc;
+
+use log::info;
+
+use esp_idf_svc::nvs;
+
+type Job = Box<dyn FnOnce(&mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<()> + Send>;
+
+/// Hands writes to the settings partition off to their own task, since a flash write can take tens of milliseconds
+/// and the main loop shouldn't be holding the state, or keeping the bus waiting, while it happens.  Writes are done in
+/// the order they're sent, so a job that reads a key and writes it back sees everything sent before it
+#[derive(Clone)]
+pub struct NvsWriter {
+    sender: mpsc::Sender<(&'static str, Job)>,
+}
+
+impl NvsWriter {
+    pub fn spawn(mut nvs_settings: nvs::EspNvs<nvs::NvsDefault>, stack_size: usize) -> anyhow::Result<Self> {
+        let (sender, receiver) = mpsc::channel::<(&'static str, Job)>();
+        std::thread::Builder::new()
+            .stack_size(stack_size)
+            .spawn(move || {
+                for (what, job) in receiver {
+                    if let Err(e) = job(&mut nvs_settings) {
+                        info!("Could not save {}: {}", what, e);
+                    }
+                }
+            })?;
+        Ok(Self { sender })
+    }
+
+    pub fn write<F>(&self, what: &'static str, job: F)
+    where F: FnOnce(&mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<()> + Send + 'static {
+        if self.sender.send((what, Box::new(job))).is_err() {
+            info!("NVS task has stopped, {} not saved", what);
+        }
+    }
+
+    pub fn set_str(&self, key: &'static str, value: String) {
+        self.write(key, move |nvs| Ok(nvs.set_str(key, &value)?));
+    }
+
+    /// Waits until everything sent so far has been written, for before a restart
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        self.write("flush", move |_| {
+            let _ = done.send(());
+            Ok(())
+        });
+        let _ = wait.recv();
+    }
+}
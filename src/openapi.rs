@@ -0,0 +1,184 @@
+use serde::Serialize;
+use serde_json::json;
+use strum::IntoEnumIterator;
+
+use crate::{setting_schema, FanSpeed, HeatPumpMode, HeatPumpStatus, VaneDirection, WideVaneDirection};
+
+// Endpoints that are only listed, with a JSON object as the response.  A new endpoint gets a line here, or a full
+// entry in document() if clients need more than that
+const OTHER_ENDPOINTS: &[(&str, &str, &str)] = &[
+    ("/config.json", "get", "The controller configuration"),
+    ("/config.json", "post", "Change part of the controller configuration"),
+    ("/config/export", "get", "Every saved setting, for /config/import"),
+    ("/config/import", "post", "Replace the saved settings with an export, then restart (bearer token)"),
+    ("/wifi_ca_cert", "post", "Store the PEM CA certificate, at most 4000 bytes, for the WPA2-Enterprise network ?ssid= names (bearer token)"),
+    ("/factory_reset", "post", "Erase all settings and restart (bearer token)"),
+    ("/model.json", "get", "What's known of the indoor unit"),
+    ("/units.json", "get", "The other units, in coordinator mode"),
+    ("/units.json", "post", "Pass a setting on to one of the other units (409 without coordinator mode)"),
+    ("/presets.json", "get", "Saved presets"),
+    ("/presets.json", "post", "Save the presets"),
+    ("/preset.json", "post", "Apply a preset"),
+    ("/away.json", "post", "Start or end away mode"),
+    ("/boost", "post", "Run a boost"),
+    ("/remote_temperature.json", "post", "Report the room temperature from another sensor"),
+    ("/system.json", "get", "Memory, tasks and wifi"),
+    ("/diagnostics.json", "get", "Everything useful for a bug report"),
+    ("/crashlog.json", "get", "The last crash"),
+    ("/energy.json", "get", "Energy use"),
+    ("/adherence.json", "get", "How closely the room has followed the setpoint"),
+    ("/history.json", "get", "The room temperature and setpoint over the last day"),
+    ("/peers.json", "get", "Other controllers found on the network"),
+    ("/ota.json", "get", "The updater's configuration and last check"),
+    ("/assets.json", "get", "Uploaded web assets"),
+    ("/strings.json", "get", "Interface strings in the configured language"),
+    ("/sniff.json", "get", "Captured bus frames"),
+    ("/trace.json", "get", "The bus trace"),
+    ("/set/dry-run", "post", "Validate a set.json body without applying it"),
+    ("/reset_protocol_errors.json", "post", "Zero the protocol error counters"),
+    ("/restart_subsystem.json", "post", "Restart one subsystem"),
+];
+
+fn enum_schema<T: IntoEnumIterator + Serialize>() -> serde_json::Value {
+    let values: Vec<_> = T::iter().map(|v| serde_json::to_value(v).unwrap()).collect();
+    json!({"type": "string", "enum": values})
+}
+
+fn json_content(schema: serde_json::Value) -> serde_json::Value {
+    json!({"application/json": {"schema": schema}})
+}
+
+fn error_response(description: &str) -> serde_json::Value {
+    json!({"description": description, "content": json_content(json!({"$ref": "#/components/schemas/Error"}))})
+}
+
+/// An OpenAPI 3.1 document for the HTTP API.  The set.json body is the same schema as /schema, so it reflects this
+/// unit's capabilities and setpoint limits
+pub fn document(state: &HeatPumpStatus) -> serde_json::Value {
+    let mut setting = setting_schema(state);
+    if let Some(o) = setting.as_object_mut() {
+        // 3.1 schemas are 2020-12 already
+        o.remove("$schema");
+    }
+
+    let mut paths = json!({
+        "/status.json": {"get": {
+            "summary": "The heat pump's current state",
+            "parameters": [{
+                "name": "fields",
+                "in": "query",
+                "description": "Comma separated, to get only those fields",
+                "schema": {"type": "string"},
+                "example": "room_temperature_c,mode,poweron",
+            }],
+            "responses": {
+                "200": {
+                    "description": "The state, with an ETag.  As CBOR with Accept: application/cbor",
+                    "content": {
+                        "application/json": {"schema": {"$ref": "#/components/schemas/Status"}},
+                        "application/cbor": {"schema": {"$ref": "#/components/schemas/Status"}},
+                    },
+                },
+                "304": {"description": "Unchanged since the If-None-Match ETag"},
+                "400": error_response("One of the fields asked for isn't in the status"),
+            },
+        }},
+        "/set.json": {"post": {
+            "summary": "Change the heat pump's settings.  Fields left out or null are left as they are",
+            "parameters": [{
+                "name": "wait",
+                "in": "query",
+                "description": "Answer once the heat pump has acknowledged the setting, with the status after that",
+                "schema": {"type": "boolean"},
+            }],
+            "requestBody": {
+                "required": true,
+                "content": json_content(json!({"$ref": "#/components/schemas/HeatPumpSetting"})),
+            },
+            "responses": {
+                "200": {
+                    "description": "Accepted, and sent to the heat pump from the main loop.  With wait, the acknowledgement and \
+                                    the status after it instead",
+                    "content": json_content(json!({"oneOf": [
+                        {"$ref": "#/components/schemas/HeatPumpSetting"},
+                        {"type": "object", "properties": {
+                            "ack": {"type": "object"},
+                            "superseded": {"type": "boolean", "description": "A later setting changed one of the same fields before this was sent"},
+                            "status": {"$ref": "#/components/schemas/Status"},
+                        }},
+                    ]})),
+                },
+                "400": error_response("Not JSON, or not a setting"),
+                "409": error_response("Not connected to the heat pump; nothing was queued"),
+                "413": error_response("Request too big"),
+                "422": error_response("A setting this unit can't take"),
+                "504": error_response("With wait, not acknowledged in time.  The setting is still queued"),
+            },
+        }},
+        "/schema": {"get": {
+            "summary": "The JSON schema of the set.json body",
+            "responses": {"200": {"description": "The schema", "content": {"application/schema+json": {}}}},
+        }},
+        "/version.json": {"get": {
+            "summary": "The firmware version and build",
+            "responses": {"200": {"description": "The version", "content": json_content(json!({"type": "object"}))}},
+        }},
+        "/events": {"get": {
+            "summary": "Server-sent status events",
+            "responses": {"307": {"description": "Redirect to the event stream's own port"}},
+        }},
+    });
+    for (path, method, summary) in OTHER_ENDPOINTS {
+        paths[*path][*method] = json!({
+            "summary": summary,
+            "responses": {"200": {"description": "OK", "content": json_content(json!({"type": "object"}))}},
+        });
+    }
+
+    json!({
+        "openapi": "3.1.0",
+        "info": {
+            "title": "Mitsubishi heat pump controller",
+            "version": env!("CARGO_PKG_VERSION"),
+        },
+        "paths": paths,
+        "components": {"schemas": {
+            "HeatPumpMode": enum_schema::<HeatPumpMode>(),
+            "FanSpeed": enum_schema::<FanSpeed>(),
+            "VaneDirection": enum_schema::<VaneDirection>(),
+            "WideVaneDirection": enum_schema::<WideVaneDirection>(),
+            "HeatPumpSetting": setting,
+            // only the heat pump's own fields; the rest are described by /diagnostics.json's sections
+            "Status": {
+                "type": "object",
+                "properties": {
+                    "connected": {"type": "boolean"},
+                    "stale": {"type": "boolean"},
+                    "poweron": {"type": ["boolean", "null"]},
+                    "mode": {"anyOf": [{"$ref": "#/components/schemas/HeatPumpMode"}, {"type": "null"}]},
+                    "desired_temperature_c": {"type": ["number", "null"]},
+                    "fan_speed": {"anyOf": [{"$ref": "#/components/schemas/FanSpeed"}, {"type": "null"}]},
+                    "vane": {"anyOf": [{"$ref": "#/components/schemas/VaneDirection"}, {"type": "null"}]},
+                    "widevane": {"anyOf": [{"$ref": "#/components/schemas/WideVaneDirection"}, {"type": "null"}]},
+                    "room_temperature_c": {"type": ["number", "null"]},
+                    "operating": {"type": ["integer", "null"]},
+                    "secs_since_boot": {"type": "string"},
+                    "time": {"type": ["string", "null"], "format": "date-time"},
+                    "mac": {"type": ["string", "null"]},
+                    "status_age_secs": {"type": ["number", "null"]},
+                },
+            },
+            "Error": {
+                "type": "object",
+                "properties": {
+                    "error": {"type": "string"},
+                    "code": {"type": "integer", "description": "Stable, see docs/error-codes.md"},
+                    "code_name": {"type": "string"},
+                    "field": {"type": ["string", "null"], "description": "The request field that was wrong"},
+                    "value": {"description": "What was given for the field"},
+                },
+                "required": ["error", "code", "code_name", "field"],
+            },
+        }},
+    })
+}
@@ -0,0 +1,250 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use embedded_svc::http::client::Client;
+use embedded_svc::http::Status;
+use embedded_svc::io::{Read, Write};
+
+use esp_idf_hal::reset;
+use esp_idf_svc::http::client::{Configuration, EspHttpConnection};
+use esp_idf_svc::ota::EspOta;
+
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+use crate::{clock, http_client, RebootWindow};
+
+// how often the task looks at whether a check is due.  It has its own task since an image download takes far longer
+// than the main loop can be held up for
+const CHECK_TICK: Duration = Duration::from_secs(10);
+// for each read of the image, rather than the whole download
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30);
+const DOWNLOAD_CHUNK: usize = 4096;
+// the P-256 key manifests are signed with, as the hex of its uncompressed SEC1 point.  Without one updates can't be
+// turned on, as there'd be no telling whose image it was
+const PUBLIC_KEY_HEX: &str = env!("OTA_PUBLIC_KEY");
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct OtaConfig {
+    pub enabled: bool,
+    pub manifest_url: String,
+    pub check_interval_mins: u32,
+    // updates are only checked for and applied in here.  Turned off, any time will do
+    pub window: RebootWindow,
+}
+impl OtaConfig {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            manifest_url: String::new(),
+            check_interval_mins: 6 * 60,
+            window: RebootWindow::new(),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.enabled && PUBLIC_KEY_HEX.is_empty() {
+            return Err("this firmware was built without an OTA_PUBLIC_KEY, so can't check update images".to_string());
+        }
+        if self.enabled && !self.manifest_url.starts_with("https://") {
+            return Err("manifest_url must start with https://".to_string());
+        }
+        if self.check_interval_mins < 15 {
+            return Err("check_interval_mins must be at least 15".to_string());
+        }
+        self.window.validate()
+    }
+}
+
+/// What's at the manifest url: the newest version, where to get it, and what it has to hash to
+#[derive(Clone, Debug, Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    pub url: String,
+    #[serde(default)]
+    pub size: Option<usize>,  // checked against what was downloaded if it's given
+    pub sha256: String,  // of the image, in hex
+    // DER ECDSA signature, in hex, of "<version> <sha256>" with the key in OTA_PUBLIC_KEY.  The version is signed
+    // too so an old signed image can't be passed off as a newer one
+    pub signature: String,
+}
+
+impl Manifest {
+    /// Checks the signature, returning the image's hash to check the download against
+    pub fn verify(&self) -> anyhow::Result<[u8; 32]> {
+        let key = from_hex(PUBLIC_KEY_HEX).and_then(|k| VerifyingKey::from_sec1_bytes(&k).ok())
+            .ok_or_else(|| anyhow::anyhow!("OTA_PUBLIC_KEY is not a P-256 public key"))?;
+        let signature = from_hex(&self.signature).and_then(|s| Signature::from_der(&s).ok())
+            .ok_or_else(|| anyhow::anyhow!("manifest signature is not a hex DER ECDSA signature"))?;
+        let sha256: [u8; 32] = from_hex(&self.sha256).and_then(|h| h.try_into().ok())
+            .ok_or_else(|| anyhow::anyhow!("manifest sha256 is not 64 hex digits"))?;
+        let signed = format!("{} {}", self.version, self.sha256.to_ascii_lowercase());
+        key.verify(signed.as_bytes(), &signature).map_err(|_| anyhow::anyhow!("manifest signature does not match"))?;
+        Ok(sha256)
+    }
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+#[derive(Debug, Serialize)]
+pub struct OtaUpdater {
+    // Fetches a manifest now and then, and installs the image it points to if that's a newer version
+    pub config: OtaConfig,
+    pub running_version: &'static str,
+    pub latest_version: Option<String>,
+    pub last_check: Option<String>,
+    pub last_error: Option<String>,
+    pub downloading: bool,
+    #[serde(skip)]
+    last_check_instant: Option<Instant>,
+}
+
+impl OtaUpdater {
+    pub fn new() -> Self {
+        Self {
+            config: OtaConfig::new(),
+            running_version: env!("CARGO_PKG_VERSION"),
+            latest_version: None,
+            last_check: None,
+            last_error: None,
+            downloading: false,
+            last_check_instant: None,
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.config.enabled && self.last_check_instant.map_or(true, |t| {
+            t.elapsed() >= Duration::from_secs(self.config.check_interval_mins as u64 * 60)
+        })
+    }
+}
+
+/// Whether a dotted version like "0.2.10" is after another.  Anything after a "-" or "+" is ignored, and a version
+/// that isn't numbers is never newer
+pub fn is_newer(candidate: &str, current: &str) -> bool {
+    fn parse(version: &str) -> Option<Vec<u64>> {
+        version.split(['-', '+']).next()?.split('.').map(|part| part.parse().ok()).collect()
+    }
+    match (parse(candidate), parse(current)) {
+        (Some(candidate), Some(current)) => candidate > current,
+        _ => false,
+    }
+}
+
+/// The updater's task.  Only returns if it fails to even start, since problems with a check are recorded and tried
+/// again next time.  A successful update restarts into the new image
+pub fn run(updater: Arc<Mutex<OtaUpdater>>) -> anyhow::Result<()> {
+    loop {
+        std::thread::sleep(CHECK_TICK);
+        let config = {
+            let u = updater.lock().unwrap();
+            if !u.is_due() {
+                continue;
+            }
+            u.config.clone()
+        };
+        // with a window but no time yet there's no knowing if it's open, so wait for SNTP
+        if config.window.enabled && !clock::unix_secs().map_or(false, |now| config.window.contains(now)) {
+            continue;
+        }
+
+        {
+            let mut u = updater.lock().unwrap();
+            u.last_check_instant = Some(Instant::now());
+            u.last_check = clock::iso8601_now();
+        }
+        match check_and_update(&config, &updater) {
+            Ok(Some(version)) => {
+                info!("Updated to version {}, restarting into it", version);
+                std::thread::sleep(Duration::from_millis(500));
+                reset::restart();
+            }
+            Ok(None) => {
+                updater.lock().unwrap().last_error = None;
+            }
+            Err(e) => {
+                info!("Update check against {} failed: {}", config.manifest_url, e);
+                let mut u = updater.lock().unwrap();
+                u.last_error = Some(e.to_string());
+                u.downloading = false;
+            }
+        }
+    }
+}
+
+/// Returns the version that was installed, if there was a newer one
+fn check_and_update(config: &OtaConfig, updater: &Mutex<OtaUpdater>) -> anyhow::Result<Option<String>> {
+    let (status, body) = http_client::get(&config.manifest_url)?;
+    if status != 200 {
+        anyhow::bail!("manifest request got status {}", status);
+    }
+    let manifest: Manifest = serde_json::from_str(&body)?;
+    updater.lock().unwrap().latest_version = Some(manifest.version.clone());
+    if !is_newer(&manifest.version, env!("CARGO_PKG_VERSION")) {
+        return Ok(None);
+    }
+    if !manifest.url.starts_with("https://") {
+        anyhow::bail!("image url {} is not https", manifest.url);
+    }
+    // before anything's downloaded, so a tampered manifest costs nothing
+    let expected_sha256 = manifest.verify()?;
+
+    info!("Version {} is available, downloading it from {}", manifest.version, manifest.url);
+    updater.lock().unwrap().downloading = true;
+    let connection = EspHttpConnection::new(&Configuration {
+        timeout: Some(DOWNLOAD_TIMEOUT),
+        crt_bundle_attach: Some(esp_idf_svc::sys::esp_crt_bundle_attach),
+        ..Default::default()
+    })?;
+    let mut client = Client::wrap(connection);
+    let mut response = client.get(&manifest.url)?.submit()?;
+    if response.status() != 200 {
+        anyhow::bail!("image request got status {}", response.status());
+    }
+
+    let mut ota = EspOta::new()?;
+    let mut update = ota.initiate_update()?;
+    match copy_image(&mut response, &mut update) {
+        Ok((size, sha256)) if manifest.size.map_or(true, |s| s == size) && sha256 == expected_sha256 => {
+            // this checks the image over before the boot partition is switched to it
+            update.complete()?;
+            Ok(Some(manifest.version))
+        }
+        Ok((size, _)) if manifest.size.map_or(false, |s| s != size) => {
+            update.abort()?;
+            anyhow::bail!("downloaded {} bytes, but the manifest said {}", size, manifest.size.unwrap_or(0))
+        }
+        Ok(_) => {
+            update.abort()?;
+            anyhow::bail!("downloaded image does not match the manifest's sha256")
+        }
+        Err(e) => {
+            update.abort()?;
+            Err(e)
+        }
+    }
+}
+
+/// Returns the size and SHA-256 of what was copied
+fn copy_image(from: &mut impl Read, to: &mut impl Write) -> anyhow::Result<(usize, [u8; 32])> {
+    let mut buf = vec![0u8; DOWNLOAD_CHUNK];
+    let mut total = 0;
+    let mut hasher = Sha256::new();
+    loop {
+        let n = from.read(&mut buf).map_err(|e| anyhow::anyhow!("reading the image: {:?}", e))?;
+        if n == 0 {
+            return Ok((total, hasher.finalize().into()));
+        }
+        hasher.update(&buf[..n]);
+        to.write_all(&buf[..n]).map_err(|e| anyhow::anyhow!("writing the image: {:?}", e))?;
+        total += n;
+    }
+}
@@ -3,6 +3,7 @@
 use log::info;
 use paste::paste;
 
+use std::collections::BTreeMap;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
@@ -12,29 +13,56 @@ use hal::prelude::*;
 use hal::gpio::AnyIOPin;
 use hal::uart;
 use hal::rmt;
-use hal::sys::{EspError, ESP_ERR_INVALID_RESPONSE, ESP_ERR_INVALID_STATE };
+use hal::sys::EspError;
 
 use embedded_svc::ws::FrameType;
 use embedded_svc::wifi as eswifi;
 
+use serde::{Deserialize, Serialize};
+
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
-    nvs::EspDefaultNvsPartition,
+    nvs,
     wifi::{BlockingWifi, EspWifi},
     http,
+    http::server::ws::EspHttpWsDetachedSender,
 };
 
 mod ws2812b;
 use ws2812b::{Ws2812B, Rgb};
 
+mod startup;
+use startup::StartupPhase;
+
+mod packet;
+use packet::{checksum, Packet};
+
+mod error;
+use error::{ErrorCode, FirmwareError};
+
 const SSID: &str = env!("WIFI_SSID");
 const PASSWORD: &str = env!("WIFI_PASS");
 const WIFI_CHANNEL: &str = env!("WIFI_CHANNEL");
+const WS_MAX_SESSIONS: &str = env!("WS_MAX_SESSIONS");
 
 static INDEX_HTML: &str = include_str!("packet-sender-index.html");
 
 const LOOP_MIN_LENGTH:Duration = Duration::from_millis(2);
 const UART_TIMEOUT:Duration = Duration::from_millis(5);
+const LED_BRIGHTNESS: u8 = 20;
+const MACRO_DEFAULT_EXPECT_TIMEOUT:Duration = Duration::from_millis(1000);
+// the main loop runs macros, so nothing else gets done while one waits
+const MACRO_MAX_STEP_TIME:Duration = Duration::from_secs(60);
+// a partial packet that hasn't been finished by then is taken to be junk
+const RX_PARTIAL_TIMEOUT:Duration = Duration::from_millis(500);
+const WS_PING_INTERVAL:Duration = Duration::from_secs(10);
+// the page polls several times a second (about once a second when its tab is in the background), so a minute of
+// nothing means nobody is there
+const WS_IDLE_TIMEOUT:Duration = Duration::from_secs(60);
+const MAX_SCRIPTS: usize = 16;
+const MAX_SCRIPT_NAME_LEN: usize = 32;
+// the scripts all live in one blob, so this keeps them well inside an NVS page
+const MAX_SCRIPTS_JSON_LEN: usize = 3800;
 
 // Not sure how much is needed, but this is the default in an esp example so <shrug>
 const HTTP_SERVER_STACK_SIZE: usize = 10240;
@@ -50,8 +78,287 @@ macro_rules! pin_from_envar {
 
 struct WebSocketSession {
     pub tx_queue: Vec<u8>,
-    pub rx_queue: Vec<u8>,
+    pub rx_queue: Vec<u8>,  // only filled for raw subscriptions
+    pub rx_items: Vec<RxItem>,
+    pub subscription: RxSubscription,
     pub session: i32,
+    pub pending_macro: Option<Vec<MacroStep>>,
+    pub macro_report: Option<MacroReport>,
+    // set with "frames:binary", after which the session is a transparent tunnel speaking UartFrames both ways
+    pub binary_frames: bool,
+    pub rx_frames: Vec<UartFrame>,
+    pub last_active: Instant,
+    // for the pings and closes that come from the main loop rather than in reply to something
+    pub sender: EspHttpWsDetachedSender,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum RxMode {
+    All,  // decoded packets, and anything that doesn't decode as it is
+    Decoded,
+    Raw,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RxSubscription {
+    pub mode: RxMode,
+    // packet types to pass along, empty for all of them.  Doesn't apply to raw
+    #[serde(default)]
+    pub types: Vec<u8>,
+}
+impl RxSubscription {
+    fn new() -> Self {
+        Self { mode: RxMode::All, types: Vec::new() }
+    }
+
+    fn wants(&self, item: &RxItem) -> bool {
+        match item {
+            RxItem::Packet(packet) => self.mode != RxMode::Raw 
+                && (self.types.is_empty() || self.types.contains(&packet.packet_type)),
+            RxItem::Junk(_) => self.mode == RxMode::All,
+        }
+    }
+}
+
+/// What came in over the uart, split up once in the main loop and shared between the sessions that want it
+#[derive(Debug, Clone)]
+enum RxItem {
+    Packet(Arc<Packet>),
+    Junk(Arc<Vec<u8>>),
+}
+
+/// Collects uart bytes until they make up whole packets, so a packet split across reads isn't reported as junk
+struct RxAssembler {
+    pending: Vec<u8>,
+    pending_since: Instant,
+}
+impl RxAssembler {
+    fn new() -> Self {
+        Self { pending: Vec::new(), pending_since: Instant::now() }
+    }
+
+    fn feed(&mut self, bytes: &[u8]) -> Vec<RxItem> {
+        if self.pending.is_empty() {
+            self.pending_since = Instant::now();
+        }
+        self.pending.extend_from_slice(bytes);
+
+        let mut items = Vec::new();
+        while !self.pending.is_empty() {
+            if self.pending[0] != 0xfc {
+                // everything up to the next sync byte is junk
+                let n = self.pending.iter().position(|b| *b == 0xfc).unwrap_or(self.pending.len());
+                items.push(RxItem::Junk(Arc::new(self.pending.drain(..n).collect())));
+                continue;
+            }
+            match Packet::from_bytes_unchecked(&self.pending) {
+                Ok(packet) => {
+                    self.pending.drain(..packet.packet_size());
+                    items.push(RxItem::Packet(Arc::new(packet)));
+                }
+                Err(_) if self.pending_since.elapsed() < RX_PARTIAL_TIMEOUT => { break; }
+                Err(_) => {
+                    // never finished, so give up on this sync byte and look for the next one
+                    let n = self.pending[1..].iter().position(|b| *b == 0xfc).map_or(self.pending.len(), |i| i + 1);
+                    items.push(RxItem::Junk(Arc::new(self.pending.drain(..n).collect())));
+                }
+            }
+            self.pending_since = Instant::now();
+        }
+        items
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Direction {
+    ToUnit = 0,
+    FromUnit = 1,
+}
+
+/// The binary websocket format for the tunnel: a direction byte, the milliseconds since boot as a u32 (LE), the
+/// length as a u16 (LE), then that many bytes as they went over the uart.  A websocket message can hold any number of
+/// these back to back.
+#[derive(Debug, Clone)]
+struct UartFrame {
+    pub direction: Direction,
+    pub ms: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl UartFrame {
+    const HEADER_LEN: usize = 7;
+
+    fn encode_into(&self, out: &mut Vec<u8>) {
+        out.push(self.direction as u8);
+        out.extend_from_slice(&self.ms.to_le_bytes());
+        out.extend_from_slice(&(self.bytes.len() as u16).to_le_bytes());
+        out.extend_from_slice(&self.bytes);
+    }
+
+    fn decode_all(mut buf: &[u8]) -> Result<Vec<UartFrame>, String> {
+        let mut frames = Vec::new();
+        while !buf.is_empty() {
+            if buf.len() < Self::HEADER_LEN {
+                return Err(format!("{} bytes left over, too short for a frame header", buf.len()));
+            }
+            let direction = match buf[0] {
+                0 => Direction::ToUnit,
+                1 => Direction::FromUnit,
+                d => { return Err(format!("unknown direction {}", d)); }
+            };
+            let ms = u32::from_le_bytes([buf[1], buf[2], buf[3], buf[4]]);
+            let len = u16::from_le_bytes([buf[5], buf[6]]) as usize;
+            if buf.len() < Self::HEADER_LEN + len {
+                return Err(format!("frame says {} bytes but only {} are there", len, buf.len() - Self::HEADER_LEN));
+            }
+            frames.push(UartFrame { direction, ms, bytes: buf[Self::HEADER_LEN..Self::HEADER_LEN + len].to_vec() });
+            buf = &buf[Self::HEADER_LEN + len..];
+        }
+        Ok(frames)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+enum MacroStep {
+    // bytes to write to the uart, the checksum is appended just like for binary frames
+    Send { bytes: Vec<u8> },
+    Wait { ms: u64 },
+    // wait for the given bytes to be the start of what comes back from the uart, failing the macro if they don't
+    Expect { bytes: Vec<u8>, timeout_ms: Option<u64> },
+}
+
+impl MacroStep {
+    /// How long the step may take, if it says
+    fn duration(&self) -> Option<Duration> {
+        match self {
+            MacroStep::Send { .. } => None,
+            MacroStep::Wait { ms } => Some(Duration::from_millis(*ms)),
+            MacroStep::Expect { timeout_ms, .. } => timeout_ms.map(Duration::from_millis),
+        }
+    }
+}
+
+/// Turns away macros with a wait or timeout longer than a step is allowed
+fn validate_steps(steps: &[MacroStep]) -> Result<(), String> {
+    match steps.iter().position(|step| step.duration().map_or(false, |d| d > MACRO_MAX_STEP_TIME)) {
+        Some(i) => Err(format!("step {} waits longer than {} s", i, MACRO_MAX_STEP_TIME.as_secs())),
+        None => Ok(()),
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct MacroStepResult {
+    pub step: usize,
+    pub op: &'static str,
+    pub ok: bool,
+    pub bytes: Vec<u8>,
+    pub elapsed_ms: u128,
+}
+
+#[derive(Debug, Serialize)]
+struct MacroReport {
+    pub ok: bool,
+    pub steps: Vec<MacroStepResult>,
+}
+
+#[derive(Debug, Deserialize)]
+struct SaveScriptRequest {
+    pub name: String,
+    pub steps: Vec<MacroStep>,
+    #[serde(default)]
+    pub on_boot: bool,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct StoredScripts {
+    pub scripts: BTreeMap<String, Vec<MacroStep>>,
+    pub on_boot: Option<String>,
+}
+
+/// Named macros kept in NVS, so a sequence that shows a problem can be replayed later, or on every boot, without a
+/// browser attached.  The handler only changes this; the main loop does the NVS writes
+struct ScriptStore {
+    pub stored: StoredScripts,
+    pub boot_report: Option<MacroReport>,
+    dirty: bool,
+}
+
+impl ScriptStore {
+    fn load(nvs_scripts: &nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<Self> {
+        let stored = match nvs_scripts.blob_len("scripts")? {
+            Some(len) => {
+                let mut buf = vec![0; len];
+                nvs_scripts.get_raw("scripts", &mut buf)?;
+                match serde_json::from_slice(&buf) {
+                    Ok(stored) => stored,
+                    Err(e) => {
+                        info!("Stored scripts could not be read, starting with none: {}", e);
+                        StoredScripts::default()
+                    }
+                }
+            }
+            None => StoredScripts::default(),
+        };
+        Ok(Self { stored, boot_report: None, dirty: false })
+    }
+
+    fn save(&mut self, req: SaveScriptRequest) -> Result<(), String> {
+        if req.name.is_empty() || req.name.len() > MAX_SCRIPT_NAME_LEN {
+            return Err(format!("name must be 1 to {} characters", MAX_SCRIPT_NAME_LEN));
+        }
+        if !self.stored.scripts.contains_key(&req.name) && self.stored.scripts.len() >= MAX_SCRIPTS {
+            return Err(format!("already {} scripts stored, delete one first", MAX_SCRIPTS));
+        }
+        validate_steps(&req.steps)?;
+        let previous = self.stored.scripts.insert(req.name.clone(), req.steps);
+        if serde_json::to_string(&self.stored).unwrap().len() > MAX_SCRIPTS_JSON_LEN {
+            // put things back the way they were
+            match previous {
+                Some(steps) => { self.stored.scripts.insert(req.name, steps); }
+                None => { self.stored.scripts.remove(&req.name); }
+            }
+            return Err("not enough room to store that script".to_string());
+        }
+        if req.on_boot {
+            self.stored.on_boot = Some(req.name);
+        } else if self.stored.on_boot.as_ref() == Some(&req.name) {
+            self.stored.on_boot = None;
+        }
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn delete(&mut self, name: &str) -> bool {
+        if self.stored.scripts.remove(name).is_none() {
+            return false;
+        }
+        if self.stored.on_boot.as_deref() == Some(name) {
+            self.stored.on_boot = None;
+        }
+        self.dirty = true;
+        true
+    }
+
+    fn take_dirty_json(&mut self) -> Option<String> {
+        if !self.dirty {
+            return None;
+        }
+        self.dirty = false;
+        Some(serde_json::to_string(&self.stored).unwrap())
+    }
+
+    fn summary_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "scripts": self.stored.scripts.iter()
+                .map(|(name, steps)| serde_json::json!({"name": name, "steps": steps.len()}))
+                .collect::<Vec<_>>(),
+            "on_boot": self.stored.on_boot,
+            "boot_report": self.boot_report,
+        })
+    }
 }
 
 fn main() -> anyhow::Result<()> {
@@ -66,9 +373,7 @@ fn main() -> anyhow::Result<()> {
     let rmtconfig = rmt::config::TransmitConfig::new().clock_divider(1);
     #[cfg(feature="ws2182onboard")]
     let mut npx = Ws2812B::new(rmt::TxRmtDriver::new(peripherals.rmt.channel0, pin_from_envar!(pins, "LED_PIN_NUM"), &rmtconfig)?);
-    // red during setup
-    #[cfg(feature="ws2182onboard")]
-    npx.set(Rgb::new(20, 0, 0))?;
+    show_startup_phase(StartupPhase::Uart, &mut npx)?;
 
     // start by setting up uart
     let uart_config = uart::config::Config::default()
@@ -87,45 +392,106 @@ fn main() -> anyhow::Result<()> {
         &uart_config
     ).unwrap();
 
-    #[cfg(feature="ws2182onboard")]
-    npx.set(Rgb::new(20, 5, 0))?;
+    let nvs_default_partition = nvs::EspDefaultNvsPartition::take()?;
+    let mut nvs_scripts = nvs::EspNvs::new(nvs_default_partition.clone(), "scripts", true)?;
+    let scripts = Arc::new(Mutex::new(ScriptStore::load(&nvs_scripts)?));
 
     // start up the wifi then try to configure the server
-    let _wifi = setup_wifi(peripherals.modem)?;
+    let _wifi = setup_wifi(peripherals.modem, nvs_default_partition, 
+                           &mut |phase| show_startup_phase(phase, &mut npx))?;
 
-    #[cfg(feature="ws2182onboard")]
-    npx.set(Rgb::new(20, 20, 0))?;
+    show_startup_phase(StartupPhase::Http, &mut npx)?;
 
     let server_configuration = http::server::Configuration {
         stack_size: HTTP_SERVER_STACK_SIZE,
         ..Default::default()
     };
     let mut server = http::server::EspHttpServer::new(&server_configuration)?;
-    let sessions = setup_handlers(&mut server)?;
+    let sessions = setup_handlers(&mut server, scripts.clone())?;
 
 
     info!("Setup complete!");
 
+    // the boot script runs once, before anything a session sends
+    let boot_script = {
+        let store = scripts.lock().unwrap();
+        store.stored.on_boot.as_ref().and_then(|name| store.stored.scripts.get(name).map(|steps| (name.clone(), steps.clone())))
+    };
+    if let Some((name, steps)) = boot_script {
+        info!("running boot script {} with {} steps", name, steps.len());
+        let report = run_macro(&uart, &steps)?;
+        info!("boot script {} finished, ok={}", name, report.ok);
+        scripts.lock().unwrap().boot_report = Some(report);
+    }
+    let boot_instant = Instant::now();
+    let mut rx_assembler = RxAssembler::new();
+    let mut last_ping = Instant::now();
+
     // serve and loop forever...
     loop {
         let loopstart = Instant::now();
 
         // green at the start of the loop
         #[cfg(feature="ws2182onboard")]
-        npx.set(Rgb::new(0, 20, 0))?;
+        npx.set(Rgb::new(0, LED_BRIGHTNESS, 0))?;
 
         {
             let mut sess = sessions.lock().unwrap();  // lock access
             // Write out any data in the tx_queues of the sessions
+            let mut written = Vec::new();
             for session in sess.iter_mut() {
                 let tx = &mut session.tx_queue;
                 while !tx.is_empty() {
                     let n_drain = 1024.min(tx.len()); // at most a kilobyte at a time
-                    let d = tx.drain(..n_drain);
+                    let d: Vec<u8> = tx.drain(..n_drain).collect();
                     info!("writing n={}",d.len());
                     uart.write(d.as_slice())?;
+                    written.push(UartFrame { direction: Direction::ToUnit, ms: ms_since(boot_instant), bytes: d });
                 }
             }
+            // the tunnel shows both directions, so what anyone wrote goes to every binary session
+            for session in sess.iter_mut().filter(|s| s.binary_frames) {
+                session.rx_frames.extend(written.iter().cloned());
+            }
+        }
+
+        // run any macros that have been uploaded.  The macro is taken out of the session so the lock isn't held while it runs
+        let macros: Vec<(i32, Vec<MacroStep>)> = sessions.lock().unwrap().iter_mut()
+            .filter_map(|s| s.pending_macro.take().map(|m| (s.session, m)))
+            .collect();
+        for (session_id, steps) in macros {
+            info!("running macro with {} steps for session {}", steps.len(), session_id);
+            let report = run_macro(&uart, &steps)?;
+            info!("macro for session {} finished, ok={}", session_id, report.ok);
+
+            let mut sess = sessions.lock().unwrap();
+            if let Some(session) = sess.iter_mut().find(|s| s.session == session_id) {
+                session.macro_report = Some(report);
+            }
+        }
+
+        if last_ping.elapsed() >= WS_PING_INTERVAL {
+            last_ping = Instant::now();
+            // drop the sessions nobody is on the other end of, so orphaned tabs can't pile up
+            sessions.lock().unwrap().retain_mut(|session| {
+                if session.last_active.elapsed() >= WS_IDLE_TIMEOUT {
+                    info!("Session {} idle for {:?}, closing it", session.session, session.last_active.elapsed());
+                    let _ = session.sender.send(FrameType::Close, &[]);
+                    return false;
+                }
+                match session.sender.send(FrameType::Ping, &[]) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        info!("Session {} could not be pinged, dropping it: {}", session.session, e);
+                        false
+                    }
+                }
+            });
+        }
+
+        if let Some(json) = scripts.lock().unwrap().take_dirty_json() {
+            info!("saving scripts");
+            nvs_scripts.set_raw("scripts", json.as_bytes())?;
         }
 
         let mut buf = [0_u8; 100];
@@ -133,11 +499,23 @@ fn main() -> anyhow::Result<()> {
         let t: u32 = timeout.into();
         let size = uart.read(&mut buf, t)?;
 
-        // Now fill the rx queues with whatever the uart returned
-        if size> 0 {
+        // Now fill the rx queues with whatever the uart returned.  The assembler is fed even on empty reads so a
+        // partial packet that never gets finished still comes out eventually
+        let items = rx_assembler.feed(&buf[..size]);
+        if size > 0 || !items.is_empty() {
             let mut sess = sessions.lock().unwrap();  // lock access
+            let ms = ms_since(boot_instant);
             for session in sess.iter_mut() {
-                session.rx_queue.extend_from_slice(&buf[..size]);
+                if session.binary_frames {
+                    if size > 0 {
+                        session.rx_frames.push(UartFrame { direction: Direction::FromUnit, ms, bytes: buf[..size].to_vec() });
+                    }
+                } else if session.subscription.mode == RxMode::Raw {
+                    session.rx_queue.extend_from_slice(&buf[..size]);
+                } else {
+                    let subscription = &session.subscription;
+                    session.rx_items.extend(items.iter().filter(|item| subscription.wants(item)).cloned());
+                }
             }
         }
 
@@ -147,7 +525,7 @@ fn main() -> anyhow::Result<()> {
 
             // magenta for napping
             #[cfg(feature="ws2182onboard")]
-            npx.set(Rgb::new(20, 0, 20))?;
+            npx.set(Rgb::new(LED_BRIGHTNESS, 0, LED_BRIGHTNESS))?;
             info!("loop too short, sleeping for {sleepdur:?}");
 
             std::thread::sleep(sleepdur);
@@ -156,9 +534,19 @@ fn main() -> anyhow::Result<()> {
     }
 }
 
-fn setup_wifi<'a>(pmodem: hal::modem::Modem) -> anyhow::Result<BlockingWifi<EspWifi<'a>>> {
+fn show_startup_phase(phase: StartupPhase, npx: &mut Ws2812B) -> anyhow::Result<()> {
+    info!("Startup phase: {:?}", phase);
+    #[cfg(feature="ws2182onboard")]
+    {
+        let (r, g, b) = phase.color(LED_BRIGHTNESS);
+        npx.set(Rgb::new(r, g, b))?;
+    }
+    Ok(())
+}
+
+fn setup_wifi<'a>(pmodem: hal::modem::Modem, nvs: nvs::EspDefaultNvsPartition,
+                  on_phase: &mut dyn FnMut(StartupPhase) -> anyhow::Result<()>) -> anyhow::Result<BlockingWifi<EspWifi<'a>>> {
     let sys_loop = EspSystemEventLoop::take()?;
-    let nvs = EspDefaultNvsPartition::take()?;
 
     let mut wifi = BlockingWifi::wrap(
         EspWifi::new(pmodem, sys_loop.clone(), Some(nvs))?,
@@ -179,6 +567,7 @@ fn setup_wifi<'a>(pmodem: hal::modem::Modem) -> anyhow::Result<BlockingWifi<EspW
     wifi.start()?;
 
     // first scan to check that there's a match.
+    on_phase(StartupPhase::WifiScan)?;
     let mut ssid_match = false;
     for result in wifi.scan()?.iter(){
         if SSID == result.ssid.as_str() {
@@ -187,6 +576,7 @@ fn setup_wifi<'a>(pmodem: hal::modem::Modem) -> anyhow::Result<BlockingWifi<EspW
         }
     }
 
+    on_phase(StartupPhase::WifiConnect)?;
     if ssid_match {
         info!("found ssid {}, connecting", SSID);
         wifi.connect()?;
@@ -209,6 +599,7 @@ fn setup_wifi<'a>(pmodem: hal::modem::Modem) -> anyhow::Result<BlockingWifi<EspW
         wifi.start()?;
     }
 
+    on_phase(StartupPhase::Ip)?;
     wifi.wait_netif_up()?;
 
     match wifi.get_configuration()? {
@@ -229,7 +620,8 @@ fn setup_wifi<'a>(pmodem: hal::modem::Modem) -> anyhow::Result<BlockingWifi<EspW
     Ok(wifi)
 }
 
-fn setup_handlers(server: &mut http::server::EspHttpServer) -> Result<Arc<Mutex<Vec<WebSocketSession>>>,EspError> {
+fn setup_handlers(server: &mut http::server::EspHttpServer, 
+                  scripts: Arc<Mutex<ScriptStore>>) -> Result<Arc<Mutex<Vec<WebSocketSession>>>,EspError> {
     
     let index_handler = |req: http::server::Request<&mut http::server::EspHttpConnection>| {
         req.into_ok_response()?.write(INDEX_HTML.as_bytes()).map(|_| ())
@@ -242,14 +634,31 @@ fn setup_handlers(server: &mut http::server::EspHttpServer) -> Result<Arc<Mutex<
     let sessions = Arc::new(Mutex::new(Vec::<WebSocketSession>::new()));
     
     let vmu = sessions.clone();
+    let max_sessions: usize = WS_MAX_SESSIONS.parse().unwrap();
 
     server.ws_handler("/ws/uart", move |ws| {
         if ws.is_new() { 
             let mut v = vmu.lock().unwrap();
+            if v.len() >= max_sessions {
+                // make room by dropping whichever session has been quiet the longest
+                if let Some((idx, _)) = v.iter().enumerate().min_by_key(|(_, s)| s.last_active) {
+                    let mut evicted = v.remove(idx);
+                    info!("Too many sessions, evicting session {}", evicted.session);
+                    let _ = evicted.sender.send(FrameType::Close, &[]);
+                }
+            }
             v.push(WebSocketSession {
                 tx_queue: Vec::new(),
                 rx_queue: Vec::new(),
+                rx_items: Vec::new(),
+                subscription: RxSubscription::new(),
                 session: ws.session(),
+                pending_macro: None,
+                macro_report: None,
+                binary_frames: false,
+                rx_frames: Vec::new(),
+                last_active: Instant::now(),
+                sender: ws.create_detached_sender()?,
             }); 
             info!("Session {} begun", ws.session());
         } else {
@@ -269,6 +678,7 @@ fn setup_handlers(server: &mut http::server::EspHttpServer) -> Result<Arc<Mutex<
                         info!("Session {} closed", ws.session());
                     } else {
                         let session = v.get_mut(idx).unwrap();
+                        session.last_active = Instant::now();
 
                         // this is the real work of the handler for recv/send
                         let (frame_type, len) = ws.recv(&mut [])?;
@@ -280,8 +690,7 @@ fn setup_handlers(server: &mut http::server::EspHttpServer) -> Result<Arc<Mutex<
                         match frame_type {
                             FrameType::Text(continuation) => {
                                 if continuation {
-                                    info!("unexpected continuation text frame");
-                                    return Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>());
+                                    return Err(FirmwareError::new(ErrorCode::WsUnexpectedFrame, "unexpected continuation text frame"));
                                 }
                                 //the last byte I think is always a null terminator, but confirm and remove if so...
                                 if let Some(v) = rvec.pop() {
@@ -289,14 +698,98 @@ fn setup_handlers(server: &mut http::server::EspHttpServer) -> Result<Arc<Mutex<
                                 }
                                 match  std::str::from_utf8(rvec.as_slice()) {
                                     Ok(s) => {
-                                        if s == "recv?" {
-                                            
-                                            let rxbuf = session.rx_queue.drain(..);
-                                            if rxbuf.len() > 0 {
+                                        if s == "recv?" && session.binary_frames {
+                                            if !session.rx_frames.is_empty() {
+                                                let mut out = Vec::new();
+                                                for frame in session.rx_frames.drain(..) {
+                                                    frame.encode_into(&mut out);
+                                                }
+                                                ws.send(FrameType::Binary(false), &out)?;
+                                            }
+                                        } else if s == "recv?" {
+                                            // each packet that decodes goes out as json, and anything that doesn't
+                                            // as it is
+                                            if !session.rx_queue.is_empty() {
+                                                ws.send(FrameType::Text(false), 
+                                                        format!("Rxed: {:?}", session.rx_queue.as_slice()).as_bytes())?;
+                                                session.rx_queue.clear();
+                                            }
+                                            for item in session.rx_items.drain(..) {
+                                                let msg = match item {
+                                                    RxItem::Packet(packet) => packet_json(&packet).to_string(),
+                                                    RxItem::Junk(bytes) => format!("Rxed: {:?}", bytes.as_slice()),
+                                                };
+                                                ws.send(FrameType::Text(false), msg.as_bytes())?;
+                                            }
+                                        } else if let Some(subjson) = s.strip_prefix("subscribe:") {
+                                            match serde_json::from_str::<RxSubscription>(subjson) {
+                                                Ok(subscription) => {
+                                                    info!("Session {} subscribed to {:?}", ws.session(), subscription);
+                                                    session.subscription = subscription;
+                                                    session.rx_queue.clear();
+                                                    session.rx_items.clear();
+                                                }
+                                                Err(e) => {
+                                                    ws.send(FrameType::Text(false), 
+                                                            format!("Subscribe error: {}", e).as_bytes())?;
+                                                }
+                                            }
+                                        } else if s == "frames:binary" || s == "frames:text" {
+                                            session.binary_frames = s == "frames:binary";
+                                            session.rx_queue.clear();
+                                            session.rx_items.clear();
+                                            session.rx_frames.clear();
+                                            info!("Session {} switched to {}", ws.session(), s);
+                                        } else if s == "macro?" {
+                                            if let Some(report) = session.macro_report.take() {
+                                                let reportjson = serde_json::to_string(&report).unwrap();
+                                                ws.send(FrameType::Text(false), 
+                                                        format!("Macro: {}", reportjson).as_bytes())?;
+                                            }
+                                        } else if s == "scripts?" {
+                                            let summary = scripts.lock().unwrap().summary_json();
+                                            ws.send(FrameType::Text(false), 
+                                                    format!("Scripts: {}", summary).as_bytes())?;
+                                        } else if let Some(scriptjson) = s.strip_prefix("save:") {
+                                            let result = serde_json::from_str::<SaveScriptRequest>(scriptjson)
+                                                .map_err(|e| e.to_string())
+                                                .and_then(|req| scripts.lock().unwrap().save(req));
+                                            if let Err(e) = result {
+                                                ws.send(FrameType::Text(false), 
+                                                        format!("Script error: {}", e).as_bytes())?;
+                                            }
+                                        } else if let Some(name) = s.strip_prefix("delete:") {
+                                            if !scripts.lock().unwrap().delete(name) {
                                                 ws.send(FrameType::Text(false), 
-                                                        format!("Rxed: {:?}", rxbuf.as_slice()).as_bytes())?;
+                                                        format!("Script error: no script named {:?}", name).as_bytes())?;
+                                            }
+                                        } else if let Some(name) = s.strip_prefix("replay:") {
+                                            let steps = scripts.lock().unwrap().stored.scripts.get(name).cloned();
+                                            match steps {
+                                                Some(steps) => {
+                                                    info!("Replaying script {} with {} steps", name, steps.len());
+                                                    session.macro_report = None;
+                                                    session.pending_macro = Some(steps);
+                                                }
+                                                None => {
+                                                    ws.send(FrameType::Text(false), 
+                                                            format!("Script error: no script named {:?}", name).as_bytes())?;
+                                                }
+                                            }
+                                        } else if let Some(macrojson) = s.strip_prefix("macro:") {
+                                            match serde_json::from_str::<Vec<MacroStep>>(macrojson).map_err(|e| e.to_string())
+                                                    .and_then(|steps| validate_steps(&steps).map(|()| steps)) {
+                                                Ok(steps) => {
+                                                    info!("Received macro with {} steps", steps.len());
+                                                    session.macro_report = None;
+                                                    session.pending_macro = Some(steps);
+                                                }
+                                                Err(e) => {
+                                                    ws.send(FrameType::Text(false), 
+                                                            format!("Macro error: {}", e).as_bytes())?;
+                                                }
                                             }
-                                        }  else {
+                                        } else {
                                             info!("Received text that was not understood: {s:?}");
                                         }
                                     },
@@ -307,35 +800,116 @@ fn setup_handlers(server: &mut http::server::EspHttpServer) -> Result<Arc<Mutex<
                             },
                             FrameType::Binary(continuation) => {
                                 if continuation {
-                                    info!("unexpected continuation binary frame");
-                                    return Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>());
+                                    return Err(FirmwareError::new(ErrorCode::WsUnexpectedFrame, "unexpected continuation binary frame"));
                                 }
 
                                 info!("Received binary: {:?}", rvec);
+                                if session.binary_frames {
+                                    // a tunnel sends whole packets, checksum and all, so nothing is added here
+                                    match UartFrame::decode_all(&rvec) {
+                                        Ok(frames) => {
+                                            for frame in frames {
+                                                if frame.direction != Direction::ToUnit {
+                                                    ws.send(FrameType::Text(false), b"Frame error: only frames to the unit can be sent")?;
+                                                    continue;
+                                                }
+                                                session.tx_queue.extend_from_slice(&frame.bytes);
+                                            }
+                                        }
+                                        Err(e) => {
+                                            ws.send(FrameType::Text(false), format!("Frame error: {}", e).as_bytes())?;
+                                        }
+                                    }
+                                } else {
                                     session.tx_queue.extend_from_slice(rvec.as_mut_slice());
-                                    session.tx_queue.push(checksum(rvec));
-                                
+                                    session.tx_queue.push(checksum(&rvec));
+                                }
+                            },
+                            FrameType::Ping | FrameType::Pong => {
+                                // nothing to do beyond noting the session is alive
                             },
                             _ => {
-                                info!("Received unknown frame type: {:?}", frame_type);
-                                return Err(EspError::from_infallible::<ESP_ERR_INVALID_RESPONSE>());
+                                return Err(FirmwareError::new(ErrorCode::WsUnexpectedFrame, 
+                                                              format!("Received unknown frame type: {:?}", frame_type)));
                             }
                         }
                     }
                 }
-                None => { return Err(EspError::from_infallible::<ESP_ERR_INVALID_STATE>()); }
+                None if ws.is_closed() => {
+                    // one that was evicted or timed out, and is already gone from the list
+                }
+                None => { 
+                    return Err(FirmwareError::new(ErrorCode::WsSessionMissing, 
+                                                  format!("no session {} for websocket traffic", ws.session())));
+                }
             }
         }
-        Ok(())
+        Ok::<(), FirmwareError>(())
     })?;
 
     Ok(sessions)
 }
 
-fn checksum(rvec: Vec<u8>) -> u8 {
-    let mut sum = 0u8;
-    for b in rvec.iter() {
-        sum += b;
+fn packet_json(packet: &Packet) -> serde_json::Value {
+    serde_json::json!({
+        "packet_type": packet.packet_type,
+        "header": [packet.h2, packet.h3],
+        "data": packet.data,
+        "checksum": packet.checksum,
+        "checksum_ok": packet.check_checksum(),
+    })
+}
+
+// wraps after ~49 days, which the frame reader can live with
+fn ms_since(start: Instant) -> u32 {
+    start.elapsed().as_millis() as u32
+}
+
+fn run_macro(uart: &uart::UartDriver, steps: &[MacroStep]) -> anyhow::Result<MacroReport> {
+    let mut results = Vec::with_capacity(steps.len());
+    let mut all_ok = true;
+
+    for (i, step) in steps.iter().enumerate() {
+        let step_start = Instant::now();
+        let (op, ok, bytes) = match step {
+            MacroStep::Send { bytes } => {
+                let mut tosend = bytes.clone();
+                tosend.push(checksum(bytes));
+                uart.write(&tosend)?;
+                ("send", true, tosend)
+            }
+            MacroStep::Wait { ms } => {
+                // scripts stored before there was a limit aren't checked, so it's applied here too
+                std::thread::sleep(Duration::from_millis(*ms).min(MACRO_MAX_STEP_TIME));
+                ("wait", true, Vec::new())
+            }
+            MacroStep::Expect { bytes, timeout_ms } => {
+                let timeout = timeout_ms.map(Duration::from_millis).unwrap_or(MACRO_DEFAULT_EXPECT_TIMEOUT).min(MACRO_MAX_STEP_TIME);
+                let mut received = Vec::new();
+                let mut buf = [0_u8; 100];
+                let t: u32 = hal::delay::TickType::from(UART_TIMEOUT).into();
+                while received.len() < bytes.len() && step_start.elapsed() < timeout {
+                    let size = uart.read(&mut buf, t)?;
+                    received.extend_from_slice(&buf[..size]);
+                }
+                ("expect", received.starts_with(bytes), received)
+            }
+        };
+
+        results.push(MacroStepResult {
+            step: i,
+            op,
+            ok,
+            bytes,
+            elapsed_ms: step_start.elapsed().as_millis(),
+        });
+
+        if !ok {
+            // no point in continuing a sequence once the unit has said something unexpected
+            all_ok = false;
+            break;
+        }
     }
-    0xfc - sum
-}
\ No newline at end of file
+
+    Ok(MacroReport { ok: all_ok, steps: results })
+}
@@ -0,0 +1,242 @@
+#![allow(dead_code)]
+
+use std::ops::{Deref, DerefMut};
+
+use serde::Serialize;
+
+/// The most data any CN105 packet carries
+pub const PACKET_DATA_MAX: usize = 16;
+/// A whole packet: the five header bytes, the data, and the checksum
+pub const PACKET_SIZE_MAX: usize = PACKET_DATA_MAX + 6;
+/// The connection string.  The unit answers it with a 0x7a packet
+pub const CONNECT_BYTES: [u8; 8] = [0xfc, 0x5a, 0x01, 0x30, 0x02, 0xca, 0x01, 0xa8];
+
+#[derive(Debug)]
+pub enum PacketError {
+    TooShort,
+    NoSync,
+    LengthMismatch,
+    BadChecksum,
+    TooLong,
+}
+impl PacketError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            PacketError::TooShort => "Packet too short to be a valid packet",
+            PacketError::NoSync => "Packet does not start with 0xfc",
+            PacketError::LengthMismatch => "Packet length in header does not match received data",
+            PacketError::BadChecksum => "Packet checksum does not match",
+            PacketError::TooLong => "Packet length in header is more than any packet carries",
+        }
+    }
+}
+impl std::fmt::Display for PacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.message())
+    }
+}
+impl std::error::Error for PacketError {}
+
+/// A packet's data, kept inline rather than on the heap since packets are made and thrown away on every poll.  It
+/// derefs to the slice, so it indexes and iterates like the Vec it replaces
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct PacketData {
+    buf: [u8; PACKET_DATA_MAX],
+    len: u8,
+}
+impl PacketData {
+    pub fn new() -> Self {
+        Self { buf: [0; PACKET_DATA_MAX], len: 0 }
+    }
+
+    /// All zeros, at most PACKET_DATA_MAX long
+    pub fn zeroed(len: usize) -> Self {
+        Self { buf: [0; PACKET_DATA_MAX], len: len.min(PACKET_DATA_MAX) as u8 }
+    }
+
+    pub fn from_slice(bytes: &[u8]) -> Result<Self, PacketError> {
+        if bytes.len() > PACKET_DATA_MAX {
+            return Err(PacketError::TooLong);
+        }
+        let mut data = Self::zeroed(bytes.len());
+        data.buf[..bytes.len()].copy_from_slice(bytes);
+        Ok(data)
+    }
+}
+impl Deref for PacketData {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        &self.buf[..self.len as usize]
+    }
+}
+impl DerefMut for PacketData {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buf[..self.len as usize]
+    }
+}
+impl std::fmt::Debug for PacketData {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.deref().fmt(f)
+    }
+}
+impl Serialize for PacketData {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.deref().serialize(serializer)
+    }
+}
+
+#[derive(Debug)]
+pub struct Packet {
+    pub packet_type: u8,
+    pub h2: u8,
+    pub h3: u8,
+    pub data: PacketData,
+    pub checksum: u8
+}
+impl Packet {
+    pub fn new() -> Self {
+        Self {
+            packet_type: 0,
+            h2: 0x01,
+            h3: 0x30,
+            data: PacketData::new(),
+            checksum: 0
+        }
+    }
+
+    pub fn new_type_size(ptype: u8, size: usize) -> Self {
+        Self {
+            packet_type: ptype,
+            h2: 0x01,
+            h3: 0x30,
+            data: PacketData::zeroed(size),
+            checksum: 0
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self>  {
+        let packet = Self::from_bytes_unchecked(bytes)?;
+        if !packet.check_checksum() {
+            return Err(PacketError::BadChecksum.into());
+        }
+        Ok(packet)
+    }
+
+    /// Like from_bytes, but without checking the checksum, for looking at packets that might be bad
+    pub fn from_bytes_unchecked(bytes: &[u8]) -> anyhow::Result<Self>  {
+        if bytes.len() < 6 {
+            return Err(PacketError::TooShort.into());
+        }
+        if bytes[0] != 0xfc {
+            return Err(PacketError::NoSync.into());
+        }
+
+        let mut packet = Self::new();
+        packet.packet_type = bytes[1];
+        packet.h2 = bytes[2];
+        packet.h3 = bytes[3];
+        let len = bytes[4] as usize;
+        if len > PACKET_DATA_MAX {
+            return Err(PacketError::TooLong.into());
+        }
+        if bytes.len() < 6+len {
+            return Err(PacketError::LengthMismatch.into());
+        }
+        packet.data = PacketData::from_slice(&bytes[5..5 + len])?;
+        packet.checksum = bytes[5 + len];
+
+        Ok(packet)
+    }
+
+    pub fn packet_size(&self) -> usize {
+        6 + self.data.len()
+    }
+
+    /// Writes the packet into the buffer, returning the part of it that's the packet
+    pub fn to_bytes<'a>(&self, buf: &'a mut [u8; PACKET_SIZE_MAX]) -> &'a [u8] {
+        let len = self.data.len();
+        buf[0] = 0xfc;
+        buf[1] = self.packet_type;
+        buf[2] = self.h2;
+        buf[3] = self.h3;
+        buf[4] = len as u8;
+        buf[5..5 + len].copy_from_slice(&self.data);
+        buf[5 + len] = self.checksum;
+        &buf[..6 + len]
+    }
+
+    pub fn compute_checksum(&self) -> u8 {
+        let mut buf = [0u8; PACKET_SIZE_MAX];
+        let bytes = self.to_bytes(&mut buf);
+        checksum(&bytes[..bytes.len() - 1])
+    }
+
+    pub fn check_checksum(&self) -> bool {
+        self.checksum == self.compute_checksum()
+    }
+
+    pub fn set_checksum(&mut self) {
+        self.checksum = self.compute_checksum();
+    }
+}
+
+/// The checksum of a packet, given everything from the 0xfc sync byte to the end of the data.  The sum wraps, as it
+/// does on the wire; real packets overflow a u8 all the time
+pub fn checksum(bytes: &[u8]) -> u8 {
+    let sum = bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+    0xfcu8.wrapping_sub(sum)
+}
+
+/// Builds a packet up from bytes as they come in: skips anything before a 0xfc sync byte, then takes the header, and
+/// then however much payload the header says there is.  Feeding it no more than wanted() bytes at a time means
+/// nothing past the end of the packet is read
+pub struct PacketParser {
+    buf: Vec<u8>,
+    skipped: usize,
+}
+impl PacketParser {
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            skipped: 0,
+        }
+    }
+
+    /// How many more bytes it takes to get to the next step
+    pub fn wanted(&self) -> usize {
+        match self.buf.len() {
+            0 => 1,
+            n if n < 5 => 5 - n,
+            n => 6 + self.buf[4] as usize - n,
+        }
+    }
+
+    /// The number of bytes thrown away looking for a sync byte
+    pub fn skipped(&self) -> usize {
+        self.skipped
+    }
+
+    /// Whether a packet has been started but not finished
+    pub fn in_packet(&self) -> bool {
+        !self.buf.is_empty()
+    }
+
+    /// Returns the packet once its last byte has come in, or the error if it isn't a good one
+    pub fn feed(&mut self, byte: u8) -> Option<anyhow::Result<Packet>> {
+        if self.buf.is_empty() && byte != 0xfc {
+            self.skipped += 1;
+            return None;
+        }
+        self.buf.push(byte);
+        if self.buf.len() == 5 && self.buf[4] as usize > PACKET_DATA_MAX {
+            // no point reading a payload that can't be real, the sync byte must have been noise
+            self.buf.clear();
+            return Some(Err(PacketError::TooLong.into()));
+        }
+        if self.buf.len() >= 5 && self.wanted() == 0 {
+            let bytes = std::mem::take(&mut self.buf);
+            return Some(Packet::from_bytes(&bytes));
+        }
+        None
+    }
+}
@@ -0,0 +1,117 @@
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PidConfig {
+    pub enabled: bool,
+    pub kp: f32,
+    pub ki: f32,  // per second
+    pub kd: f32,  // seconds
+    pub max_trim_c: f32,  // the commanded setpoint never moves further than this from the user target
+    pub interval_secs: u64,
+}
+impl PidConfig {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            kp: 1.0,
+            ki: 0.001,
+            kd: 0.0,
+            max_trim_c: 2.0,
+            interval_secs: 300,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, value) in [("kp", self.kp), ("ki", self.ki), ("kd", self.kd), ("max_trim_c", self.max_trim_c)] {
+            if !value.is_finite() || value < 0.0 {
+                return Err(format!("{} must be a non-negative number", name));
+            }
+        }
+        if self.interval_secs == 0 {
+            return Err("interval_secs must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PidTrim {
+    // Adjusts the setpoint sent to the heat pump so the room (rather than the unit's own sensor) ends up at the user target
+    pub config: PidConfig,
+    pub user_target_c: Option<f32>,
+    pub trim_c: f32,
+    pub integral: f32,
+    pub last_error_c: Option<f32>,
+    #[serde(skip)]
+    pub last_update: Option<Instant>,
+}
+
+impl PidTrim {
+    pub fn new() -> Self {
+        Self {
+            config: PidConfig::new(),
+            user_target_c: None,
+            trim_c: 0.0,
+            integral: 0.0,
+            last_error_c: None,
+            last_update: None,
+        }
+    }
+
+    /// Called when the user asks for a new temperature, which becomes the target to trim around
+    pub fn set_user_target(&mut self, target_c: f32) {
+        self.user_target_c = Some(target_c);
+        self.reset();
+    }
+
+    pub fn reset(&mut self) {
+        self.trim_c = 0.0;
+        self.integral = 0.0;
+        self.last_error_c = None;
+        self.last_update = None;
+    }
+
+    /// Steps the controller if the interval has passed, returning a new setpoint to command if it changed.
+    /// The same sign works for both heating and cooling: a room that is too cold always wants a higher setpoint.
+    pub fn update(&mut self, room_temperature_c: f32) -> Option<f32> {
+        if !self.config.enabled {
+            return None;
+        }
+        let target_c = self.user_target_c?;
+
+        let now = Instant::now();
+        let dt = match self.last_update {
+            Some(t) if now - t < Duration::from_secs(self.config.interval_secs) => { return None; }
+            Some(t) => (now - t).as_secs_f32(),
+            None => self.config.interval_secs as f32,
+        };
+        self.last_update = Some(now);
+
+        let error_c = target_c - room_temperature_c;
+        self.integral += error_c * dt;
+        // anti-windup: don't let the integral term alone exceed the allowed trim
+        if self.config.ki > 0.0 {
+            let max_integral = self.config.max_trim_c / self.config.ki;
+            self.integral = self.integral.clamp(-max_integral, max_integral);
+        }
+        let derivative = match self.last_error_c {
+            Some(last) => (error_c - last) / dt,
+            None => 0.0,
+        };
+        self.last_error_c = Some(error_c);
+
+        let output = self.config.kp * error_c + self.config.ki * self.integral + self.config.kd * derivative;
+        // the heat pump only takes half-degree steps
+        let trim_c = (output.clamp(-self.config.max_trim_c, self.config.max_trim_c) * 2.0).round() / 2.0;
+
+        if trim_c == self.trim_c {
+            return None;
+        }
+        info!("PID trim changing from {} to {} C (room {} C, target {} C)", self.trim_c, trim_c, room_temperature_c, target_c);
+        self.trim_c = trim_c;
+        Some(target_c + trim_c)
+    }
+}
@@ -0,0 +1,50 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+const WINDOW: Duration = Duration::from_secs(60);
+// addresses tracked at once.  Past this, new ones only count towards the overall limit until the window is up
+const CLIENTS_MAX: usize = 16;
+
+/// Counts requests in one-minute windows, both overall and for each address they come from
+#[derive(Debug)]
+pub struct RateLimiter {
+    window_start: Instant,
+    total: u32,
+    per_client: HashMap<IpAddr, u32>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            window_start: Instant::now(),
+            total: 0,
+            per_client: HashMap::new(),
+        }
+    }
+
+    /// Counts a request if it's within the limits (0 being no limit), or returns how long until the next one would be
+    pub fn check(&mut self, client: Option<IpAddr>, per_min: u32, client_per_min: u32) -> Result<(), Duration> {
+        if self.window_start.elapsed() >= WINDOW {
+            self.window_start = Instant::now();
+            self.total = 0;
+            self.per_client.clear();
+        }
+        let retry_after = WINDOW.saturating_sub(self.window_start.elapsed());
+
+        if per_min != 0 && self.total >= per_min {
+            return Err(retry_after);
+        }
+        if let Some(client) = client {
+            if self.per_client.len() < CLIENTS_MAX || self.per_client.contains_key(&client) {
+                let count = self.per_client.entry(client).or_insert(0);
+                if client_per_min != 0 && *count >= client_per_min {
+                    return Err(retry_after);
+                }
+                *count += 1;
+            }
+        }
+        self.total += 1;
+        Ok(())
+    }
+}
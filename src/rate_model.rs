@@ -0,0 +1,103 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::clock;
+
+// how far from the setpoint the room has to be for the heat pump to count as working towards it
+const APPROACH_MARGIN_C: f32 = 0.5;
+// the room temperature only comes in half-degree steps, so it takes a while for a change to show
+const SAMPLE_WINDOW: Duration = Duration::from_secs(10*60);
+const SMOOTHING_ALPHA: f32 = 0.2;
+const SAVE_INTERVAL: Duration = Duration::from_secs(30*60);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RateEstimate {
+    pub c_per_min: f32,  // towards the setpoint, so positive is good for both heating and cooling
+    pub samples: u32,
+    pub updated: Option<String>,
+}
+
+#[derive(Debug)]
+struct Window {
+    key: String,
+    direction: f32,
+    setpoint_c: f32,
+    start: Instant,
+    start_c: f32,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RateModel {
+    // How fast the room moves towards the setpoint for each mode and fan speed, learned while running
+    pub rates: BTreeMap<String, RateEstimate>,
+    #[serde(skip)]
+    window: Option<Window>,
+    #[serde(skip)]
+    pub last_save: Option<Instant>,
+    #[serde(skip)]
+    pub dirty: bool,
+}
+
+impl RateModel {
+    pub fn new() -> Self {
+        Self {
+            rates: BTreeMap::new(),
+            window: None,
+            last_save: None,
+            dirty: false,
+        }
+    }
+
+    /// Called every loop.  key is e.g. "Heat/High", and direction is 1 when heating and -1 when cooling, or None
+    /// if the heat pump isn't trying to move the temperature (off, fan only, no reading...)
+    pub fn observe(&mut self, key_direction: Option<(String, f32)>, room_c: f32, setpoint_c: f32) {
+        let (key, direction) = match key_direction {
+            Some(kd) if kd.1 * (setpoint_c - room_c) > APPROACH_MARGIN_C => kd,
+            _ => {
+                // reached the setpoint or stopped trying, so whatever was being measured is over
+                self.window = None;
+                return;
+            }
+        };
+
+        let now = Instant::now();
+        let same = self.window.as_ref().map_or(false, |w| w.key == key && w.setpoint_c == setpoint_c);
+        if same {
+            let window = self.window.as_ref().unwrap();
+            if now - window.start < SAMPLE_WINDOW {
+                return;
+            }
+            let minutes = (now - window.start).as_secs_f32() / 60.0;
+            let sample = window.direction * (room_c - window.start_c) / minutes;
+            self.record(&key, sample);
+        }
+        self.window = Some(Window { key, direction, setpoint_c, start: now, start_c: room_c });
+    }
+
+    fn record(&mut self, key: &str, sample: f32) {
+        let estimate = self.rates.entry(key.to_string())
+            .or_insert(RateEstimate { c_per_min: sample, samples: 0, updated: None });
+        if estimate.samples > 0 {
+            estimate.c_per_min += SMOOTHING_ALPHA * (sample - estimate.c_per_min);
+        }
+        estimate.samples += 1;
+        estimate.updated = clock::iso8601_now();
+        self.dirty = true;
+    }
+
+    /// The learned heating rates for each fan speed together, weighted by how much each has been seen
+    pub fn heating_c_per_min(&self) -> Option<f32> {
+        let (total, samples) = self.rates.iter().filter(|(key, _)| key.starts_with("Heat/"))
+            .fold((0.0, 0), |(total, samples), (_, rate)| (total + rate.c_per_min * rate.samples as f32, samples + rate.samples));
+        if samples == 0 || total <= 0.0 {
+            return None;
+        }
+        Some(total / samples as f32)
+    }
+
+    pub fn needs_save(&self) -> bool {
+        self.dirty && self.last_save.map_or(true, |t| t.elapsed() >= SAVE_INTERVAL)
+    }
+}
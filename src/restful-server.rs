@@ -1,15 +1,20 @@
 #![feature(const_trait_impl)]
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use strum::IntoEnumIterator;
-use strum_macros::{FromRepr, EnumIter};
+use strum::EnumCount;
+use strum_macros::{FromRepr, EnumIter, EnumCount};
 use log::info;
 use paste::paste;
 
 use enumset::EnumSet;
 
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::{Duration, Instant};
+use std::mem::ManuallyDrop;
+use std::net::{IpAddr, Ipv4Addr, TcpStream};
+use std::os::fd::FromRawFd;
 
 use esp_idf_hal as hal;
 
@@ -18,24 +23,137 @@ use hal::task::watchdog;
 use hal::gpio::{AnyIOPin, PinDriver, Pull, InputMode, InputPin};
 use hal::uart;
 use hal::rmt;
+#[cfg(feature="pulsemeter")]
+use hal::pcnt;
 use hal::sys::EspError;
+use hal::io::EspIOError;
 use hal::reset;
     
 use embedded_svc::wifi as eswifi;
 use embedded_svc::http::Headers;
 use embedded_svc::io::{Read, Write};
+use embedded_svc::ws::FrameType;
 
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
-    wifi::{BlockingWifi, EspWifi, WifiDeviceId},
+    wifi::{BlockingWifi, EspWifi, WifiDeviceId, WifiDriver},
+    netif::{EspNetif, NetifConfiguration, NetifStack},
+    ipv4,
     nvs,
     http,
     mdns,
+    sntp,
 };
 
 mod ws2812b;
 use ws2812b::{Ws2812B, Rgb};
 
+mod startup;
+use startup::StartupPhase;
+
+#[cfg(feature="ethernet")]
+mod ethernet;
+
+mod thermostat;
+use thermostat::{Thermostat, ThermostatConfig};
+
+mod schedule;
+use schedule::{Schedule, ScheduleConfig};
+
+mod pid;
+use pid::{PidConfig, PidTrim};
+
+mod http_client;
+mod cloud_push;
+use cloud_push::{CloudPush, CloudPushConfig};
+
+mod alerts;
+use alerts::{Alerts, AlertConfig};
+
+mod coordinator;
+use coordinator::{Coordinator, CoordinatorConfig, UnitSetRequest};
+
+mod follower;
+use follower::{Follower, FollowConfig};
+
+mod clock;
+
+mod syslog;
+
+mod ssdp;
+
+mod udp_discovery;
+
+mod sse;
+
+mod diagnostics;
+
+mod energy_meter;
+
+mod integrity;
+mod assets;
+mod http_cache;
+use integrity::Integrity;
+
+mod link_quality;
+use link_quality::LinkQuality;
+
+mod api;
+use api::{enum_names, FanSpeed, HeatPumpMode, UnitSetting, VaneDirection, WideVaneDirection};
+
+mod packet;
+use packet::{Packet, PacketData, PacketError, CONNECT_BYTES, PACKET_DATA_MAX, PACKET_SIZE_MAX};
+
+mod error;
+use error::{ErrorCode, FirmwareError};
+
+mod bus_trace;
+use bus_trace::BusTrace;
+
+mod transport;
+use transport::Transport;
+
+#[cfg(feature="button")]
+mod button;
+#[cfg(feature="button")]
+use button::{Press, PressDetector};
+
+mod config;
+use config::{ConfigExport, ConfigUpdate, ControllerConfig, IMPORT_MAX_LEN, LED_DEFAULT_BRIGHTNESS, REBOOT_PERIOD_DEFAULT_MINS, MODE_SETTLE_DEFAULT_SECS};
+
+mod sniffer;
+use sniffer::{Sniffer, SnifferConfig};
+
+mod crash_log;
+mod ota_update;
+use ota_update::{OtaConfig, OtaUpdater};
+mod safe_mode;
+use crash_log::{CrashLog, WatchdogConfig};
+
+mod nvs_writer;
+use nvs_writer::NvsWriter;
+
+mod status_probe;
+use status_probe::StatusProbe;
+
+mod rate_model;
+use rate_model::{RateModel, RateEstimate};
+
+mod history;
+use history::{History, Sample};
+
+mod adherence;
+use adherence::{Adherence, AdherenceConfig, DayAdherence};
+use energy_meter::{EnergyMeter, EnergyMeterConfig};
+
+mod strings;
+use strings::Language;
+
+mod openapi;
+mod rate_limit;
+use rate_limit::RateLimiter;
+mod cbor;
+
 use serde::{Deserialize, Serialize};
 use serde_json::json;
 
@@ -45,26 +163,102 @@ const WIFI_CHANNEL: &str = env!("WIFI_CHANNEL");
 const RESET_ON_SSID_NOT_FOUND: &str = env!("RESET_ON_SSID_NOT_FOUND");
 
 static INDEX_HTML: &str = include_str!("restful-server-index.html");
+// compressed by build.rs
+static INDEX_HTML_GZ: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/index.html.gz"));
+// how often to look for a status change to send to event stream listeners
+const SSE_CHECK_INTERVAL: Duration = Duration::from_millis(500);
+// these change every time status.json is asked for, so they're left out of its ETag
+const STATUS_CLOCK_FIELDS: [&str; 4] = ["secs_since_boot", "time", "status_age_secs", "bus_paused_secs"];
 
 const LOOP_MIN_LENGTH:Duration = Duration::from_millis(2);
+// settings in NVS are re-read this often, or straight after they're written, rather than every loop
+const NVS_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+// how often the sniffer's recent frames are written to flash, if that's on.  Not too often to spare the flash
+const SNIFF_FLASH_INTERVAL: Duration = Duration::from_secs(60);
 const CONNECT_DELAY:Duration = Duration::from_millis(2000);
 const RESPONSE_DELAY:Duration = Duration::from_millis(1000);
-
-const REBOOT_PERIOD:Option<Duration> = Some(Duration::from_secs(90*60));
-
-const CONNECT_BYTES: [u8; 8] = [0xfc, 0x5a, 0x01, 0x30, 0x02, 0xca, 0x01, 0xa8];
+// a packet is taken to be over once the line has been quiet this long (a byte is about 4.6 ms at 2400 baud).  The
+// limit is a bit more than the longest packet the header allows, so a line full of noise can't hold up the loop
+const PACKET_BYTE_GAP:Duration = Duration::from_millis(50);
+const PACKET_READ_LIMIT:Duration = Duration::from_millis(1500);
 
 // Not sure how much is needed, but this is the default in an esp example so <shrug>
 const HTTP_SERVER_STACK_SIZE: usize = 10240;
+// for the thread that talks to the heat pump while the network is still coming up
+const EARLY_UART_STACK_SIZE: usize = 8192;
+// for the thread watching for TWDT trips in recover mode
+const WATCHDOG_SUPERVISOR_STACK_SIZE: usize = 4096;
+const LED_TASK_STACK_SIZE: usize = 4096;
+// the wifi network list is the biggest thing it serializes
+const NVS_TASK_STACK_SIZE: usize = 6144;
+// TLS needs a good deal of stack
+const OTA_TASK_STACK_SIZE: usize = 12288;
+const OUTBOUND_TASK_STACK_SIZE: usize = 12288;
+// how often the outbound task looks for requests to make
+const OUTBOUND_TICK: Duration = Duration::from_millis(500);
+// how often the LED task looks at the sense pin and moves blinks along
+const LED_TICK: Duration = Duration::from_millis(50);
+const BUTTON_TASK_STACK_SIZE: usize = 4096;
+// how long BOOT has to be held down for a factory reset, and how often it's looked at meanwhile
+const FACTORY_RESET_HOLD: Duration = Duration::from_secs(10);
+const BUTTON_POLL: Duration = Duration::from_millis(50);
+// the control button needs looking at more often, to debounce it and catch double presses
+#[cfg(feature="button")]
+const CONTROL_BUTTON_POLL: Duration = Duration::from_millis(10);
 // maximum payload for post requests
 const HTTP_SERVER_MAX_LEN: usize = 512;
 
 const CONNECT_TIMEOUT: Duration = Duration::from_secs(90);
+
+// how many networks can be stored to try in addition to the compiled-in one
+const WIFI_NETWORKS_MAX: usize = 5;
+// PEM certificates for WPA2-Enterprise don't fit in set.json.  NVS strings top out a little above this
+const WIFI_CA_CERT_MAX_LEN: usize = 4000;
+
+// the hardware pulse counter is 16 bits, so it gets cleared well before it could overflow
+#[cfg(feature="pulsemeter")]
+const PULSE_COUNTER_CLEAR_AT: i16 = 16384;
 const WIFI_DISCONNECTED_RESET_TIME: Duration = Duration::from_secs(30);
-const TWDT_TIME: Duration = Duration::from_secs(10); // Only used *after* startup
+// in the watchdog's recover mode, the subsystems to restart on successive trips before giving up and resetting.  The
+// steps start over once it's been quiet for a while
+const WATCHDOG_RECOVER_STEPS: [Subsystem; 3] = [Subsystem::Uart, Subsystem::Http, Subsystem::Wifi];
+const WATCHDOG_RECOVER_QUIET: Duration = Duration::from_secs(3600);
+
+// reconnecting after the wifi drops: the wait between attempts doubles from the first to the max, and after
+// this many failed attempts we give up and reboot
+const WIFI_RECONNECT_FIRST_BACKOFF: Duration = Duration::from_secs(5);
+const WIFI_RECONNECT_MAX_BACKOFF: Duration = Duration::from_secs(300);
+const WIFI_RECONNECT_MAX_ATTEMPTS: u32 = 8;
 
 const HTTP_PORT: u16 = 8923;
-const LED_DEFAULT_BRIGHTNESS: u8 = 20;
+const MDNS_SERVICE_TYPE: &str = "_eteq-mheatpump";
+const MDNS_EXTRA_SERVICES_MAX: usize = 4;
+const PEER_BROWSE_INTERVAL: Duration = Duration::from_secs(60);
+const PEER_BROWSE_TIMEOUT: Duration = Duration::from_millis(1000);
+const PEER_BROWSE_MAX_RESULTS: usize = 16;
+
+// how many bad reads in a row before we decide it's probably a hardware problem rather than a glitch
+const BUS_BAD_READS_BEFORE_WARNING: u32 = 5;
+// how many of the latest reads go into the link quality, and how quickly the response time average moves
+const BUS_RECENT_READS: usize = 50;
+const BUS_LATENCY_ALPHA: f32 = 0.1;
+
+// setpoint used for away mode frost protection if one isn't given
+const AWAY_DEFAULT_FROST_SETPOINT_C: f32 = 16.0;
+
+// how long a boost lasts if a duration isn't given
+const BOOST_DEFAULT_DURATION_SECS: u64 = 30*60;
+// the longest the bus can be handed over to e.g. a service tool before polling picks up again on its own
+const BUS_PAUSE_MAX_SECS: u64 = 4*3600;
+// how long /debug/packet.json waits for the loop to send its packet and read back the response
+const DEBUG_PACKET_WAIT: Duration = Duration::from_secs(5);
+// how long set.json?wait=true waits for the heat pump to acknowledge the setting and report back its state, counting
+// the debounce.  The http server handles one request at a time, so everything else waits this long too at worst
+const SET_WAIT: Duration = Duration::from_secs(3);
+
+// The range the heat pumps themselves accept, used as the default limits for every mode
+const SETPOINT_DEFAULT_MIN_C: f32 = 16.0;
+const SETPOINT_DEFAULT_MAX_C: f32 = 31.0;
 
 
 macro_rules! pin_from_envar {
@@ -96,14 +290,118 @@ struct HeatPumpStatus {
     pub vane: VaneDirection,
     pub widevane: WideVaneDirection,
     pub isee_mode: ISeeMode, // This might be incorrect?
-    pub room_temperature_c: f32,
+    pub room_temperature_c: f32,  // smoothed if room_temperature_smoothing is enabled
+    pub room_temperature_c_raw: f32,
+    pub room_temperature_smoothing: TemperatureSmoothing,
     pub room_temperature_c_2: f32,
     pub operating: u8,
     pub error_data: Option<Vec<u8>>,
-    pub last_status_packets: HashMap<u8, Vec<u8>>,
+    pub last_error: Option<HeatPumpError>,
+    pub last_ack: Option<AckRecord>,
+    pub last_status_packets: LastStatusPackets,
     pub desired_settings: Option<HeatPumpSetting>,
     pub controller_led_brightness: u8,
     pub controller_location: Option<String>,
+    pub setpoint_limits: SetpointLimits,
+    pub bus_warning: Option<String>,
+    pub away: Option<AwayState>,
+    pub boost: Option<BoostState>,
+    pub wifi_ap_channel: u8,
+    pub reset_reason: String,
+    pub boot_count: u32,
+    pub wifi_reconnects: u32,
+    pub wifi_country: Option<String>,
+    pub wifi_networks: Vec<WifiNetwork>,  // as of boot, which is when they're used
+    pub wifi_link: Option<WifiLink>,
+    pub wifi_radio: WifiRadioConfig,
+    pub static_ip: StaticIpConfig,  // as of boot, which is when it's used
+    pub network: Option<NetworkInfo>,  // the addressing actually in use
+    pub ntp_server: String,
+    pub mdns: MdnsConfig,  // as of boot, which is when it's used
+    pub syslog_server: Option<String>,
+    pub thermostat: Thermostat,
+    pub schedule: Schedule,
+    pub pid: PidTrim,
+    pub cloud_push: CloudPush,
+    #[serde(skip)]
+    pub coordinator: Coordinator,  // the other units are at units.json
+    pub follower: Follower,
+    pub bus_watchdog: BusWatchdogConfig,
+    pub secs_since_valid_packet: Option<f32>,
+    #[serde(skip)]
+    pub status_updated: Option<Instant>,  // when the heat pump's settings last came in, for the staleness of status.json
+    #[serde(skip)]
+    pub set_seq: u64,  // counts queued settings, so set.json?wait=true can tell when its own has been acknowledged
+    #[serde(skip)]
+    pub acked_set_seq: u64,  // set_seq as of the last acknowledged setting
+    #[serde(skip)]
+    pub field_set_seq: [u64; 6],  // the set_seq that last set each of the unit's fields, as in unit_fields_set
+    #[serde(skip)]
+    pub acked_field_seq: [u64; 6],  // field_set_seq as of the last acknowledged setting
+    #[serde(skip)]
+    pub acked_at: Option<Instant>,
+    #[serde(skip)]
+    pub set_at: Option<Instant>,  // when the last set.json came in, which sending waits on for the debounce
+    #[serde(skip)]
+    pub set_limiter: RateLimiter,
+    pub protocol_errors: ProtocolErrors,
+    pub link_quality: Option<LinkQuality>,
+    pub alerts: Alerts,
+    pub mode_settle_secs: u32,
+    pub reboot_period_mins: u32,  // 0 means never
+    pub settling: bool,
+    pub reboot_window: RebootWindow,
+    #[serde(skip)]
+    pub ota: Arc<Mutex<OtaUpdater>>,  // separately locked so a slow download doesn't hold up the loop
+    pub ui_language: Language,
+    pub capabilities: Capabilities,
+    pub energy_meter: EnergyMeter,
+    #[serde(skip)]
+    pub history: History,  // at history.json
+    #[serde(skip)]
+    pub adherence: Adherence,  // served on its own at adherence.json since it gets long
+    #[serde(skip)]
+    pub rate_model: RateModel,  // likewise at model.json
+    #[serde(skip)]
+    pub integrity: Option<Integrity>,  // at system.json, filled in once the stored settings have been read at boot
+    #[serde(skip)]
+    pub bus_trace: Arc<Mutex<BusTrace>>,  // separately locked since it's written in the middle of talking to the unit
+    pub sniffer: Sniffer,
+    #[serde(skip)]
+    pub status_probe: StatusProbe,  // at bus/probe.json
+    #[serde(skip)]
+    pub bus_paused_until: Option<Instant>,
+    #[serde(skip)]
+    pub debug_token: Option<String>,  // /debug/packet.json is off without one.  Never serialized, like the other tokens
+    #[serde(skip)]
+    pub debug_packet: Option<DebugPacket>,
+    pub watchdog: WatchdogConfig,  // as of boot, which is when it's used
+    #[serde(skip)]
+    pub crash_log: Arc<Mutex<CrashLog>>,  // separately locked so the watchdog supervisor can't get stuck behind the loop
+    #[serde(skip)]
+    pub peers: Vec<Peer>,
+    #[serde(skip)]
+    pub peers_updated: Option<Instant>,
+    #[serde(skip)]
+    pub presets: HashMap<String, HeatPumpSetting>,
+    #[serde(skip)]
+    pub presets_dirty: bool,  // true if the presets need to be written to NVS
+    #[serde(skip)]
+    pub pending_subsystem_restart: Option<Subsystem>,
+    #[serde(skip)]
+    pub config: ControllerConfig,
+    #[serde(skip)]
+    pub pending_config: Option<ConfigUpdate>,
+    #[serde(skip)]
+    pub reset_protocol_errors: bool,
+    #[serde(skip)]
+    pub wifi_reprovision: bool,  // forget the stored networks and reboot into AP mode
+    #[serde(skip)]
+    pub pending_import: Option<ConfigExport>,
+    #[serde(skip)]
+    pub pending_ca_cert: Option<(String, Option<String>)>,  // an ssid and its new certificate, None removing it
+    #[serde(skip)]
+    pub factory_reset: bool,  // wipe the settings namespace and reboot into AP mode
     pub tx_pin: String,
     pub rx_pin: String,
     pub led_pin: String,
@@ -121,13 +419,85 @@ impl HeatPumpStatus {
             widevane: WideVaneDirection::Mid,
             isee_mode: ISeeMode::Unknown,
             room_temperature_c: -999.0,
+            room_temperature_c_raw: -999.0,
+            room_temperature_smoothing: TemperatureSmoothing::new(),
             room_temperature_c_2: -999.0,
             operating: 0,
             error_data: None,
-            last_status_packets: HashMap::new(),
+            last_error: None,
+            last_ack: None,
+            last_status_packets: LastStatusPackets::new(),
             desired_settings: None,
             controller_led_brightness: LED_DEFAULT_BRIGHTNESS,
             controller_location: None,
+            setpoint_limits: SetpointLimits::new(),
+            bus_warning: None,
+            away: None,
+            boost: None,
+            reset_reason: String::new(),
+            boot_count: 0,
+            wifi_reconnects: 0,
+            wifi_ap_channel: WIFI_CHANNEL.parse().unwrap(),
+            wifi_country: None,
+            wifi_networks: Vec::new(),
+            wifi_link: None,
+            wifi_radio: WifiRadioConfig::new(),
+            static_ip: StaticIpConfig::new(),
+            network: None,
+            ntp_server: clock::DEFAULT_NTP_SERVER.to_string(),
+            mdns: MdnsConfig::new(),
+            syslog_server: None,
+            thermostat: Thermostat::new(),
+            schedule: Schedule::new(),
+            pid: PidTrim::new(),
+            cloud_push: CloudPush::new(),
+            coordinator: Coordinator::new(),
+            follower: Follower::new(),
+            bus_watchdog: BusWatchdogConfig::new(),
+            secs_since_valid_packet: None,
+            status_updated: None,
+            set_seq: 0,
+            acked_set_seq: 0,
+            field_set_seq: [0; 6],
+            acked_field_seq: [0; 6],
+            acked_at: None,
+            set_at: None,
+            set_limiter: RateLimiter::new(),
+            protocol_errors: ProtocolErrors::new(),
+            link_quality: None,
+            alerts: Alerts::new(),
+            mode_settle_secs: MODE_SETTLE_DEFAULT_SECS,
+            reboot_period_mins: REBOOT_PERIOD_DEFAULT_MINS,
+            settling: false,
+            reboot_window: RebootWindow::new(),
+            ota: Arc::new(Mutex::new(OtaUpdater::new())),
+            ui_language: Language::En,
+            capabilities: Capabilities::new(),
+            energy_meter: EnergyMeter::new(),
+            history: History::new(),
+            adherence: Adherence::new(),
+            rate_model: RateModel::new(),
+            integrity: None,
+            bus_trace: Arc::new(Mutex::new(BusTrace::new())),
+            sniffer: Sniffer::new(),
+            status_probe: StatusProbe::new(),
+            bus_paused_until: None,
+            debug_token: None,
+            debug_packet: None,
+            watchdog: WatchdogConfig::new(),
+            crash_log: Arc::new(Mutex::new(CrashLog::new())),
+            peers: Vec::new(),
+            peers_updated: None,
+            presets: HashMap::new(),
+            presets_dirty: false,
+            pending_subsystem_restart: None,
+            config: ControllerConfig::default(),
+            pending_config: None,
+            reset_protocol_errors: false,
+            wifi_reprovision: false,
+            pending_import: None,
+            pending_ca_cert: None,
+            factory_reset: false,
             tx_pin: env!("TX_PIN_NUM").to_string(),
             rx_pin: env!("RX_PIN_NUM").to_string(),
             led_pin: env!("LED_PIN_NUM").to_string(),
@@ -135,7 +505,31 @@ impl HeatPumpStatus {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+// Where the heat pump was last set to, as built up from every setting it acknowledged.  Kept in NVS so it can be sent
+// again after a power cut, for units that don't restart themselves
+impl UnitSetting {
+    pub fn record(&mut self, setting: &HeatPumpSetting) {
+        self.poweron = setting.poweron.or(self.poweron);
+        self.mode = setting.mode.or(self.mode);
+        self.desired_temperature_c = setting.desired_temperature_c.or(self.desired_temperature_c);
+        self.fan_speed = setting.fan_speed.or(self.fan_speed);
+        self.vane = setting.vane.or(self.vane);
+        self.widevane = setting.widevane.or(self.widevane);
+    }
+
+    pub fn to_setting(&self) -> HeatPumpSetting {
+        let mut setting = HeatPumpSetting::new();
+        setting.poweron = self.poweron;
+        setting.mode = self.mode;
+        setting.desired_temperature_c = self.desired_temperature_c;
+        setting.fan_speed = self.fan_speed;
+        setting.vane = self.vane;
+        setting.widevane = self.widevane;
+        setting
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct HeatPumpSetting {
     // The desired state of the heatpump as requrest by user
     pub poweron: Option<bool>,
@@ -146,11 +540,41 @@ struct HeatPumpSetting {
     pub widevane: Option<WideVaneDirection>,
     pub controller_led_brightness: Option<u8>,
     pub controller_location: Option<String>,
+    pub setpoint_limits: Option<SetpointLimits>,
+    pub wifi_ap_channel: Option<u8>,
+    pub wifi_country: Option<String>,
+    pub wifi_networks: Option<Vec<WifiNetwork>>,
+    pub wifi_radio: Option<WifiRadioConfig>,
+    pub static_ip: Option<StaticIpConfig>,
+    pub sniffer: Option<SnifferConfig>,
+    pub watchdog: Option<WatchdogConfig>,
+    pub ntp_server: Option<String>,
+    pub mdns: Option<MdnsConfig>,
+    pub syslog_server: Option<String>,  // "host" or "host:port", empty to turn off
+    pub thermostat: Option<ThermostatConfig>,
+    pub schedule: Option<ScheduleConfig>,
+    pub pid: Option<PidConfig>,
+    pub cloud_push: Option<CloudPushConfig>,
+    // never serialized, so a token that's still waiting to be stored can't leak through status.json
+    #[serde(default, skip_serializing)]
+    pub debug_token: Option<String>,  // an empty string turns /debug/packet.json back off
+    pub coordinator: Option<CoordinatorConfig>,
+    pub follow: Option<FollowConfig>,
+    pub bus_watchdog: Option<BusWatchdogConfig>,
+    pub alerts: Option<AlertConfig>,
+    pub mode_settle_secs: Option<u32>,
+    pub reboot_period_mins: Option<u32>,
+    pub reboot_window: Option<RebootWindow>,
+    pub ota: Option<OtaConfig>,
+    pub room_temperature_smoothing: Option<TemperatureSmoothing>,
+    pub ui_language: Option<Language>,
+    pub capabilities: Option<Capabilities>,
+    pub energy_meter: Option<EnergyMeterConfig>,
+    pub adherence: Option<AdherenceConfig>,
 }
 
 
 impl HeatPumpSetting {
-    #[allow(dead_code)]
     pub fn new() -> Self{
 
         Self {
@@ -162,8 +586,44 @@ impl HeatPumpSetting {
             widevane: None,
             controller_led_brightness: None,
             controller_location: None,
+            setpoint_limits: None,
+            wifi_ap_channel: None,
+            wifi_country: None,
+            wifi_networks: None,
+            wifi_radio: None,
+            static_ip: None,
+            sniffer: None,
+            watchdog: None,
+            ntp_server: None,
+            mdns: None,
+            syslog_server: None,
+            thermostat: None,
+            schedule: None,
+            pid: None,
+            cloud_push: None,
+            debug_token: None,
+            coordinator: None,
+            follow: None,
+            bus_watchdog: None,
+            alerts: None,
+            mode_settle_secs: None,
+            reboot_period_mins: None,
+            reboot_window: None,
+            ota: None,
+            room_temperature_smoothing: None,
+            ui_language: None,
+            capabilities: None,
+            energy_meter: None,
+            adherence: None,
         }
     }
+    /// Clears the fields that need the debug_token to change, for settings that come from somewhere other than
+    /// set.json, where there's no bearer token to check
+    pub fn drop_privileged(&mut self) {
+        self.debug_token = None;
+        self.ota = None;
+    }
+
     pub fn requires_packet(&self) -> bool {
         // setting changes on just the controller don't require updating the heat pump itself.  In that case this is false
         self.poweron.is_some() | 
@@ -174,6 +634,31 @@ impl HeatPumpSetting {
         self.widevane.is_some()
     }
 
+    /// Fills in whatever this leaves out from an older setting that hasn't gone out yet, so that neither is lost
+    pub fn merge_older(&mut self, older: HeatPumpSetting) {
+        macro_rules! or_older {
+            ($($field:ident),* $(,)?) => {{
+                // taking it apart like this means a new field that isn't listed won't compile
+                let HeatPumpSetting { $($field),* } = older;
+                $( if self.$field.is_none() { self.$field = $field; } )*
+            }};
+        }
+        or_older!(
+            poweron, mode, desired_temperature_c, fan_speed, vane, widevane, controller_led_brightness,
+            controller_location, setpoint_limits, wifi_ap_channel, wifi_country, wifi_networks, wifi_radio,
+            static_ip, sniffer, watchdog, ntp_server, mdns, syslog_server, thermostat, schedule, pid, cloud_push,
+            debug_token,
+            coordinator, follow, bus_watchdog, alerts, mode_settle_secs, reboot_period_mins, reboot_window, ota,
+            room_temperature_smoothing, ui_language, capabilities, energy_meter, adherence
+        );
+    }
+
+    /// Which of the unit's fields this sets: poweron, mode, desired_temperature_c, fan_speed, vane and widevane
+    pub fn unit_fields_set(&self) -> [bool; 6] {
+        [self.poweron.is_some(), self.mode.is_some(), self.desired_temperature_c.is_some(),
+         self.fan_speed.is_some(), self.vane.is_some(), self.widevane.is_some()]
+    }
+
     pub fn to_packet(&self) -> Packet {
         let mut packet = Packet::new_type_size(0x41, 16);
         packet.data[0] = 1; // this sets the regular standard "set" command mode
@@ -219,768 +704,5221 @@ impl HeatPumpSetting {
 
         packet
     }
-}
 
-#[derive(Debug)]
-struct Packet {
-    pub packet_type: u8,
-    pub h2: u8,
-    pub h3: u8,
-    pub data: Vec<u8>,
-    pub checksum: u8
-}
-impl Packet {
-    pub fn new() -> Self {
-        Self {
-            packet_type: 0,
-            h2: 0x01,
-            h3: 0x30,
-            data: Vec::new(),
-            checksum: 0
+    pub fn packet_flags(packet: &Packet) -> Vec<&'static str> {
+        // the inverse of the flag bits set in to_packet, for showing what a set packet will change
+        let mut flags = Vec::new();
+        for (bit, name) in [(0, "poweron"), (1, "mode"), (2, "desired_temperature_c"), (3, "fan_speed"), (4, "vane")] {
+            if packet.data[1] & (1 << bit) != 0 { flags.push(name); }
         }
+        if packet.data[2] & 1 != 0 { flags.push("widevane"); }
+        flags
     }
 
-    pub fn new_type_size(ptype: u8, size: usize) -> Self {
-        Self {
-            packet_type: ptype,
-            h2: 0x01,
-            h3: 0x30,
-            data: vec![0u8; size],
-            checksum: 0
-        }
+    pub fn predict(&self, state: &HeatPumpStatus) -> serde_json::Value {
+        // what the heat pump side of the state should look like once this is applied
+        json!({
+            "poweron": self.poweron.unwrap_or(state.poweron),
+            "mode": self.mode.unwrap_or(state.mode),
+            "desired_temperature_c": self.desired_temperature_c.unwrap_or(state.desired_temperature_c),
+            "fan_speed": self.fan_speed.unwrap_or(state.fan_speed),
+            "vane": self.vane.unwrap_or(state.vane),
+            "widevane": self.widevane.unwrap_or(state.widevane),
+        })
     }
+}
 
-    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self>  {
-        if bytes.len() < 6 {
-            anyhow::bail!("Packet too short to be a valid packet");
-        }
-        if bytes[0] != 0xfc {
-            anyhow::bail!("Packet does not start with 0xfc");
-        }
+#[derive(Debug, Deserialize)]
+struct PresetRequest {
+    // used both for applying (just name) and editing (name and setting, where a null setting deletes the preset)
+    pub name: String,
+    #[serde(default)]
+    pub setting: Option<HeatPumpSetting>,
+}
+
+#[derive(Debug, Deserialize)]
+struct AwayRequest {
+    pub enabled: bool,
+    #[serde(default)]
+    pub frost_protection: Option<bool>,  // if false, the heat pump is just turned off while away
+    #[serde(default)]
+    pub setpoint_c: Option<f32>,
+    #[serde(default)]
+    pub duration_secs: Option<u64>,  // None means until cancelled
+}
+
+#[derive(Debug, Serialize)]
+struct AwayState {
+    pub frost_protection: bool,
+    pub setpoint_c: f32,
+    pub remaining_secs: Option<f32>,
+    #[serde(skip)]
+    pub ends: Option<Instant>,
+    #[serde(skip)]
+    pub restore: HeatPumpSetting,  // what to go back to once away mode is over
+}
+
+#[derive(Debug, Deserialize)]
+struct DebugPacketRequest {
+    pub hex: String,  // the whole packet from the 0xfc on, but without the checksum
+}
+
+#[derive(Debug)]
+struct DebugPacket {
+    // a raw packet from /debug/packet.json for the loop to send, and what came back once it has
+    pub bytes: Vec<u8>,
+    pub response: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct BusPauseRequest {
+    pub secs: u64,  // 0 to resume now
+}
+
+#[derive(Debug, Deserialize)]
+struct BoostRequest {
+    pub enabled: bool,
+    #[serde(default)]
+    pub setpoint_c: Option<f32>,  // defaults to the end of the allowed range for the current mode
+    #[serde(default)]
+    pub duration_secs: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct BoostState {
+    pub setpoint_c: Option<f32>,
+    pub remaining_secs: f32,
+    #[serde(skip)]
+    pub ends: Instant,
+    #[serde(skip)]
+    pub restore: HeatPumpSetting,  // what to go back to once the boost is over
+}
 
-        let mut packet = Self::new();
-        packet.packet_type = bytes[1];
-        packet.h2 = bytes[2];
-        packet.h3 = bytes[3];
-        let len = bytes[4] as usize;
-        if bytes.len() < 6+len {
-            anyhow::bail!("Packet length in header does not match received data");
+impl HeatPumpStatus {
+    pub fn current_setting(&self) -> HeatPumpSetting {
+        // the heat pump-side settings as they are now, e.g. to go back to later
+        let mut setting = HeatPumpSetting::new();
+        setting.poweron = Some(self.poweron);
+        setting.mode = Some(self.mode);
+        setting.desired_temperature_c = Some(self.desired_temperature_c);
+        setting.fan_speed = Some(self.fan_speed);
+        setting
+    }
+
+    /// Queues a setting for the main loop, returning its set_seq.  One that comes in while another is still waiting to
+    /// be sent is merged into it, so e.g. a mode change followed quickly by a temperature goes out as one packet with both
+    pub fn queue_setting(&mut self, mut setting: HeatPumpSetting) -> u64 {
+        self.set_seq += 1;
+        for (seq, set) in self.field_set_seq.iter_mut().zip(setting.unit_fields_set()) {
+            if set {
+                *seq = self.set_seq;
+            }
         }
-        for i in 0..len {
-            packet.data.push(bytes[5 + i as usize]);
+        if let Some(pending) = self.desired_settings.take() {
+            setting.merge_older(pending);
         }
-        packet.checksum = bytes[5 + len];
+        self.set_at = Some(Instant::now());
+        self.desired_settings = Some(setting);
+        self.set_seq
+    }
 
-        if !packet.check_checksum() {
-            anyhow::bail!("Packet checksum does not match");
+    pub fn end_away(&mut self) {
+        if let Some(away) = self.away.take() {
+            info!("ending away mode, restoring {:?}", away.restore);
+            self.queue_setting(away.restore);
         }
-
-        Ok(packet)
     }
 
-    pub fn packet_size(&self) -> usize {
-        6 + self.data.len() as usize
+    /// When the periodic reboot should happen as a unix time, or None if it is off or the clock isn't set
+    pub fn next_reboot(&self, boot_instant: Instant) -> Option<u64> {
+        if self.reboot_period_mins == 0 {
+            return None;
+        }
+        let period = Duration::from_secs(self.reboot_period_mins as u64 * 60);
+        let due = clock::unix_secs()? + period.saturating_sub(boot_instant.elapsed()).as_secs();
+        if !self.reboot_window.enabled || self.reboot_window.contains(due) {
+            Some(due)
+        } else {
+            Some(self.reboot_window.next_start_after(due))
+        }
     }
 
-    pub fn to_bytes(&self) -> Vec<u8> {
-        let mut bytes = Vec::with_capacity(6 + self.data.len());
-        bytes.push(0xfc);
-        bytes.push(self.packet_type);
-        bytes.push(self.h2);
-        bytes.push(self.h3);
-        bytes.push(self.data.len() as u8);
-        for d in self.data.iter() { bytes.push(*d); }
-        bytes.push(self.checksum);
-        bytes
+    /// How much longer the bus is paused for, or None if it isn't
+    pub fn bus_paused_remaining_secs(&self) -> Option<f32> {
+        self.bus_paused_until.map(|t| t.saturating_duration_since(Instant::now()).as_secs_f32())
     }
 
-    pub fn compute_checksum(&self) -> u8 {
-        let mut sum = 0xfcu8;
-        sum += self.packet_type;
-        sum += self.h2;
-        sum += self.h3;
-        sum += self.data.len() as u8;
-        for i in 0..self.data.len() {
-            sum += self.data[i as usize];
+    pub fn end_boost(&mut self) {
+        if let Some(boost) = self.boost.take() {
+            info!("ending boost, restoring {:?}", boost.restore);
+            self.queue_setting(boost.restore);
         }
-        0xfc - sum
     }
 
-    pub fn check_checksum(&self) -> bool {
-        self.checksum == self.compute_checksum()
+    /// The setting to boost with: max fan and, unless one is given, the hottest or coolest setpoint
+    /// allowed for the current mode
+    pub fn boost_setting(&self, setpoint_c: Option<f32>) -> HeatPumpSetting {
+        let mut setting = HeatPumpSetting::new();
+        setting.poweron = Some(true);
+        setting.fan_speed = Some(FanSpeed::VeryHigh);
+        setting.desired_temperature_c = setpoint_c.or_else(|| {
+            let range = self.setpoint_limits.for_mode(self.mode)?;
+            match self.mode {
+                HeatPumpMode::Heat => Some(range.max_c),
+                HeatPumpMode::Cool | HeatPumpMode::Dry => Some(range.min_c),
+                _ => None,  // no obvious direction to push auto in
+            }
+        });
+        setting
     }
+}
 
-    pub fn set_checksum(&mut self) {
-        self.checksum = self.compute_checksum();
+#[derive(Debug, Clone, Serialize)]
+struct Peer {
+    // another controller found via mDNS
+    pub hostname: Option<String>,
+    pub instance_name: Option<String>,
+    pub addresses: Vec<String>,
+    pub port: u16,
+    pub location: Option<String>,
+    pub version: Option<String>,
+}
+impl Peer {
+    pub fn from_query_result(result: &mdns::QueryResult) -> Self {
+        Self {
+            hostname: result.hostname.clone(),
+            instance_name: result.instance_name.clone(),
+            addresses: result.addr.iter().map(|a| a.to_string()).collect(),
+            port: result.port,
+            location: result.txt.iter().find(|(k, _)| k == "location").map(|(_, v)| v.clone()),
+            version: result.txt.iter().find(|(k, _)| k == "version").map(|(_, v)| v.clone()),
+        }
     }
 }
 
-#[derive(Clone, Copy, FromRepr, Debug, Serialize, Deserialize, EnumIter)]
-enum StatusPacketType {
-    Settings = 2,
-    RoomTemperature = 3,
-    ErrorCodeMaybe = 4, // not sure, but this is what https://github.com/SwiCago/HeatPump/issues/39 seems to suggest?
-    Timers = 5,
-    MiscInfo = 6,
-    StandbyMode = 9, // Also unsure but its what https://github.com/SwiCago/HeatPump thinks and is also asked for by Kumo Cloud...
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum BusWatchdogAction {
+    Reboot,
+    ReinitUart,
 }
 
-#[derive(Clone, Copy, FromRepr, Debug, Serialize, Deserialize)]
-enum HeatPumpMode {
-    Off = 0,
-    Heat = 1,
-    Dry = 2,
-    Cool = 3,
-    Fan = 7,
-    Auto = 8,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BusWatchdogConfig {
+    // What to do if we think we're connected but haven't had a valid packet from the heat pump in timeout_secs
+    pub enabled: bool,
+    pub timeout_secs: u64,
+    pub action: BusWatchdogAction,
 }
-
-#[derive(Clone, Copy, FromRepr, Debug, Serialize, Deserialize)]
-enum FanSpeed {
-    Auto = 0,
-    Quiet = 1,
-    Low = 2,
-    Med = 3,
-    High = 5,
-    VeryHigh = 6,
+impl BusWatchdogConfig {
+    pub fn new() -> Self {
+        Self {
+            enabled: true,
+            timeout_secs: 300,
+            action: BusWatchdogAction::ReinitUart,
+        }
+    }
 }
 
-#[derive(Clone, Copy, FromRepr, Debug, Serialize, Deserialize)]
-enum VaneDirection {
-    Auto = 0,
-    Horizontal=1,
-    MidHorizontal=2,
-    Midpoint=3,
-    MidVertical=4,
-    Vertical=5,
-    Swing=7,
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Capabilities {
+    // What the connected unit can do.  Some (e.g. ducted) units ignore vane commands, so they're refused up front
+    pub vane: bool,
+    pub widevane: bool,
 }
-
-#[derive(Clone, Copy, FromRepr, Debug, Serialize, Deserialize)]
-enum WideVaneDirection {
-    FarLeft=1,
-    Left=2,
-    Mid=3,
-    Right=4,
-    FarRight=5,
-    Split=8,
-    Swing=0x0c,
-    // ISee=0x80, //not really clear what's going on here, for now we just ignore this bit
-    Unknown=999,
+impl Capabilities {
+    pub fn new() -> Self {
+        Self {
+            vane: true,
+            widevane: true,
+        }
+    }
 }
 
-#[derive(Clone, Copy, FromRepr, Debug, Serialize, Deserialize)]
-enum ISeeMode {
-    Unknown=999,
-    Direct=2,
-    Indirect=1,
+#[derive(Debug, Clone, Serialize)]
+struct AckRecord {
+    // The payload of the last 0x61 response to a set packet. What the bytes mean isn't known (they're usually
+    // all zero), but some units seem to put rejection reasons there so they're kept to compare between units
+    pub data: Vec<u8>,
+    pub hex: String,
+    pub nonzero_bytes: Vec<(usize, u8)>,  // (index, value) of anything that isn't 0, which is the interesting part
+    pub sent_flags: Vec<&'static str>,  // what the set packet this acknowledges was changing
+    pub time: Option<String>,
+    pub secs_since_boot: f32,
 }
-
-fn set_led<T:InputPin, MODE: InputMode>(r:u8, g:u8, b:u8, npx: &mut Ws2812B, 
-                                        led_off_sense_pin: &PinDriver<T, MODE>) -> anyhow::Result<()> {
-    #[cfg(feature="ws2182onboard")]
-    if led_off_sense_pin.is_high() {
-        npx.set(Rgb::new(r, g, b))?;
-    } else {
-        npx.set(Rgb::new(0, 0, 0))?;
+impl AckRecord {
+    pub fn new(ack: &Packet, sent: &Packet, boot_instant: Instant) -> Self {
+        Self {
+            data: ack.data.to_vec(),
+            hex: ack.data.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "),
+            nonzero_bytes: ack.data.iter().enumerate().filter(|(_, b)| **b != 0).map(|(i, b)| (i, *b)).collect(),
+            sent_flags: HeatPumpSetting::packet_flags(sent),
+            time: clock::iso8601_now(),
+            secs_since_boot: boot_instant.elapsed().as_secs_f32(),
+        }
     }
-
-    Ok(())
 }
 
+#[derive(Debug, Clone)]
+struct StatusPacketRecord {
+    pub data: PacketData,
+    pub received: Instant,
+    pub time: Option<String>,
+}
 
-fn main() -> anyhow::Result<()> {
-    esp_idf_svc::sys::link_patches();
-    esp_idf_svc::log::EspLogger::initialize_default();
+/// The last of each kind of status packet and when it came in.  There's a slot per StatusPacketType, so a unit
+/// sending type bytes we don't know can't grow it, and it always comes out in the same order
+#[derive(Debug)]
+struct LastStatusPackets {
+    slots: [Option<StatusPacketRecord>; StatusPacketType::COUNT],
+}
+impl LastStatusPackets {
+    pub fn new() -> Self {
+        Self { slots: std::array::from_fn(|_| None) }
+    }
 
-    let boot_instant = Instant::now();
+    fn slot(status_type: StatusPacketType) -> usize {
+        StatusPacketType::iter().position(|t| t as usize == status_type as usize).unwrap()
+    }
 
-    let peripherals = Peripherals::take().unwrap();
-    let pins = peripherals.pins;
+    pub fn record(&mut self, status_type: StatusPacketType, data: PacketData) {
+        self.slots[Self::slot(status_type)] = Some(StatusPacketRecord {
+            data,
+            received: Instant::now(),
+            time: clock::iso8601_now(),
+        });
+    }
+}
+impl Serialize for LastStatusPackets {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        // keyed by the type byte, as it was when this was a map
+        let mut map = serializer.serialize_map(None)?;
+        for (status_type, record) in StatusPacketType::iter().zip(self.slots.iter()) {
+            if let Some(record) = record {
+                map.serialize_entry(&(status_type as u8).to_string(), &json!({
+                    "data": record.data,
+                    "time": record.time,
+                    "age_secs": record.received.elapsed().as_secs_f32(),
+                }))?;
+            }
+        }
+        map.end()
+    }
+}
 
-    //LED_OFF_SEND_PIN LED_OFF_SENSE_PIN
-    let mut  led_off_send_pin = PinDriver::output(pin_from_envar!(pins, "LED_OFF_SEND_PIN"))?;
-    let mut  led_off_sense_pin = PinDriver::input(pin_from_envar!(pins, "LED_OFF_SENSE_PIN"))?;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct TemperatureSmoothing {
+    // Exponential smoothing of the room temperature, since the unit only reports it in half-degree steps.
+    // alpha is the weight of each new reading, so smaller is smoother (and slower)
+    pub enabled: bool,
+    pub alpha: f32,
+}
+impl TemperatureSmoothing {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            alpha: 0.1,
+        }
+    }
 
-    // pulling down and having the send pin pull high myseteriously wasn't working so we have the sense pin high for leds on
-    led_off_send_pin.set_low()?;
-    led_off_sense_pin.set_pull(Pull::Up)?;
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.alpha > 0.0 && self.alpha <= 1.0) {
+            return Err("alpha must be greater than 0 and at most 1".to_string());
+        }
+        Ok(())
+    }
 
-    // set up NVS since that is needed to remember led brightness
-    let nvs_default_partition: nvs::EspNvsPartition<nvs::NvsDefault> = nvs::EspDefaultNvsPartition::take()?;
-    let mut nvs_settings = nvs::EspNvs::new(nvs_default_partition.clone(), "settings", true)?;
-    let mut led_brightness = nvs_settings.get_u8("led_brightness")?.unwrap_or(LED_DEFAULT_BRIGHTNESS); 
-    
-    #[cfg(feature="ws2182onboard")]
-    let rmtconfig = rmt::config::TransmitConfig::new().clock_divider(1);
-    #[cfg(feature="ws2182onboard")]
-    let mut npx = Ws2812B::new(rmt::TxRmtDriver::new(peripherals.rmt.channel0, pin_from_envar!(pins, "LED_PIN_NUM"), &rmtconfig)?);
-    // reddish-orangish during setup
-    set_led(led_brightness, led_brightness/4, 0, &mut npx, &led_off_sense_pin)?;
+    pub fn apply(&self, previous_c: f32, reading_c: f32) -> f32 {
+        if !self.enabled || previous_c <= -999.0 {
+            reading_c
+        } else {
+            self.alpha * reading_c + (1.0 - self.alpha) * previous_c
+        }
+    }
+}
 
-    // start by setting up uart
-    let uart_config = uart::config::Config::default()
-        .baudrate(Hertz(2400))
-        .data_bits(uart::config::DataBits::DataBits8)
-        .parity_even()
-        .stop_bits(uart::config::StopBits::STOP1)
-        .flow_control(uart::config::FlowControl::None);
+#[derive(Debug, Clone, Serialize)]
+struct HeatPumpError {
+    // A record of the last error the heat pump reported, kept after the error clears on the bus
+    pub data: Vec<u8>,
+    pub code: String,
+    pub active: bool,
+    pub raised_at: Option<String>,
+    pub cleared_at: Option<String>,
+    pub secs_since_boot_raised: f32,
+    pub secs_since_boot_cleared: Option<f32>,
+}
+impl HeatPumpError {
+    pub fn new(data: &[u8], boot_instant: Instant) -> Self {
+        Self {
+            data: data.to_vec(),
+            code: Self::decode(data),
+            active: true,
+            raised_at: clock::iso8601_now(),
+            cleared_at: None,
+            secs_since_boot_raised: boot_instant.elapsed().as_secs_f32(),
+            secs_since_boot_cleared: None,
+        }
+    }
 
-    let uart: uart::UartDriver = uart::UartDriver::new(
-        peripherals.uart1,
-        pin_from_envar!(pins, "TX_PIN_NUM"),
-        pin_from_envar!(pins, "RX_PIN_NUM"),
-        Option::<AnyIOPin>::None,
-        Option::<AnyIOPin>::None,
-        &uart_config
-    ).unwrap();
+    pub fn decode(data: &[u8]) -> String {
+        // bytes 4 and 5 of the error packet hold the code (0x80 in byte 4 means no error). The mapping to the
+        // codes shown on the remote isn't known so it is just given in hex
+        match (data.get(4), data.get(5)) {
+            (Some(hi), Some(lo)) => format!("{:02x}{:02x}", hi, lo),
+            _ => "unknown".to_string(),
+        }
+    }
 
+    pub fn clear(&mut self, boot_instant: Instant) {
+        self.active = false;
+        self.cleared_at = clock::iso8601_now();
+        self.secs_since_boot_cleared = Some(boot_instant.elapsed().as_secs_f32());
+    }
+}
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RebootWindow {
+    // Confines the periodic reboot to a time of day, e.g. 3 to 4 for 03:00-04:00 local.  end_hour < start_hour wraps past midnight
+    pub enabled: bool,
+    pub start_hour: u8,
+    pub end_hour: u8,
+    pub utc_offset_minutes: i32,
+}
+impl RebootWindow {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            start_hour: 3,
+            end_hour: 4,
+            utc_offset_minutes: 0,
+        }
+    }
 
-    // start up the wifi then try to configure the server
-    let (wifi, wifimac) = match setup_wifi(peripherals.modem, nvs_default_partition.clone()) {
-        Ok(res) => { res },
-        Err(e) => {
-            set_led(led_brightness, 0, 0, &mut npx, &led_off_sense_pin)?;
-            info!("wifi did not successfully start due to {}. Waiting {} secs and then restarting!", 
-                  e, WIFI_DISCONNECTED_RESET_TIME.as_secs_f32());
-            std::thread::sleep(WIFI_DISCONNECTED_RESET_TIME);
-            reset::restart();
-            return Err(e);
+    pub fn validate(&self) -> Result<(), String> {
+        if self.start_hour > 23 || self.end_hour > 24 {
+            return Err("start_hour must be 0-23 and end_hour 0-24".to_string());
         }
-    };
-    let macstr = match wifimac {
-        Some (mac) => Some(format!("{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}", mac[0], mac[1], mac[2], mac[3], mac[4], mac[5])),
-        None => None
-    };
-    //Go to yellow once wifi is started
-    set_led(led_brightness, led_brightness, 0, &mut npx, &led_off_sense_pin)?;
+        if self.utc_offset_minutes.abs() > 14*60 {
+            return Err("utc_offset_minutes must be within +/- 14 hours".to_string());
+        }
+        Ok(())
+    }
 
-    let server_configuration = http::server::Configuration {
-        stack_size: HTTP_SERVER_STACK_SIZE,
-        http_port: HTTP_PORT,
-        ..Default::default()
-    };
-    let mut server = http::server::EspHttpServer::new(&server_configuration)?;
-    let state = setup_handlers(&mut server, boot_instant, macstr.clone())?;
+    /// The first time the window opens after the given time
+    pub fn next_start_after(&self, unix_secs: u64) -> u64 {
+        let offset = self.utc_offset_minutes as i64 * 60;
+        let local_secs = unix_secs as i64 + offset;
+        let mut start = local_secs - local_secs.rem_euclid(86400) + self.start_hour as i64 * 3600;
+        if start <= local_secs {
+            start += 86400;
+        }
+        (start - offset) as u64
+    }
 
-    // now start mdns
-    let _mdnso = match macstr {
-        Some (s) => {
-            let mut mdns = mdns::EspMdns::take()?;
+    pub fn contains(&self, unix_secs: u64) -> bool {
+        let local_secs = unix_secs as i64 + self.utc_offset_minutes as i64 * 60;
+        let hour = local_secs.rem_euclid(86400) / 3600;
+        let (start, end) = (self.start_hour as i64, self.end_hour as i64);
+        if start <= end {
+            hour >= start && hour < end
+        } else {
+            hour >= start || hour < end
+        }
+    }
+}
 
-            mdns.set_hostname(["heatpump-controller-", &s].concat())?;
-            mdns.set_instance_name(["Mitsubishi heatpump controller w/mac ", &s].concat())?;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MdnsService {
+    pub service_type: String,  // e.g. "_http"
+    pub proto: String,  // "_tcp" or "_udp"
+}
 
-            mdns.add_service(None, "_eteq-mheatpump", "_tcp", HTTP_PORT, &[])?;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct MdnsConfig {
+    // What gets advertised besides MDNS_SERVICE_TYPE, which always is since that's how the controllers find each other
+    pub instance_name: Option<String>,  // None for the default, which includes the mac address
+    pub extra_services: Vec<MdnsService>,  // all pointing at the http port
+}
+impl MdnsConfig {
+    pub fn new() -> Self {
+        Self {
+            instance_name: None,
+            extra_services: Vec::new(),
+        }
+    }
 
-            Some(mdns)
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(name) = &self.instance_name {
+            if name.is_empty() || name.len() > 63 {
+                return Err("instance_name must be 1-63 bytes".to_string());
+            }
         }
-        None => {
-            info!("No IP address, not starting mdns");
-            None
+        if self.extra_services.len() > MDNS_EXTRA_SERVICES_MAX {
+            return Err(format!("at most {} extra services can be advertised", MDNS_EXTRA_SERVICES_MAX));
         }
-    };
+        for service in self.extra_services.iter() {
+            // service names are at most 15 characters (RFC 6335), plus the leading underscore
+            if !service.service_type.starts_with('_') || service.service_type.len() < 2 || service.service_type.len() > 16 {
+                return Err(format!("service_type {:?} must be an underscore followed by 1-15 characters", service.service_type));
+            }
+            if service.service_type == MDNS_SERVICE_TYPE {
+                return Err(format!("{} is always advertised", MDNS_SERVICE_TYPE));
+            }
+            if service.proto != "_tcp" && service.proto != "_udp" {
+                return Err(format!("proto {:?} must be \"_tcp\" or \"_udp\"", service.proto));
+            }
+        }
+        Ok(())
+    }
+}
 
+#[derive(Debug, Deserialize)]
+struct RemoteTemperature {
+    pub temperature_c: f32,
+}
 
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Subsystem {
+    // parts of the controller that can be restarted on their own via /restart_subsystem.json
+    Wifi,
+    Http,
+    Uart,
+}
 
-    // set up the TWDT to catch any hangs in the main loop
+#[derive(Debug, Deserialize)]
+struct SubsystemRestartRequest {
+    pub subsystem: Subsystem,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct SetpointRange {
+    pub min_c: f32,
+    pub max_c: f32,
+}
+impl SetpointRange {
+    pub fn contains(&self, temperature_c: f32) -> bool {
+        temperature_c >= self.min_c && temperature_c <= self.max_c
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SetpointLimits {
+    // The allowed desired temperature for each mode that has one. If clamp is false, out-of-range requests are rejected
+    pub heat: SetpointRange,
+    pub cool: SetpointRange,
+    pub dry: SetpointRange,
+    pub auto: SetpointRange,
+    pub clamp: bool,
+}
+impl SetpointLimits {
+    pub fn new() -> Self {
+        let default_range = SetpointRange { min_c: SETPOINT_DEFAULT_MIN_C, max_c: SETPOINT_DEFAULT_MAX_C };
+        Self {
+            heat: default_range,
+            cool: default_range,
+            dry: default_range,
+            auto: default_range,
+            clamp: false,
+        }
+    }
+
+    pub fn for_mode(&self, mode: HeatPumpMode) -> Option<SetpointRange> {
+        match mode {
+            HeatPumpMode::Heat => Some(self.heat),
+            HeatPumpMode::Cool => Some(self.cool),
+            HeatPumpMode::Dry => Some(self.dry),
+            HeatPumpMode::Auto => Some(self.auto),
+            HeatPumpMode::Off | HeatPumpMode::Fan => None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, range) in [("heat", self.heat), ("cool", self.cool), ("dry", self.dry), ("auto", self.auto)] {
+            if range.min_c > range.max_c {
+                return Err(format!("min_c is above max_c for {}", name));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WifiNetwork {
+    pub ssid: String,
+    // stored separately in NVS and never serialized so it can't leak through status.json.  This means the
+    // passwords have to be given every time the list is set
+    #[serde(default, skip_serializing)]
+    pub password: Option<String>,  // for WPA2-Enterprise this is the EAP password
+    #[serde(default)]
+    pub eap: Option<EapConfig>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EapConfig {
+    // WPA2-Enterprise (PEAP/TTLS) credentials, the password being the network's password
+    pub identity: String,
+    pub username: String,
+    // a PEM certificate to check the server against.  Stored separately in NVS like the password, and without it
+    // the server isn't verified
+    #[serde(default, skip_serializing)]
+    pub ca_cert: Option<String>,
+}
+
+fn load_static_ip(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<StaticIpConfig> {
+    Ok(match nvs_get_string(nvs, "static_ip")? {
+        Some(staticjson) => match serde_json::from_str::<StaticIpConfig>(&staticjson) {
+            Ok(config) => config,
+            Err(e) => {
+                info!("Could not parse stored static IP config, using DHCP: {}", e);
+                StaticIpConfig::new()
+            }
+        },
+        None => StaticIpConfig::new(),
+    })
+}
+
+fn load_wifi_networks(nvs: &nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<Vec<WifiNetwork>> {
+    let mut networks = match nvs_get_string(nvs, "wifi_networks")? {
+        Some(networksjson) => match serde_json::from_str::<Vec<WifiNetwork>>(&networksjson) {
+            Ok(networks) => networks,
+            Err(e) => {
+                info!("Could not parse stored wifi networks, only using the compiled-in one: {}", e);
+                Vec::new()
+            }
+        },
+        None => Vec::new(),
+    };
+    for (i, network) in networks.iter_mut().enumerate() {
+        network.password = nvs_get_string(nvs, &format!("wifi_pass_{}", i))?;
+        if let Some(eap) = network.eap.as_mut() {
+            eap.ca_cert = nvs_get_string(nvs, &format!("wifi_ca_{}", i))?;
+        }
+    }
+    Ok(networks)
+}
+
+fn save_wifi_networks(nvs: &mut nvs::EspNvs<nvs::NvsDefault>, networks: &[WifiNetwork]) -> anyhow::Result<()> {
+    nvs.set_str("wifi_networks", &serde_json::to_string(networks)?)?;
+    for i in 0..WIFI_NETWORKS_MAX {
+        let key = format!("wifi_pass_{}", i);
+        match networks.get(i).and_then(|n| n.password.as_ref()) {
+            Some(password) => { nvs.set_str(&key, password)?; }
+            None => { nvs.remove(&key)?; }
+        }
+        let key = format!("wifi_ca_{}", i);
+        match networks.get(i).and_then(|n| n.eap.as_ref()).and_then(|e| e.ca_cert.as_ref()) {
+            Some(ca_cert) => { nvs.set_str(&key, ca_cert)?; }
+            None => { nvs.remove(&key)?; }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct StaticIpConfig {
+    // for networks without reliable DHCP.  Only the station uses it; the AP keeps its own addressing
+    pub enabled: bool,
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    #[serde(default)]
+    pub dns: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub secondary_dns: Option<Ipv4Addr>,
+}
+impl StaticIpConfig {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            ip: Ipv4Addr::UNSPECIFIED,
+            netmask: Ipv4Addr::new(255, 255, 255, 0),
+            gateway: Ipv4Addr::UNSPECIFIED,
+            dns: None,
+            secondary_dns: None,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let mask = u32::from(self.netmask);
+        if mask == 0 || mask.leading_ones() + mask.trailing_zeros() != 32 {
+            return Err("netmask must be contiguous, like 255.255.255.0".to_string());
+        }
+        if self.ip.is_unspecified() || self.ip.is_broadcast() || self.ip.is_multicast() {
+            return Err("ip must be a unicast address".to_string());
+        }
+        if u32::from(self.ip) & mask != u32::from(self.gateway) & mask {
+            return Err("gateway must be on the same subnet as ip".to_string());
+        }
+        Ok(())
+    }
+
+    /// The station's netif, with these addresses if enabled or DHCP otherwise
+    pub fn sta_netif(&self) -> Result<EspNetif, EspError> {
+        if !self.enabled {
+            return EspNetif::new(NetifStack::Sta);
+        }
+        let settings = ipv4::ClientSettings {
+            ip: self.ip,
+            subnet: ipv4::Subnet { gateway: self.gateway, mask: ipv4::Mask(u32::from(self.netmask).leading_ones() as u8) },
+            dns: self.dns,
+            secondary_dns: self.secondary_dns,
+        };
+        EspNetif::new_with_conf(&NetifConfiguration {
+            ip_configuration: ipv4::Configuration::Client(ipv4::ClientConfiguration::Fixed(settings)),
+            ..NetifConfiguration::wifi_default_client()
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct NetworkInfo {
+    pub ip: Ipv4Addr,
+    pub netmask: Ipv4Addr,
+    pub gateway: Ipv4Addr,
+    pub dns: Option<Ipv4Addr>,
+    pub is_static: bool,
+}
+impl NetworkInfo {
+    pub fn get(netif: &EspNetif, is_static: bool) -> Option<Self> {
+        let info = netif.get_ip_info().ok()?;
+        if info.ip.is_unspecified() {
+            return None;
+        }
+        Some(Self {
+            ip: info.ip,
+            netmask: Ipv4Addr::from(u32::MAX.checked_shl(32 - info.subnet.mask.0 as u32).unwrap_or(0)),
+            gateway: info.subnet.gateway,
+            dns: info.dns.filter(|d| !d.is_unspecified()),
+            is_static,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct WifiLink {
+    // The station's current connection, for correlating disconnects with weak signal
+    pub rssi: i8,
+    pub channel: u8,
+    pub bssid: String,
+    pub connected_secs: f32,
+}
+impl WifiLink {
+    /// None if not connected as a station (including when running as an AP)
+    pub fn get(connected_since: Option<Instant>) -> Option<Self> {
+        let mut record: hal::sys::wifi_ap_record_t = Default::default();
+        if unsafe { hal::sys::esp_wifi_sta_get_ap_info(&mut record) } != hal::sys::ESP_OK {
+            return None;
+        }
+        let b = record.bssid;
+        Some(Self {
+            rssi: record.rssi,
+            channel: record.primary,
+            bssid: format!("{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", b[0], b[1], b[2], b[3], b[4], b[5]),
+            connected_secs: connected_since.map_or(0.0, |t| t.elapsed().as_secs_f32()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum WifiProtocol {
+    B,
+    G,
+    N,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct WifiRadioConfig {
+    // For installs where the signal is marginal, e.g. a controller inside a metal line-set cover
+    pub tx_power_dbm: Option<f32>,  // None for the most the country allows
+    pub protocols: Vec<WifiProtocol>,  // empty for the default of b/g/n
+    pub rssi_log_secs: u32,  // how often to log a summary of the signal strength, 0 to not
+}
+impl WifiRadioConfig {
+    pub fn new() -> Self {
+        Self {
+            tx_power_dbm: None,
+            protocols: Vec::new(),
+            rssi_log_secs: 0,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if let Some(dbm) = self.tx_power_dbm {
+            if !(2.0..=20.0).contains(&dbm) {
+                return Err("tx_power_dbm must be between 2 and 20".to_string());
+            }
+        }
+        // the driver only takes these combinations
+        let mut protocols = self.protocols.clone();
+        protocols.sort_by_key(|p| *p as u8);
+        protocols.dedup();
+        if ![vec![], vec![WifiProtocol::B], vec![WifiProtocol::B, WifiProtocol::G],
+             vec![WifiProtocol::B, WifiProtocol::G, WifiProtocol::N]].contains(&protocols) {
+            return Err("protocols must be [\"b\"], [\"b\", \"g\"] or [\"b\", \"g\", \"n\"]".to_string());
+        }
+        Ok(())
+    }
+
+    /// Sets the station's tx power and protocols.  The power changes straight away, the protocols on the next connect
+    pub fn apply(&self) -> Result<(), EspError> {
+        // the driver takes power in units of 0.25 dBm, and caps it at what the country allows
+        let quarter_dbm = (self.tx_power_dbm.unwrap_or(20.0) * 4.0).round() as i8;
+        hal::sys::esp!(unsafe { hal::sys::esp_wifi_set_max_tx_power(quarter_dbm) })?;
+
+        let bitmap = if self.protocols.is_empty() {
+            hal::sys::WIFI_PROTOCOL_11B | hal::sys::WIFI_PROTOCOL_11G | hal::sys::WIFI_PROTOCOL_11N
+        } else {
+            self.protocols.iter().fold(0, |bits, p| bits | match p {
+                WifiProtocol::B => hal::sys::WIFI_PROTOCOL_11B,
+                WifiProtocol::G => hal::sys::WIFI_PROTOCOL_11G,
+                WifiProtocol::N => hal::sys::WIFI_PROTOCOL_11N,
+            })
+        };
+        hal::sys::esp!(unsafe { hal::sys::esp_wifi_set_protocol(hal::sys::wifi_interface_t_WIFI_IF_STA, bitmap as u8) })
+    }
+}
+
+struct RssiTrend {
+    // Accumulates the signal strength between log lines, so a slow fade shows up in the log (and syslog)
+    pub started: Instant,
+    pub count: u32,
+    pub sum: i64,
+    pub min: i8,
+    pub max: i8,
+}
+impl RssiTrend {
+    pub fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            count: 0,
+            sum: 0,
+            min: i8::MAX,
+            max: i8::MIN,
+        }
+    }
+
+    pub fn sample(&mut self, rssi: i8) {
+        self.count += 1;
+        self.sum += rssi as i64;
+        self.min = self.min.min(rssi);
+        self.max = self.max.max(rssi);
+    }
+
+    /// Logs and starts afresh once the interval has passed
+    pub fn log_if_due(&mut self, interval_secs: u32) {
+        if interval_secs == 0 || self.started.elapsed() < Duration::from_secs(interval_secs as u64) {
+            return;
+        }
+        if self.count > 0 {
+            info!("wifi RSSI over the last {} secs: min {} avg {:.1} max {} dBm", interval_secs,
+                  self.min, self.sum as f32 / self.count as f32, self.max);
+        } else {
+            info!("wifi RSSI over the last {} secs: not connected", interval_secs);
+        }
+        *self = Self::new();
+    }
+}
+
+#[derive(Debug)]
+struct WifiReconnect {
+    // Tries to get the station connection back in place, with exponential backoff, rather than rebooting
+    pub attempts: u32,
+    pub next_attempt: Option<Instant>,
+    pub disconnected_since: Option<Instant>,
+    pub recovery_ap: bool,  // whether the AP has been brought up alongside the station to stay reachable
+}
+impl WifiReconnect {
+    pub fn new() -> Self {
+        Self {
+            attempts: 0,
+            next_attempt: None,
+            disconnected_since: None,
+            recovery_ap: false,
+        }
+    }
+
+    pub fn is_due(&self) -> bool {
+        self.next_attempt.map_or(true, |t| Instant::now() >= t)
+    }
+
+    pub fn record_attempt(&mut self) {
+        self.attempts += 1;
+        self.disconnected_since.get_or_insert_with(Instant::now);
+        let backoff = WIFI_RECONNECT_FIRST_BACKOFF.saturating_mul(1 << (self.attempts - 1).min(16))
+                                                  .min(WIFI_RECONNECT_MAX_BACKOFF);
+        self.next_attempt = Some(Instant::now() + backoff);
+    }
+
+    pub fn gave_up(&self) -> bool {
+        self.attempts >= WIFI_RECONNECT_MAX_ATTEMPTS && self.is_due()
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+struct ProtocolErrors {
+    // Running counts of everything that went wrong talking to the heat pump, to make wiring problems visible
+    pub checksum_failures: u64,
+    pub short_packets: u64,  // too short, or shorter than the length in the header says
+    pub bad_sync: u64,
+    pub unexpected_packet_types: u64,
+    pub read_timeouts: u64,
+}
+impl ProtocolErrors {
+    pub fn new() -> Self {
+        Self {
+            checksum_failures: 0,
+            short_packets: 0,
+            bad_sync: 0,
+            unexpected_packet_types: 0,
+            read_timeouts: 0,
+        }
+    }
+}
+
+struct BusHealth {
+    // Tracks reads that look like electrical problems on the CN105 lines rather than just a unit that isn't answering
+    pub consecutive_bad_reads: u32,
+    pub last_symptom: Option<&'static str>,
+    pub last_valid_packet: Option<Instant>,
+    pub errors: ProtocolErrors,
+    pub recent_reads: VecDeque<bool>,  // whether each of the latest reads (including timeouts) got a good packet
+    pub latency_ms: Option<f32>,  // moving average of how long the heat pump takes to start answering
+}
+impl BusHealth {
+    pub fn new() -> Self {
+        Self {
+            consecutive_bad_reads: 0,
+            last_symptom: None,
+            last_valid_packet: None,
+            errors: ProtocolErrors::new(),
+            recent_reads: VecDeque::new(),
+            latency_ms: None,
+        }
+    }
+
+    fn record_outcome(&mut self, good: bool) {
+        if self.recent_reads.len() >= BUS_RECENT_READS {
+            self.recent_reads.pop_front();
+        }
+        self.recent_reads.push_back(good);
+    }
+
+    pub fn record_timeout(&mut self) {
+        self.errors.read_timeouts += 1;
+        self.record_outcome(false);
+    }
+
+    pub fn record_latency(&mut self, latency: Duration) {
+        let ms = latency.as_secs_f32() * 1000.0;
+        self.latency_ms = Some(self.latency_ms.map_or(ms, |avg| avg + BUS_LATENCY_ALPHA * (ms - avg)));
+    }
+
+    /// The fraction of the latest reads that were good, or None before there have been any
+    pub fn success_rate(&self) -> Option<f32> {
+        if self.recent_reads.is_empty() {
+            None
+        } else {
+            Some(self.recent_reads.iter().filter(|g| **g).count() as f32 / self.recent_reads.len() as f32)
+        }
+    }
+
+    pub fn record_read(&mut self, bytes: &[u8], parse_error: Option<&anyhow::Error>) {
+        if bytes.is_empty() {
+            // nothing read is just "no response", which isn't evidence either way
+            return;
+        }
+        match parse_error.and_then(|e| e.downcast_ref::<PacketError>()) {
+            Some(PacketError::TooShort) | Some(PacketError::LengthMismatch) | Some(PacketError::TooLong) => { self.errors.short_packets += 1; }
+            Some(PacketError::NoSync) => { self.errors.bad_sync += 1; }
+            Some(PacketError::BadChecksum) => { self.errors.checksum_failures += 1; }
+            None => {}
+        }
+        let parsed_ok = parse_error.is_none();
+        let symptom = if bytes.iter().all(|b| *b == 0x00) {
+            Some("every byte read was 0x00")
+        } else if bytes.iter().all(|b| *b == 0xff) {
+            Some("every byte read was 0xFF")
+        } else if bytes[0] != 0xfc {
+            Some("data did not start with the 0xfc sync byte")
+        } else if !parsed_ok {
+            Some("packets were malformed or failed the checksum")
+        } else {
+            None
+        };
+
+        match symptom {
+            Some(s) => {
+                self.consecutive_bad_reads += 1;
+                self.last_symptom = Some(s);
+            }
+            None => {
+                self.consecutive_bad_reads = 0;
+                self.last_symptom = None;
+                self.last_valid_packet = Some(Instant::now());
+            }
+        }
+        self.record_outcome(symptom.is_none());
+    }
+
+    pub fn warning(&self) -> Option<String> {
+        if self.consecutive_bad_reads < BUS_BAD_READS_BEFORE_WARNING {
+            return None;
+        }
+        Some(format!("{} for the last {} reads from the heat pump. This usually means a missing level shifter \
+                      or swapped/loose TX/RX wiring, check the hardware", 
+                     self.last_symptom.unwrap_or("bad data"), self.consecutive_bad_reads))
+    }
+}
+
+#[derive(Clone, Copy, FromRepr, Debug, Serialize, Deserialize, EnumIter, EnumCount)]
+enum StatusPacketType {
+    Settings = 2,
+    RoomTemperature = 3,
+    ErrorCodeMaybe = 4, // not sure, but this is what https://github.com/SwiCago/HeatPump/issues/39 seems to suggest?
+    Timers = 5,
+    MiscInfo = 6,
+    StandbyMode = 9, // Also unsure but its what https://github.com/SwiCago/HeatPump thinks and is also asked for by Kumo Cloud...
+}
+
+#[cfg(feature="button")]
+impl HeatPumpMode {
+    /// The mode after this one when cycling through them with the button
+    pub fn next_in_cycle(&self) -> Self {
+        match self {
+            HeatPumpMode::Heat => HeatPumpMode::Cool,
+            HeatPumpMode::Cool => HeatPumpMode::Dry,
+            HeatPumpMode::Dry => HeatPumpMode::Fan,
+            HeatPumpMode::Fan => HeatPumpMode::Auto,
+            HeatPumpMode::Auto | HeatPumpMode::Off => HeatPumpMode::Heat,
+        }
+    }
+}
+
+#[derive(Clone, Copy, FromRepr, Debug, Serialize, Deserialize)]
+enum ISeeMode {
+    Unknown=999,
+    Direct=2,
+    Indirect=1,
+}
+
+fn set_led<T:InputPin, MODE: InputMode>(r:u8, g:u8, b:u8, npx: &mut Ws2812B, 
+                                        led_off_sense_pin: &PinDriver<T, MODE>) -> anyhow::Result<()> {
+    #[cfg(feature="ws2182onboard")]
+    if led_off_sense_pin.is_high() {
+        npx.set(Rgb::new(r, g, b))?;
+    } else {
+        npx.set(Rgb::new(0, 0, 0))?;
+    }
+
+    Ok(())
+}
+
+/// What the LED should be showing.  The LED task does the blinking itself, so blinks keep time however long the main
+/// loop takes to get around
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LedPattern {
+    Solid(u8, u8, u8),
+    Blink { on: (u8, u8, u8), off: (u8, u8, u8), period_ms: u128 },
+}
+
+enum LedMessage {
+    Pattern(LedPattern),  // from the main loop
+    Override(Option<LedPattern>),  // shown instead of the loop's pattern until cleared, e.g. for a button countdown
+}
+
+/// Makes the requests to other hosts: the coordinator's units, the leader being followed, the cloud relay and the
+/// alert services.  Each can take up to the HTTP client timeout, so they're kept off the main loop where a few in a
+/// row would trip the watchdog.  Results go back through the state like the handlers'
+fn outbound_task(state: Arc<Mutex<HeatPumpStatus>>, boot_instant: Instant, macstr: Option<String>) {
+    loop {
+        std::thread::sleep(OUTBOUND_TICK);
+
+        // as the coordinator, keep up with the other units and pass on settings for them.  At most one request each
+        // per pass, without the lock held, so a slow unit can't hold everything up
+        let (unit_poll, unit_set) = {
+            let mut realstate = state.lock().unwrap();
+            (realstate.coordinator.next_poll_url(), realstate.coordinator.next_set())
+        };
+        if let Some((hostname, url)) = unit_poll {
+            let result = http_client::get(&url);
+            state.lock().unwrap().coordinator.record_poll(&hostname, result);
+        }
+        if let Some((hostname, url, body)) = unit_set {
+            info!("passing setting {} on to {}", body, hostname);
+            let result = http_client::post_json(&url, &[], &body);
+            state.lock().unwrap().coordinator.record_set(&hostname, result);
+        }
+
+        // likewise for the leader, if following one
+        let leader_poll = state.lock().unwrap().follower.poll_url();
+        if let Some(url) = leader_poll {
+            let result = http_client::get(&url);
+            let mut realstate = state.lock().unwrap();
+            if let Some(mut setting) = realstate.follower.record_poll(result) {
+                match validate_setting(&mut setting, &realstate) {
+                    Ok(()) => {
+                        if let Some(temperature_c) = setting.desired_temperature_c {
+                            realstate.pid.set_user_target(temperature_c);
+                        }
+                        // anything already pending goes first, so merge rather than replace
+                        match realstate.desired_settings.as_mut() {
+                            Some(pending) => {
+                                pending.poweron = setting.poweron.or(pending.poweron);
+                                pending.mode = setting.mode.or(pending.mode);
+                                pending.desired_temperature_c = setting.desired_temperature_c.or(pending.desired_temperature_c);
+                            }
+                            None => { realstate.desired_settings = Some(setting); }
+                        }
+                    }
+                    Err(errjson) => { info!("setting copied from the leader was not valid: {}", errjson); }
+                }
+            }
+        }
+
+        // push the status to the relay if that's configured.  The lock isn't held during the request since it can be slow
+        let push_body = {
+            let realstate = state.lock().unwrap();
+            if realstate.cloud_push.is_due() {
+                Some((status_json(&realstate, boot_instant, &macstr).to_string(), realstate.cloud_push.config.clone()))
+            } else {
+                None
+            }
+        };
+        if let Some((body, config)) = push_body {
+            let result = cloud_push::send(&config, &body);
+            state.lock().unwrap().cloud_push.record(result);
+        }
+
+        // likewise for any alerts
+        let (alert_messages, alert_config) = {
+            let mut realstate = state.lock().unwrap();
+            let connected = realstate.connected;
+            let error_data = realstate.error_data.clone();
+            let messages = realstate.alerts.check(connected, &error_data);
+            (messages, realstate.alerts.config.clone())
+        };
+        for message in alert_messages {
+            let result = alerts::send(&alert_config, &message);
+            state.lock().unwrap().alerts.record(&message, result);
+        }
+    }
+}
+
+/// Owns the LED once startup is done, showing whatever pattern the main loop last sent
+fn led_task<T:InputPin, MODE: InputMode>(mut npx: Ws2812B, led_off_sense_pin: PinDriver<T, MODE>, 
+                                         messages: mpsc::Receiver<LedMessage>, boot_instant: Instant) -> anyhow::Result<()> {
+    let mut pattern = LedPattern::Solid(0, 0, 0);
+    let mut override_pattern = None;
+    loop {
+        match messages.recv_timeout(LED_TICK) {
+            Ok(LedMessage::Pattern(p)) => { pattern = p; }
+            Ok(LedMessage::Override(p)) => { override_pattern = p; }
+            Err(mpsc::RecvTimeoutError::Timeout) => {}
+            Err(mpsc::RecvTimeoutError::Disconnected) => { return Ok(()); }
+        }
+        let (r, g, b) = match override_pattern.unwrap_or(pattern) {
+            LedPattern::Solid(r, g, b) => (r, g, b),
+            LedPattern::Blink { on, off, period_ms } => {
+                if boot_instant.elapsed().as_millis() % period_ms < period_ms / 2 { on } else { off }
+            }
+        };
+        // set every tick, since the sense pin can change at any time
+        set_led(r, g, b, &mut npx, &led_off_sense_pin)?;
+    }
+}
+
+/// Watches the BOOT button, and does a factory reset if it's held down long enough.  The LED blinks faster and faster
+/// while it's held so it's clear something is about to happen, and letting go before the end calls it off
+fn button_task<T:InputPin, MODE: InputMode>(button_pin: PinDriver<T, MODE>, mut nvs_settings: nvs::EspNvs<nvs::NvsDefault>,
+                                            leds: mpsc::Sender<LedMessage>) -> anyhow::Result<()> {
+    let mut pressed_at: Option<Instant> = None;
+    let mut shown = None;
+    loop {
+        std::thread::sleep(BUTTON_POLL);
+        // the button pulls the pin low
+        if button_pin.is_high() {
+            if pressed_at.take().is_some() && shown.is_some() {
+                info!("BOOT button let go, not doing the factory reset");
+                shown = None;
+                let _ = leds.send(LedMessage::Override(None));
+            }
+            continue;
+        }
+
+        let held = pressed_at.get_or_insert_with(Instant::now).elapsed();
+        if held >= FACTORY_RESET_HOLD {
+            info!("BOOT button held for {:?}, doing a factory reset", FACTORY_RESET_HOLD);
+            let _ = leds.send(LedMessage::Override(Some(LedPattern::Solid(LED_DEFAULT_BRIGHTNESS, 0, 0))));
+            config::factory_reset(&mut nvs_settings)?;
+            std::thread::sleep(Duration::from_millis(500));
+            reset::restart();
+        }
+        // a short press is left alone, and after that white blinks count down the rest
+        let remaining = FACTORY_RESET_HOLD - held;
+        let period_ms = if held < Duration::from_secs(1) {
+            continue;
+        } else if remaining > Duration::from_secs(5) {
+            1000
+        } else if remaining > Duration::from_secs(2) {
+            500
+        } else {
+            200
+        };
+        if shown != Some(period_ms) {
+            if shown.is_none() {
+                info!("BOOT button held, factory reset in {:?} unless it's let go", remaining);
+            }
+            shown = Some(period_ms);
+            let on = (LED_DEFAULT_BRIGHTNESS, LED_DEFAULT_BRIGHTNESS, LED_DEFAULT_BRIGHTNESS);
+            let _ = leds.send(LedMessage::Override(Some(LedPattern::Blink { on, off: (0, 0, 0), period_ms })));
+        }
+    }
+}
+
+/// A short press of the control button turns the heat pump on or off, and a double press moves on to the next mode.
+/// Either goes out the same way as a setting from the API
+#[cfg(feature="button")]
+fn control_button_task<T:InputPin, MODE: InputMode>(button_pin: PinDriver<T, MODE>,
+                                                    state: Arc<Mutex<HeatPumpStatus>>) -> anyhow::Result<()> {
+    let mut detector = PressDetector::new();
+    loop {
+        std::thread::sleep(CONTROL_BUTTON_POLL);
+        // the button pulls the pin low
+        let press = match detector.update(button_pin.is_low()) {
+            Some(press) => press,
+            None => continue,
+        };
+
+        let mut stateg = state.lock().unwrap();
+        if !stateg.connected {
+            info!("{:?} press of the control button ignored, as the heat pump isn't connected", press);
+            continue;
+        }
+        // go from what's about to be sent if there's something, so pressing again quickly undoes the first one
+        let pending = stateg.desired_settings.as_ref();
+        let mut setting = HeatPumpSetting::new();
+        match press {
+            Press::Single => {
+                let poweron = pending.and_then(|p| p.poweron).unwrap_or(stateg.poweron);
+                setting.poweron = Some(!poweron);
+            }
+            Press::Double => {
+                let mode = pending.and_then(|p| p.mode).unwrap_or(stateg.mode);
+                setting.mode = Some(mode.next_in_cycle());
+            }
+        }
+        match validate_setting(&mut setting, &stateg) {
+            Ok(()) => {
+                info!("control button {:?} press: {:?}", press, setting);
+                stateg.queue_setting(setting);
+            }
+            Err(errjson) => { info!("control button setting was not valid: {}", errjson); }
+        }
+    }
+}
+
+fn show_startup_phase<T:InputPin, MODE: InputMode>(phase: StartupPhase, brightness: u8, npx: &mut Ws2812B, 
+                                                   led_off_sense_pin: &PinDriver<T, MODE>) -> anyhow::Result<()> {
+    info!("Startup phase: {:?}", phase);
+    let (r, g, b) = phase.color(brightness);
+    set_led(r, g, b, npx, led_off_sense_pin)
+}
+
+
+fn main() -> anyhow::Result<()> {
+    esp_idf_svc::sys::link_patches();
+    syslog::LOGGER.initialize();
+
+    let boot_instant = Instant::now();
+
+    let peripherals = Peripherals::take().unwrap();
+    let pins = peripherals.pins;
+
+    // set up NVS since that is needed to remember led brightness, and before that to know if this is a crash loop
+    let nvs_default_partition: nvs::EspNvsPartition<nvs::NvsDefault> = nvs::EspDefaultNvsPartition::take()?;
+    let mut nvs_settings = nvs::EspNvs::new(nvs_default_partition.clone(), "settings", true)?;
+    let crash_streak = safe_mode::record_boot(&mut nvs_settings, reset::ResetReason::get())?;
+    if crash_streak >= safe_mode::CRASHES_BEFORE_SAFE_MODE {
+        // the uart and LED are left alone, since either could be what keeps crashing it
+        return run_safe_mode(peripherals.modem, nvs_default_partition, nvs_settings, crash_streak);
+    }
+    let mut crash_streak_cleared = crash_streak == 0;
+
+    //LED_OFF_SEND_PIN LED_OFF_SENSE_PIN
+    let mut  led_off_send_pin = PinDriver::output(pin_from_envar!(pins, "LED_OFF_SEND_PIN"))?;
+    let mut  led_off_sense_pin = PinDriver::input(pin_from_envar!(pins, "LED_OFF_SENSE_PIN"))?;
+
+    // pulling down and having the send pin pull high myseteriously wasn't working so we have the sense pin high for leds on
+    led_off_send_pin.set_low()?;
+    led_off_sense_pin.set_pull(Pull::Up)?;
+
+    #[cfg(feature="ws2182onboard")]
+    let rmtconfig = rmt::config::TransmitConfig::new().clock_divider(1);
+    #[cfg(feature="ws2182onboard")]
+    let mut npx = Ws2812B::new(rmt::TxRmtDriver::new(peripherals.rmt.channel0, pin_from_envar!(pins, "LED_PIN_NUM"), &rmtconfig)?);
+    show_startup_phase(StartupPhase::Nvs, LED_DEFAULT_BRIGHTNESS, &mut npx, &led_off_sense_pin)?;
+
+    let mut config = ControllerConfig::load(&mut nvs_settings)?;
+    let mut led_brightness = config.led_brightness;
+    let mut last_applied = match nvs_get_string(&nvs_settings, "last_applied")? {
+        Some(appliedjson) => serde_json::from_str::<UnitSetting>(&appliedjson).unwrap_or_else(|e| {
+            info!("Could not parse the last applied setting, not restoring it: {}", e);
+            UnitSetting::new()
+        }),
+        None => UnitSetting::new(),
+    };
+    // a change to it that's still to go to NVS
+    let mut unsaved_applied: Option<String> = None;
+    // sent once the heat pump is connected, unless something else has been asked for by then
+    let mut restore_setting = if config.restore_on_boot && last_applied.to_setting().requires_packet() {
+        Some(last_applied.to_setting())
+    } else {
+        None
+    };
+
+    // count boots since the flash was erased, and note why this one happened
+    let boot_count = nvs_settings.get_u32("boot_count")?.unwrap_or(0) + 1;
+    nvs_settings.set_u32("boot_count", boot_count)?;
+    let reset_reason = format!("{:?}", reset::ResetReason::get());
+    info!("boot number {}, reset reason: {}", boot_count, reset_reason);
+
+    // the wifi settings only take effect at boot, so they are read once here rather than in the loop
+    let wifi_ap_channel = nvs_settings.get_u8("wifi_channel")?.unwrap_or(WIFI_CHANNEL.parse().unwrap());
+    let wifi_country = nvs_get_string(&nvs_settings, "wifi_country")?;
+    let wifi_networks = load_wifi_networks(&nvs_settings)?;
+    // only for the one boot after a reprovision request, so a power cut later doesn't leave it stuck as an AP
+    let wifi_force_ap = nvs_settings.get_u8("wifi_force_ap")?.unwrap_or(0) == 1;
+    if wifi_force_ap {
+        nvs_settings.remove("wifi_force_ap")?;
+    }
+    let static_ip = load_static_ip(&nvs_settings)?;
+    let ntp_server = nvs_get_string(&nvs_settings, "ntp_server")?.unwrap_or(clock::DEFAULT_NTP_SERVER.to_string());
+    let wifi_radio = match nvs_get_string(&nvs_settings, "wifi_radio")? {
+        Some(radiojson) => match serde_json::from_str::<WifiRadioConfig>(&radiojson) {
+            Ok(config) => config,
+            Err(e) => {
+                info!("Could not parse stored wifi radio config, using defaults: {}", e);
+                WifiRadioConfig::new()
+            }
+        },
+        None => WifiRadioConfig::new(),
+    };
+    // sniffing is decided at boot too, since it means never sending anything to the heat pump
+    let sniffer_config = match nvs_get_string(&nvs_settings, "sniffer")? {
+        Some(snifferjson) => match serde_json::from_str::<SnifferConfig>(&snifferjson) {
+            Ok(config) => config,
+            Err(e) => {
+                info!("Could not parse stored sniffer config, not sniffing: {}", e);
+                SnifferConfig::new()
+            }
+        },
+        None => SnifferConfig::new(),
+    };
+    let sniffing = sniffer_config.enabled;
+    let sniffer_log = match nvs_settings.blob_len("sniff_log")? {
+        Some(len) if sniffer_config.flash_log => {
+            let mut buf = vec![0; len];
+            nvs_settings.get_raw("sniff_log", &mut buf)?;
+            Some(String::from_utf8_lossy(&buf).into_owned())
+        }
+        _ => None,
+    };
+
+    show_startup_phase(StartupPhase::Uart, led_brightness, &mut npx, &led_off_sense_pin)?;
+
+    // start by setting up uart
+    let uart_config = uart::config::Config::default()
+        .baudrate(Hertz(2400))
+        .data_bits(uart::config::DataBits::DataBits8)
+        .parity_even()
+        .stop_bits(uart::config::StopBits::STOP1)
+        .flow_control(uart::config::FlowControl::None);
+
+    let uart: uart::UartDriver = uart::UartDriver::new(
+        peripherals.uart1,
+        pin_from_envar!(pins, "TX_PIN_NUM"),
+        pin_from_envar!(pins, "RX_PIN_NUM"),
+        Option::<AnyIOPin>::None,
+        Option::<AnyIOPin>::None,
+        &uart_config
+    ).unwrap();
+
+    // the network can take a minute to come up on a bad one, so the heat pump is connected to and polled in its own
+    // thread meanwhile.  The main loop takes the uart back once everything else is set up
+    let state = Arc::new(Mutex::new(HeatPumpStatus::new()));
+    {
+        let mut realstate = state.lock().unwrap();
+        realstate.sniffer.config = sniffer_config;
+        if let Some(log) = &sniffer_log {
+            realstate.sniffer.load_previous_log(log);
+        }
+    }
+    if sniffing {
+        info!("Sniffing the heat pump bus, nothing will be sent to it");
+    }
+    let early_uart_stop = Arc::new(AtomicBool::new(false));
+    let early_uart = {
+        let state = state.clone();
+        let stop = early_uart_stop.clone();
+        std::thread::Builder::new()
+            .stack_size(EARLY_UART_STACK_SIZE)
+            .spawn(move || early_uart_loop(uart, &state, &stop, sniffing, boot_instant))?
+    };
+
+    // count pulses from an S0 energy meter output in hardware.  S0 outputs are open collector, so the pin needs
+    // an external pull-up
+    #[cfg(feature="pulsemeter")]
+    let mut pulse_counter = {
+        let mut counter = pcnt::PcntDriver::new(
+            peripherals.pcnt0,
+            Some(pin_from_envar!(pins, "METER_PIN_NUM")),
+            Option::<AnyIOPin>::None,
+            Option::<AnyIOPin>::None,
+            Option::<AnyIOPin>::None,
+        )?;
+        counter.channel_config(pcnt::PcntChannel::Channel0, pcnt::PinIndex::Pin0, pcnt::PinIndex::Pin1, 
+                               &pcnt::PcntChannelConfig {
+            lctrl_mode: pcnt::PcntControlMode::Keep,
+            hctrl_mode: pcnt::PcntControlMode::Keep,
+            pos_mode: pcnt::PcntCountMode::Increment,
+            neg_mode: pcnt::PcntCountMode::Hold,
+            counter_h_lim: i16::MAX,
+            counter_l_lim: 0,
+        })?;
+        // the glitch filter debounces, ignoring anything shorter than 1023 APB clocks (~13 us)
+        counter.set_filter_value(1023)?;
+        counter.filter_enable()?;
+        counter.counter_pause()?;
+        counter.counter_clear()?;
+        counter.counter_resume()?;
+        counter
+    };
+
+
+    // start up the network (wifi, or wired with the ethernet feature) then try to configure the server
+    #[cfg(not(feature="ethernet"))]
+    let wifi_result = setup_wifi(peripherals.modem, nvs_default_partition.clone(), wifi_ap_channel, wifi_country.as_deref(),
+                                 &wifi_networks, &static_ip, wifi_force_ap, &mut |phase| show_startup_phase(phase, led_brightness, &mut npx, &led_off_sense_pin));
+    #[cfg(feature="ethernet")]
+    let wifi_result = ethernet::setup_ethernet(
+        peripherals.spi2,
+        pin_from_envar!(pins, "ETH_SCLK_PIN_NUM"),
+        pin_from_envar!(pins, "ETH_MOSI_PIN_NUM"),
+        pin_from_envar!(pins, "ETH_MISO_PIN_NUM"),
+        pin_from_envar!(pins, "ETH_CS_PIN_NUM"),
+        pin_from_envar!(pins, "ETH_INT_PIN_NUM"),
+        pin_from_envar!(pins, "ETH_RST_PIN_NUM"),
+        &mut |phase| show_startup_phase(phase, led_brightness, &mut npx, &led_off_sense_pin));
+    // with ethernet this is the wired interface, which has no reconnect handling to do below
+    #[cfg_attr(feature="ethernet", allow(unused_mut))]
+    let (mut wifi, wifimac) = match wifi_result {
+        Ok(res) => { res },
+        Err(e) => {
+            set_led(led_brightness, 0, 0, &mut npx, &led_off_sense_pin)?;
+            info!("wifi did not successfully start due to {}. Waiting {} secs and then restarting!", 
+                  e, WIFI_DISCONNECTED_RESET_TIME.as_secs_f32());
+            std::thread::sleep(WIFI_DISCONNECTED_RESET_TIME);
+            reset::restart();
+            return Err(e);
+        }
+    };
+    #[cfg(not(feature="ethernet"))]
+    if let Err(e) = wifi_radio.apply() {
+        info!("Could not apply wifi radio config {:?}: {}", wifi_radio, e);
+    }
+    let macstr = match wifimac {
+        Some (mac) => Some(format!("{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}", mac[0], mac[1], mac[2], mac[3], mac[4], mac[5])),
+        None => None
+    };
+    show_startup_phase(StartupPhase::Http, led_brightness, &mut npx, &led_off_sense_pin)?;
+
+    // start getting wall-clock time.  This syncs in the background so nothing waits for it
+    let mut sntp_conf = sntp::SntpConf::default();
+    sntp_conf.servers[0] = ntp_server.as_str();
+    let sntp = sntp::EspSntp::new(&sntp_conf)?;
+    let mut time_synced = false;
+
+    let syslog_hostname = macstr.as_ref().map_or("heatpump-controller".to_string(), 
+                                                  |s| ["heatpump-controller-", s.as_str()].concat());
+    let syslog_server = nvs_get_string(&nvs_settings, "syslog_server")?;
+    if let Err(e) = syslog::LOGGER.set_server(syslog_server.as_deref(), &syslog_hostname) {
+        info!("Could not start logging to syslog server {:?}: {}", syslog_server, e);
+    }
+
+    // uploaded web assets are served in place of the built-in ones.  Without them the built-in ones do fine
+    if let Err(e) = assets::mount() {
+        info!("Could not mount the assets partition, only serving the built-in assets: {}", e);
+    }
+    let server_configuration = http::server::Configuration {
+        stack_size: HTTP_SERVER_STACK_SIZE,
+        http_port: HTTP_PORT,
+        uri_match_wildcard: true,  // for /assets/*
+        ..Default::default()
+    };
+    {
+        let mut realstate = state.lock().unwrap();
+        realstate.wifi_ap_channel = wifi_ap_channel;
+        realstate.boot_count = boot_count;
+        realstate.reset_reason = reset_reason;
+        realstate.wifi_country = wifi_country;
+        realstate.wifi_networks = wifi_networks;
+        realstate.wifi_radio = wifi_radio;
+        realstate.static_ip = static_ip.clone();
+        realstate.ntp_server = ntp_server.clone();
+        realstate.syslog_server = syslog_server;
+    }
+    let mut server = http::server::EspHttpServer::new(&server_configuration)?;
+    setup_handlers(&mut server, state.clone(), boot_instant, macstr.clone(), nvs_default_partition.clone())?;
+
+    // check what's embedded in the firmware, and note any stored settings that can't be read back while loading them
+    let mut integrity = Integrity::new();
+    if let Some(limitsjson) = nvs_get_string(&nvs_settings, "setpoint_limits")? {
+        match serde_json::from_str::<SetpointLimits>(&limitsjson) {
+            Ok(limits) => { state.lock().unwrap().setpoint_limits = limits; }
+            Err(e) => {
+                integrity.record_config_error("setpoint_limits", &e);
+                info!("Could not parse stored setpoint limits, using defaults: {}", e);
+            }
+        }
+    }
+    if let Some(thermostatjson) = nvs_get_string(&nvs_settings, "thermostat")? {
+        match serde_json::from_str::<ThermostatConfig>(&thermostatjson) {
+            Ok(config) => { state.lock().unwrap().thermostat.config = config; }
+            Err(e) => {
+                integrity.record_config_error("thermostat", &e);
+                info!("Could not parse stored thermostat config, using defaults: {}", e);
+            }
+        }
+    }
+    if let Some(schedulejson) = nvs_get_string(&nvs_settings, "schedule")? {
+        match serde_json::from_str::<ScheduleConfig>(&schedulejson) {
+            Ok(config) => { state.lock().unwrap().schedule.config = config; }
+            Err(e) => {
+                integrity.record_config_error("schedule", &e);
+                info!("Could not parse stored schedule, leaving it off: {}", e);
+            }
+        }
+    }
+    if let Some(smoothingjson) = nvs_get_string(&nvs_settings, "temp_smoothing")? {
+        match serde_json::from_str::<TemperatureSmoothing>(&smoothingjson) {
+            Ok(smoothing) => { state.lock().unwrap().room_temperature_smoothing = smoothing; }
+            Err(e) => {
+                integrity.record_config_error("temp_smoothing", &e);
+                info!("Could not parse stored temperature smoothing, not smoothing: {}", e);
+            }
+        }
+    }
+    if let Some(pidjson) = nvs_get_string(&nvs_settings, "pid")? {
+        match serde_json::from_str::<PidConfig>(&pidjson) {
+            Ok(config) => { state.lock().unwrap().pid.config = config; }
+            Err(e) => {
+                integrity.record_config_error("pid", &e);
+                info!("Could not parse stored PID config, using defaults: {}", e);
+            }
+        }
+    }
+    state.lock().unwrap().debug_token = nvs_get_string(&nvs_settings, "debug_token")?;
+    if let Some(pushjson) = nvs_get_string(&nvs_settings, "cloud_push")? {
+        match serde_json::from_str::<CloudPushConfig>(&pushjson) {
+            Ok(mut config) => {
+                config.token = nvs_get_string(&nvs_settings, "cloud_token")?;
+                state.lock().unwrap().cloud_push.config = config;
+            }
+            Err(e) => {
+                integrity.record_config_error("cloud_push", &e);
+                info!("Could not parse stored cloud push config, not pushing: {}", e);
+            }
+        }
+    }
+    if let Some(coordinatorjson) = nvs_get_string(&nvs_settings, "coordinator")? {
+        match serde_json::from_str::<CoordinatorConfig>(&coordinatorjson) {
+            Ok(config) => { state.lock().unwrap().coordinator.config = config; }
+            Err(e) => {
+                integrity.record_config_error("coordinator", &e);
+                info!("Could not parse stored coordinator config, not coordinating: {}", e);
+            }
+        }
+    }
+    if let Some(followjson) = nvs_get_string(&nvs_settings, "follow")? {
+        match serde_json::from_str::<FollowConfig>(&followjson) {
+            Ok(config) => { state.lock().unwrap().follower.set_config(config); }
+            Err(e) => {
+                integrity.record_config_error("follow", &e);
+                info!("Could not parse stored follow config, not following: {}", e);
+            }
+        }
+    }
+    if let Some(watchdogjson) = nvs_get_string(&nvs_settings, "bus_watchdog")? {
+        match serde_json::from_str::<BusWatchdogConfig>(&watchdogjson) {
+            Ok(config) => { state.lock().unwrap().bus_watchdog = config; }
+            Err(e) => {
+                integrity.record_config_error("bus_watchdog", &e);
+                info!("Could not parse stored bus watchdog config, using defaults: {}", e);
+            }
+        }
+    }
+    if let Some(alertsjson) = nvs_get_string(&nvs_settings, "alerts")? {
+        match serde_json::from_str::<AlertConfig>(&alertsjson) {
+            Ok(mut config) => {
+                config.token = nvs_get_string(&nvs_settings, "alert_token")?;
+                config.user_key = nvs_get_string(&nvs_settings, "alert_user")?;
+                state.lock().unwrap().alerts.config = config;
+            }
+            Err(e) => {
+                integrity.record_config_error("alerts", &e);
+                info!("Could not parse stored alert config, not alerting: {}", e);
+            }
+        }
+    }
+    if let Some(adherencejson) = nvs_get_string(&nvs_settings, "adherence_cfg")? {
+        match serde_json::from_str::<AdherenceConfig>(&adherencejson) {
+            Ok(config) => { state.lock().unwrap().adherence.config = config; }
+            Err(e) => {
+                integrity.record_config_error("adherence_cfg", &e);
+                info!("Could not parse stored adherence config, using defaults: {}", e);
+            }
+        }
+    }
+    if let Some(ratesjson) = nvs_get_string(&nvs_settings, "rate_model")? {
+        match serde_json::from_str::<BTreeMap<String, RateEstimate>>(&ratesjson) {
+            Ok(rates) => { state.lock().unwrap().rate_model.rates = rates; }
+            Err(e) => {
+                integrity.record_config_error("rate_model", &e);
+                info!("Could not parse stored rate model, learning afresh: {}", e);
+            }
+        }
+    }
+    if let Some(daysjson) = nvs_get_string(&nvs_settings, "adherence")? {
+        match serde_json::from_str::<Vec<DayAdherence>>(&daysjson) {
+            Ok(days) => { state.lock().unwrap().adherence.restore(days.into()); }
+            Err(e) => {
+                integrity.record_config_error("adherence", &e);
+                info!("Could not parse stored adherence history, starting afresh: {}", e);
+            }
+        }
+    }
+    if let Some(meterjson) = nvs_get_string(&nvs_settings, "energy_meter")? {
+        match serde_json::from_str::<EnergyMeterConfig>(&meterjson) {
+            Ok(config) => { state.lock().unwrap().energy_meter.set_config(config); }
+            Err(e) => {
+                integrity.record_config_error("energy_meter", &e);
+                info!("Could not parse stored energy meter config, using defaults: {}", e);
+            }
+        }
+    }
+    if let Some(pulses) = nvs_settings.get_u64("meter_pulses")? {
+        state.lock().unwrap().energy_meter.restore(pulses);
+    }
+    if let Some(capabilitiesjson) = nvs_get_string(&nvs_settings, "capabilities")? {
+        match serde_json::from_str::<Capabilities>(&capabilitiesjson) {
+            Ok(capabilities) => { state.lock().unwrap().capabilities = capabilities; }
+            Err(e) => {
+                integrity.record_config_error("capabilities", &e);
+                info!("Could not parse stored capabilities, assuming everything is supported: {}", e);
+            }
+        }
+    }
+    if let Some(languagejson) = nvs_get_string(&nvs_settings, "ui_language")? {
+        match serde_json::from_str::<Language>(&languagejson) {
+            Ok(language) => { state.lock().unwrap().ui_language = language; }
+            Err(e) => {
+                integrity.record_config_error("ui_language", &e);
+                info!("Could not parse stored UI language, using English: {}", e);
+            }
+        }
+    }
+    if let Some(windowjson) = nvs_get_string(&nvs_settings, "reboot_window")? {
+        match serde_json::from_str::<RebootWindow>(&windowjson) {
+            Ok(window) => { state.lock().unwrap().reboot_window = window; }
+            Err(e) => {
+                integrity.record_config_error("reboot_window", &e);
+                info!("Could not parse stored reboot window, rebooting any time: {}", e);
+            }
+        }
+    }
+    if let Some(otajson) = nvs_get_string(&nvs_settings, "ota")? {
+        match serde_json::from_str::<OtaConfig>(&otajson) {
+            Ok(config) => { state.lock().unwrap().ota.lock().unwrap().config = config; }
+            Err(e) => {
+                integrity.record_config_error("ota", &e);
+                info!("Could not parse stored OTA config, not checking for updates: {}", e);
+            }
+        }
+    }
+    if let Some(presetsjson) = nvs_get_string(&nvs_settings, "presets")? {
+        match serde_json::from_str::<HashMap<String, HeatPumpSetting>>(&presetsjson) {
+            Ok(presets) => { state.lock().unwrap().presets = presets; }
+            Err(e) => {
+                integrity.record_config_error("presets", &e);
+                info!("Could not parse stored presets, starting with none: {}", e);
+            }
+        }
+    }
+    if let Some(mdnsjson) = nvs_get_string(&nvs_settings, "mdns")? {
+        match serde_json::from_str::<MdnsConfig>(&mdnsjson) {
+            Ok(config) => { state.lock().unwrap().mdns = config; }
+            Err(e) => {
+                integrity.record_config_error("mdns", &e);
+                info!("Could not parse stored mdns config, using defaults: {}", e);
+            }
+        }
+    }
+    if let Some(watchdogjson) = nvs_get_string(&nvs_settings, "watchdog")? {
+        match serde_json::from_str::<WatchdogConfig>(&watchdogjson) {
+            Ok(config) => { state.lock().unwrap().watchdog = config; }
+            Err(e) => {
+                integrity.record_config_error("watchdog", &e);
+                info!("Could not parse stored watchdog config, using defaults: {}", e);
+            }
+        }
+    }
+    let crash_log = state.lock().unwrap().crash_log.clone();
+    if let Some(crashjson) = nvs_get_string(&nvs_settings, "crash_log")? {
+        match CrashLog::load(&crashjson) {
+            Ok(log) => { *crash_log.lock().unwrap() = log; }
+            Err(e) => {
+                integrity.record_config_error("crash_log", &e);
+                info!("Could not parse stored crash log, starting a new one: {}", e);
+            }
+        }
+    }
+    // the loop runs in this task, so the trip that caused a watchdog panic was in it
+    let loop_task = crash_log::current_task_name();
+    if crash_log::tripped_before_reset() || matches!(reset::ResetReason::get(), reset::ResetReason::TaskWatchdog) {
+        crash_log.lock().unwrap().record(boot_count - 1, None, &loop_task, "panic reset");
+    }
+    if !integrity.ok() {
+        info!("Startup integrity check found problems: {:?}", integrity);
+    }
+    state.lock().unwrap().integrity = Some(integrity);
+
+    // now start mdns
+    let mdnso = match &macstr {
+        Some (s) => {
+            let mut mdns = mdns::EspMdns::take()?;
+
+            let mdns_config = state.lock().unwrap().mdns.clone();
+            mdns.set_hostname(["heatpump-controller-", s.as_str()].concat())?;
+            match &mdns_config.instance_name {
+                Some(name) => { mdns.set_instance_name(name)?; }
+                None => { mdns.set_instance_name(["Mitsubishi heatpump controller w/mac ", s.as_str()].concat())?; }
+            }
+
+            // the location lets peers (see /peers.json) show which controller is which without asking each one, and
+            // the rest lets discovery tools describe it without fetching status.json.  These are as of boot
+            let location = config.location.clone().unwrap_or_default();
+            let capabilities = state.lock().unwrap().capabilities.clone();
+            let port = HTTP_PORT.to_string();
+            let txt = [
+                ("location", location.as_str()),
+                ("version", env!("CARGO_PKG_VERSION")),
+                ("port", port.as_str()),
+                ("vane", if capabilities.vane { "1" } else { "0" }),
+                ("widevane", if capabilities.widevane { "1" } else { "0" }),
+            ];
+            mdns.add_service(None, MDNS_SERVICE_TYPE, "_tcp", HTTP_PORT, &txt)?;
+
+            // e.g. _http._tcp so generic browsers list the controller too.  A bad one shouldn't stop the rest
+            for service in mdns_config.extra_services.iter() {
+                if let Err(e) = mdns.add_service(None, &service.service_type, &service.proto, HTTP_PORT, &[]) {
+                    info!("Could not advertise mdns service {}.{}: {}", service.service_type, service.proto, e);
+                }
+            }
+
+            Some(mdns)
+        }
+        None => {
+            info!("No IP address, not starting mdns");
+            None
+        }
+    };
+
+    // and SSDP, for the UPnP side of the world
+    let mut ssdpo = match &macstr {
+        Some(s) => match ssdp::Ssdp::new(s) {
+            Ok(ssdp) => Some(ssdp),
+            Err(e) => {
+                info!("Could not start SSDP responder: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+    // and a plain UDP broadcast responder for networks that block multicast altogether
+    let discovery = match &macstr {
+        Some(s) => match udp_discovery::UdpDiscovery::new(s) {
+            Ok(d) => Some(d),
+            Err(e) => {
+                info!("Could not start UDP discovery responder: {}", e);
+                None
+            }
+        },
+        None => None,
+    };
+
+
+
+    // the event stream for browsers that want pushed status updates
+    let mut sse_server = match sse::SseServer::new() {
+        Ok(s) => Some(s),
+        Err(e) => {
+            info!("Could not start the event stream server: {}", e);
+            None
+        }
+    };
+    let mut last_sse_check = Instant::now();
+
+    // set up the TWDT to catch any hangs in the main loop.  Only *after* startup, which can take longer
+    let watchdog_config = state.lock().unwrap().watchdog.clone();
     let twdt_config = watchdog::TWDTConfig {
-        duration: TWDT_TIME,
-        panic_on_trigger: true,
+        duration: Duration::from_secs(watchdog_config.timeout_secs),
+        panic_on_trigger: watchdog_config.panic,
         //subscribed_idle_tasks: enum_set!(hal::cpu::Core::Core0)
         subscribed_idle_tasks: EnumSet::new()  // do not subscribe the idle task
     };
-    let mut twdt_driver = watchdog::TWDTDriver::new(
-        peripherals.twdt,
-        &twdt_config,
+    let mut twdt_driver = watchdog::TWDTDriver::new(
+        peripherals.twdt,
+        &twdt_config,
+    )?;
+    let mut watchdog = twdt_driver.watch_current_task()?;
+    if !watchdog_config.panic {
+        let supervisor_log = crash_log.clone();
+        let supervisor_nvs = nvs::EspNvs::new(nvs_default_partition.clone(), "settings", true)?;
+        let task = loop_task.clone();
+        std::thread::Builder::new()
+            .stack_size(WATCHDOG_SUPERVISOR_STACK_SIZE)
+            .spawn(move || crash_log::supervise(supervisor_log, supervisor_nvs, task, boot_count, boot_instant))?;
+    }
+    let updater = state.lock().unwrap().ota.clone();
+    std::thread::Builder::new()
+        .stack_size(OTA_TASK_STACK_SIZE)
+        .spawn(move || {
+            if let Err(e) = ota_update::run(updater) {
+                info!("OTA update task stopped: {}", e);
+            }
+        })?;
+    let outbound_state = state.clone();
+    let outbound_macstr = macstr.clone();
+    std::thread::Builder::new()
+        .stack_size(OUTBOUND_TASK_STACK_SIZE)
+        .spawn(move || outbound_task(outbound_state, boot_instant, outbound_macstr))?;
+    let mut seen_twdt_trips = crash_log::twdt_trips();
+    let mut watchdog_recover_step = 0;
+    let mut watchdog_recovered: Option<Instant> = None;
+
+    info!("Setup complete!");
+    show_startup_phase(StartupPhase::HeatPumpConnect, led_brightness, &mut npx, &led_off_sense_pin)?;
+
+    // from here on the LED has its own task, and the loop just tells it what to show
+    let (led_sender, led_receiver) = mpsc::channel();
+    std::thread::Builder::new()
+        .stack_size(LED_TASK_STACK_SIZE)
+        .spawn(move || {
+            if let Err(e) = led_task(npx, led_off_sense_pin, led_receiver, boot_instant) {
+                info!("LED task stopped: {}", e);
+            }
+        })?;
+    let mut last_led_pattern = None;
+
+    // and settings are saved by their own task, so a flash write doesn't hold up the bus or the handlers
+    let nvs_writer = NvsWriter::spawn(nvs::EspNvs::new(nvs_default_partition.clone(), "settings", true)?,
+                                      NVS_TASK_STACK_SIZE)?;
+
+    let mut boot_button_pin = PinDriver::input(pin_from_envar!(pins, "BOOT_BUTTON_PIN_NUM"))?;
+    boot_button_pin.set_pull(Pull::Up)?;
+    let button_nvs = nvs::EspNvs::new(nvs_default_partition.clone(), "settings", true)?;
+    let button_leds = led_sender.clone();
+    std::thread::Builder::new()
+        .stack_size(BUTTON_TASK_STACK_SIZE)
+        .spawn(move || {
+            if let Err(e) = button_task(boot_button_pin, button_nvs, button_leds) {
+                info!("BOOT button task stopped: {}", e);
+            }
+        })?;
+    #[cfg(feature="button")]
+    {
+        let mut control_button_pin = PinDriver::input(pin_from_envar!(pins, "CONTROL_BUTTON_PIN_NUM"))?;
+        control_button_pin.set_pull(Pull::Up)?;
+        let button_state = state.clone();
+        std::thread::Builder::new()
+            .stack_size(BUTTON_TASK_STACK_SIZE)
+            .spawn(move || {
+                if let Err(e) = control_button_task(control_button_pin, button_state) {
+                    info!("control button task stopped: {}", e);
+                }
+            })?;
+    }
+
+    early_uart_stop.store(true, Ordering::Relaxed);
+    let (uart, mut bus_health, mut last_status_request) = match early_uart.join() {
+        Ok(res) => res?,
+        Err(_) => anyhow::bail!("early heat pump thread panicked"),
+    };
+    let mut last_peer_browse: Option<Instant> = None;
+    let mut settle_until: Option<Instant> = None;
+    let mut wifi_reconnect = WifiReconnect::new();
+    #[cfg(not(feature="ethernet"))]
+    let mut rssi_trend = RssiTrend::new();
+    let bus_trace = state.lock().unwrap().bus_trace.clone();
+    let mut wifi_connected_since = Some(Instant::now());
+    #[cfg(feature="pulsemeter")]
+    let mut last_pulse_count: i16 = 0;
+    let mut mode_settle_secs = config.mode_settle_secs;
+    let mut reboot_period_mins = config.reboot_period_mins;
+    let mut nvs_refreshed: Option<Instant> = None;
+    let mut sniff_flushed = Instant::now();
+
+    // serve and loop forever...  The heat pump is dealt with first each time around, so its response windows aren't
+    // missed waiting on NVS reads or the LED
+    loop {
+        let loopstart = Instant::now();
+        watchdog.feed()?;
+        crash_log::heartbeat();
+
+        let settling = settle_until.map_or(false, |t| Instant::now() < t);
+
+        if !time_synced && sntp.get_sync_status() == sntp::SyncStatus::Completed {
+            time_synced = true;
+            info!("Time synchronized from {}, it is now {:?}", ntp_server, clock::iso8601_now());
+        }
+
+        let (debug_bytes, bus_paused, connected, data_to_send, error_active, probe_type) = { 
+            let mut realstate = state.lock().unwrap();
+            realstate.settling = settling;
+            if realstate.bus_paused_until.map_or(false, |t| Instant::now() >= t) {
+                // whatever was plugged in meanwhile may have changed things, so start again from the handshake
+                info!("Bus pause over, reconnecting to the heat pump");
+                realstate.bus_paused_until = None;
+                realstate.connected = false;
+            }
+            // hold off while sets are still coming in quickly, so they go out as one
+            let debouncing = realstate.set_at.map_or(false, |t| t.elapsed() < Duration::from_millis(config.set_debounce_ms));
+            (realstate.debug_packet.as_ref().filter(|p| p.response.is_none()).map(|p| p.bytes.clone()),
+             realstate.bus_paused_until.is_some(), realstate.connected, realstate.desired_settings.is_some() && !debouncing,
+             realstate.error_data.is_some(), realstate.status_probe.next_type())
+         };  
+        // the set_seq of the setting that went out this pass, if one did
+        let mut sent_seq = None;
+
+
+        // This is the business part of the loop
+        
+        if sniffing {
+            sniff_uart(&uart, &state)?;
+        } else if bus_paused {
+            // leave the bus alone for whatever else is plugged into it
+        } else if let Some(bytes) = debug_bytes {
+            // a raw packet is sent whether or not we're connected, since it might be an experiment with connecting
+            info!("Writing raw packet from /debug/packet.json: {:?}", bytes);
+            uart.discard_input()?;
+            uart.write(&bytes)?;
+            bus_trace.lock().unwrap().record_tx(&bytes);
+            let response = read_raw(&uart, RESPONSE_DELAY, &bus_trace)?;
+            let responsejson = if response.is_empty() {
+                serde_json::Value::Null
+            } else {
+                let parsed = Packet::from_bytes(&response);
+                json!({
+                    "hex": response.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "),
+                    "decode": bus_trace::decode(&response),
+                    "error": parsed.as_ref().err().map(|e| e.to_string()),
+                    "packet_type": parsed.as_ref().ok().map(|p| p.packet_type),
+                    "data": parsed.as_ref().ok().map(|p| p.data.clone()),
+                })
+            };
+            if let Some(pending) = state.lock().unwrap().debug_packet.as_mut() {
+                pending.response = Some(json!({
+                    "sent": bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "),
+                    "response": responsejson,
+                }));
+            }
+        } else if connected {
+            if data_to_send {
+                // what goes out is copied, so the state isn't locked while the heat pump takes its time answering.  A
+                // set.json that comes in meanwhile is merged into desired_settings and goes out after this one
+                let (sending, seq, field_seq) = {
+                    let realstate = state.lock().unwrap();
+                    (realstate.desired_settings.clone().unwrap(), realstate.set_seq, realstate.field_set_seq)
+                };
+                if sending.requires_packet() {
+                    let packet_to_send = sending.to_packet();
+                    let changes_mode = sending.mode.is_some();
+
+                    let mut txbuf = [0u8; PACKET_SIZE_MAX];
+                    let txbytes = packet_to_send.to_bytes(&mut txbuf);
+                    info!("Writing to heat pump: {:?}", txbytes);
+                    uart.write(txbytes)?;
+                    bus_trace.lock().unwrap().record_tx(txbytes);
+
+                    // now check that we got a packet back
+                    let response = read_packet(&uart, RESPONSE_DELAY, &mut bus_health, &bus_trace)?;
+                    let mut realstate = state.lock().unwrap();
+                    match response {
+                        Some(p) => { 
+                            if p.packet_type == 0x61 {
+                                info!("Got expected response to setting change request: {:?}", p);
+                                let ack = AckRecord::new(&p, &packet_to_send, boot_instant);
+                                if !ack.nonzero_bytes.is_empty() {
+                                    info!("Set response has non-zero payload bytes {:?} for a change to {:?}", 
+                                          ack.nonzero_bytes, ack.sent_flags);
+                                }
+                                realstate.last_ack = Some(ack);
+                                realstate.acked_set_seq = seq;
+                                realstate.acked_field_seq = field_seq;
+                                realstate.acked_at = Some(Instant::now());
+                                sent_seq = Some(seq);
+                                let previous = serde_json::to_string(&last_applied)?;
+                                last_applied.record(&sending);
+                                let appliedjson = serde_json::to_string(&last_applied)?;
+                                if appliedjson != previous {
+                                    // written once the bus has been dealt with, rather than holding up the state
+                                    unsaved_applied = Some(appliedjson);
+                                }
+                                if changes_mode {
+                                    settle_until = Some(Instant::now() + Duration::from_secs(mode_settle_secs as u64));
+                                }
+                            } else {
+                                // leave the setting queued so it is sent again
+                                info!("Got unexpected packet type in response to setting change request: {:?}", p);
+                                bus_health.errors.unexpected_packet_types += 1;
+                            }
+                        }
+                        None => {
+                            info!("No response to setting change request, assuming disconnected");
+                            bus_health.record_timeout();
+                            realstate.connected = false;
+                        }
+                    };
+                } else {
+                    sent_seq = Some(seq);
+                }
+
+            } else if last_status_request.elapsed() > Duration::from_millis(config.status_poll_ms) {
+                if request_status(&uart, &state, &mut bus_health, &bus_trace, settling, boot_instant)? {
+                    last_status_request = Instant::now();
+                }
+            } else if let Some(info_type) = probe_type {
+                // types the unit doesn't know may well get no answer, so unlike status that isn't a disconnect
+                uart.discard_input()?;
+                let response = request_info(&uart, info_type, &mut bus_health, &bus_trace)?;
+                info!("Probed info type 0x{:02x}: {:?}", info_type, response);
+                state.lock().unwrap().status_probe.record(info_type, response.as_ref());
+            } 
+
+
+        } else {
+            try_connect(&uart, &state, &mut bus_health, &bus_trace)?;
+        }
+
+
+        // now the things that can wait until the heat pump has been dealt with
+        let crash_json = crash_log.lock().unwrap().take_dirty_json();
+        if let Some(json) = crash_json {
+            nvs_writer.set_str("crash_log", json);
+        }
+        if let Some(json) = unsaved_applied.take() {
+            nvs_writer.set_str("last_applied", json);
+        }
+        if sniffing && sniff_flushed.elapsed() >= SNIFF_FLASH_INTERVAL {
+            sniff_flushed = Instant::now();
+            let log = state.lock().unwrap().sniffer.take_flash_log();
+            if let Some(log) = log {
+                nvs_writer.write("sniff_log", move |nvs| Ok(nvs.set_raw("sniff_log", log.as_bytes()).map(|_| ())?));
+            }
+        }
+        if nvs_refreshed.map_or(true, |t| t.elapsed() >= NVS_REFRESH_INTERVAL) {
+            nvs_refreshed = Some(Instant::now());
+            // so what was last saved is read back, not what was there before it
+            nvs_writer.flush();
+            config = ControllerConfig::load(&mut nvs_settings)?;
+            led_brightness = config.led_brightness;
+            mode_settle_secs = config.mode_settle_secs;
+            reboot_period_mins = config.reboot_period_mins;
+
+            let mut realstate = state.lock().unwrap();
+            realstate.controller_led_brightness = led_brightness;
+            realstate.controller_location = config.location.clone();
+            realstate.mode_settle_secs = mode_settle_secs;
+            realstate.reboot_period_mins = reboot_period_mins;
+            realstate.config = config.clone();
+        }
+
+        // with the recovery AP up is_connected would also want the AP side connected, so just ask about the station
+        #[cfg(not(feature="ethernet"))]
+        let wifi_connected = if wifi_reconnect.recovery_ap {
+            wifi.wifi().driver().is_sta_connected()?
+        } else {
+            wifi.is_connected()?
+        };
+        // a cable doesn't need reconnecting: the driver picks the link back up on its own and DHCP renews
+        #[cfg(feature="ethernet")]
+        let wifi_connected = wifi.is_connected()?;
+        if wifi_connected {
+            wifi_connected_since.get_or_insert_with(Instant::now);
+        } else {
+            wifi_connected_since = None;
+        }
+
+        let led_pattern = if !wifi_connected && !cfg!(feature="ethernet") {
+            // blinking red every half-second while the wifi is disconnected
+            LedPattern::Blink { on: (led_brightness, 0, 0), off: (0, 0, 0), period_ms: 500 }
+        } else if connected && error_active {
+            // blinking yellow/red once a second when the heat pump is reporting an error
+            LedPattern::Blink { on: (led_brightness, led_brightness, 0), off: (led_brightness, 0, 0), period_ms: 1000 }
+        } else if connected {
+            // green for connected
+            LedPattern::Solid(0, led_brightness, 0)
+        } else {
+            // magenta for disconnected
+            LedPattern::Solid(led_brightness, 0, led_brightness)
+        };
+        if last_led_pattern != Some(led_pattern) {
+            last_led_pattern = Some(led_pattern);
+            // a dead LED task has already logged why, and shouldn't take the heat pump down with it
+            let _ = led_sender.send(LedMessage::Pattern(led_pattern));
+        }
+        #[cfg(not(feature="ethernet"))]
+        {
+            let mut realstate = state.lock().unwrap();
+            realstate.wifi_link = WifiLink::get(wifi_connected_since);
+            // a DHCP lease can change on a reconnect, so this is kept up to date rather than read once
+            realstate.network = match realstate.wifi_link {
+                Some(_) => NetworkInfo::get(wifi.wifi().sta_netif(), static_ip.enabled),
+                None => NetworkInfo::get(wifi.wifi().ap_netif(), false),
+            };
+            if let Some(link) = &realstate.wifi_link {
+                rssi_trend.sample(link.rssi);
+            }
+            rssi_trend.log_if_due(realstate.wifi_radio.rssi_log_secs);
+        }
+
+        // if the wifi dropped, try to reconnect in place so the heat pump keeps being looked after, and only reset
+        // if that keeps failing
+        #[cfg(feature="ethernet")]
+        if !wifi_connected && wifi_reconnect.disconnected_since.get_or_insert_with(Instant::now).elapsed() > WIFI_RECONNECT_MAX_BACKOFF {
+            info!("Ethernet link down for more than {:?}, restarting", WIFI_RECONNECT_MAX_BACKOFF);
+            std::thread::sleep(Duration::from_millis(100));
+            reset::restart();
+        } else if wifi_connected {
+            wifi_reconnect.disconnected_since = None;
+        }
+        #[cfg(not(feature="ethernet"))]
+        if ! wifi_connected {
+            if wifi_reconnect.gave_up() {
+                info!("Wifi still disconnected after {} reconnect attempts, restarting", wifi_reconnect.attempts);
+                std::thread::sleep(Duration::from_millis(100));
+                reset::restart();
+            } else if wifi_reconnect.is_due() {
+                wifi_reconnect.record_attempt();
+                info!("Wifi disconnected! Reconnect attempt {} of {}", wifi_reconnect.attempts, WIFI_RECONNECT_MAX_ATTEMPTS);
+                {
+                    let mut realstate = state.lock().unwrap();
+                    realstate.alerts.record_wifi_reconnect();
+                    realstate.wifi_reconnects += 1;
+                }
+                // bring up the AP as well so the controller can still be reached locally while the router is out
+                if !wifi_reconnect.recovery_ap {
+                    if let eswifi::Configuration::Client(client) = wifi.get_configuration()? {
+                        info!("bringing up recovery AP {} alongside the station", SSID);
+                        let mixed = eswifi::Configuration::Mixed(client, access_point_configuration(wifi_ap_channel));
+                        match wifi.wifi_mut().set_configuration(&mixed) {
+                            Ok(()) => { wifi_reconnect.recovery_ap = true; }
+                            Err(e) => { info!("could not start recovery AP: {}", e); }
+                        }
+                    }
+                }
+                // these don't block, so the loop carries on and the next pass sees whether it worked
+                let _ = wifi.wifi_mut().disconnect();
+                if let Err(e) = wifi.wifi_mut().connect() {
+                    info!("Wifi reconnect attempt failed to start: {}", e);
+                }
+            }
+        } else if wifi_reconnect.attempts > 0 {
+            info!("Wifi reconnected after {} attempts and {:?}", wifi_reconnect.attempts, 
+                  wifi_reconnect.disconnected_since.map(|t| t.elapsed()));
+            if wifi_reconnect.recovery_ap {
+                // only the mode is changed so the station connection isn't disturbed
+                info!("taking down recovery AP");
+                hal::sys::esp!(unsafe { hal::sys::esp_wifi_set_mode(hal::sys::wifi_mode_t_WIFI_MODE_STA) })?;
+            }
+            wifi_reconnect = WifiReconnect::new();
+        }
+        
+
+        #[cfg(feature="pulsemeter")]
+        {
+            let count = pulse_counter.get_counter_value()?;
+            let mut realstate = state.lock().unwrap();
+            if count > last_pulse_count {
+                realstate.energy_meter.add_pulses((count - last_pulse_count) as u64);
+            }
+            if count >= PULSE_COUNTER_CLEAR_AT {
+                pulse_counter.counter_clear()?;
+                last_pulse_count = 0;
+            } else {
+                last_pulse_count = count;
+            }
+            if realstate.energy_meter.needs_save() {
+                let pulses = realstate.energy_meter.total_pulses;
+                nvs_writer.write("meter_pulses", move |nvs| Ok(nvs.set_u64("meter_pulses", pulses)?));
+                realstate.energy_meter.saved_pulses = realstate.energy_meter.total_pulses;
+            }
+        }
+
+        let bus_warning = bus_health.warning();
+        {
+            let mut realstate = state.lock().unwrap();
+            if bus_warning.is_some() && realstate.bus_warning.is_none() {
+                info!("Heat pump bus problem: {}", bus_warning.as_ref().unwrap());
+            }
+            realstate.bus_warning = bus_warning;
+        }
+
+        // the TWDT only catches the loop hanging, this catches the bus going quiet while we think we're connected
+        {
+            let mut realstate = state.lock().unwrap();
+            realstate.secs_since_valid_packet = bus_health.last_valid_packet.map(|t| t.elapsed().as_secs_f32());
+            if realstate.reset_protocol_errors {
+                info!("resetting protocol error counts {:?}", bus_health.errors);
+                bus_health.errors = ProtocolErrors::new();
+                realstate.reset_protocol_errors = false;
+            }
+            realstate.protocol_errors = bus_health.errors;
+            let rssi = realstate.wifi_link.as_ref().map(|l| l.rssi);
+            realstate.link_quality = Some(link_quality::compute(rssi, bus_health.success_rate(), bus_health.latency_ms));
+            let timeout = Duration::from_secs(realstate.bus_watchdog.timeout_secs);
+            let tripped = realstate.bus_watchdog.enabled && realstate.connected &&
+                          bus_health.last_valid_packet.map_or(false, |t| t.elapsed() > timeout);
+            if tripped {
+                info!("No valid packet from the heat pump in {} secs despite being connected", timeout.as_secs());
+                match realstate.bus_watchdog.action {
+                    BusWatchdogAction::Reboot => {
+                        std::thread::sleep(Duration::from_millis(100));
+                        reset::restart();
+                    }
+                    BusWatchdogAction::ReinitUart => {
+                        realstate.pending_subsystem_restart = Some(Subsystem::Uart);
+                    }
+                }
+            }
+        }
+
+        // we put the non-heat pump settings (which don't care about connection status) at the end so that if the above fails they don't happen
+        // we also put in its own block so that its locks are self-contained
+        {
+            let mut realstate = state.lock().unwrap();
+            if realstate.desired_settings.is_some() {
+                // so whatever is written below shows up on the next loop
+                nvs_refreshed = None;
+                let desired_settings = realstate.desired_settings.as_mut().unwrap();
+                // the ones that live in the config are saved along with anything POSTed to /config.json, below
+                let config_update = ConfigUpdate {
+                    led_brightness: desired_settings.controller_led_brightness.take(),
+                    location: desired_settings.controller_location.take(),
+                    reboot_period_mins: desired_settings.reboot_period_mins.take(),
+                    mode_settle_secs: desired_settings.mode_settle_secs.take(),
+                    status_poll_ms: None,
+                    restore_on_boot: None,
+                    set_debounce_ms: None,
+                    set_rate_per_min: None,
+                    set_client_rate_per_min: None,
+                };
+                if !config_update.is_empty() {
+                    realstate.pending_config.get_or_insert_with(ConfigUpdate::default).merge(config_update);
+                }
+                let desired_settings = realstate.desired_settings.as_mut().unwrap();
+                if desired_settings.wifi_ap_channel.is_some() {
+                    let channel = desired_settings.wifi_ap_channel.unwrap();
+                    nvs_writer.write("wifi_channel", move |nvs| Ok(nvs.set_u8("wifi_channel", channel)?));
+                    info!("setting wifi AP channel to {:?}, will take effect on next boot", desired_settings.wifi_ap_channel.unwrap());
+                    desired_settings.wifi_ap_channel = None;
+                }
+                if desired_settings.wifi_networks.is_some() {
+                    let mut networks = desired_settings.wifi_networks.take().unwrap();
+                    info!("setting wifi networks to {:?} (takes effect on restart)", 
+                          networks.iter().map(|n| &n.ssid).collect::<Vec<_>>());
+                    nvs_writer.write("wifi_networks", move |nvs| {
+                        // certificates usually come in through /wifi_ca_cert rather than here, so a network that's
+                        // still in the list keeps the one it had
+                        let stored = load_wifi_networks(nvs)?;
+                        for network in networks.iter_mut() {
+                            let ssid = network.ssid.clone();
+                            if let Some(eap) = network.eap.as_mut().filter(|eap| eap.ca_cert.is_none()) {
+                                eap.ca_cert = stored.iter().find(|n| n.ssid == ssid)
+                                                    .and_then(|n| n.eap.as_ref()).and_then(|e| e.ca_cert.clone());
+                            }
+                        }
+                        save_wifi_networks(nvs, &networks)
+                    });
+                }
+                if desired_settings.sniffer.is_some() {
+                    let config = desired_settings.sniffer.take().unwrap();
+                    nvs_writer.set_str("sniffer", serde_json::to_string(&config)?);
+                    info!("setting sniffer config to {:?}, will take effect on next boot", config);
+                }
+                if desired_settings.wifi_radio.is_some() {
+                    let config = desired_settings.wifi_radio.take().unwrap();
+                    nvs_writer.set_str("wifi_radio", serde_json::to_string(&config)?);
+                    info!("setting wifi radio config to {:?}", config);
+                    #[cfg(not(feature="ethernet"))]
+                    if let Err(e) = config.apply() {
+                        info!("Could not apply wifi radio config: {}", e);
+                    }
+                    realstate.wifi_radio = config;
+                }
+                if desired_settings.static_ip.is_some() {
+                    let config = desired_settings.static_ip.take().unwrap();
+                    nvs_writer.set_str("static_ip", serde_json::to_string(&config)?);
+                    info!("setting static IP config to {:?}, will take effect on next boot", config);
+                }
+                if desired_settings.wifi_country.is_some() {
+                    let cc_str = desired_settings.wifi_country.as_ref().unwrap();
+                    nvs_writer.set_str("wifi_country", cc_str.clone());
+                    info!("setting wifi country to {:?}, will take effect on next boot", cc_str);
+                    desired_settings.wifi_country = None;
+                }
+                if desired_settings.thermostat.is_some() {
+                    let config = desired_settings.thermostat.take().unwrap();
+                    nvs_writer.set_str("thermostat", serde_json::to_string(&config)?);
+                    info!("setting thermostat config to {:?}", config);
+                    realstate.thermostat.config = config;
+                }
+                if desired_settings.schedule.is_some() {
+                    let config = desired_settings.schedule.take().unwrap();
+                    nvs_writer.set_str("schedule", serde_json::to_string(&config)?);
+                    info!("setting schedule to {:?}", config);
+                    realstate.schedule.set_config(config);
+                }
+                if desired_settings.room_temperature_smoothing.is_some() {
+                    let smoothing = desired_settings.room_temperature_smoothing.take().unwrap();
+                    nvs_writer.set_str("temp_smoothing", serde_json::to_string(&smoothing)?);
+                    info!("setting room temperature smoothing to {:?}", smoothing);
+                    realstate.room_temperature_smoothing = smoothing;
+                    // restart the average from the latest raw reading
+                    realstate.room_temperature_c = realstate.room_temperature_c_raw;
+                }
+                if desired_settings.pid.is_some() {
+                    let config = desired_settings.pid.take().unwrap();
+                    nvs_writer.set_str("pid", serde_json::to_string(&config)?);
+                    info!("setting PID config to {:?}", config);
+                    realstate.pid.config = config;
+                    realstate.pid.reset();
+                }
+                if desired_settings.debug_token.is_some() {
+                    let token = desired_settings.debug_token.take().unwrap();
+                    if token.is_empty() {
+                        nvs_writer.write("debug_token", |nvs| Ok(nvs.remove("debug_token").map(|_| ())?));
+                        info!("turning off the raw packet endpoint");
+                        realstate.debug_token = None;
+                    } else {
+                        nvs_writer.set_str("debug_token", token.clone());
+                        info!("setting a new token for the raw packet endpoint");
+                        realstate.debug_token = Some(token);
+                    }
+                }
+                if desired_settings.cloud_push.is_some() {
+                    let config = desired_settings.cloud_push.take().unwrap();
+                    nvs_writer.set_str("cloud_push", serde_json::to_string(&config)?);
+                    if let Some(token) = &config.token {
+                        nvs_writer.set_str("cloud_token", token.clone());
+                    }
+                    info!("setting cloud push config to {:?}", config.url);
+                    let token = config.token.clone().or(realstate.cloud_push.config.token.take());
+                    realstate.cloud_push.config = config;
+                    realstate.cloud_push.config.token = token;
+                }
+                if desired_settings.coordinator.is_some() {
+                    let config = desired_settings.coordinator.take().unwrap();
+                    nvs_writer.set_str("coordinator", serde_json::to_string(&config)?);
+                    info!("setting coordinator config to {:?}", config);
+                    realstate.coordinator.config = config;
+                }
+                if desired_settings.follow.is_some() {
+                    let config = desired_settings.follow.take().unwrap();
+                    nvs_writer.set_str("follow", serde_json::to_string(&config)?);
+                    info!("setting follow config to {:?}", config);
+                    realstate.follower.set_config(config);
+                    let peers = realstate.peers.clone();
+                    realstate.follower.update_leader(&peers);
+                }
+                if desired_settings.bus_watchdog.is_some() {
+                    let config = desired_settings.bus_watchdog.take().unwrap();
+                    nvs_writer.set_str("bus_watchdog", serde_json::to_string(&config)?);
+                    info!("setting bus watchdog config to {:?}", config);
+                    realstate.bus_watchdog = config;
+                }
+                if desired_settings.alerts.is_some() {
+                    let mut config = desired_settings.alerts.take().unwrap();
+                    nvs_writer.set_str("alerts", serde_json::to_string(&config)?);
+                    if let Some(token) = &config.token {
+                        nvs_writer.set_str("alert_token", token.clone());
+                    }
+                    if let Some(user_key) = &config.user_key {
+                        nvs_writer.set_str("alert_user", user_key.clone());
+                    }
+                    info!("setting alert config to {:?}", config);
+                    // keep the secrets we already have if new ones weren't given
+                    config.token = config.token.or(realstate.alerts.config.token.take());
+                    config.user_key = config.user_key.or(realstate.alerts.config.user_key.take());
+                    realstate.alerts.config = config;
+                }
+                if desired_settings.ntp_server.is_some() {
+                    let ntp_str = desired_settings.ntp_server.as_ref().unwrap();
+                    nvs_writer.set_str("ntp_server", ntp_str.clone());
+                    info!("setting NTP server to {:?}, will take effect on next boot", ntp_str);
+                    desired_settings.ntp_server = None;
+                }
+                if desired_settings.watchdog.is_some() {
+                    let config = desired_settings.watchdog.take().unwrap();
+                    nvs_writer.set_str("watchdog", serde_json::to_string(&config)?);
+                    info!("setting watchdog config to {:?}, will take effect on next boot", config);
+                }
+                if desired_settings.mdns.is_some() {
+                    let config = desired_settings.mdns.take().unwrap();
+                    nvs_writer.set_str("mdns", serde_json::to_string(&config)?);
+                    info!("setting mdns config to {:?}, will take effect on next boot", config);
+                }
+                if desired_settings.syslog_server.is_some() {
+                    let syslog_str = desired_settings.syslog_server.take().unwrap();
+                    nvs_writer.set_str("syslog_server", syslog_str.clone());
+                    info!("setting syslog server to {:?}", syslog_str);
+                    if let Err(e) = syslog::LOGGER.set_server(Some(&syslog_str), &syslog_hostname) {
+                        info!("Could not start logging to syslog server {:?}: {}", syslog_str, e);
+                    }
+                    realstate.syslog_server = if syslog_str.is_empty() { None } else { Some(syslog_str) };
+                }
+                if desired_settings.adherence.is_some() {
+                    let config = desired_settings.adherence.take().unwrap();
+                    nvs_writer.set_str("adherence_cfg", serde_json::to_string(&config)?);
+                    info!("setting adherence config to {:?}", config);
+                    realstate.adherence.config = config;
+                }
+                if desired_settings.energy_meter.is_some() {
+                    let config = desired_settings.energy_meter.take().unwrap();
+                    nvs_writer.set_str("energy_meter", serde_json::to_string(&config)?);
+                    info!("setting energy meter config to {:?}", config);
+                    realstate.energy_meter.set_config(config);
+                }
+                if desired_settings.capabilities.is_some() {
+                    let capabilities = desired_settings.capabilities.take().unwrap();
+                    nvs_writer.set_str("capabilities", serde_json::to_string(&capabilities)?);
+                    info!("setting unit capabilities to {:?}", capabilities);
+                    realstate.capabilities = capabilities;
+                }
+                if desired_settings.ui_language.is_some() {
+                    let language = desired_settings.ui_language.take().unwrap();
+                    nvs_writer.set_str("ui_language", serde_json::to_string(&language)?);
+                    info!("setting UI language to {:?}", language);
+                    realstate.ui_language = language;
+                }
+                if desired_settings.reboot_window.is_some() {
+                    let window = desired_settings.reboot_window.take().unwrap();
+                    nvs_writer.set_str("reboot_window", serde_json::to_string(&window)?);
+                    info!("setting reboot window to {:?}", window);
+                    realstate.reboot_window = window;
+                }
+                if desired_settings.ota.is_some() {
+                    let config = desired_settings.ota.take().unwrap();
+                    nvs_writer.set_str("ota", serde_json::to_string(&config)?);
+                    info!("setting OTA updates to {:?}", config);
+                    realstate.ota.lock().unwrap().config = config;
+                }
+                if desired_settings.setpoint_limits.is_some() {
+                    let limits = desired_settings.setpoint_limits.take().unwrap();
+                    nvs_writer.set_str("setpoint_limits", serde_json::to_string(&limits)?);
+                    info!("setting setpoint limits to {:?}", limits);
+                    realstate.setpoint_limits = limits;
+                }
+                // only once what was sent above is all there is.  A set.json that came in since was merged into it, and
+                // still has to go out
+                if sent_seq == Some(realstate.set_seq) { realstate.desired_settings = None; }
+            }
+        }
+
+        let config_update = state.lock().unwrap().pending_config.take();
+        if let Some(update) = config_update {
+            config.apply(&update);
+            let saving = config.clone();
+            nvs_writer.write("config", move |nvs| saving.save(nvs));
+            info!("config is now {:?}", config);
+            // so the change shows up on the next loop
+            nvs_refreshed = None;
+        }
+
+        {
+            let mut realstate = state.lock().unwrap();
+            if realstate.presets_dirty {
+                nvs_writer.set_str("presets", serde_json::to_string(&realstate.presets)?);
+                info!("saved {} presets", realstate.presets.len());
+                realstate.presets_dirty = false;
+            }
+        }
+
+        {
+            let mut realstate = state.lock().unwrap();
+            if let Some(away) = realstate.away.as_mut() {
+                if let Some(ends) = away.ends {
+                    let now = Instant::now();
+                    if now >= ends {
+                        realstate.end_away();
+                    } else {
+                        away.remaining_secs = Some((ends - now).as_secs_f32());
+                    }
+                }
+            }
+            if let Some(boost) = realstate.boost.as_mut() {
+                let now = Instant::now();
+                if now >= boost.ends {
+                    realstate.end_boost();
+                } else {
+                    boost.remaining_secs = (boost.ends - now).as_secs_f32();
+                }
+            }
+        }
+
+        {
+            let mut realstate = state.lock().unwrap();
+            let has_setpoint = matches!(realstate.mode, HeatPumpMode::Heat | HeatPumpMode::Cool | 
+                                                        HeatPumpMode::Dry | HeatPumpMode::Auto);
+            if realstate.history.is_due() {
+                let active = realstate.connected && realstate.poweron;
+                let sample = Sample {
+                    unix_secs: clock::unix_secs(),
+                    room_c: Some(realstate.room_temperature_c).filter(|&t| realstate.connected && t > -999.0),
+                    setpoint_c: Some(realstate.desired_temperature_c).filter(|_| active && has_setpoint),
+                    heating: active && matches!(realstate.mode, HeatPumpMode::Heat),
+                };
+                realstate.history.push(sample);
+                let status = &mut *realstate;
+                status.adherence.update(&status.history);
+            }
+            if realstate.adherence.needs_save() {
+                nvs_writer.set_str("adherence", serde_json::to_string(&realstate.adherence.days)?);
+                realstate.adherence.last_save = Some(Instant::now());
+            }
+
+            let direction = match realstate.mode {
+                HeatPumpMode::Heat => Some(1.0),
+                HeatPumpMode::Cool | HeatPumpMode::Dry => Some(-1.0),
+                _ => None,
+            };
+            let key_direction = match direction {
+                Some(d) if realstate.connected && realstate.poweron && realstate.room_temperature_c > -999.0 => 
+                    Some((format!("{:?}/{:?}", realstate.mode, realstate.fan_speed), d)),
+                _ => None,
+            };
+            let (room_c, setpoint_c) = (realstate.room_temperature_c, realstate.desired_temperature_c);
+            realstate.rate_model.observe(key_direction, room_c, setpoint_c);
+            if realstate.rate_model.needs_save() {
+                nvs_writer.set_str("rate_model", serde_json::to_string(&realstate.rate_model.rates)?);
+                realstate.rate_model.last_save = Some(Instant::now());
+                realstate.rate_model.dirty = false;
+            }
+        }
+
+        {
+            // the external thermostat only acts once any pending settings have gone out
+            let mut realstate = state.lock().unwrap();
+            if realstate.connected {
+                if let Some(mut setting) = restore_setting.take() {
+                    if realstate.desired_settings.is_some() {
+                        info!("not restoring the last applied setting, as a newer one is already waiting");
+                    } else {
+                        match validate_setting(&mut setting, &realstate) {
+                            Ok(()) => {
+                                info!("restoring the last applied setting after boot: {:?}", setting);
+                                realstate.desired_settings = Some(setting);
+                            }
+                            Err(errjson) => { info!("last applied setting was not valid: {}", errjson); }
+                        }
+                    }
+                }
+            }
+            // the schedule waits while away mode or a boost has the setpoint
+            if realstate.connected && realstate.desired_settings.is_none() && !realstate.settling &&
+               realstate.away.is_none() && realstate.boost.is_none() {
+                if let Some(unix_secs) = clock::unix_secs() {
+                    let heating = realstate.poweron && matches!(realstate.mode, HeatPumpMode::Heat);
+                    let room_c = Some(realstate.room_temperature_c).filter(|&t| t > -999.0);
+                    // what's been seen since boot, or what's been learned before it until there's enough of that
+                    let warmup_c_per_min = realstate.history.warmup_c_per_min().or_else(|| realstate.rate_model.heating_c_per_min());
+                    if let Some(target_c) = realstate.schedule.evaluate(unix_secs, heating, room_c, warmup_c_per_min) {
+                        match realstate.schedule.preheat {
+                            Some(preheat) => info!("schedule pre-heating to {} C, {:.0} minutes ahead", target_c, preheat.lead_mins),
+                            None => info!("schedule setting the setpoint to {} C", target_c),
+                        }
+                        let mut setting = HeatPumpSetting::new();
+                        setting.desired_temperature_c = Some(target_c);
+                        match validate_setting(&mut setting, &realstate) {
+                            Ok(()) => {
+                                // like one from the user, it's what the PID trims around
+                                realstate.pid.set_user_target(setting.desired_temperature_c.unwrap());
+                                realstate.desired_settings = Some(setting);
+                            }
+                            Err(errjson) => { info!("schedule setting was not valid: {}", errjson); }
+                        }
+                    }
+                }
+            }
+            if realstate.connected && realstate.desired_settings.is_none() && !realstate.settling {
+                let mode = realstate.mode;
+                if let Some(mut setting) = realstate.thermostat.evaluate(mode) {
+                    match validate_setting(&mut setting, &realstate) {
+                        Ok(()) => { realstate.desired_settings = Some(setting); }
+                        Err(errjson) => { info!("thermostat setting was not valid: {}", errjson); }
+                    }
+                }
+            }
+
+            // similarly the PID trim, which also needs a real room temperature
+            if realstate.connected && realstate.desired_settings.is_none() && !realstate.settling && 
+               realstate.room_temperature_c > -999.0 {
+                if realstate.pid.user_target_c.is_none() {
+                    // nothing has been requested since boot, so trim around whatever the unit is set to
+                    let current = realstate.desired_temperature_c;
+                    realstate.pid.set_user_target(current);
+                }
+                let room_temperature_c = realstate.room_temperature_c;
+                if let Some(setpoint_c) = realstate.pid.update(room_temperature_c) {
+                    let mut setting = HeatPumpSetting::new();
+                    setting.desired_temperature_c = Some(setpoint_c);
+                    match validate_setting(&mut setting, &realstate) {
+                        Ok(()) => { realstate.desired_settings = Some(setting); }
+                        Err(errjson) => { info!("PID setpoint was not valid: {}", errjson); }
+                    }
+                }
+            }
+        }
+
+        // look for other controllers every so often
+        if let Some(mdns) = &mdnso {
+            if last_peer_browse.map_or(true, |t| t.elapsed() >= PEER_BROWSE_INTERVAL) {
+                last_peer_browse = Some(Instant::now());
+                let mut results = vec![mdns::QueryResult::default(); PEER_BROWSE_MAX_RESULTS];
+                match mdns.query_ptr(MDNS_SERVICE_TYPE, "_tcp", PEER_BROWSE_TIMEOUT, PEER_BROWSE_MAX_RESULTS, &mut results) {
+                    Ok(n) => {
+                        let own_hostname = macstr.as_ref().map(|s| ["heatpump-controller-", s.as_str()].concat());
+                        let peers: Vec<Peer> = results[..n].iter()
+                            .filter(|r| r.hostname != own_hostname)
+                            .map(Peer::from_query_result)
+                            .collect();
+                        info!("found {} peer controllers", peers.len());
+                        let mut realstate = state.lock().unwrap();
+                        realstate.coordinator.update_units(&peers);
+                        realstate.follower.update_leader(&peers);
+                        realstate.peers = peers;
+                        realstate.peers_updated = Some(Instant::now());
+                    }
+                    Err(e) => { info!("mDNS peer browse failed: {}", e); }
+                }
+            }
+        }
+
+        if let Some(ssdp) = ssdpo.as_mut() {
+            ssdp.poll();
+        }
+        if let Some(d) = &discovery {
+            d.poll(&state.lock().unwrap());
+        }
+        if let Some(sse) = sse_server.as_mut() {
+            sse.poll();
+            if sse.has_listeners() && last_sse_check.elapsed() >= SSE_CHECK_INTERVAL {
+                last_sse_check = Instant::now();
+                let status = status_json(&state.lock().unwrap(), boot_instant, &macstr);
+                let etag = status_etag(&status);
+                sse.send_status(&status, &etag);
+            }
+        }
+
+        // in recover mode, a watchdog trip the loop came back from gets the next subsystem restarted, and if that
+        // doesn't stop them, a reset
+        let twdt_trips = crash_log::twdt_trips();
+        if !watchdog_config.panic && twdt_trips != seen_twdt_trips {
+            seen_twdt_trips = twdt_trips;
+            if watchdog_recovered.map_or(false, |t| t.elapsed() > WATCHDOG_RECOVER_QUIET) {
+                watchdog_recover_step = 0;
+            }
+            watchdog_recovered = Some(Instant::now());
+            match WATCHDOG_RECOVER_STEPS.get(watchdog_recover_step) {
+                Some(subsystem) => {
+                    crash_log.lock().unwrap().record(boot_count, Some(boot_instant), &loop_task, 
+                                                     &format!("restarting {:?}", subsystem));
+                    state.lock().unwrap().pending_subsystem_restart = Some(*subsystem);
+                    watchdog_recover_step += 1;
+                }
+                None => {
+                    let mut log = crash_log.lock().unwrap();
+                    log.record(boot_count, Some(boot_instant), &loop_task, "reset, restarting subsystems didn't help");
+                    nvs_writer.flush();
+                    if let Some(json) = log.take_dirty_json() {
+                        nvs_settings.set_str("crash_log", &json)?;
+                    }
+                    crash_log::clear_trip_marker();
+                    std::thread::sleep(Duration::from_millis(100));
+                    reset::restart();
+                }
+            }
+        }
+
+        // restart any individual subsystems that were asked for.  The lock is not held while doing this since the http
+        // server can't stop while a handler is waiting on it
+        let subsystem_restart = state.lock().unwrap().pending_subsystem_restart.take();
+        match subsystem_restart {
+            #[cfg(feature="ethernet")]
+            Some(Subsystem::Wifi) => {
+                info!("asked to restart wifi, but this controller is on ethernet");
+            }
+            #[cfg(not(feature="ethernet"))]
+            Some(Subsystem::Wifi) => {
+                info!("restarting wifi");
+                {
+                    let mut realstate = state.lock().unwrap();
+                    realstate.alerts.record_wifi_reconnect();
+                    realstate.wifi_reconnects += 1;
+                }
+                // none of these block, so the watchdog keeps being fed while the station comes back.  Waiting for it
+                // is left to the reconnect backoff above, the same as for a dropped connection
+                if let Err(e) = wifi.wifi_mut().stop() {
+                    info!("could not stop wifi for the restart: {}", e);
+                }
+                if let Err(e) = wifi.wifi_mut().start() {
+                    info!("could not start wifi after stopping it: {}", e);
+                }
+                match wifi.get_configuration() {
+                    Ok(eswifi::Configuration::Client(_)) | Ok(eswifi::Configuration::Mixed(_, _)) => {
+                        if let Err(e) = wifi.wifi_mut().connect() {
+                            info!("wifi reconnect after restart failed to start: {}", e);
+                        }
+                        wifi_reconnect.record_attempt();
+                    }
+                    Ok(_) => {}
+                    Err(e) => { info!("could not read the wifi configuration after restart: {}", e); }
+                }
+            }
+            Some(Subsystem::Http) => {
+                info!("restarting http server");
+                drop(server);
+                server = http::server::EspHttpServer::new(&server_configuration)?;
+                setup_handlers(&mut server, state.clone(), boot_instant, macstr.clone(), nvs_default_partition.clone())?;
+            }
+            Some(Subsystem::Uart) => {
+                info!("flushing uart and re-doing the heat pump handshake");
+                uart.clear_rx()?;
+                // the error counts are kept since they're the history of what led to this
+                bus_health = BusHealth { errors: bus_health.errors, ..BusHealth::new() };
+                state.lock().unwrap().connected = false;
+            }
+            None => {}
+        }
+
+        // the stored credentials are cleared here rather than in the handler so NVS is only written from the loop and
+        // its NVS task
+        if state.lock().unwrap().wifi_reprovision {
+            // anything still queued goes first, so it can't land on top of the cleared networks
+            nvs_writer.flush();
+            save_wifi_networks(&mut nvs_settings, &[])?;
+            nvs_settings.set_u8("wifi_force_ap", 1)?;
+            info!("Cleared stored wifi networks, restarting into AP mode for reprovisioning");
+            // give the http response a moment to get out
+            std::thread::sleep(Duration::from_millis(500));
+            reset::restart();
+        }
+
+        let pending_ca_cert = state.lock().unwrap().pending_ca_cert.take();
+        if let Some((ssid, ca_cert)) = pending_ca_cert {
+            // on the NVS task, so it sees a network list saved just before it
+            nvs_writer.write("wifi CA certificate", move |nvs| {
+                let mut networks = load_wifi_networks(nvs)?;
+                match networks.iter_mut().find(|n| n.ssid == ssid).and_then(|n| n.eap.as_mut()) {
+                    Some(eap) => {
+                        info!("{} the CA certificate for {} (takes effect on restart)", 
+                              if ca_cert.is_some() { "storing" } else { "removing" }, ssid);
+                        eap.ca_cert = ca_cert;
+                        save_wifi_networks(nvs, &networks)?;
+                    }
+                    None => { info!("not storing a CA certificate for {}, it's no longer a stored enterprise network", ssid); }
+                }
+                Ok(())
+            });
+        }
+
+        let pending_import = state.lock().unwrap().pending_import.take();
+        if let Some(import) = pending_import {
+            nvs_writer.flush();
+            import.write(&mut nvs_settings)?;
+            info!("Imported the config, restarting to use it");
+            std::thread::sleep(Duration::from_millis(500));
+            reset::restart();
+        }
+
+        // like reprovisioning, this comes back up in AP mode rather than joining the compiled-in network
+        if state.lock().unwrap().factory_reset {
+            nvs_writer.flush();
+            config::factory_reset(&mut nvs_settings)?;
+            info!("Restarting into AP mode");
+            std::thread::sleep(Duration::from_millis(500));
+            reset::restart();
+        }
+
+        if !crash_streak_cleared && boot_instant.elapsed() >= safe_mode::STABLE_UPTIME {
+            info!("Up for {:?}, so no longer counting the crashes before this boot", safe_mode::STABLE_UPTIME);
+            nvs_writer.write("crash streak", safe_mode::clear);
+            crash_streak_cleared = true;
+        }
+
+        // Restart if needed
+        if reboot_period_mins > 0 {
+            // if there's a window only reboot inside it, but without the time we can't know so just go ahead
+            let in_window = {
+                let window = &state.lock().unwrap().reboot_window;
+                match clock::unix_secs() {
+                    Some(now) if window.enabled => window.contains(now),
+                    _ => true,
+                }
+            };
+            if boot_instant.elapsed() >= Duration::from_secs(reboot_period_mins as u64 * 60) && in_window {
+                info!("restarting due to uptime restart trigger");
+                std::thread::sleep(Duration::from_millis(100));
+                reset::restart();
+            }
+        }
+
+        // check to see if we need to delay because the loop was too fast
+        let loopelapsed = loopstart.elapsed();
+        if loopelapsed < LOOP_MIN_LENGTH {
+            let sleepdur = LOOP_MIN_LENGTH - loopelapsed;
+
+            std::thread::sleep(sleepdur);
+        }
+    }
+}
+
+
+fn settle_tolerant<T>(value: Option<T>, what: &str, byte: u8, settling: bool) -> anyhow::Result<Option<T>> {
+    // units can report transitional garbage just after a mode change, so only treat unknown values as errors otherwise
+    match value {
+        Some(v) => Ok(Some(v)),
+        None if settling => {
+            info!("ignoring unknown {} byte {:#x} while settling", what, byte);
+            Ok(None)
+        }
+        None => { Err(FirmwareError::new(ErrorCode::BadStatusPacket, format!("Unknown {} byte {:#x} in status packet", what, byte)).into()) }
+    }
+}
+
+/// Connects to and polls the heat pump until told to stop, handing back the uart and what it learned of the bus
+fn early_uart_loop(uart: uart::UartDriver<'static>, stateref: &Arc<Mutex<HeatPumpStatus>>, stop: &AtomicBool,
+                   sniffing: bool, boot_instant: Instant) -> anyhow::Result<(uart::UartDriver<'static>, BusHealth, Instant)> {
+    let mut bus_health = BusHealth::new();
+    let bus_trace = stateref.lock().unwrap().bus_trace.clone();
+    let mut last_status_request = Instant::now() - RESPONSE_DELAY;
+    while !stop.load(Ordering::Relaxed) {
+        if sniffing {
+            sniff_uart(&uart, stateref)?;
+            std::thread::sleep(Duration::from_millis(20));
+        } else if !stateref.lock().unwrap().connected {
+            try_connect(&uart, stateref, &mut bus_health, &bus_trace)?;
+        } else if last_status_request.elapsed() > RESPONSE_DELAY {
+            if request_status(&uart, stateref, &mut bus_health, &bus_trace, false, boot_instant)? {
+                last_status_request = Instant::now();
+            }
+        } else {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+    Ok((uart, bus_health, last_status_request))
+}
+
+/// Passes on whatever has come in on the bus to the sniffer, without ever writing
+fn sniff_uart(uart: &dyn Transport, stateref: &Arc<Mutex<HeatPumpStatus>>) -> anyhow::Result<()> {
+    let mut buf = [0u8; 64];
+    let nread = uart.read(&mut buf, Duration::ZERO)?;
+    stateref.lock().unwrap().sniffer.feed(&buf[..nread]);
+    Ok(())
+}
+
+/// Sends the connection string, marking the state connected if the heat pump answers
+fn try_connect(uart: &dyn Transport, stateref: &Arc<Mutex<HeatPumpStatus>>, bus_health: &mut BusHealth,
+               bus_trace: &Mutex<BusTrace>) -> anyhow::Result<()> {
+    info!("Sending Connection string!");
+    uart.write(&CONNECT_BYTES)?;
+    bus_trace.lock().unwrap().record_tx(&CONNECT_BYTES);
+
+    // check for a response
+    match read_packet(uart, CONNECT_DELAY, bus_health, bus_trace) {
+        Ok(Some(response)) => {
+            if response.packet_type == 0x7A {
+                info!("Connected!");
+                stateref.lock().unwrap().connected = true;
+            }
+        }
+        Ok(None) => {
+            info!("No response to connection string");
+        }
+        Err(e) if e.downcast_ref::<PacketError>().is_some() => {
+            info!("Invalid response to connection string: {}", e);
+        }
+        Err(e) => { return Err(e); }
+    }
+    Ok(())
+}
+
+/// Sends an info request (0x42) for the given type, returning the response if one comes back in time
+fn request_info(uart: &dyn Transport, info_type: u8, bus_health: &mut BusHealth, 
+                bus_trace: &Mutex<BusTrace>) -> anyhow::Result<Option<Packet>> {
+    let mut packet = Packet::new_type_size(0x42, 16);
+    packet.data[0] = info_type;
+    packet.set_checksum();
+    let mut txbuf = [0u8; PACKET_SIZE_MAX];
+    let txbytes = packet.to_bytes(&mut txbuf);
+    uart.write(txbytes)?;
+    bus_trace.lock().unwrap().record_tx(txbytes);
+
+    read_packet(uart, RESPONSE_DELAY, bus_health, bus_trace)
+}
+
+/// Asks the heat pump for each of the status packets in turn, returning whether they all came back
+fn request_status(uart: &dyn Transport, stateref: &Arc<Mutex<HeatPumpStatus>>, bus_health: &mut BusHealth,
+                  bus_trace: &Mutex<BusTrace>, settling: bool, boot_instant: Instant) -> anyhow::Result<bool> {
+    info!("Requesting status");
+    // First make sure there's no junk left unread in the uart
+    uart.discard_input()?;
+
+    let mut all_done = false;
+    // ask for status from a subset of status packets
+    for ptype in StatusPacketType::iter() {
+        all_done = false;
+        // if no response after the delay time, we probably got disconnected?
+        let status_packet = match request_info(uart, ptype as u8, bus_health, bus_trace)? {
+            Some(p) => { p }
+            None => {
+                info!("No response to status packet request for type {:?}, assuming disconnected", ptype);
+                bus_health.record_timeout();
+                stateref.lock().unwrap().connected = false;
+                break;
+            }
+        };
+        if status_packet.packet_type != 0x62 || status_packet.data.first().and_then(|t| StatusPacketType::from_repr(*t as usize)).is_none() {
+            bus_health.errors.unexpected_packet_types += 1;
+        }
+        
+        if let Err(e) = status_to_state(&status_packet, stateref, settling, boot_instant) {
+            if settling {
+                info!("Ignoring bad status packet while settling after a mode change: {}", e);
+            } else {
+                return Err(e);
+            }
+        }
+        all_done = true;
+    } 
+    if all_done {
+        info!("Done requesting status, have {} ms reminaing before next request", RESPONSE_DELAY.as_millis());     
+    }
+    Ok(all_done)
+}
+
+fn status_to_state(packet: &Packet, stateref: &Arc<Mutex<HeatPumpStatus>>, settling: bool, boot_instant: Instant) -> anyhow::Result<()> {
+    if packet.packet_type != 0x62 {
+        return Err(FirmwareError::new(ErrorCode::BadStatusPacket, "Packet is not a status reply packet!").into());
+    } 
+    if packet.data.len() != 16 {
+        return Err(FirmwareError::new(ErrorCode::BadStatusPacket, "Status packet is not length 16").into());
+    }
+
+    let mut state = stateref.lock().unwrap();
+
+    let status_type = StatusPacketType::from_repr(packet.data[0] as usize);
+    match status_type {
+        Some(StatusPacketType::Settings) => {
+            // settings
+            state.poweron = packet.data[3] != 0;
+            state.isee_present = packet.data[4] & 0b00001000 > 0;
+            // drop the isee bit when computing the mode
+            let modebyte = packet.data[4] & 0b11110111;
+            if let Some(mode) = settle_tolerant(HeatPumpMode::from_repr(modebyte as usize), "mode", modebyte, settling)? {
+                state.mode = mode;
+            }
+
+            // I don't really understand why the temperature is done this way, but it's what this does so I assume its right? https://github.com/SwiCago/HeatPump/blob/b4c34f1f66e45affe70a556a955db02a0fa80d81/src/HeatPump.cpp#L649
+            // done as floats so a garbled byte gives a silly temperature rather than an overflow panic
+            if packet.data[11] != 0 {
+                state.desired_temperature_c = (packet.data[11] as f32 - 128.0)/2.0;
+            } else {
+                state.desired_temperature_c = packet.data[5] as f32 + 10.0; 
+            }
+
+            if let Some(fan_speed) = settle_tolerant(FanSpeed::from_repr(packet.data[6] as usize), "fan speed", packet.data[6], settling)? {
+                state.fan_speed = fan_speed;
+            }
+            if let Some(vane) = settle_tolerant(VaneDirection::from_repr(packet.data[7] as usize), "vane", packet.data[7], settling)? {
+                state.vane = vane;
+            }
+            let wvmod = packet.data[10] & (!0x80); // not sure what this bit is for.  TODO: figure out
+            
+            state.widevane = WideVaneDirection::from_repr(wvmod as usize).unwrap_or(WideVaneDirection::Unknown);
+            
+        }
+        Some(StatusPacketType::RoomTemperature) => {
+            if packet.data[6] != 0 {
+                state.room_temperature_c_raw = (packet.data[6] as f32 - 128.0)/2.0;
+            } else {
+                state.room_temperature_c_raw = packet.data[3] as f32 + 10.0; 
+            }
+            state.room_temperature_c = state.room_temperature_smoothing.apply(state.room_temperature_c, state.room_temperature_c_raw);
+
+
+            if packet.data[7] != 0 {
+                state.room_temperature_c_2 = (packet.data[7] as f32 - 128.0)/2.0;
+            } else {
+                state.room_temperature_c_2 = -999.0;
+            }
+
+            // byte 8 seems to have isee info direct/indirect for some reason
+            state.isee_mode = ISeeMode::from_repr(packet.data[8] as usize).unwrap_or(ISeeMode::Unknown);
+            
+        }
+        Some(StatusPacketType::ErrorCodeMaybe) => {
+            if packet.data[4] == 0x80 {
+                state.error_data = None;
+                if let Some(last_error) = state.last_error.as_mut() {
+                    if last_error.active {
+                        info!("heat pump error {} cleared", last_error.code);
+                        last_error.clear(boot_instant);
+                    }
+                }
+            } else {
+                let is_new = match &state.last_error {
+                    Some(last_error) => !last_error.active || last_error.data[..] != packet.data[..],
+                    None => true,
+                };
+                if is_new {
+                    let last_error = HeatPumpError::new(&packet.data, boot_instant);
+                    info!("heat pump raised error {}", last_error.code);
+                    state.last_error = Some(last_error);
+                }
+                state.error_data = Some(packet.data.to_vec());
+            }
+        }
+        Some(StatusPacketType::Timers) => {
+            // ignore timers
+        }
+        Some(StatusPacketType::MiscInfo) => {
+            //state.compressorfreq = packet.data[3];  // does not appear in my heatpump
+            state.operating = packet.data[4];
+        }
+        Some(StatusPacketType::StandbyMode) => {
+            // not sure what to do with this right now...
+        }
+        _ => {
+            info!("unrecognized status packet type: {}", packet.data[0]);
+        }
+    }
+
+    if let Some(status_type) = status_type {
+        state.last_status_packets.record(status_type, packet.data);
+    }
+    state.status_updated = Some(Instant::now());
+
+    Ok(())
+}
+
+// the fields that come from the heat pump itself, which mean nothing until it has been heard from
+const HEAT_PUMP_STATUS_FIELDS: [&str; 12] = ["poweron", "isee_present", "mode", "desired_temperature_c", "fan_speed",
+    "vane", "widevane", "isee_mode", "room_temperature_c", "room_temperature_c_raw", "room_temperature_c_2", "operating"];
+
+/// The json for status.json, which is also what gets pushed elsewhere.  It has the same shape whether or not the heat
+/// pump is connected: when it isn't, "stale" is true and the heat pump's fields are the last known values, as of
+/// "status_age_secs" ago, or null if it has never been heard from since boot
+fn status_json(stateg: &HeatPumpStatus, boot_instant: Instant, wifimacstr: &Option<String>) -> serde_json::Value {
+    let secs = boot_instant.elapsed().as_secs_f32();
+    let timestamp_str =  serde_json::Value::String(format!("{}", secs));
+    let macval = match wifimacstr {
+        Some(s) => serde_json::Value::String(s.to_string()),
+        None => serde_json::Value::Null
+    };
+
+    let mut statusjson = serde_json::to_value(stateg).unwrap();
+
+    // add the timestamp, mac & staleness.  HeatPumpStatus is a struct, so this is always an object
+    if let Some(o) = statusjson.as_object_mut() {
+        o.insert("secs_since_boot".to_string(), timestamp_str);
+        o.insert("time".to_string(), json!(clock::iso8601_now()));
+        o.insert("next_reboot".to_string(), json!(stateg.next_reboot(boot_instant).map(clock::iso8601)));
+        o.insert("mac".to_string(), macval);
+        o.insert("stale".to_string(), json!(!stateg.connected));
+        o.insert("status_age_secs".to_string(), json!(stateg.status_updated.map(|t| t.elapsed().as_secs_f32())));
+        o.insert("bus_paused_secs".to_string(), json!(stateg.bus_paused_remaining_secs()));
+        if stateg.status_updated.is_none() {
+            for field in HEAT_PUMP_STATUS_FIELDS {
+                o.insert(field.to_string(), serde_json::Value::Null);
+            }
+        }
+        o.insert("tx_pin".to_string(), json!(env!("TX_PIN_NUM")));
+        o.insert("rx_pin".to_string(), json!(env!("RX_PIN_NUM")));
+        o.insert("led_pin".to_string(), json!(env!("LED_PIN_NUM")));
+    }
+    statusjson
+}
+
+/// The body of the 409 for anything that would need to send to the heat pump while it isn't connected.  Nothing is
+/// queued in that case, so the client should retry once status.json is no longer stale
+fn unavailable_json(stateg: &HeatPumpStatus) -> serde_json::Value {
+    let mut errjson = FirmwareError::new(ErrorCode::HeatPumpUnavailable, "not connected to the heat pump").to_json();
+    errjson["status_age_secs"] = json!(stateg.status_updated.map(|t| t.elapsed().as_secs_f32()));
+    errjson
+}
+
+/// The response to a set.json body that didn't deserialize.  A mode, fan speed or vane that isn't one of the names is
+/// a 422 that lists them, rather than a 400
+fn setting_json_error(body: &[u8], e: &serde_json::Error) -> (u16, &'static str, serde_json::Value) {
+    let err = FirmwareError::from_json_error(body, e);
+    let allowed = match err.field.as_deref() {
+        Some("mode") => enum_names::<HeatPumpMode>(),
+        Some("fan_speed") => enum_names::<FanSpeed>(),
+        Some("vane") => enum_names::<VaneDirection>(),
+        Some("widevane") => enum_names::<WideVaneDirection>(),
+        _ => return (400, "Bad Request", err.to_json()),
+    };
+    let field = err.field.unwrap_or_default();
+    let given = serde_json::from_slice::<serde_json::Value>(body).ok().map(|v| v[field.as_str()].clone());
+    let message = err.message.trim_start_matches("JSON error: ");
+    let mut errjson = FirmwareError::new(ErrorCode::InvalidSetting, message).with_field(&field).with_value(given).to_json();
+    errjson["allowed"] = json!(allowed);
+    (422, "Unprocessable Entity", errjson)
+}
+
+/// The 422 body for a setting that failed validation
+fn invalid_setting(field: &str, message: impl std::fmt::Display, value: impl Serialize) -> serde_json::Value {
+    FirmwareError::new(ErrorCode::InvalidSetting, message).with_field(field).with_value(value).to_json()
+}
+
+fn validate_setting(form: &mut HeatPumpSetting, state: &HeatPumpStatus) -> Result<(), serde_json::Value> {
+    // checks a requested setting against the controller's limits, possibly modifying it (e.g. clamping).  
+    // The error is the json to send back to the client
+    // capabilities changed in the same request apply to it
+    let capabilities = form.capabilities.as_ref().unwrap_or(&state.capabilities);
+    if form.vane.is_some() && !capabilities.vane {
+        return Err(invalid_setting("vane", "this unit does not support vane control", form.vane));
+    }
+    if form.widevane.is_some() && !capabilities.widevane {
+        return Err(invalid_setting("widevane", "this unit does not support widevane control", form.widevane));
+    }
+
+    if let Some(limits) = &form.setpoint_limits {
+        if let Err(msg) = limits.validate() {
+            return Err(invalid_setting("setpoint_limits", msg, limits));
+        }
+    }
+
+    if let Some(thermostat) = &form.thermostat {
+        if let Err(msg) = thermostat.validate() {
+            return Err(invalid_setting("thermostat", msg, thermostat));
+        }
+    }
+    if let Some(schedule) = &form.schedule {
+        if let Err(msg) = schedule.validate() {
+            return Err(invalid_setting("schedule", msg, schedule));
+        }
+    }
+    if let Some(adherence) = &form.adherence {
+        if let Err(msg) = adherence.validate() {
+            return Err(invalid_setting("adherence", msg, adherence));
+        }
+    }
+    if let Some(meter) = &form.energy_meter {
+        if let Err(msg) = meter.validate() {
+            return Err(invalid_setting("energy_meter", msg, meter));
+        }
+    }
+    if let Some(smoothing) = &form.room_temperature_smoothing {
+        if let Err(msg) = smoothing.validate() {
+            return Err(invalid_setting("room_temperature_smoothing", msg, smoothing));
+        }
+    }
+    if let Some(pid) = &form.pid {
+        if let Err(msg) = pid.validate() {
+            return Err(invalid_setting("pid", msg, pid));
+        }
+    }
+    if let Some(token) = &form.debug_token {
+        if !token.is_empty() && (token.len() < 16 || token.len() > 64) {
+            // not repeated back, it's a secret
+            return Err(FirmwareError::new(ErrorCode::InvalidSetting, "debug_token must be between 16 and 64 bytes, or empty to turn it off")
+                       .with_field("debug_token").to_json());
+        }
+    }
+    if let Some(cloud_push) = &form.cloud_push {
+        if let Err(msg) = cloud_push.validate() {
+            return Err(invalid_setting("cloud_push", msg, cloud_push));
+        }
+    }
+    if let Some(bus_watchdog) = &form.bus_watchdog {
+        if bus_watchdog.timeout_secs < 10 {
+            return Err(invalid_setting("bus_watchdog", "bus_watchdog timeout_secs must be at least 10", bus_watchdog));
+        }
+    }
+    if let Some(alerts) = &form.alerts {
+        if let Err(msg) = alerts.validate() {
+            return Err(invalid_setting("alerts", msg, alerts));
+        }
+    }
+    if let Some(coordinator) = &form.coordinator {
+        if let Err(msg) = coordinator.validate() {
+            return Err(invalid_setting("coordinator", msg, coordinator));
+        }
+    }
+    if let Some(follow) = &form.follow {
+        if let Err(msg) = follow.validate() {
+            return Err(invalid_setting("follow", msg, follow));
+        }
+    }
+    if let Some(watchdog) = &form.watchdog {
+        if let Err(msg) = watchdog.validate() {
+            return Err(invalid_setting("watchdog", msg, watchdog));
+        }
+    }
+    if let Some(mdns) = &form.mdns {
+        if let Err(msg) = mdns.validate() {
+            return Err(invalid_setting("mdns", msg, mdns));
+        }
+    }
+    if let Some(reboot_window) = &form.reboot_window {
+        if let Err(msg) = reboot_window.validate() {
+            return Err(invalid_setting("reboot_window", msg, reboot_window));
+        }
+    }
+    if let Some(ota) = &form.ota {
+        if let Err(msg) = ota.validate() {
+            return Err(invalid_setting("ota", msg, ota));
+        }
+    }
+    if let Some(mins) = form.reboot_period_mins {
+        if mins != 0 && mins < 10 {
+            return Err(invalid_setting("reboot_period_mins", "reboot_period_mins must be 0 (off) or at least 10", mins));
+        }
+    }
+    if let Some(channel) = form.wifi_ap_channel {
+        if !(1..=14).contains(&channel) {
+            return Err(invalid_setting("wifi_ap_channel", "wifi_ap_channel must be between 1 and 14", channel));
+        }
+    }
+    if let Some(networks) = &form.wifi_networks {
+        if networks.len() > WIFI_NETWORKS_MAX {
+            return Err(FirmwareError::new(ErrorCode::InvalidSetting, format!("at most {} wifi networks can be stored", WIFI_NETWORKS_MAX))
+                       .with_field("wifi_networks").to_json());
+        }
+        for network in networks {
+            if network.ssid.is_empty() || network.ssid.len() > 32 {
+                return Err(invalid_setting("wifi_networks", "ssids must be 1-32 bytes", &network.ssid));
+            }
+            match &network.eap {
+                None => {
+                    if network.password.as_ref().map_or(false, |p| p.len() > 64) {
+                        return Err(invalid_setting("wifi_networks", "passwords must be at most 64 bytes", &network.ssid));
+                    }
+                }
+                Some(eap) => {
+                    if eap.identity.len() > 128 || eap.username.len() > 128 || 
+                       network.password.as_ref().map_or(true, |p| p.is_empty() || p.len() > 128) {
+                        return Err(invalid_setting("wifi_networks", "enterprise networks need a password, and identity, username and \
+                                                                   password must be at most 128 bytes", &network.ssid));
+                    }
+                    if eap.ca_cert.as_ref().map_or(false, |c| !c.starts_with("-----BEGIN CERTIFICATE-----")) {
+                        return Err(invalid_setting("wifi_networks", "ca_cert must be a PEM certificate", &network.ssid));
+                    }
+                }
+            }
+        }
+    }
+    if let Some(radio) = &form.wifi_radio {
+        if let Err(msg) = radio.validate() {
+            return Err(invalid_setting("wifi_radio", msg, radio));
+        }
+    }
+    if let Some(static_ip) = &form.static_ip {
+        if let Err(msg) = static_ip.validate() {
+            return Err(invalid_setting("static_ip", msg, static_ip));
+        }
+    }
+    if let Some(cc) = &form.wifi_country {
+        if cc.len() != 2 || !cc.chars().all(|c| c.is_ascii_uppercase()) {
+            return Err(invalid_setting("wifi_country", "wifi_country must be a two-letter uppercase country code like \"US\"", cc));
+        }
+    }
+
+    if let Some(temperature_c) = form.desired_temperature_c {
+        // check against the mode we are about to be in, which is the current one if it isn't being changed
+        let mode = form.mode.unwrap_or(state.mode);
+        // and against limits changed in the same request, the way capabilities are
+        let limits = form.setpoint_limits.clone().unwrap_or_else(|| state.setpoint_limits.clone());
+        if let Some(range) = limits.for_mode(mode) {
+            if !range.contains(temperature_c) {
+                if limits.clamp {
+                    let clamped = temperature_c.clamp(range.min_c, range.max_c);
+                    info!("clamping requested temperature {} to {} for mode {:?}", temperature_c, clamped, mode);
+                    form.desired_temperature_c = Some(clamped);
+                } else {
+                    let mut errjson = invalid_setting("desired_temperature_c",
+                        format!("desired_temperature_c {} is outside the allowed range for mode {:?}", temperature_c, mode), temperature_c);
+                    errjson["mode"] = json!(mode);
+                    errjson["min_c"] = json!(range.min_c);
+                    errjson["max_c"] = json!(range.max_c);
+                    return Err(errjson);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks an import with the same limits as set.json and presets.json, so it can't store anything they'd refuse
+fn validate_import(import: &ConfigExport, state: &HeatPumpStatus) -> Result<(), serde_json::Value> {
+    let mut setting = serde_json::from_value::<HeatPumpSetting>(import.setting_json())
+        .map_err(|e| FirmwareError::new(ErrorCode::InvalidSetting, e.to_string()).to_json())?;
+    if let Err(mut errjson) = validate_setting(&mut setting, state) {
+        // named the way the export has it
+        if let Some(field) = errjson["field"].as_str() {
+            errjson["field"] = json!(ConfigExport::stored_key(field));
+        }
+        return Err(errjson);
+    }
+
+    if let Some(presets) = import.settings.get("presets") {
+        let presets = serde_json::from_value::<HashMap<String, HeatPumpSetting>>(presets.clone())
+            .map_err(|e| FirmwareError::new(ErrorCode::InvalidSetting, format!("presets: {}", e)).with_field("presets").to_json())?;
+        for (name, mut preset) in presets {
+            if preset.debug_token.is_some() || preset.ota.is_some() {
+                // not repeated back, the token is a secret
+                return Err(FirmwareError::new(ErrorCode::InvalidSetting, format!("preset {:?} sets debug_token or ota, which a preset can't", name))
+                           .with_field("presets").to_json());
+            }
+            // checked against the capabilities being imported alongside it, if there are any
+            if preset.capabilities.is_none() {
+                preset.capabilities = setting.capabilities.clone();
+            }
+            if let Err(mut errjson) = validate_setting(&mut preset, state) {
+                errjson["preset"] = json!(name);
+                return Err(errjson);
+            }
+        }
+    }
+    if let Some(rates) = import.settings.get("rate_model") {
+        serde_json::from_value::<BTreeMap<String, RateEstimate>>(rates.clone())
+            .map_err(|e| FirmwareError::new(ErrorCode::InvalidSetting, format!("rate_model: {}", e)).with_field("rate_model").to_json())?;
+    }
+    Ok(())
+}
+
+/// A JSON Schema for set.json, reflecting this controller's setpoint limits and what the unit supports, so clients
+/// can check a form before sending it
+fn setting_schema(state: &HeatPumpStatus) -> serde_json::Value {
+    let limits = &state.setpoint_limits;
+    let ranges = [(HeatPumpMode::Heat, limits.heat), (HeatPumpMode::Cool, limits.cool),
+                  (HeatPumpMode::Dry, limits.dry), (HeatPumpMode::Auto, limits.auto)];
+
+    let mut properties = json!({
+        "poweron": {"type": ["boolean", "null"]},
+        "mode": {"enum": enum_names::<HeatPumpMode>()},
+        "desired_temperature_c": {
+            "type": ["number", "null"],
+            "multipleOf": 0.5,
+            "minimum": ranges.iter().map(|(_, r)| r.min_c).fold(f32::INFINITY, f32::min),
+            "maximum": ranges.iter().map(|(_, r)| r.max_c).fold(f32::NEG_INFINITY, f32::max),
+        },
+        "fan_speed": {"enum": enum_names::<FanSpeed>()},
+        "controller_led_brightness": {"type": ["integer", "null"], "minimum": 0, "maximum": 255},
+        "controller_location": {"type": ["string", "null"]},
+    });
+    // leaving out what the unit can't do means a generated form won't offer it
+    if state.capabilities.vane {
+        properties["vane"] = json!({"enum": enum_names::<VaneDirection>()});
+    }
+    if state.capabilities.widevane {
+        let widevanes: Vec<_> = WideVaneDirection::iter().filter(|w| !matches!(w, WideVaneDirection::Unknown))
+            .map(|w| serde_json::to_value(w).unwrap()).collect();
+        properties["widevane"] = json!({"enum": widevanes});
+    }
+    // null is always allowed, meaning "leave as is"
+    for name in ["mode", "fan_speed", "vane", "widevane"] {
+        if let Some(values) = properties[name]["enum"].as_array_mut() {
+            values.push(serde_json::Value::Null);
+        }
+    }
+
+    // the temperature range depends on the mode being set, or the current one if it isn't
+    let per_mode: Vec<_> = ranges.iter().map(|(mode, range)| {
+        let condition = if std::mem::discriminant(mode) == std::mem::discriminant(&state.mode) {
+            json!({"anyOf": [
+                {"properties": {"mode": {"const": mode}}, "required": ["mode"]},
+                {"properties": {"mode": {"type": "null"}}},
+            ]})
+        } else {
+            json!({"properties": {"mode": {"const": mode}}, "required": ["mode"]})
+        };
+        json!({
+            "if": condition,
+            "then": {"properties": {"desired_temperature_c": {"minimum": range.min_c, "maximum": range.max_c}}},
+        })
+    }).collect();
+
+    json!({
+        "$schema": "https://json-schema.org/draft/2020-12/schema",
+        "title": "HeatPumpSetting",
+        "description": "The body of a POST to set.json.  Only the most common fields are described here",
+        "type": "object",
+        "properties": properties,
+        // with clamping on, out-of-range temperatures are clamped rather than rejected
+        "allOf": if limits.clamp { vec![] } else { per_mode },
+    })
+}
+
+/// Whether the request has the token as its bearer token.  With no token set nothing is authorized
+fn bearer_authorized(req: &impl Headers, token: &Option<String>) -> bool {
+    match token {
+        Some(token) => req.header("Authorization") == Some(format!("Bearer {}", token).as_str()),
+        None => false,
+    }
+}
+
+/// set.json fields that need the current debug_token as a bearer token to change: the token itself and the OTA
+/// config.  The first debug_token can be set without one, as there's nothing to give yet
+fn check_privileged(form: &HeatPumpSetting, req: &impl Headers, token: &Option<String>) -> Result<(), FirmwareError> {
+    if bearer_authorized(req, token) {
+        return Ok(());
+    }
+    if form.debug_token.is_some() && token.is_some() {
+        return Err(FirmwareError::new(ErrorCode::Unauthorized, "changing debug_token needs the current one as a bearer token")
+                   .with_field("debug_token"));
+    }
+    // it decides where the firmware comes from
+    if form.ota.is_some() {
+        return Err(FirmwareError::new(ErrorCode::Unauthorized, "changing ota needs a debug_token to be set, and given as a bearer token")
+                   .with_field("ota"));
+    }
+    Ok(())
+}
+
+/// An uploaded asset, preferring a gzipped copy (uploaded as "<name>.gz") if the client takes gzip.  The bool is
+/// whether it's the gzipped one
+fn find_asset(name: &str, gzip: bool) -> Option<(Vec<u8>, bool)> {
+    if gzip {
+        if let Some(contents) = assets::read(&format!("{}.gz", name)) {
+            return Some((contents, true));
+        }
+    }
+    assets::read(name).map(|contents| (contents, false))
+}
+
+/// A JSON response's body and content type, or CBOR instead for a client that asks for it with Accept
+fn negotiated_body(req: &impl Headers, jval: &serde_json::Value) -> (Vec<u8>, &'static str) {
+    if cbor::accepted(req.header("Accept")) {
+        (cbor::to_vec(jval), "application/cbor")
+    } else {
+        (jval.to_string().into_bytes(), "application/json")
+    }
+}
+
+/// Sends a static body with its ETag, or just a 304 if the client already has it
+fn respond_cached(req: http::server::Request<&mut http::server::EspHttpConnection>, content_type: &str, body: &[u8],
+                  gzipped: bool, etag: &str) -> Result<(), EspIOError> {
+    if http_cache::not_modified(req.header("If-None-Match"), etag) {
+        return req.into_response(304, Some("Not Modified"), &[("ETag", etag)]).map(|_| ());
+    }
+    // no-cache still caches, it just has the browser check the ETag each time
+    let mut headers = vec![("Content-Type", content_type), ("ETag", etag), ("Cache-Control", "no-cache"),
+                           ("Vary", "Accept-Encoding")];
+    if gzipped {
+        headers.push(("Content-Encoding", "gzip"));
+    }
+    req.into_response(200, Some("OK"), &headers)?
+        .write_all(body)
+}
+
+/// The ETag for a status.json body, leaving out what changes on every request anyway
+fn status_etag(status: &serde_json::Value) -> String {
+    let mut stable = status.clone();
+    if let Some(o) = stable.as_object_mut() {
+        for field in STATUS_CLOCK_FIELDS {
+            o.remove(field);
+        }
+        if let Some(packets) = o.get_mut("last_status_packets").and_then(|p| p.as_object_mut()) {
+            for record in packets.values_mut() {
+                if let Some(r) = record.as_object_mut() {
+                    r.remove("age_secs");
+                }
+            }
+        }
+    }
+    http_cache::etag(stable.to_string().as_bytes())
+}
+
+/// The address a request came from, for telling clients apart, or None if it can't be had
+fn client_ip(connection: &mut http::server::EspHttpConnection) -> Option<IpAddr> {
+    let raw = connection.raw_connection().ok()?;
+    let fd = unsafe { hal::sys::httpd_req_to_sockfd(raw.handle()) };
+    if fd < 0 {
+        return None;
+    }
+    // the server's socket, so it mustn't be closed when this goes
+    let stream = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+    // the server listens on IPv6, so IPv4 clients show up mapped
+    stream.peer_addr().ok().map(|addr| addr.ip().to_canonical())
+}
+
+/// A query parameter's value, percent-decoded, "" if it's there without one
+fn query_param(uri: &str, name: &str) -> Option<String> {
+    let query = uri.split_once('?').map_or("", |(_, q)| q);
+    query.split('&').find_map(|param| {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        (key == name).then(|| percent_decode(value))
+    })
+}
+
+/// Undoes the escaping of a query string value: "+" for a space, and "%XX" for any byte.  A "%" not followed by two
+/// hex digits is kept as it is
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() && bytes[i + 1].is_ascii_hexdigit() && bytes[i + 2].is_ascii_hexdigit() => {
+                decoded.push(u8::from_str_radix(&value[i + 1..i + 3], 16).unwrap());
+                i += 2;
+            }
+            b => decoded.push(b),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Whether a query parameter is there and not turned off, so "?wait", "?wait=1" and "?wait=true" all count
+fn query_flag(uri: &str, name: &str) -> bool {
+    query_param(uri, name).map_or(false, |value| !matches!(value.as_str(), "0" | "false" | "no"))
+}
+
+/// Cuts status.json down to the fields asked for with ?fields=a,b,c, or says which one there's no such field as
+fn select_fields(status: serde_json::Value, fields: &str) -> Result<serde_json::Value, String> {
+    let mut all = match status {
+        serde_json::Value::Object(o) => o,
+        other => return Ok(other),
+    };
+    let mut selected = serde_json::Map::new();
+    for field in fields.split(',').filter(|f| !f.is_empty()) {
+        match all.remove(field) {
+            Some(value) => { selected.insert(field.to_string(), value); }
+            None if selected.contains_key(field) => {}
+            None => { return Err(field.to_string()); }
+        }
+    }
+    Ok(serde_json::Value::Object(selected))
+}
+
+/// For set.json?wait=true, once the setting is queued: waits for the loop to send it and the heat pump to acknowledge
+/// it, then for the status request after that, so the response has the state the setting led to
+fn wait_for_ack(state: &Mutex<HeatPumpStatus>, seq: u64, fields: [bool; 6], boot_instant: Instant, wifimacstr: &Option<String>)
+        -> (u16, &'static str, serde_json::Value) {
+    let wait_start = Instant::now();
+    loop {
+        std::thread::sleep(Duration::from_millis(50));
+        let stateg = state.lock().unwrap();
+        let timed_out = wait_start.elapsed() > SET_WAIT;
+        if stateg.acked_set_seq >= seq {
+            // acked_at is always set by then, and None is before any status
+            if stateg.status_updated > stateg.acked_at || timed_out {
+                return (200, "OK", json!({
+                    "ack": stateg.last_ack,
+                    // a later setting changed one of the same fields before this one was sent.  Being merged with
+                    // settings for other fields and sent together doesn't count
+                    "superseded": fields.iter().zip(stateg.acked_field_seq).any(|(set, acked)| *set && acked > seq),
+                    "status": status_json(&stateg, boot_instant, wifimacstr),
+                }));
+            }
+        } else if !stateg.connected {
+            return (504, "Gateway Timeout", FirmwareError::new(ErrorCode::TimedOut,
+                "lost the heat pump before it acknowledged the setting, which is still queued for when it's back").to_json());
+        } else if timed_out {
+            return (504, "Gateway Timeout", FirmwareError::new(ErrorCode::TimedOut,
+                "the heat pump didn't acknowledge the setting in time, it's still queued").to_json());
+        }
+    }
+}
+
+/// The asset name from a /assets/<name> uri, without any query string
+fn asset_name(uri: &str) -> &str {
+    let path = uri.split('?').next().unwrap_or("");
+    path.strip_prefix("/assets/").unwrap_or("")
+}
+
+/// What went into this firmware image, all worked out by build.rs
+fn version_json() -> serde_json::Value {
+    let build_secs: u64 = env!("BUILD_UNIX_SECS").parse().unwrap();
+    let features: Vec<&str> = env!("BUILD_FEATURES").split(',').filter(|f| !f.is_empty()).collect();
+    json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "git_hash": env!("BUILD_GIT_HASH"),
+        "build_time": clock::iso8601(build_secs),
+        "build_unix_secs": build_secs,
+        "features": features,
+        "chip": env!("BUILD_MCU"),
+        "esp_idf_version": env!("BUILD_ESP_IDF_VERSION"),
+    })
+}
+
+fn nvs_get_string(nvs_settings: &nvs::EspNvs<nvs::NvsDefault>, key: &str) -> anyhow::Result<Option<String>> {
+    match nvs_settings.str_len(key)? {
+        Some(size) => {
+            let mut buf = vec![0; size];
+            nvs_settings.get_str(key, &mut buf)?;
+            buf.pop(); // remove the null terminator
+            Ok(Some(String::from_utf8(buf)?))
+        }
+        None => { Ok(None) }
+    }
+}
+
+/// Reads the reply to a request, keeping track of how the bus is doing
+fn read_packet(uart: &dyn Transport, wait: Duration, bus_health: &mut BusHealth, 
+               bus_trace: &Mutex<BusTrace>) -> anyhow::Result<Option<Packet>> {
+    let reply = transport::read_reply(uart, wait, PACKET_BYTE_GAP, PACKET_READ_LIMIT)?;
+    if let Some(latency) = reply.latency {
+        bus_health.record_latency(latency);
+    }
+    bus_trace.lock().unwrap().record_rx(&reply.bytes);
+
+    if reply.skipped > 0 {
+        info!("Skipped {} bytes before the packet: {:?}", reply.skipped, &reply.bytes[..reply.skipped]);
+        bus_health.errors.bad_sync += 1;
+    }
+    bus_health.record_read(&reply.bytes[reply.skipped..], reply.packet.as_ref().err());
+    reply.packet
+}
+
+/// Waits up to `wait` for anything to come in, then reads until the line goes quiet, without trying to make a
+/// packet of it
+fn read_raw(uart: &dyn Transport, wait: Duration, bus_trace: &Mutex<BusTrace>) -> anyhow::Result<Vec<u8>> {
+    let read_start = Instant::now();
+
+    let mut bytes_read: Vec<u8> = Vec::new();
+    let mut rbuf = [0u8; 32];
+    loop {
+        let nread = uart.read(&mut rbuf, if bytes_read.is_empty() { wait } else { PACKET_BYTE_GAP })?;
+        if nread == 0 {
+            break;
+        }
+        bytes_read.extend_from_slice(&rbuf[..nread]);
+        if read_start.elapsed() > wait + PACKET_READ_LIMIT {
+            break;
+        }
+    }
+    bus_trace.lock().unwrap().record_rx(&bytes_read);
+    Ok(bytes_read)
+}
+
+fn access_point_configuration(ap_channel: u8) -> eswifi::AccessPointConfiguration {
+    // used both when no network is found at boot and as the recovery AP when the station drops
+    eswifi::AccessPointConfiguration {
+        ssid: SSID.try_into().unwrap(),
+        ssid_hidden: false,
+        auth_method: eswifi::AuthMethod::WPA2Personal,
+        password: PASSWORD.try_into().unwrap(),
+        channel: ap_channel,
+        secondary_channel: None,
+        ..Default::default()
+    }
+}
+
+fn client_configuration(ssid: &str, password: &str) -> anyhow::Result<eswifi::Configuration> {
+    Ok(eswifi::Configuration::Client(
+        eswifi::ClientConfiguration {
+        ssid: ssid.try_into().map_err(|_| anyhow::anyhow!("ssid {} is too long", ssid))?,
+        bssid: None,
+        auth_method: if password.is_empty() { eswifi::AuthMethod::None } else { eswifi::AuthMethod::WPA2Personal },
+        password: password.try_into().map_err(|_| anyhow::anyhow!("password for {} is too long", ssid))?,
+        channel: None,
+    }))
+}
+
+/// The certificate isn't copied by the supplicant, so it has to live forever.  Each different one is leaked once and
+/// kept, so reconnecting doesn't leak it again
+fn static_ca_cert(pem: &str) -> anyhow::Result<&'static std::ffi::CStr> {
+    static CA_CERTS: Mutex<Vec<&'static std::ffi::CStr>> = Mutex::new(Vec::new());
+    let mut certs = CA_CERTS.lock().unwrap();
+    if let Some(cert) = certs.iter().find(|cert| cert.to_bytes() == pem.as_bytes()).copied() {
+        return Ok(cert);
+    }
+    let cert: &'static std::ffi::CStr = Box::leak(std::ffi::CString::new(pem)?.into_boxed_c_str());
+    certs.push(cert);
+    Ok(cert)
+}
+
+fn set_eap_credentials(eap: Option<&EapConfig>, password: &str) -> anyhow::Result<()> {
+    // the enterprise settings live outside the wifi configuration, so they have to be switched off again for
+    // networks that don't use them
+    let eap = match eap {
+        Some(eap) => eap,
+        None => {
+            hal::sys::esp!(unsafe { hal::sys::esp_wifi_sta_enterprise_disable() })?;
+            return Ok(());
+        }
+    };
+    unsafe {
+        hal::sys::esp!(hal::sys::esp_eap_client_set_identity(eap.identity.as_ptr(), eap.identity.len() as i32))?;
+        hal::sys::esp!(hal::sys::esp_eap_client_set_username(eap.username.as_ptr(), eap.username.len() as i32))?;
+        hal::sys::esp!(hal::sys::esp_eap_client_set_password(password.as_ptr(), password.len() as i32))?;
+        if let Some(ca_cert) = &eap.ca_cert {
+            let pem_bytes = static_ca_cert(ca_cert)?.to_bytes_with_nul();
+            hal::sys::esp!(hal::sys::esp_eap_client_set_ca_cert(pem_bytes.as_ptr(), pem_bytes.len() as i32))?;
+        }
+        hal::sys::esp!(hal::sys::esp_wifi_sta_enterprise_enable())?;
+    }
+    Ok(())
+}
+
+fn connect_to_network(wifi: &mut BlockingWifi<EspWifi>, network: &WifiNetwork) -> anyhow::Result<()> {
+    let password = network.password.as_deref().unwrap_or("");
+    match &network.eap {
+        Some(_) => {
+            let mut configuration = client_configuration(&network.ssid, "")?;
+            if let eswifi::Configuration::Client(c) = &mut configuration {
+                c.auth_method = eswifi::AuthMethod::WPA2Enterprise;
+            }
+            wifi.set_configuration(&configuration)?;
+        }
+        None => {
+            wifi.set_configuration(&client_configuration(&network.ssid, password)?)?;
+        }
+    }
+    set_eap_credentials(network.eap.as_ref(), password)?;
+    wifi.connect()?;
+    wifi.ip_wait_while(|| wifi.wifi().is_up().map(|s| !s), Some(CONNECT_TIMEOUT))?;
+    Ok(())
+}
+
+/// Brings up just the network and enough of a web server to see what went wrong, for when the firmware keeps crashing.
+/// POSTing to /safe_mode/exit boots normally again
+fn run_safe_mode(modem: hal::modem::Modem, nvs_partition: nvs::EspDefaultNvsPartition,
+                 mut nvs_settings: nvs::EspNvs<nvs::NvsDefault>, crash_streak: u32) -> anyhow::Result<()> {
+    info!("{} crashes in a row, starting in safe mode", crash_streak);
+    let wifi_ap_channel = nvs_settings.get_u8("wifi_channel")?.unwrap_or(WIFI_CHANNEL.parse().unwrap());
+    let wifi_country = nvs_get_string(&nvs_settings, "wifi_country")?;
+    let wifi_networks = load_wifi_networks(&nvs_settings)?;
+    let static_ip = load_static_ip(&nvs_settings)?;
+    let (_wifi, _) = match setup_wifi(modem, nvs_partition, wifi_ap_channel, wifi_country.as_deref(), &wifi_networks, &static_ip, false,
+                                      &mut |phase| { info!("Safe mode startup phase: {:?}", phase); Ok(()) }) {
+        Ok(res) => res,
+        Err(e) => {
+            info!("wifi did not start in safe mode due to {}. Waiting {} secs and then restarting!",
+                  e, WIFI_DISCONNECTED_RESET_TIME.as_secs_f32());
+            std::thread::sleep(WIFI_DISCONNECTED_RESET_TIME);
+            reset::restart();
+            return Err(e);
+        }
+    };
+
+    let diagnostics = json!({
+        "safe_mode": true,
+        "crash_streak": crash_streak,
+        "reset_reason": format!("{:?}", reset::ResetReason::get()),
+        "boot_count": nvs_settings.get_u32("boot_count")?,
+        "crash_log": nvs_get_string(&nvs_settings, "crash_log")?
+            .and_then(|crashjson| serde_json::from_str::<serde_json::Value>(&crashjson).ok()),
+        "version": version_json(),
+    });
+
+    let server_configuration = http::server::Configuration {
+        stack_size: HTTP_SERVER_STACK_SIZE,
+        http_port: HTTP_PORT,
+        ..Default::default()
+    };
+    let mut server = http::server::EspHttpServer::new(&server_configuration)?;
+    let safe_mode_handler = move |req: http::server::Request<&mut http::server::EspHttpConnection>| {
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write_all(diagnostics.to_string().as_bytes())
+    };
+    server.fn_handler("/", http::Method::Get, safe_mode_handler.clone())?;
+    server.fn_handler("/safe_mode.json", http::Method::Get, safe_mode_handler)?;
+    server.fn_handler("/version.json", http::Method::Get, |req| {
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write_all(version_json().to_string().as_bytes())
+    })?;
+    let exit_requested = Arc::new(AtomicBool::new(false));
+    let handler_exit = exit_requested.clone();
+    server.fn_handler("/safe_mode/exit", http::Method::Post, move |req| {
+        info!("leaving safe mode on request");
+        handler_exit.store(true, Ordering::Relaxed);
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write_all(json!({"restarting": true}).to_string().as_bytes())
+    })?;
+
+    // NVS is only written from here, like the main loop does
+    while !exit_requested.load(Ordering::Relaxed) {
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    safe_mode::clear(&mut nvs_settings)?;
+    std::thread::sleep(Duration::from_millis(500));
+    reset::restart();
+    Ok(())
+}
+
+fn setup_wifi<'a>(pmodem: hal::modem::Modem, dnvs: nvs::EspDefaultNvsPartition, ap_channel: u8, country: Option<&str>,
+                  networks: &[WifiNetwork], static_ip: &StaticIpConfig, force_ap: bool,
+                  on_phase: &mut dyn FnMut(StartupPhase) -> anyhow::Result<()>) -> anyhow::Result<(BlockingWifi<EspWifi<'a>>, Option<[u8; 6]>)> {
+    let sys_loop = EspSystemEventLoop::take()?;
+
+    let mut wifi = BlockingWifi::wrap(
+        EspWifi::wrap_all(WifiDriver::new(pmodem, sys_loop.clone(), Some(dnvs))?,
+                          static_ip.sta_netif()?, EspNetif::new(NetifStack::Ap)?)?,
+        sys_loop,
     )?;
-    let mut watchdog = twdt_driver.watch_current_task()?;
 
-    info!("Setup complete!");
+    if let Some(cc) = country {
+        // the regulatory domain determines which channels and tx powers are allowed, so set it before starting anything
+        info!("setting wifi country code to {}", cc);
+        let cc_cstr = std::ffi::CString::new(cc)?;
+        hal::sys::esp!(unsafe { hal::sys::esp_wifi_set_country_code(cc_cstr.as_ptr(), true) })?;
+    }
+
+    wifi.set_configuration(&client_configuration(SSID, PASSWORD)?)?;
+
+    wifi.start()?;
+
+    // the stored networks in priority order, then the compiled-in one as the last resort
+    let mut candidates = networks.to_vec();
+    if !candidates.iter().any(|n| n.ssid == SSID) {
+        candidates.push(WifiNetwork { ssid: SSID.to_string(), password: Some(PASSWORD.to_string()), eap: None });
+    }
+
+    // first scan to check which are around
+    on_phase(StartupPhase::WifiScan)?;
+    let scan_results = if force_ap { Vec::new() } else { wifi.scan()? };
+    let available: Vec<&WifiNetwork> = candidates.iter()
+        .filter(|n| scan_results.iter().any(|r| r.ssid.as_str() == n.ssid))
+        .collect();
+
+    on_phase(StartupPhase::WifiConnect)?;
+    if !available.is_empty() {
+        // fall back down the list if connecting fails, giving the last error if none work
+        let mut result = Ok(());
+        for network in available {
+            info!("found ssid {}, connecting", network.ssid);
+            result = connect_to_network(&mut wifi, network);
+            match &result {
+                Ok(()) => { break; }
+                Err(e) => {
+                    info!("could not connect to {}: {}", network.ssid, e);
+                    let _ = wifi.disconnect();
+                }
+            }
+        }
+        result?;
+    } else if RESET_ON_SSID_NOT_FOUND == "yes" && !force_ap {
+        info!("Did not find any of {:?} in list {:?}!", candidates.iter().map(|n| &n.ssid).collect::<Vec<_>>(), scan_results);
+        return Err(NoSSIDError{}.into());
+    } else {
+        if force_ap {
+            info!("Reprovisioning, so not looking for networks and creating AP w/ ssid: {}", SSID);
+        } else {
+            info!("Did not find ssid in list below, so creating AP w/ ssid: {}", SSID);
+        }
+        info!("Scan Results: {:?}", scan_results);
+        wifi.stop()?;
+        
+        let wifi_configuration_ap = eswifi::Configuration::AccessPoint(access_point_configuration(ap_channel));
+        
+        wifi.set_configuration(&wifi_configuration_ap)?;
+        
+        wifi.start()?;
+    }
+
+    //wifi.wait_netif_up()?;
+    // the below is exactly what the above does as of this writing, but allows for a custom timeout
+    // wich is necessary for some esp32c6 chips on at least some networks.
+    on_phase(StartupPhase::Ip)?;
+    wifi.ip_wait_while(|| wifi.wifi().is_up().map(|s| !s), Some(CONNECT_TIMEOUT))?;
+
+    let maco = match wifi.get_configuration()? {
+        eswifi::Configuration::Client(c) => {
+            let ip = wifi.wifi().sta_netif().get_ip_info()?;
+            info!("Connected to {} w/ip info: {:?}", c.ssid, ip);
+            Some(wifi.wifi().get_mac(WifiDeviceId::Sta)?)
+        },
+        eswifi::Configuration::AccessPoint(a) => {
+            let ip = wifi.wifi().ap_netif().get_ip_info()?;
+            info!("Created AP {} w/ip info:  {:?}", a.ssid, ip);
+            Some(wifi.wifi().get_mac(WifiDeviceId::Ap)?)
+        }
+        _ => {
+            info!("Unexpected configuration, no IP address");
+            None // Not sure what the configuration is so don't know which MAC to give
+        }
+
+    };
+
+    Ok((wifi, maco))
+}
+
+fn setup_handlers(server: &mut http::server::EspHttpServer, state: Arc<Mutex<HeatPumpStatus>>, 
+                  boot_instant: Instant, wifimacstr:Option<String>, nvs_partition: nvs::EspDefaultNvsPartition) -> Result<(), EspError> {
+
+    let index_handler = |req: http::server::Request<&mut http::server::EspHttpConnection>| {
+        let gzip = http_cache::accepts_gzip(req.header("Accept-Encoding"));
+        if let Some((html, gzipped)) = find_asset("index.html", gzip) {
+            let etag = http_cache::etag(&html);
+            return respond_cached(req, "text/html", &html, gzipped, &etag);
+        }
+        // better an honest error than a page that half-works
+        if !integrity::asset_ok("index.html") {
+            return req.into_response(500, Some("Internal Server Error"), &[("Content-Type", "application/json")])?
+                .write_all(FirmwareError::new(ErrorCode::CorruptAsset, "index.html is corrupt in this firmware image, see /system.json")
+                           .to_json().to_string().as_bytes());
+        }
+        // the crcs are worked out at build time, so don't need working out again here
+        if gzip {
+            let etag = format!("\"{:08x}\"", env!("INDEX_HTML_GZ_CRC32").parse::<u32>().unwrap());
+            respond_cached(req, "text/html", INDEX_HTML_GZ, true, &etag)
+        } else {
+            let etag = format!("\"{:08x}\"", env!("INDEX_HTML_CRC32").parse::<u32>().unwrap());
+            respond_cached(req, "text/html", INDEX_HTML.as_bytes(), false, &etag)
+        }
+    };
+
+    server.fn_handler("/", http::Method::Get, index_handler)?;
+    server.fn_handler("/index.html", http::Method::Get, index_handler)?;
+
+
+    let own_mac = wifimacstr.clone().unwrap_or_default();  // the status handler below takes the original
+    let set_mac = wifimacstr.clone();
+
+    let inner_state1 = state.clone();
+
+    server.fn_handler("/status.json", http::Method::Get, move |req| {
+        let mut resp = status_json(&inner_state1.lock().unwrap(), boot_instant, &wifimacstr);
+        if let Some(fields) = query_param(req.uri(), "fields") {
+            resp = match select_fields(resp, &fields) {
+                Ok(selected) => selected,
+                Err(field) => {
+                    let errjson = FirmwareError::new(ErrorCode::InvalidSetting, format!("status.json has no field {:?}", field)).with_field(&field).to_json();
+                    return req.into_response(400, Some("Bad Request"), &[("Content-Type", "application/json")])?
+                        .write_all(errjson.to_string().as_bytes());
+                }
+            };
+        }
+
+        // so a poller can skip the body when nothing but the clock has moved on
+        let mut etag = status_etag(&resp);
+        let (body, content_type) = negotiated_body(&req, &resp);
+        if content_type == "application/cbor" {
+            // the same status as CBOR is a different body, so needs its own ETag
+            etag.insert_str(etag.len() - 1, "-cbor");
+        }
+        if http_cache::not_modified(req.header("If-None-Match"), &etag) {
+            return req.into_response(304, Some("Not Modified"), &[("ETag", etag.as_str())]).map(|_| ());
+        }
+        let response_headers = &[("Content-Type", content_type), ("ETag", etag.as_str()), ("Cache-Control", "no-cache"), ("Vary", "Accept")];
+        req.into_response(200, Some("OK"), response_headers)?
+        .write_all(&body)
+        .map(|_| ())
+    })?;
+
+
+    let inner_state2 = state.clone();
+
+    server.fn_handler("/set.json", http::Method::Post, move |mut req| {
+        let wait = query_flag(req.uri(), "wait");
+        let client = client_ip(req.connection());
+        let limited = {
+            let mut stateg = inner_state2.lock().unwrap();
+            let (per_min, client_per_min) = (stateg.config.set_rate_per_min, stateg.config.set_client_rate_per_min);
+            stateg.set_limiter.check(client, per_min, client_per_min)
+        };
+        if let Err(retry_after) = limited {
+            let retry_secs = (retry_after.as_secs() + 1).to_string();
+            info!("turning away a set.json from {:?}, over the rate limit", client);
+            req.into_response(429, Some("Too Many Requests"), &[("Content-Type", "application/json"), ("Retry-After", retry_secs.as_str())])?
+                .write_all(FirmwareError::new(ErrorCode::RateLimited, format!("too many set requests, try again in {} seconds", retry_secs))
+                           .to_json().to_string().as_bytes())?;
+            return Ok(());
+        }
+
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > HTTP_SERVER_MAX_LEN {
+            req.into_response(413, Some("Payload Too Large"), &[("Content-Type", "application/json")])?
+                .write_all(FirmwareError::new(ErrorCode::RequestTooBig, "Request too big").to_json().to_string().as_bytes())?;
+        } else {
+            let mut buf = vec![0; len];
+            req.read_exact(&mut buf)?;
+            
+            match serde_json::from_slice::<HeatPumpSetting>(&buf) {
+                Ok(mut form) => {
+                    let response_headers = &[("Content-Type", "application/json")];
+                    let mut stateg = inner_state2.lock().unwrap();
+
+                    // worked out under the lock, but written after it's dropped, so a slow client can't hold up the main loop
+                    let refused = if let Err(e) = check_privileged(&form, &req, &stateg.debug_token) {
+                        Some((401, "Unauthorized", e.to_json()))
+                    } else if !stateg.connected && form.requires_packet() {
+                        Some((409, "Conflict", unavailable_json(&stateg)))
+                    } else if let Err(errjson) = validate_setting(&mut form, &stateg) {
+                        Some((422, "Unprocessable Entity", errjson))
+                    } else {
+                        None
+                    };
+                    if let Some((status, reason, errjson)) = refused {
+                        drop(stateg);
+                        req.into_response(status, Some(reason), response_headers)?
+                            .write_all(errjson.to_string().as_bytes())?;
+                        return Ok(());
+                    }
+
+                    if let Some(temperature_c) = form.desired_temperature_c {
+                        // a temperature from the user is the new target for the PID to trim around
+                        stateg.pid.set_user_target(temperature_c);
+                    }
+
+                    // controller-only settings don't go to the heat pump, so have nothing to wait for
+                    if wait && form.requires_packet() {
+                        let fields = form.unit_fields_set();
+                        let seq = stateg.queue_setting(form);
+                        drop(stateg);
+                        let (status, reason, jval) = wait_for_ack(&inner_state2, seq, fields, boot_instant, &set_mac);
+                        req.into_response(status, Some(reason), response_headers)?.write_all(jval.to_string().as_bytes())?;
+                        return Ok(());
+                    }
+
+                    let jval = serde_json::to_value(&form).unwrap();
+                    stateg.queue_setting(form);
+                    drop(stateg);
+                    req.into_response(200, Some("OK"), response_headers)?.write_all(jval.to_string().as_bytes())?;
+                }
+                Err(e) => {
+                    let (status, reason, errjson) = setting_json_error(&buf, &e);
+                    req.into_response(status, Some(reason), &[("Content-Type", "application/json")])?
+                        .write_all(errjson.to_string().as_bytes())?;
+                }
+            }
+        }
+        
+        Ok::<(), FirmwareError>(())
+    })?;
+
+
+    let inner_state3 = state.clone();
+
+    server.fn_handler("/presets.json", http::Method::Get, move |req| {
+        let presetsjson = serde_json::to_string(&inner_state3.lock().unwrap().presets).unwrap();
+
+        let response_headers = &[("Content-Type", "application/json")];
+        req.into_response(200, Some("OK"), response_headers)?
+        .write_all(presetsjson.as_bytes())
+        .map(|_| ())
+    })?;
+
+
+    let inner_state4 = state.clone();
+
+    server.fn_handler("/presets.json", http::Method::Post, move |mut req| {
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > HTTP_SERVER_MAX_LEN {
+            req.into_response(413, Some("Payload Too Large"), &[("Content-Type", "application/json")])?
+                .write_all(FirmwareError::new(ErrorCode::RequestTooBig, "Request too big").to_json().to_string().as_bytes())?;
+        } else {
+            let mut buf = vec![0; len];
+            req.read_exact(&mut buf)?;
+
+            match serde_json::from_slice::<PresetRequest>(&buf) {
+                Ok(preset) => {
+                    let response_headers = &[("Content-Type", "application/json")];
+                    let mut stateg = inner_state4.lock().unwrap();
+                    match preset.setting {
+                        Some(mut setting) => {
+                            // anyone can apply a preset, so it can't carry anything that needs the token
+                            setting.drop_privileged();
+                            if let Err(errjson) = validate_setting(&mut setting, &stateg) {
+                                req.into_response(422, Some("Unprocessable Entity"), response_headers)?
+                                    .write_all(errjson.to_string().as_bytes())?;
+                                return Ok(());
+                            }
+                            info!("saving preset {:?}: {:?}", preset.name, setting);
+                            stateg.presets.insert(preset.name, setting);
+                        }
+                        None => {
+                            info!("deleting preset {:?}", preset.name);
+                            stateg.presets.remove(&preset.name);
+                        }
+                    }
+                    stateg.presets_dirty = true;
+
+                    let presetsjson = serde_json::to_string(&stateg.presets).unwrap();
+                    req.into_response(200, Some("OK"), response_headers)?.write_all(presetsjson.as_bytes())?;
+                }
+                Err(e) => {
+                    req.into_response(400, Some("Bad Request"), &[("Content-Type", "application/json")])?
+                        .write_all(FirmwareError::from_json_error(&buf, &e).to_json().to_string().as_bytes())?;
+                }
+            }
+        }
+
+        Ok::<(), FirmwareError>(())
+    })?;
 
-    let mut last_status_request = Instant::now() - RESPONSE_DELAY;
 
-    // serve and loop forever...
-    loop {
-        let loopstart = Instant::now();
-        watchdog.feed()?;
+    let inner_state5 = state.clone();
+
+    server.fn_handler("/preset.json", http::Method::Post, move |mut req| {
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > HTTP_SERVER_MAX_LEN {
+            req.into_response(413, Some("Payload Too Large"), &[("Content-Type", "application/json")])?
+                .write_all(FirmwareError::new(ErrorCode::RequestTooBig, "Request too big").to_json().to_string().as_bytes())?;
+        } else {
+            let mut buf = vec![0; len];
+            req.read_exact(&mut buf)?;
+
+            match serde_json::from_slice::<PresetRequest>(&buf) {
+                Ok(preset) => {
+                    let mut stateg = inner_state5.lock().unwrap();
+                    match stateg.presets.get(&preset.name).cloned() {
+                        Some(mut setting) => {
+                            let response_headers = &[("Content-Type", "application/json")];
+                            if !stateg.connected {
+                                req.into_response(409, Some("Conflict"), response_headers)?
+                                    .write_all(unavailable_json(&stateg).to_string().as_bytes())?;
+                                return Ok(());
+                            }
+                            // the limits may have changed since the preset was saved, so check again
+                            if let Err(errjson) = validate_setting(&mut setting, &stateg) {
+                                req.into_response(422, Some("Unprocessable Entity"), response_headers)?
+                                    .write_all(errjson.to_string().as_bytes())?;
+                                return Ok(());
+                            }
+                            info!("applying preset {:?}", preset.name);
+                            let jval = serde_json::to_value(&setting).unwrap();
+                            req.into_response(200, Some("OK"), response_headers)?.write_all(jval.to_string().as_bytes())?;
+                            stateg.queue_setting(setting);
+                        }
+                        None => {
+                            req.into_response(404, Some("Not Found"), &[("Content-Type", "application/json")])?
+                                .write_all(FirmwareError::new(ErrorCode::NotFound, format!("No preset named {:?}", preset.name))
+                                           .to_json().to_string().as_bytes())?;
+                        }
+                    }
+                }
+                Err(e) => {
+                    req.into_response(400, Some("Bad Request"), &[("Content-Type", "application/json")])?
+                        .write_all(FirmwareError::from_json_error(&buf, &e).to_json().to_string().as_bytes())?;
+                }
+            }
+        }
+
+        Ok::<(), FirmwareError>(())
+    })?;
+
+    let inner_state6 = state.clone();
+
+    server.fn_handler("/away.json", http::Method::Post, move |mut req| {
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > HTTP_SERVER_MAX_LEN {
+            req.into_response(413, Some("Payload Too Large"), &[("Content-Type", "application/json")])?
+                .write_all(FirmwareError::new(ErrorCode::RequestTooBig, "Request too big").to_json().to_string().as_bytes())?;
+        } else {
+            let mut buf = vec![0; len];
+            req.read_exact(&mut buf)?;
+
+            match serde_json::from_slice::<AwayRequest>(&buf) {
+                Ok(away) => {
+                    let response_headers = &[("Content-Type", "application/json")];
+                    let mut stateg = inner_state6.lock().unwrap();
+
+                    // both starting and ending away mode send to the heat pump
+                    if !stateg.connected && (away.enabled || stateg.away.is_some()) {
+                        req.into_response(409, Some("Conflict"), response_headers)?
+                            .write_all(unavailable_json(&stateg).to_string().as_bytes())?;
+                        return Ok(());
+                    }
+
+                    if away.enabled {
+                        let frost_protection = away.frost_protection.unwrap_or(true);
+                        let setpoint_c = away.setpoint_c.unwrap_or(AWAY_DEFAULT_FROST_SETPOINT_C);
+
+                        let mut setting = HeatPumpSetting::new();
+                        if frost_protection {
+                            setting.poweron = Some(true);
+                            setting.mode = Some(HeatPumpMode::Heat);
+                            setting.desired_temperature_c = Some(setpoint_c);
+                        } else {
+                            setting.poweron = Some(false);
+                        }
+                        if let Err(errjson) = validate_setting(&mut setting, &stateg) {
+                            req.into_response(422, Some("Unprocessable Entity"), response_headers)?
+                                .write_all(errjson.to_string().as_bytes())?;
+                            return Ok(());
+                        }
 
-        led_brightness = nvs_settings.get_u8("led_brightness")?.unwrap_or(LED_DEFAULT_BRIGHTNESS);
+                        // if we're already away (or boosting) keep the original settings to restore rather than
+                        // the away ones
+                        let restore = match (stateg.away.take(), stateg.boost.take()) {
+                            (Some(previous), _) => previous.restore,
+                            (None, Some(boost)) => boost.restore,
+                            (None, None) => stateg.current_setting(),
+                        };
+                        let duration = away.duration_secs.map(Duration::from_secs);
+                        info!("entering away mode for {:?}, frost protection: {}", duration, frost_protection);
+                        stateg.away = Some(AwayState {
+                            frost_protection,
+                            setpoint_c,
+                            remaining_secs: duration.map(|d| d.as_secs_f32()),
+                            ends: duration.map(|d| Instant::now() + d),
+                            restore,
+                        });
+                        stateg.queue_setting(setting);
+                    } else {
+                        stateg.end_away();
+                    }
 
-        let controller_location = match nvs_settings.str_len("controller_loc")? {
-            Some(size) => {
-                let mut controller_location_buf = vec![0; size];
-                nvs_settings.get_str("controller_loc", &mut controller_location_buf)?;
-                controller_location_buf.pop(); // remove the null terminator
-                Some(String::from_utf8(controller_location_buf)?)
+                    let jval = json!({"away": stateg.away});
+                    req.into_response(200, Some("OK"), response_headers)?.write_all(jval.to_string().as_bytes())?;
+                }
+                Err(e) => {
+                    req.into_response(400, Some("Bad Request"), &[("Content-Type", "application/json")])?
+                        .write_all(FirmwareError::from_json_error(&buf, &e).to_json().to_string().as_bytes())?;
+                }
             }
-            None => { None }
-        };
+        }
 
-        let (connected, mut data_to_send) = { 
-            let mut realstate = state.lock().unwrap();
+        Ok::<(), FirmwareError>(())
+    })?;
 
-            // update state from what we got from nvs just above
-            realstate.controller_led_brightness = led_brightness;
-            realstate.controller_location = controller_location;
 
-            (realstate.connected, realstate.desired_settings.is_some())
-         };  
+    let inner_state7 = state.clone();
 
+    server.fn_handler("/remote_temperature.json", http::Method::Post, move |mut req| {
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > HTTP_SERVER_MAX_LEN {
+            req.into_response(413, Some("Payload Too Large"), &[("Content-Type", "application/json")])?
+                .write_all(FirmwareError::new(ErrorCode::RequestTooBig, "Request too big").to_json().to_string().as_bytes())?;
+        } else {
+            let mut buf = vec![0; len];
+            req.read_exact(&mut buf)?;
 
-        // update the LED state at the start of the loop based on connected status
-        if connected {
-            // green for connected
-            set_led(0, led_brightness, 0, &mut npx, &led_off_sense_pin)?;
+            match serde_json::from_slice::<RemoteTemperature>(&buf) {
+                Ok(remote) => {
+                    let mut stateg = inner_state7.lock().unwrap();
+                    stateg.thermostat.set_remote_temperature(remote.temperature_c);
+
+                    let response_headers = &[("Content-Type", "application/json")];
+                    let jval = serde_json::to_value(&stateg.thermostat).unwrap();
+                    req.into_response(200, Some("OK"), response_headers)?.write_all(jval.to_string().as_bytes())?;
+                }
+                Err(e) => {
+                    req.into_response(400, Some("Bad Request"), &[("Content-Type", "application/json")])?
+                        .write_all(FirmwareError::from_json_error(&buf, &e).to_json().to_string().as_bytes())?;
+                }
+            }
+        }
+
+        Ok::<(), FirmwareError>(())
+    })?;
+
+
+    let inner_state8 = state.clone();
+
+    server.fn_handler("/restart_subsystem.json", http::Method::Post, move |mut req| {
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > HTTP_SERVER_MAX_LEN {
+            req.into_response(413, Some("Payload Too Large"), &[("Content-Type", "application/json")])?
+                .write_all(FirmwareError::new(ErrorCode::RequestTooBig, "Request too big").to_json().to_string().as_bytes())?;
         } else {
-            // magenta for disconnected
-            set_led(led_brightness, 0, led_brightness, &mut npx, &led_off_sense_pin)?;
+            let mut buf = vec![0; len];
+            req.read_exact(&mut buf)?;
+
+            match serde_json::from_slice::<SubsystemRestartRequest>(&buf) {
+                Ok(restart) => {
+                    info!("requested restart of {:?}", restart.subsystem);
+                    inner_state8.lock().unwrap().pending_subsystem_restart = Some(restart.subsystem);
+
+                    let response_headers = &[("Content-Type", "application/json")];
+                    req.into_response(200, Some("OK"), response_headers)?
+                        .write_all(json!({"restarting": restart.subsystem}).to_string().as_bytes())?;
+                }
+                Err(e) => {
+                    req.into_response(400, Some("Bad Request"), &[("Content-Type", "application/json")])?
+                        .write_all(FirmwareError::from_json_error(&buf, &e).to_json().to_string().as_bytes())?;
+                }
+            }
         }
 
-        // check whether we need to reset because of a disconnected wifi
-        if ! wifi.is_connected()? {
-            info!("Wifi disconnected! Restarting after pause of {} secs", WIFI_DISCONNECTED_RESET_TIME.as_secs_f32());
-            
-            // this waits until WIFI_DISCONNECTED_RESET_TIME, blinking the red LED every half-second
-            let start_countdown = Instant::now();
-            let mut toggle_time = start_countdown;
-            while start_countdown.elapsed() < WIFI_DISCONNECTED_RESET_TIME {
-                if toggle_time.elapsed() < Duration::from_millis(250) {
-                    set_led(led_brightness, 0, 0, &mut npx, &led_off_sense_pin)?;
-                } else if toggle_time.elapsed() < Duration::from_millis(500) {
-                    set_led(0, 0, 0, &mut npx, &led_off_sense_pin)?;
-                } else {
-                    toggle_time = Instant::now();
+        Ok::<(), FirmwareError>(())
+    })?;
+
+    let inner_state9 = state.clone();
+
+    server.fn_handler("/peers.json", http::Method::Get, move |req| {
+        let stateg = inner_state9.lock().unwrap();
+        let peersjson = json!({
+            "peers": stateg.peers,
+            "secs_since_update": stateg.peers_updated.map(|t| t.elapsed().as_secs_f32()),
+        });
+
+        let response_headers = &[("Content-Type", "application/json")];
+        req.into_response(200, Some("OK"), response_headers)?
+        .write_all(peersjson.to_string().as_bytes())
+        .map(|_| ())
+    })?;
+
+    let inner_state10 = state.clone();
+
+    server.fn_handler("/set/dry-run", http::Method::Post, move |mut req| {
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > HTTP_SERVER_MAX_LEN {
+            req.into_response(413, Some("Payload Too Large"), &[("Content-Type", "application/json")])?
+                .write_all(FirmwareError::new(ErrorCode::RequestTooBig, "Request too big").to_json().to_string().as_bytes())?;
+        } else {
+            let mut buf = vec![0; len];
+            req.read_exact(&mut buf)?;
+
+            match serde_json::from_slice::<HeatPumpSetting>(&buf) {
+                Ok(mut form) => {
+                    // exactly what set.json would do, except that nothing is queued for the heat pump
+                    let response_headers = &[("Content-Type", "application/json")];
+                    let stateg = inner_state10.lock().unwrap();
+
+                    if let Err(errjson) = validate_setting(&mut form, &stateg) {
+                        req.into_response(422, Some("Unprocessable Entity"), response_headers)?
+                            .write_all(errjson.to_string().as_bytes())?;
+                        return Ok(());
+                    }
+
+                    let packetjson = if form.requires_packet() {
+                        let packet = form.to_packet();
+                        let mut buf = [0u8; PACKET_SIZE_MAX];
+                        let bytes = packet.to_bytes(&mut buf);
+                        json!({
+                            "hex": bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "),
+                            "bytes": bytes,
+                            "flags": HeatPumpSetting::packet_flags(&packet),
+                        })
+                    } else {
+                        serde_json::Value::Null
+                    };
+
+                    let jval = json!({
+                        "setting": form,
+                        "packet": packetjson,
+                        "predicted_state": form.predict(&stateg),
+                    });
+                    req.into_response(200, Some("OK"), response_headers)?.write_all(jval.to_string().as_bytes())?;
+                }
+                Err(e) => {
+                    let (status, reason, errjson) = setting_json_error(&buf, &e);
+                    req.into_response(status, Some(reason), &[("Content-Type", "application/json")])?
+                        .write_all(errjson.to_string().as_bytes())?;
                 }
             }
-            reset::restart();
         }
-        
 
-        // This is the business part of the loop
-        
-        if connected {
-            if data_to_send {
-                let mut realstate = state.lock().unwrap();
+        Ok::<(), FirmwareError>(())
+    })?;
 
-                let desired_settings = realstate.desired_settings.as_ref().unwrap();
-                if desired_settings.requires_packet() {
-                    let packet_to_send = desired_settings.to_packet();
+    // each websocket session's position in the log, polled by sending "logs?"
+    let log_sessions = Arc::new(Mutex::new(HashMap::<i32, u64>::new()));
+    let inner_log_sessions = log_sessions.clone();
+    server.ws_handler("/ws/logs", move |ws| {
+        let mut sessions = log_sessions.lock().unwrap();
+        if ws.is_new() {
+            // start from the oldest line kept so the browser gets some history
+            sessions.insert(ws.session(), syslog::LOGGER.oldest_seq());
+        } else if ws.is_closed() {
+            sessions.remove(&ws.session());
+        } else {
+            let (frame_type, len) = ws.recv(&mut [])?;
+            let mut rvec = vec![0u8; len];
+            ws.recv(rvec.as_mut_slice())?;
+            if let Some(v) = rvec.pop() {
+                if v != 0 { rvec.push(v);}
+            }
+
+            match frame_type {
+                FrameType::Text(false) if rvec.as_slice() == b"logs?" => {
+                    let seq = sessions.get_mut(&ws.session())
+                        .ok_or(FirmwareError::new(ErrorCode::WsSessionMissing, "no websocket session to send to"))?;
+                    let (lines, next_seq) = syslog::LOGGER.lines_since(*seq);
+                    *seq = next_seq;
+                    if !lines.is_empty() {
+                        ws.send(FrameType::Text(false), lines.join("\n").as_bytes())?;
+                    }
+                }
+                _ => {
+                    // logging this would just echo back into the stream, so ignore it quietly
+                }
+            }
+        }
+        Ok::<(), FirmwareError>(())
+    })?;
 
-                    info!("Writing to heat pump: {:?}", packet_to_send.to_bytes());
-                    uart.write(&packet_to_send.to_bytes())?;
+    let inner_state11 = state.clone();
 
-                    // now check that we got a packet back
-                    let wait_start = Instant::now();
-                    while wait_start.elapsed() < RESPONSE_DELAY {
-                        if uart.remaining_read()? > 0 {
-                            break;
-                        }
-                        std::thread::sleep(Duration::from_millis(5));
+    server.fn_handler("/boost", http::Method::Post, move |mut req| {
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > HTTP_SERVER_MAX_LEN {
+            req.into_response(413, Some("Payload Too Large"), &[("Content-Type", "application/json")])?
+                .write_all(FirmwareError::new(ErrorCode::RequestTooBig, "Request too big").to_json().to_string().as_bytes())?;
+        } else {
+            let mut buf = vec![0; len];
+            req.read_exact(&mut buf)?;
+
+            match serde_json::from_slice::<BoostRequest>(&buf) {
+                Ok(boost) => {
+                    let response_headers = &[("Content-Type", "application/json")];
+                    let mut stateg = inner_state11.lock().unwrap();
+
+                    if !stateg.connected && (boost.enabled || stateg.boost.is_some()) {
+                        req.into_response(409, Some("Conflict"), response_headers)?
+                            .write_all(unavailable_json(&stateg).to_string().as_bytes())?;
+                        return Ok(());
                     }
-                    match read_packet(&uart)? {
-                        Some(p) => { 
-                            if p.packet_type == 0x61 {
-                                info!("Got expected response to setting change request: {:?}", p);
-                                data_to_send = false;
-                            } else {
-                                panic!("Got unexpected packet type in response to setting change request: {:?}", p);
-                            }
-                        }
-                        None => {
-                            info!("No response to setting change request, assuming disconnected");
-                            realstate.connected = false;
+
+                    if boost.enabled {
+                        if stateg.away.is_some() {
+                            req.into_response(409, Some("Conflict"), response_headers)?
+                                .write_all(FirmwareError::new(ErrorCode::Busy, "cannot boost while away mode is on").to_json().to_string().as_bytes())?;
+                            return Ok(());
                         }
-                    };
-                } else {
-                    data_to_send = false;
-                }
-
-            } else if last_status_request.elapsed() > RESPONSE_DELAY {
-                info!("Requesting status");
-                // First make sure there's no junk left unread in the uart
-                while uart.remaining_read()? > 0 { uart.read(&mut [0u8; 1], 1)?; }
-
-                let mut all_done = false;
-                // ask for status from a subset of status packets
-                for ptype in StatusPacketType::iter() {
-                    all_done = false;
-                    let mut packet = Packet::new_type_size(0x42, 16);
-                    packet.data[0] = ptype as u8;
-                    packet.set_checksum();
-                    uart.write(&packet.to_bytes())?;
-
-                    // wait for the delay time, if no response after that, we probably got disconnected?
-                    let wait_start = Instant::now();
-                    while wait_start.elapsed() < RESPONSE_DELAY {
-                        if uart.remaining_read()? > 0 {
-                            break;
+
+                        let mut setting = stateg.boost_setting(boost.setpoint_c);
+                        if let Err(errjson) = validate_setting(&mut setting, &stateg) {
+                            req.into_response(422, Some("Unprocessable Entity"), response_headers)?
+                                .write_all(errjson.to_string().as_bytes())?;
+                            return Ok(());
                         }
-                        std::thread::sleep(Duration::from_millis(5));
+
+                        // boosting again just extends it, going back to what was there before the first boost
+                        let restore = match stateg.boost.take() {
+                            Some(previous) => previous.restore,
+                            None => stateg.current_setting(),
+                        };
+                        let duration = Duration::from_secs(boost.duration_secs.unwrap_or(BOOST_DEFAULT_DURATION_SECS));
+                        info!("boosting for {:?} with {:?}", duration, setting);
+                        stateg.boost = Some(BoostState {
+                            setpoint_c: setting.desired_temperature_c,
+                            remaining_secs: duration.as_secs_f32(),
+                            ends: Instant::now() + duration,
+                            restore,
+                        });
+                        stateg.queue_setting(setting);
+                    } else {
+                        stateg.end_boost();
                     }
 
-                    let status_packet = match read_packet(&uart)? {
-                        Some(p) => { p }
-                        None => {
-                            info!("No response to status packet request for type {:?}, assuming disconnected", ptype);
-                            state.lock().unwrap().connected = false;
-                            break;
+                    let jval = json!({"boost": stateg.boost});
+                    req.into_response(200, Some("OK"), response_headers)?.write_all(jval.to_string().as_bytes())?;
+                }
+                Err(e) => {
+                    req.into_response(400, Some("Bad Request"), &[("Content-Type", "application/json")])?
+                        .write_all(FirmwareError::from_json_error(&buf, &e).to_json().to_string().as_bytes())?;
+                }
+            }
+        }
+
+        Ok::<(), FirmwareError>(())
+    })?;
+
+    server.fn_handler("/diagnostics.json", http::Method::Get, move |req| {
+        let mut jval = serde_json::to_value(diagnostics::collect()).unwrap();
+        jval["http"] = json!({
+            // the server is started with the default socket limit
+            "max_sessions": http::server::Configuration::default().max_open_sockets,
+            "log_ws_sessions": inner_log_sessions.lock().unwrap().len(),
+        });
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write_all(jval.to_string().as_bytes())
+    })?;
+
+    let inner_state12 = state.clone();
+
+    server.fn_handler("/strings.json", http::Method::Get, move |req| {
+        let language = inner_state12.lock().unwrap().ui_language;
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write_all(strings::table(language).to_string().as_bytes())
+    })?;
+
+    let inner_state13 = state.clone();
+
+    server.fn_handler("/reset_protocol_errors.json", http::Method::Post, move |req| {
+        // the counts are owned by the main loop, which resets them on its next pass
+        inner_state13.lock().unwrap().reset_protocol_errors = true;
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write_all(json!({"reset": true}).to_string().as_bytes())
+    })?;
+
+    let inner_state14 = state.clone();
+
+    server.fn_handler("/energy.json", http::Method::Get, move |req| {
+        let jval = serde_json::to_value(&inner_state14.lock().unwrap().energy_meter).unwrap();
+        let (body, content_type) = negotiated_body(&req, &jval);
+        req.into_response(200, Some("OK"), &[("Content-Type", content_type), ("Vary", "Accept")])?
+            .write_all(&body)
+    })?;
+
+    let inner_state15 = state.clone();
+
+    server.fn_handler("/adherence.json", http::Method::Get, move |req| {
+        let jval = serde_json::to_value(&inner_state15.lock().unwrap().adherence).unwrap();
+        let (body, content_type) = negotiated_body(&req, &jval);
+        req.into_response(200, Some("OK"), &[("Content-Type", content_type), ("Vary", "Accept")])?
+            .write_all(&body)
+    })?;
+
+    let inner_state42 = state.clone();
+
+    server.fn_handler("/history.json", http::Method::Get, move |req| {
+        let jval = serde_json::to_value(&inner_state42.lock().unwrap().history).unwrap();
+        let (body, content_type) = negotiated_body(&req, &jval);
+        req.into_response(200, Some("OK"), &[("Content-Type", content_type), ("Vary", "Accept")])?
+            .write_all(&body)
+    })?;
+
+    let inner_state16 = state.clone();
+
+    server.fn_handler("/model.json", http::Method::Get, move |req| {
+        let jval = serde_json::to_value(&inner_state16.lock().unwrap().rate_model).unwrap();
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write_all(jval.to_string().as_bytes())
+    })?;
+
+    let inner_state17 = state.clone();
+
+    server.fn_handler("/trace.json", http::Method::Get, move |req| {
+        // clone the trace out so the state lock isn't held while waiting on the trace lock
+        let bus_trace = inner_state17.lock().unwrap().bus_trace.clone();
+        let jval = serde_json::to_value(&*bus_trace.lock().unwrap()).unwrap();
+        let (body, content_type) = negotiated_body(&req, &jval);
+        req.into_response(200, Some("OK"), &[("Content-Type", content_type), ("Vary", "Accept")])?
+            .write_all(&body)
+    })?;
+
+    let inner_state18 = state.clone();
+
+    server.fn_handler("/trace.mmd", http::Method::Get, move |req| {
+        let bus_trace = inner_state18.lock().unwrap().bus_trace.clone();
+        let mermaid = bus_trace.lock().unwrap().to_mermaid();
+        req.into_response(200, Some("OK"), &[("Content-Type", "text/plain")])?
+            .write_all(mermaid.as_bytes())
+    })?;
+
+    let inner_state19 = state.clone();
+
+    server.fn_handler("/system.json", http::Method::Get, move |req| {
+        let jval = serde_json::to_value(&inner_state19.lock().unwrap().integrity).unwrap();
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write_all(jval.to_string().as_bytes())
+    })?;
+
+    server.fn_handler("/version.json", http::Method::Get, move |req| {
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write_all(version_json().to_string().as_bytes())
+    })?;
+
+    let inner_state20 = state.clone();
+    let description_mac = own_mac.clone();
+
+    server.fn_handler("/description.xml", http::Method::Get, move |req| {
+        let friendly_name = inner_state20.lock().unwrap().controller_location.clone()
+            .map_or("Mitsubishi heatpump controller".to_string(), |l| format!("Mitsubishi heatpump controller ({})", l));
+        req.into_response(200, Some("OK"), &[("Content-Type", "text/xml")])?
+            .write_all(ssdp::description_xml(&description_mac, &friendly_name).as_bytes())
+    })?;
+
+    let inner_state21 = state.clone();
+
+    server.fn_handler("/wifi/reprovision", http::Method::Post, move |req| {
+        // everything but the stored networks is kept.  The compiled-in network is part of the firmware so it can't be
+        // forgotten, but the next boot goes straight to AP mode rather than joining it
+        info!("wifi reprovisioning requested");
+        inner_state21.lock().unwrap().wifi_reprovision = true;
+        let jval = json!({"reprovisioning": true, "ap_ssid": SSID});
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write_all(jval.to_string().as_bytes())
+    })?;
+
+    let inner_state22 = state.clone();
+    let units_mac = own_mac.clone();
+
+    server.fn_handler("/units.json", http::Method::Get, move |req| {
+        let stateg = inner_state22.lock().unwrap();
+        let own_hostname = ["heatpump-controller-", units_mac.as_str()].concat();
+        let own_status = status_json(&stateg, boot_instant, &Some(units_mac.clone()));
+        let mut units = vec![json!({
+            "hostname": own_hostname,
+            "location": stateg.controller_location,
+            "status": own_status,
+            "this_controller": true,
+        })];
+        units.extend(stateg.coordinator.units.iter().map(|u| serde_json::to_value(u).unwrap()));
+        let jval = json!({"coordinator": stateg.coordinator.config.enabled, "units": units});
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write_all(jval.to_string().as_bytes())
+    })?;
+
+    let inner_state23 = state.clone();
+    let units_mac = own_mac.clone();
+
+    server.fn_handler("/units.json", http::Method::Post, move |mut req| {
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > HTTP_SERVER_MAX_LEN {
+            req.into_response(413, Some("Payload Too Large"), &[("Content-Type", "application/json")])?
+                .write_all(FirmwareError::new(ErrorCode::RequestTooBig, "Request too big").to_json().to_string().as_bytes())?;
+        } else {
+            let mut buf = vec![0; len];
+            req.read_exact(&mut buf)?;
+
+            match serde_json::from_slice::<UnitSetRequest>(&buf) {
+                Ok(request) => {
+                    let response_headers = &[("Content-Type", "application/json")];
+                    let mut stateg = inner_state23.lock().unwrap();
+                    if !stateg.coordinator.config.enabled {
+                        req.into_response(409, Some("Conflict"), response_headers)?
+                            .write_all(FirmwareError::new(ErrorCode::Disabled, "coordinator mode is not enabled").to_json().to_string().as_bytes())?;
+                        return Ok(());
+                    }
+
+                    // this controller is one of the units too, and is set just like set.json would
+                    let own_hostname = ["heatpump-controller-", units_mac.as_str()].concat();
+                    let mut own_result = serde_json::Value::Null;
+                    if Coordinator::matches(&own_hostname, stateg.controller_location.as_deref(), &request.target) {
+                        match serde_json::from_value::<HeatPumpSetting>(request.setting.clone()) {
+                            // the request has no token of its own for this controller
+                            Ok(mut form) => match { form.drop_privileged(); validate_setting(&mut form, &stateg) } {
+                                Ok(()) => {
+                                    if let Some(temperature_c) = form.desired_temperature_c {
+                                        stateg.pid.set_user_target(temperature_c);
+                                    }
+                                    own_result = serde_json::to_value(&form).unwrap();
+                                    stateg.queue_setting(form);
+                                }
+                                Err(errjson) => { own_result = errjson; }
+                            },
+                            Err(e) => { own_result = FirmwareError::from_json_error(&[], &e).to_json(); }
                         }
-                    };
-                    
-                    status_to_state(&status_packet, &state)?;
-                    all_done = true;
-                } 
-                if all_done {
-                    last_status_request = Instant::now();
-                    info!("Done requesting status, have {} ms reminaing before next request", RESPONSE_DELAY.as_millis());     
+                    }
+
+                    let queued = stateg.coordinator.queue_set(&request);
+                    let jval = json!({"this_controller": own_result, "queued": queued});
+                    req.into_response(200, Some("OK"), response_headers)?.write_all(jval.to_string().as_bytes())?;
                 }
-            } 
+                Err(e) => {
+                    req.into_response(400, Some("Bad Request"), &[("Content-Type", "application/json")])?
+                        .write_all(FirmwareError::from_json_error(&buf, &e).to_json().to_string().as_bytes())?;
+                }
+            }
+        }
+
+        Ok::<(), FirmwareError>(())
+    })?;
+
+    let inner_state24 = state.clone();
+
+    server.fn_handler("/schema", http::Method::Get, move |req| {
+        let jval = setting_schema(&inner_state24.lock().unwrap());
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/schema+json")])?
+            .write_all(jval.to_string().as_bytes())
+    })?;
+
+    let inner_state40 = state.clone();
+
+    server.fn_handler("/api/openapi.json", http::Method::Get, move |req| {
+        let jval = openapi::document(&inner_state40.lock().unwrap());
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write_all(jval.to_string().as_bytes())
+    })?;
+
+    let inner_state25 = state.clone();
+
+    server.fn_handler("/sniff.json", http::Method::Get, move |req| {
+        let stateg = inner_state25.lock().unwrap();
+        let jval = json!({
+            "config": stateg.sniffer.config,
+            "frames": stateg.sniffer.frames,
+            "previous_log": stateg.sniffer.previous_log,
+        });
+
+        let response_headers = &[("Content-Type", "application/json")];
+        req.into_response(200, Some("OK"), response_headers)?
+        .write_all(jval.to_string().as_bytes())
+        .map(|_| ())
+    })?;
+
+    // like the log websocket, each session's position is kept and the frames since are sent on "sniff?"
+    let inner_state26 = state.clone();
+    let sniff_sessions = Mutex::new(HashMap::<i32, u64>::new());
+    server.ws_handler("/ws/sniff", move |ws| {
+        let mut sessions = sniff_sessions.lock().unwrap();
+        if ws.is_new() {
+            sessions.insert(ws.session(), inner_state26.lock().unwrap().sniffer.oldest_seq());
+        } else if ws.is_closed() {
+            sessions.remove(&ws.session());
+        } else {
+            let (frame_type, len) = ws.recv(&mut [])?;
+            let mut rvec = vec![0u8; len];
+            ws.recv(rvec.as_mut_slice())?;
+            if let Some(v) = rvec.pop() {
+                if v != 0 { rvec.push(v);}
+            }
+
+            if let FrameType::Text(false) = frame_type {
+                if rvec.as_slice() == b"sniff?" {
+                    let seq = sessions.get_mut(&ws.session())
+                        .ok_or(FirmwareError::new(ErrorCode::WsSessionMissing, "no websocket session to send to"))?;
+                    let (lines, next_seq) = inner_state26.lock().unwrap().sniffer.lines_since(*seq);
+                    *seq = next_seq;
+                    if !lines.is_empty() {
+                        ws.send(FrameType::Text(false), lines.join("\n").as_bytes())?;
+                    }
+                }
+            }
+        }
+        Ok::<(), FirmwareError>(())
+    })?;
+
+    let inner_state27 = state.clone();
+
+    server.fn_handler("/crashlog.json", http::Method::Get, move |req| {
+        let (config, crash_log) = {
+            let stateg = inner_state27.lock().unwrap();
+            (stateg.watchdog.clone(), stateg.crash_log.clone())
+        };
+        let jval = json!({
+            "watchdog": config,
+            "entries": crash_log.lock().unwrap().entries,
+        });
+
+        let response_headers = &[("Content-Type", "application/json")];
+        req.into_response(200, Some("OK"), response_headers)?
+        .write_all(jval.to_string().as_bytes())
+        .map(|_| ())
+    })?;
+
+    let inner_state28 = state.clone();
+
+    server.fn_handler("/bus/probe", http::Method::Post, move |req| {
+        let response_headers = &[("Content-Type", "application/json")];
+        let mut stateg = inner_state28.lock().unwrap();
+        if !stateg.connected {
+            req.into_response(409, Some("Conflict"), response_headers)?
+                .write_all(unavailable_json(&stateg).to_string().as_bytes())?;
+            return Ok(());
+        }
+        info!("starting a probe of the unit's info packet types");
+        stateg.status_probe.start();
+        stateg.status_probe.update_secs();
+        req.into_response(200, Some("OK"), response_headers)?
+            .write_all(serde_json::to_string(&stateg.status_probe).unwrap().as_bytes())?;
+
+        Ok::<(), FirmwareError>(())
+    })?;
+
+    let inner_state29 = state.clone();
+
+    server.fn_handler("/bus/probe.json", http::Method::Get, move |req| {
+        let mut stateg = inner_state29.lock().unwrap();
+        stateg.status_probe.update_secs();
+        let probejson = serde_json::to_string(&stateg.status_probe).unwrap();
+
+        let response_headers = &[("Content-Type", "application/json")];
+        req.into_response(200, Some("OK"), response_headers)?
+        .write_all(probejson.as_bytes())
+        .map(|_| ())
+    })?;
 
+    let inner_state30 = state.clone();
 
+    server.fn_handler("/bus/pause", http::Method::Post, move |mut req| {
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > HTTP_SERVER_MAX_LEN {
+            req.into_response(413, Some("Payload Too Large"), &[("Content-Type", "application/json")])?
+                .write_all(FirmwareError::new(ErrorCode::RequestTooBig, "Request too big").to_json().to_string().as_bytes())?;
         } else {
-            //try to connect
-            info!("Sending Connection string!");
-            uart.write(&CONNECT_BYTES)?;
+            let mut buf = vec![0; len];
+            req.read_exact(&mut buf)?;
+
+            match serde_json::from_slice::<BusPauseRequest>(&buf) {
+                Ok(pause) => {
+                    let response_headers = &[("Content-Type", "application/json")];
+                    if pause.secs > BUS_PAUSE_MAX_SECS {
+                        let errjson = FirmwareError::new(ErrorCode::InvalidSetting, format!("secs must be at most {}", BUS_PAUSE_MAX_SECS))
+                            .with_field("secs").with_value(pause.secs).to_json();
+                        req.into_response(422, Some("Unprocessable Entity"), response_headers)?
+                            .write_all(errjson.to_string().as_bytes())?;
+                        return Ok(());
+                    }
 
-            std::thread::sleep(CONNECT_DELAY);
+                    let mut stateg = inner_state30.lock().unwrap();
+                    if pause.secs == 0 {
+                        // the loop notices it's over and reconnects
+                        if stateg.bus_paused_until.is_some() {
+                            stateg.bus_paused_until = Some(Instant::now());
+                        }
+                    } else {
+                        info!("pausing the heat pump bus for {} secs", pause.secs);
+                        stateg.bus_paused_until = Some(Instant::now() + Duration::from_secs(pause.secs));
+                        stateg.connected = false;
+                    }
 
-            // check for a response
-            let mut rbuf = [0u8; 22];
-            let nread = uart.read(&mut rbuf, 1)?;
-            if nread > 0 {
-                let resp = &rbuf[..nread];
-                let response = Packet::from_bytes(resp)?;
-                if response.packet_type == 0x7A {
-                    info!("Connected!");
-                    state.lock().unwrap().connected = true;
+                    let jval = json!({"bus_paused_secs": stateg.bus_paused_remaining_secs()});
+                    req.into_response(200, Some("OK"), response_headers)?.write_all(jval.to_string().as_bytes())?;
                 }
-                if nread > response.packet_size() {
-                    info!("{} extra bytes in connect response, ignoring", nread - response.packet_size());
+                Err(e) => {
+                    req.into_response(400, Some("Bad Request"), &[("Content-Type", "application/json")])?
+                        .write_all(FirmwareError::from_json_error(&buf, &e).to_json().to_string().as_bytes())?;
                 }
-            } else {
-                info!("No response to connection string");
             }
         }
 
+        Ok::<(), FirmwareError>(())
+    })?;
+
+    let inner_state31 = state.clone();
+
+    server.fn_handler("/debug/packet.json", http::Method::Post, move |mut req| {
+        let response_headers = &[("Content-Type", "application/json")];
+        if !bearer_authorized(&req, &inner_state31.lock().unwrap().debug_token) {
+            req.into_response(403, Some("Forbidden"), response_headers)?
+                .write_all(FirmwareError::new(ErrorCode::Unauthorized, "needs a debug_token to be set, and given as a bearer token").to_json().to_string().as_bytes())?;
+            return Ok(());
+        }
+
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > HTTP_SERVER_MAX_LEN {
+            req.into_response(413, Some("Payload Too Large"), &[("Content-Type", "application/json")])?
+                .write_all(FirmwareError::new(ErrorCode::RequestTooBig, "Request too big").to_json().to_string().as_bytes())?;
+            return Ok(());
+        }
+        let mut buf = vec![0; len];
+        req.read_exact(&mut buf)?;
+
+        let request = match serde_json::from_slice::<DebugPacketRequest>(&buf) {
+            Ok(request) => request,
+            Err(e) => {
+                req.into_response(400, Some("Bad Request"), &[("Content-Type", "application/json")])?
+                        .write_all(FirmwareError::from_json_error(&buf, &e).to_json().to_string().as_bytes())?;
+                return Ok(());
+            }
+        };
+        let hex: String = request.hex.chars().filter(|c| !c.is_whitespace()).collect();
+        let bytes: Option<Vec<u8>> = if hex.is_ascii() && hex.len() % 2 == 0 {
+            (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i+2], 16).ok()).collect()
+        } else {
+            None
+        };
+        let packet = match bytes {
+            Some(b) if b.len() >= 5 && b[0] == 0xfc && b[4] as usize == b.len() - 5 && b.len() - 5 <= PACKET_DATA_MAX => {
+                let mut packet = Packet::new();
+                packet.packet_type = b[1];
+                packet.h2 = b[2];
+                packet.h3 = b[3];
+                packet.data = PacketData::from_slice(&b[5..])?;
+                packet.set_checksum();
+                packet
+            }
+            _ => {
+                let errjson = FirmwareError::new(ErrorCode::BadPacketHex, 
+                                                "hex must be a packet from the 0xfc to the end of the data (at most 16 bytes of it), with a length byte that matches").to_json();
+                req.into_response(422, Some("Unprocessable Entity"), response_headers)?
+                    .write_all(errjson.to_string().as_bytes())?;
+                return Ok(());
+            }
+        };
 
-        // we put the non-heat pump settings (which don't care about connection status) at the end so that if the above fails they don't happen
-        // we also put in its own block so that its locks are self-contained
         {
-            let mut realstate = state.lock().unwrap();
-            if realstate.desired_settings.is_some() {
-                let desired_settings = realstate.desired_settings.as_mut().unwrap();
-                if desired_settings.controller_led_brightness.is_some() {
-                    nvs_settings.set_u8("led_brightness", desired_settings.controller_led_brightness.unwrap())?;
-                    info!("setting LED brightness to {:?}", desired_settings.controller_led_brightness.unwrap());
-                    desired_settings.controller_led_brightness = None;
-                }
-                if desired_settings.controller_location.is_some() {
-                    let cl_str = desired_settings.controller_location.as_ref().unwrap();
-                    nvs_settings.set_str("controller_loc", &cl_str)?;
-                    info!("setting controller location to {:?}", cl_str);
-                    desired_settings.controller_location = None;
-                }
-                // data_to_send is false if it was successfully sent above, in which case we assume we are all good having sent the above
-                if !data_to_send { realstate.desired_settings = None; }
+            let mut stateg = inner_state31.lock().unwrap();
+            if stateg.debug_packet.is_some() {
+                req.into_response(409, Some("Conflict"), response_headers)?
+                    .write_all(FirmwareError::new(ErrorCode::Busy, "another raw packet is already being sent").to_json().to_string().as_bytes())?;
+                return Ok(());
             }
+            stateg.debug_packet = Some(DebugPacket { bytes: packet.to_bytes(&mut [0u8; PACKET_SIZE_MAX]).to_vec(), response: None });
         }
 
-        // Restart if needed
-        if REBOOT_PERIOD.is_some() {
-            if boot_instant.elapsed() >= REBOOT_PERIOD.unwrap() {
-                info!("restarting due to uptime restart trigger");
-                std::thread::sleep(Duration::from_millis(100));
-                reset::restart();
+        // the loop owns the uart, so wait for it to get to this
+        let wait_start = Instant::now();
+        let response = loop {
+            std::thread::sleep(Duration::from_millis(50));
+            let mut stateg = inner_state31.lock().unwrap();
+            if stateg.debug_packet.as_ref().map_or(false, |p| p.response.is_some()) || wait_start.elapsed() > DEBUG_PACKET_WAIT {
+                break stateg.debug_packet.take().and_then(|p| p.response);
+            }
+        };
+        match response {
+            Some(jval) => {
+                req.into_response(200, Some("OK"), response_headers)?.write_all(jval.to_string().as_bytes())?;
+            }
+            None => {
+                req.into_response(504, Some("Gateway Timeout"), response_headers)?
+                    .write_all(FirmwareError::new(ErrorCode::TimedOut, "the packet wasn't sent in time, e.g. as the bus is paused or being sniffed").to_json().to_string().as_bytes())?;
             }
         }
 
-        // check to see if we need to delay because the loop was too fast
-        let loopelapsed = loopstart.elapsed();
-        if loopelapsed < LOOP_MIN_LENGTH {
-            let sleepdur = LOOP_MIN_LENGTH - loopelapsed;
+        Ok::<(), FirmwareError>(())
+    })?;
 
-            std::thread::sleep(sleepdur);
-        }
-    }
-}
+    let inner_state32 = state.clone();
 
+    server.fn_handler("/config.json", http::Method::Get, move |req| {
+        let jval = serde_json::to_value(&inner_state32.lock().unwrap().config).unwrap();
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write_all(jval.to_string().as_bytes())
+            .map(|_| ())
+    })?;
 
-fn status_to_state(packet: &Packet, stateref: &Arc<Mutex<HeatPumpStatus>>) -> anyhow::Result<()> {
-    if packet.packet_type != 0x62 {
-        anyhow::bail!("Packet is not a status reply packet!");
-    } 
-    if packet.data.len() != 16 {
-        anyhow::bail!("Status packet is not length 16");
-    }
+    let inner_state33 = state.clone();
 
-    let mut state = stateref.lock().unwrap();
+    server.fn_handler("/config.json", http::Method::Post, move |mut req| {
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > HTTP_SERVER_MAX_LEN {
+            req.into_response(413, Some("Payload Too Large"), &[("Content-Type", "application/json")])?
+                .write_all(FirmwareError::new(ErrorCode::RequestTooBig, "Request too big").to_json().to_string().as_bytes())?;
+        } else {
+            let mut buf = vec![0; len];
+            req.read_exact(&mut buf)?;
 
-    match StatusPacketType::from_repr(packet.data[0] as usize) {
-        Some(StatusPacketType::Settings) => {
-            // settings
-            state.poweron = packet.data[3] != 0;
-            state.isee_present = packet.data[4] & 0b00001000 > 0;
-            // drop the isee bit when computing the mode
-            state.mode = HeatPumpMode::from_repr((packet.data[4] & 0b11110111) as usize).unwrap(); 
+            match serde_json::from_slice::<ConfigUpdate>(&buf) {
+                Ok(update) => {
+                    let response_headers = &[("Content-Type", "application/json")];
+                    if let Err(msg) = update.validate() {
+                        req.into_response(422, Some("Unprocessable Entity"), response_headers)?
+                            .write_all(FirmwareError::new(ErrorCode::InvalidSetting, msg).to_json().to_string().as_bytes())?;
+                        return Ok(());
+                    }
 
-            // I don't really understand why the temperature is done this way, but it's what this does so I assume its right? https://github.com/SwiCago/HeatPump/blob/b4c34f1f66e45affe70a556a955db02a0fa80d81/src/HeatPump.cpp#L649
-            if packet.data[11] != 0 {
-                state.desired_temperature_c = ((packet.data[11] - 128) as f32)/2.0;
-            } else {
-                state.desired_temperature_c = (packet.data[5] + 10) as f32; 
-            }
+                    // the loop applies and stores it, so answer with what the config is about to be
+                    let mut stateg = inner_state33.lock().unwrap();
+                    let mut config = stateg.config.clone();
+                    config.apply(&update);
+                    stateg.pending_config.get_or_insert_with(ConfigUpdate::default).merge(update);
 
-            state.fan_speed = FanSpeed::from_repr(packet.data[6] as usize).unwrap();
-            state.vane = VaneDirection::from_repr(packet.data[7] as usize).unwrap();
-            let wvmod = packet.data[10] & (!0x80); // not sure what this bit is for.  TODO: figure out
-            
-            state.widevane = WideVaneDirection::from_repr(wvmod as usize).unwrap_or(WideVaneDirection::Unknown);
-            
-        }
-        Some(StatusPacketType::RoomTemperature) => {
-            if packet.data[6] != 0 {
-                state.room_temperature_c = ((packet.data[6] - 128) as f32)/2.0;
-            } else {
-                state.room_temperature_c = (packet.data[3] + 10) as f32; 
+                    let jval = serde_json::to_value(&config).unwrap();
+                    req.into_response(200, Some("OK"), response_headers)?.write_all(jval.to_string().as_bytes())?;
+                }
+                Err(e) => {
+                    req.into_response(400, Some("Bad Request"), &[("Content-Type", "application/json")])?
+                        .write_all(FirmwareError::from_json_error(&buf, &e).to_json().to_string().as_bytes())?;
+                }
             }
+        }
 
+        Ok::<(), FirmwareError>(())
+    })?;
 
-            if packet.data[7] != 0 {
-                state.room_temperature_c_2 = ((packet.data[7] - 128) as f32)/2.0;
-            } else {
-                state.room_temperature_c_2 = -999.0;
-            }
+    let inner_state41 = state.clone();
+    let ca_cert_nvs_partition = nvs_partition.clone();
 
-            // byte 8 seems to have isee info direct/indirect for some reason
-            state.isee_mode = ISeeMode::from_repr(packet.data[8] as usize).unwrap_or(ISeeMode::Unknown);
-            
+    server.fn_handler("/wifi_ca_cert", http::Method::Post, move |mut req| {
+        // the PEM body is sent as it is, for the network named by ?ssid=, and an empty body removes it.  Anyone
+        // who can set the certificate can point the controller at their own RADIUS server, hence the token
+        let response_headers = &[("Content-Type", "application/json")];
+        if !bearer_authorized(&req, &inner_state41.lock().unwrap().debug_token) {
+            req.into_response(403, Some("Forbidden"), response_headers)?
+                .write_all(FirmwareError::new(ErrorCode::Unauthorized, "needs a debug_token to be set, and given as a bearer token").to_json().to_string().as_bytes())?;
+            return Ok(());
         }
-        Some(StatusPacketType::ErrorCodeMaybe) => {
-            if packet.data[4] == 0x80 {
-                state.error_data = None
-            } else {
 
-                state.error_data = Some(packet.data.clone());
-            }
-        }
-        Some(StatusPacketType::Timers) => {
-            // ignore timers
-        }
-        Some(StatusPacketType::MiscInfo) => {
-            //state.compressorfreq = packet.data[3];  // does not appear in my heatpump
-            state.operating = packet.data[4];
-        }
-        Some(StatusPacketType::StandbyMode) => {
-            // not sure what to do with this right now...
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > WIFI_CA_CERT_MAX_LEN {
+            req.into_response(413, Some("Payload Too Large"), response_headers)?
+                .write_all(FirmwareError::new(ErrorCode::RequestTooBig, format!("a certificate can be at most {} bytes", WIFI_CA_CERT_MAX_LEN))
+                           .to_json().to_string().as_bytes())?;
+            return Ok(());
         }
-        _ => {
-            info!("unrecognized status packet type: {}", packet.data[0]);
+        let mut buf = vec![0; len];
+        req.read_exact(&mut buf)?;
+
+        let ssid = query_param(req.uri(), "ssid").unwrap_or_default();
+        let pem = String::from_utf8_lossy(&buf).trim().to_string();
+        // only networks that are already stored, so a certificate can't end up on the wrong one
+        let networks = match nvs::EspNvs::new(ca_cert_nvs_partition.clone(), "settings", false)
+            .map_err(anyhow::Error::from)
+            .and_then(|nvs_settings| load_wifi_networks(&nvs_settings)) {
+            Ok(networks) => networks,
+            Err(e) => {
+                req.into_response(500, Some("Internal Server Error"), response_headers)?
+                    .write_all(FirmwareError::new(ErrorCode::EspIdf, format!("could not read the wifi networks: {}", e)).to_json().to_string().as_bytes())?;
+                return Ok(());
+            }
+        };
+        let refused = if !networks.iter().any(|n| n.ssid == ssid && n.eap.is_some()) {
+            Some(invalid_setting("ssid", "ssid must name a stored WPA2-Enterprise network", &ssid))
+        } else if !pem.is_empty() && !pem.starts_with("-----BEGIN CERTIFICATE-----") {
+            Some(FirmwareError::new(ErrorCode::InvalidSetting, "the body must be a PEM certificate, or empty to remove it")
+                 .with_field("ca_cert").to_json())
+        } else {
+            None
+        };
+        if let Some(errjson) = refused {
+            req.into_response(422, Some("Unprocessable Entity"), response_headers)?
+                .write_all(errjson.to_string().as_bytes())?;
+            return Ok(());
         }
-    }
-
-    state.last_status_packets.insert(packet.data[0], packet.data.clone());
-
-    Ok(())
-}
-
-fn read_packet(uart: &uart::UartDriver) -> anyhow::Result<Option<Packet>> {
-    let uart_byte_time: u64 = (100 / uart.baudrate()?.0 + 1) as u64;
-
-    // read out anything waiting in the uart
-    let mut bytes_read: Vec<u8> = Vec::new();
-    let mut rbuf = [0u8; 16+6];  // typical packet size
-    while uart.remaining_read()? > 0 {
-        let nread = uart.read(&mut rbuf, 1)?;
-        for i in 0..nread { bytes_read.push(rbuf[i as usize]); }
-        std::thread::sleep(Duration::from_millis(uart_byte_time*2));  // wait a full two byte times just in case
-    }
-
-    match bytes_read.len() {
-        0 => {Ok(None)},
-        _ => { Ok(Some(Packet::from_bytes(&bytes_read)?))}
-    }
-}
 
-fn setup_wifi<'a>(pmodem: hal::modem::Modem, dnvs: nvs::EspDefaultNvsPartition) -> anyhow::Result<(BlockingWifi<EspWifi<'a>>, Option<[u8; 6]>)> {
-    let sys_loop = EspSystemEventLoop::take()?;
+        let storing = !pem.is_empty();
+        inner_state41.lock().unwrap().pending_ca_cert = Some((ssid.clone(), if storing { Some(pem) } else { None }));
+        let jval = json!({"ssid": ssid, "ca_cert": storing, "restart_needed": true});
+        req.into_response(200, Some("OK"), response_headers)?.write_all(jval.to_string().as_bytes())?;
 
-    let mut wifi = BlockingWifi::wrap(
-        EspWifi::new(pmodem, sys_loop.clone(), Some(dnvs))?,
-        sys_loop,
-    )?;
+        Ok::<(), FirmwareError>(())
+    })?;
 
-    let wifi_configuration: eswifi::Configuration = eswifi::Configuration::Client(
-        eswifi::ClientConfiguration {
-        ssid: SSID.try_into().unwrap(),
-        bssid: None,
-        auth_method: eswifi::AuthMethod::WPA2Personal,
-        password: PASSWORD.try_into().unwrap(),
-        channel: None,
-    });
+    let inner_state34 = state.clone();
+
+    server.fn_handler("/config/export", http::Method::Get, move |req| {
+        // a read-only handle of its own, so this doesn't have to wait on the loop
+        let export = nvs::EspNvs::new(nvs_partition.clone(), "settings", false)
+            .map_err(anyhow::Error::from)
+            .and_then(|nvs_settings| ConfigExport::read(&nvs_settings));
+        match export {
+            Ok(export) => {
+                let location = inner_state34.lock().unwrap().config.location.clone();
+                info!("exporting the config of {}", location.as_deref().unwrap_or("this controller"));
+                req.into_response(200, Some("OK"), &[("Content-Type", "application/json"),
+                                                     ("Content-Disposition", "attachment; filename=\"heatpump-config.json\"")])?
+                    .write_all(serde_json::to_string(&export).unwrap().as_bytes())?;
+            }
+            Err(e) => {
+                req.into_response(500, Some("Internal Server Error"), &[("Content-Type", "application/json")])?
+                    .write_all(FirmwareError::new(ErrorCode::EspIdf, format!("could not read the settings: {}", e)).to_json().to_string().as_bytes())?;
+            }
+        }
 
-    wifi.set_configuration(&wifi_configuration)?;
+        Ok::<(), FirmwareError>(())
+    })?;
 
-    wifi.start()?;
+    let inner_state35 = state.clone();
 
-    // first scan to check that there's a match.
-    let mut ssid_match = false;
-    let scan_results = wifi.scan()?;
-    for result in scan_results.iter(){
-        if SSID == result.ssid.as_str() {
-            ssid_match = true;
-            break;
+    server.fn_handler("/config/import", http::Method::Post, move |mut req| {
+        // an import can set anything, the debug_token and OTA config included
+        if !bearer_authorized(&req, &inner_state35.lock().unwrap().debug_token) {
+            req.into_response(403, Some("Forbidden"), &[("Content-Type", "application/json")])?
+                .write_all(FirmwareError::new(ErrorCode::Unauthorized, "needs a debug_token to be set, and given as a bearer token").to_json().to_string().as_bytes())?;
+            return Ok(());
         }
-    }
 
-    if ssid_match {
-        info!("found ssid {}, connecting", SSID);
-        wifi.connect()?;
-    } else if RESET_ON_SSID_NOT_FOUND == "yes" {
-        info!("Did not find ssid {:?} in list {:?}!", SSID, scan_results);
-        return Err(NoSSIDError{}.into());
-    } else {
-        info!("Did not find ssid in list below, so creating AP w/ ssid: {}", SSID);
-        info!("Scan Results: {:?}", scan_results);
-        wifi.stop()?;
-        
-        let wifi_configuration_ap = eswifi::Configuration::AccessPoint(eswifi::AccessPointConfiguration {
-            ssid: SSID.try_into().unwrap(),
-            ssid_hidden: false,
-            auth_method: eswifi::AuthMethod::WPA2Personal,
-            password: PASSWORD.try_into().unwrap(),
-            channel: WIFI_CHANNEL.parse().unwrap(),
-            secondary_channel: None,
-            ..Default::default()
-        });
-        
-        wifi.set_configuration(&wifi_configuration_ap)?;
-        
-        wifi.start()?;
-    }
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > IMPORT_MAX_LEN {
+            req.into_response(413, Some("Payload Too Large"), &[("Content-Type", "application/json")])?
+                .write_all(FirmwareError::new(ErrorCode::RequestTooBig, "Request too big").to_json().to_string().as_bytes())?;
+        } else {
+            let mut buf = vec![0; len];
+            req.read_exact(&mut buf)?;
 
-    //wifi.wait_netif_up()?;
-    // the below is exactly what the above does as of this writing, but allows for a custom timeout
-    // wich is necessary for some esp32c6 chips on at least some networks.
-    wifi.ip_wait_while(|| wifi.wifi().is_up().map(|s| !s), Some(CONNECT_TIMEOUT))?;
+            match serde_json::from_slice::<ConfigExport>(&buf) {
+                Ok(import) => {
+                    let response_headers = &[("Content-Type", "application/json")];
+                    if let Err(msg) = import.validate() {
+                        req.into_response(422, Some("Unprocessable Entity"), response_headers)?
+                            .write_all(FirmwareError::new(ErrorCode::InvalidSetting, msg).to_json().to_string().as_bytes())?;
+                        return Ok(());
+                    }
+                    let mut stateg = inner_state35.lock().unwrap();
+                    if let Err(errjson) = validate_import(&import, &stateg) {
+                        req.into_response(422, Some("Unprocessable Entity"), response_headers)?
+                            .write_all(errjson.to_string().as_bytes())?;
+                        return Ok(());
+                    }
 
-    let maco = match wifi.get_configuration()? {
-        eswifi::Configuration::Client(c) => {
-            let ip = wifi.wifi().sta_netif().get_ip_info()?;
-            info!("Connected to {} w/ip info: {:?}", c.ssid, ip);
-            Some(wifi.wifi().get_mac(WifiDeviceId::Sta)?)
-        },
-        eswifi::Configuration::AccessPoint(a) => {
-            let ip = wifi.wifi().ap_netif().get_ip_info()?;
-            info!("Created AP {} w/ip info:  {:?}", a.ssid, ip);
-            Some(wifi.wifi().get_mac(WifiDeviceId::Ap)?)
-        }
-        _ => {
-            info!("Unexpected configuration, no IP address");
-            None // Not sure what the configuration is so don't know which MAC to give
+                    // the loop stores it and restarts, since most of the settings are only read on boot
+                    let keys: Vec<String> = import.settings.keys().cloned().collect();
+                    info!("config import requested, with {:?}", keys);
+                    stateg.pending_import = Some(import);
+                    let jval = json!({"imported": keys, "restarting": true});
+                    req.into_response(200, Some("OK"), response_headers)?.write_all(jval.to_string().as_bytes())?;
+                }
+                Err(e) => {
+                    req.into_response(400, Some("Bad Request"), &[("Content-Type", "application/json")])?
+                        .write_all(FirmwareError::from_json_error(&buf, &e).to_json().to_string().as_bytes())?;
+                }
+            }
         }
 
-    };
+        Ok::<(), FirmwareError>(())
+    })?;
 
-    Ok((wifi, maco))
-}
+    let inner_state36 = state.clone();
 
-fn setup_handlers(server: &mut http::server::EspHttpServer, boot_instant: Instant, wifimacstr:Option<String>) -> Result<Arc<Mutex<HeatPumpStatus>> , EspError> {
-    let state = Arc::new(Mutex::new(HeatPumpStatus::new()));
+    server.fn_handler("/factory_reset", http::Method::Post, move |req| {
+        let response_headers = &[("Content-Type", "application/json")];
+        if !bearer_authorized(&req, &inner_state36.lock().unwrap().debug_token) {
+            req.into_response(403, Some("Forbidden"), response_headers)?
+                .write_all(FirmwareError::new(ErrorCode::Unauthorized, "needs a debug_token to be set, and given as a bearer token").to_json().to_string().as_bytes())?;
+            return Ok(());
+        }
 
-    let index_handler = |req: http::server::Request<&mut http::server::EspHttpConnection>| {
-        req.into_ok_response()?
-            .write_all(INDEX_HTML.as_bytes())
-    };
+        info!("factory reset requested");
+        inner_state36.lock().unwrap().factory_reset = true;
+        let jval = json!({"factory_reset": true, "ap_ssid": SSID});
+        req.into_response(200, Some("OK"), response_headers)?.write_all(jval.to_string().as_bytes())?;
 
-    server.fn_handler("/", http::Method::Get, index_handler)?;
-    server.fn_handler("/index.html", http::Method::Get, index_handler)?;
+        Ok::<(), FirmwareError>(())
+    })?;
 
+    let inner_state37 = state.clone();
 
-    let inner_state1 = state.clone();
+    server.fn_handler("/ota.json", http::Method::Get, move |req| {
+        let updater = inner_state37.lock().unwrap().ota.clone();
+        let jval = serde_json::to_value(&*updater.lock().unwrap()).unwrap();
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write_all(jval.to_string().as_bytes())
+    })?;
 
-    server.fn_handler("/status.json", http::Method::Get, move |req| {
-        let secs = boot_instant.elapsed().as_secs_f32();
-        let timestamp_str =  serde_json::Value::String(format!("{}", secs));
-        let macval = match &wifimacstr {
-            Some(s) => serde_json::Value::String(s.to_string()),
-            None => serde_json::Value::Null
-        };
+    server.fn_handler("/assets.json", http::Method::Get, |req| {
+        let jval = json!({"files": assets::list(), "max_len": assets::ASSET_MAX_LEN});
+        req.into_response(200, Some("OK"), &[("Content-Type", "application/json")])?
+            .write_all(jval.to_string().as_bytes())
+    })?;
 
-        let stateg = inner_state1.lock().unwrap();
-        let resp = if stateg.connected {
-            let statusjson = serde_json::to_value(&stateg as &HeatPumpStatus).unwrap();
+    server.fn_handler("/assets/*", http::Method::Get, |req| {
+        let name = asset_name(req.uri()).to_string();
+        let gzip = http_cache::accepts_gzip(req.header("Accept-Encoding"));
+        match find_asset(&name, gzip) {
+            Some((contents, gzipped)) => {
+                let etag = http_cache::etag(&contents);
+                respond_cached(req, assets::content_type(&name), &contents, gzipped, &etag)?;
+            }
+            None => {
+                req.into_response(404, Some("Not Found"), &[("Content-Type", "application/json")])?
+                    .write_all(FirmwareError::new(ErrorCode::NotFound, format!("No asset named {:?}", name)).to_json().to_string().as_bytes())?;
+            }
+        }
+        Ok::<(), FirmwareError>(())
+    })?;
 
-            // add the timestamp & mac
-            let json = match statusjson {
-                serde_json::Value::Object(mut o) => {
-                    o.insert("secs_since_boot".to_string(), timestamp_str);
-                    o.insert("mac".to_string(), macval);
-                    serde_json::Value::Object(o)
-                }
-                _ => {
-                    panic!("Got a json that is not a map!  This should be impossible")
-                }
-            };
-            json
-        } else {
+    let inner_state38 = state.clone();
 
-            let clocval = match &stateg.controller_location {
-                Some(s) => serde_json::Value::String(s.to_string()),
-                None => serde_json::Value::Null
-            };
-            
-            let j = json!({
-                "connected": false,
-                "controller_led_brightness": stateg.controller_led_brightness,
-                "secs_since_boot": timestamp_str,
-                "mac": macval,
-                "controller_location": clocval,
-                "tx_pin": env!("TX_PIN_NUM"),
-                "rx_pin": env!("RX_PIN_NUM"),
-                "led_pin": env!("LED_PIN_NUM"),
-            });
-            j
-        };
-        
+    server.fn_handler("/assets/*", http::Method::Post, move |mut req| {
         let response_headers = &[("Content-Type", "application/json")];
-        req.into_response(200, Some("OK"), response_headers)?
-        .write_all(resp.to_string().as_bytes())
-        .map(|_| ())
-    })?;
-
+        if !bearer_authorized(&req, &inner_state38.lock().unwrap().debug_token) {
+            req.into_response(403, Some("Forbidden"), response_headers)?
+                .write_all(FirmwareError::new(ErrorCode::Unauthorized, "needs a debug_token to be set, and given as a bearer token").to_json().to_string().as_bytes())?;
+            return Ok(());
+        }
+        let name = asset_name(req.uri()).to_string();
+        if !assets::valid_name(&name) {
+            req.into_response(422, Some("Unprocessable Entity"), response_headers)?
+                .write_all(FirmwareError::new(ErrorCode::InvalidSetting, "asset names are letters, numbers, '.', '-' and '_', up to 24 of them").to_json().to_string().as_bytes())?;
+            return Ok(());
+        }
+        let len = req.content_len().unwrap_or(0) as usize;
+        if len > assets::ASSET_MAX_LEN {
+            req.into_response(413, Some("Payload Too Large"), response_headers)?
+                .write_all(FirmwareError::new(ErrorCode::RequestTooBig, "Request too big").to_json().to_string().as_bytes())?;
+            return Ok(());
+        }
 
-    let inner_state2 = state.clone();
+        // in pieces, since a whole asset could be more than there's heap for
+        let mut writer = assets::AssetWriter::create(&name).map_err(|e| FirmwareError::new(ErrorCode::Io, e))?;
+        let mut buf = [0u8; 1024];
+        let mut remaining = len;
+        while remaining > 0 {
+            let n = remaining.min(buf.len());
+            req.read_exact(&mut buf[..n])?;
+            writer.write(&buf[..n]).map_err(|e| FirmwareError::new(ErrorCode::Io, e))?;
+            remaining -= n;
+        }
+        writer.finish().map_err(|e| FirmwareError::new(ErrorCode::Io, e))?;
+        info!("stored asset {} ({} bytes)", name, len);
 
-    server.fn_handler("/set.json", http::Method::Post, move |mut req| {
-        let len = req.content_len().unwrap_or(0) as usize;
-        if len > HTTP_SERVER_MAX_LEN {
-            req.into_status_response(413)?
-                .write_all("Request too big".as_bytes())?;
-        } else {
-            let mut buf = vec![0; len];
-            req.read_exact(&mut buf).unwrap();
-            
-            match serde_json::from_slice::<HeatPumpSetting>(&buf) {
-                Ok(form) => {
-                    let jval = serde_json::to_value(&form).unwrap();
+        let jval = json!({"name": name, "size": len});
+        req.into_response(200, Some("OK"), response_headers)?.write_all(jval.to_string().as_bytes())?;
+        Ok::<(), FirmwareError>(())
+    })?;
 
-                    let response_headers = &[("Content-Type", "application/json")];
-                    req.into_response(200, Some("OK"), response_headers)?.write(jval.to_string().as_bytes())?;
+    let inner_state39 = state.clone();
 
-                    let mut stateg = inner_state2.lock().unwrap();
-                    stateg.desired_settings = Some(form);
-                }
-                Err(e) => {
-                    req.into_status_response(400)?.write_all(format!("JSON error: {}", e).as_bytes())?;
-                }
+    server.fn_handler("/assets/*", http::Method::Delete, move |req| {
+        let response_headers = &[("Content-Type", "application/json")];
+        if !bearer_authorized(&req, &inner_state39.lock().unwrap().debug_token) {
+            req.into_response(403, Some("Forbidden"), response_headers)?
+                .write_all(FirmwareError::new(ErrorCode::Unauthorized, "needs a debug_token to be set, and given as a bearer token").to_json().to_string().as_bytes())?;
+            return Ok(());
+        }
+        let name = asset_name(req.uri()).to_string();
+        match assets::remove(&name) {
+            Ok(()) => {
+                info!("removed asset {}", name);
+                req.into_response(200, Some("OK"), response_headers)?
+                    .write_all(json!({"removed": name}).to_string().as_bytes())?;
+            }
+            Err(_) => {
+                req.into_response(404, Some("Not Found"), response_headers)?
+                    .write_all(FirmwareError::new(ErrorCode::NotFound, format!("No asset named {:?}", name)).to_json().to_string().as_bytes())?;
             }
         }
-        
-        Ok::<(), hal::io::EspIOError>(())
+        Ok::<(), FirmwareError>(())
+    })?;
+
+    server.fn_handler("/events", http::Method::Get, |req| {
+        // the stream itself is on its own port, since it would tie up this server for as long as it's open
+        let host = req.header("Host").unwrap_or("").split(':').next().unwrap_or("").to_string();
+        let location = format!("http://{}:{}/events", host, sse::SSE_PORT);
+        req.into_response(307, Some("Temporary Redirect"), &[("Location", location.as_str())])
+            .map(|_| ())
     })?;
 
-    Ok(state)
+    Ok(())
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_debug_token_is_not_serialized() {
+        let mut state = HeatPumpStatus::new();
+        let mut setting = HeatPumpSetting::new();
+        setting.debug_token = Some("not-for-status-json".to_string());
+        state.queue_setting(setting);
+
+        let statusjson = status_json(&state, Instant::now(), &None);
+        assert!(statusjson["desired_settings"].is_object());
+        assert!(statusjson["desired_settings"].get("debug_token").is_none());
+        assert!(!statusjson.to_string().contains("not-for-status-json"));
+    }
+
+    #[test]
+    fn status_json_reads_back_as_the_shared_unit_status() {
+        // what heatpumpctl and a follower read it with
+        let mut state = HeatPumpStatus::new();
+        state.mode = HeatPumpMode::Cool;
+        let statusjson = status_json(&state, Instant::now(), &None);
+        let unit = serde_json::from_value::<api::UnitStatus>(statusjson).unwrap();
+        assert_eq!(unit.mode, HeatPumpMode::Cool);
+    }
+
+    #[test]
+    fn setpoint_is_checked_against_limits_set_alongside_it() {
+        let state = HeatPumpStatus::new();
+        let mut limits = SetpointLimits::new();
+        limits.heat = SetpointRange { min_c: 16.0, max_c: 20.0 };
+
+        let mut setting = HeatPumpSetting::new();
+        setting.mode = Some(HeatPumpMode::Heat);
+        setting.desired_temperature_c = Some(22.0);
+        setting.setpoint_limits = Some(limits.clone());
+        let errjson = validate_setting(&mut setting, &state).unwrap_err();
+        assert_eq!(errjson["field"], "desired_temperature_c");
+        assert_eq!(errjson["max_c"], 20.0);
+
+        limits.clamp = true;
+        setting.setpoint_limits = Some(limits);
+        assert!(validate_setting(&mut setting, &state).is_ok());
+        assert_eq!(setting.desired_temperature_c, Some(20.0));
+    }
+}
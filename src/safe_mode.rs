@@ -0,0 +1,37 @@
+use std::time::Duration;
+
+use log::info;
+
+use esp_idf_hal::reset::ResetReason;
+use esp_idf_svc::nvs;
+
+// this many crashes in a row, each before the firmware had been up for STABLE_UPTIME, means the next boot is in safe mode
+pub const CRASHES_BEFORE_SAFE_MODE: u32 = 3;
+// up this long and it's not crash looping, whatever happens afterwards
+pub const STABLE_UPTIME: Duration = Duration::from_secs(5 * 60);
+
+/// Whether a reset was the firmware falling over, rather than a power cycle or a restart it asked for
+pub fn is_crash(reason: ResetReason) -> bool {
+    matches!(reason, ResetReason::Panic | ResetReason::InterruptWatchdog | ResetReason::TaskWatchdog | ResetReason::Watchdog)
+}
+
+/// Counts this boot towards the crash streak if it followed a crash, or ends the streak if it didn't.  Returns how many
+/// crashes in a row there have now been
+pub fn record_boot(nvs_settings: &mut nvs::EspNvs<nvs::NvsDefault>, reason: ResetReason) -> anyhow::Result<u32> {
+    let streak = if is_crash(reason) {
+        nvs_settings.get_u32("crash_streak")?.unwrap_or(0) + 1
+    } else {
+        0
+    };
+    nvs_settings.set_u32("crash_streak", streak)?;
+    if streak > 0 {
+        info!("{} crashes in a row, the last a {:?} reset", streak, reason);
+    }
+    Ok(streak)
+}
+
+/// For once the firmware has been up long enough, or when leaving safe mode on request
+pub fn clear(nvs_settings: &mut nvs::EspNvs<nvs::NvsDefault>) -> anyhow::Result<()> {
+    nvs_settings.set_u32("crash_streak", 0)?;
+    Ok(())
+}
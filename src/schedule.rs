@@ -0,0 +1,181 @@
+use serde::{Deserialize, Serialize};
+
+const SECS_PER_DAY: i64 = 24*60*60;
+const PERIODS_MAX: usize = 8;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ComfortPeriod {
+    #[serde(default)]
+    pub days: Vec<u8>,  // 0 is Monday, and none means every day
+    pub start: String,  // local time, "HH:MM"
+    pub end: String,  // one before the start runs past midnight
+    pub temperature_c: f32,
+}
+impl ComfortPeriod {
+    /// The start and end as seconds after local midnight
+    fn secs(&self) -> Option<(i64, i64)> {
+        Some((parse_hhmm(&self.start)?, parse_hhmm(&self.end)?))
+    }
+
+    fn on_day(&self, weekday: i64) -> bool {
+        self.days.is_empty() || self.days.iter().any(|&d| d as i64 == weekday)
+    }
+}
+
+fn parse_hhmm(time: &str) -> Option<i64> {
+    let (hours, minutes) = time.split_once(':')?;
+    let hours = hours.parse::<i64>().ok().filter(|h| (0..24).contains(h))?;
+    let minutes = minutes.parse::<i64>().ok().filter(|m| (0..60).contains(m))?;
+    Some((hours*60 + minutes)*60)
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ScheduleConfig {
+    pub enabled: bool,
+    pub utc_offset_minutes: i32,  // so the times are local ones
+    pub periods: Vec<ComfortPeriod>,
+    pub setback_c: f32,  // the setpoint outside the comfort periods
+    pub preheat: bool,  // start heating early, so the room is up to temperature when a period starts
+    pub max_preheat_mins: u32,
+}
+impl ScheduleConfig {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            utc_offset_minutes: 0,
+            periods: Vec::new(),
+            setback_c: 17.0,
+            preheat: true,
+            max_preheat_mins: 120,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.utc_offset_minutes.abs() > 14*60 {
+            return Err("utc_offset_minutes must be within +/- 14 hours".to_string());
+        }
+        if self.periods.len() > PERIODS_MAX {
+            return Err(format!("at most {} periods can be scheduled", PERIODS_MAX));
+        }
+        for period in &self.periods {
+            match period.secs() {
+                None => { return Err(format!("period times must be \"HH:MM\", not {:?} to {:?}", period.start, period.end)); }
+                Some((start, end)) if start == end => { return Err(format!("period {} to {} is empty", period.start, period.end)); }
+                Some(_) => {}
+            }
+            if period.days.iter().any(|&d| d > 6) {
+                return Err("period days go from 0 (Monday) to 6 (Sunday)".to_string());
+            }
+            if !period.temperature_c.is_finite() {
+                return Err("period temperature_c must be a number".to_string());
+            }
+        }
+        if !self.setback_c.is_finite() {
+            return Err("setback_c must be a number".to_string());
+        }
+        // the preheat lookahead only goes as far as tomorrow
+        if self.max_preheat_mins > 24*60 {
+            return Err("max_preheat_mins can be at most a day".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct Preheat {
+    pub temperature_c: f32,
+    pub period_starts_unix: u64,
+    pub lead_mins: f32,  // how early it was started
+}
+
+#[derive(Debug, Serialize)]
+pub struct Schedule {
+    // Moves the setpoint between the comfort periods' temperatures and the setback, starting a period early when the
+    // room will take a while to warm up
+    pub config: ScheduleConfig,
+    pub target_c: Option<f32>,  // what it last asked for
+    pub preheat: Option<Preheat>,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self {
+            config: ScheduleConfig::new(),
+            target_c: None,
+            preheat: None,
+        }
+    }
+
+    /// Starts over with a new config, so it's applied on the next evaluate even if the target hasn't changed
+    pub fn set_config(&mut self, config: ScheduleConfig) {
+        *self = Self { config, ..Self::new() };
+    }
+
+    /// The setpoint the schedule wants at unix_secs, or None if it's off.  Pre-heating only happens while heating,
+    /// and needs the room temperature and how fast the room has been seen to warm up; without them a period just
+    /// starts on time
+    pub fn target(&mut self, unix_secs: u64, heating: bool, room_c: Option<f32>, warmup_c_per_min: Option<f32>) -> Option<f32> {
+        if !self.config.enabled {
+            self.preheat = None;
+            return None;
+        }
+
+        let local_secs = unix_secs as i64 + self.config.utc_offset_minutes as i64 * 60;
+        let today = local_secs.div_euclid(SECS_PER_DAY);
+        let secs_of_day = local_secs.rem_euclid(SECS_PER_DAY);
+        let weekday = (today + 3).rem_euclid(7);  // 1970-01-01 was a Thursday
+
+        // the soonest period still to come: how many seconds until it, when that is in unix time, and its temperature
+        let mut next: Option<(i64, u64, f32)> = None;
+        for period in &self.config.periods {
+            let (start, end) = match period.secs() {
+                Some(secs) => secs,
+                None => { continue; }
+            };
+            let length = (end - start).rem_euclid(SECS_PER_DAY);
+            // yesterday's may still be going if it runs past midnight, and tomorrow's may need pre-heating for
+            for day in -1..=1 {
+                if !period.on_day((weekday + day).rem_euclid(7)) {
+                    continue;
+                }
+                let since = secs_of_day - (day*SECS_PER_DAY + start);
+                if (0..length).contains(&since) {
+                    self.preheat = None;
+                    return Some(period.temperature_c);
+                }
+                if since < 0 && next.map_or(true, |(until, _, _)| -since < until) {
+                    next = Some((-since, (unix_secs as i64 - since) as u64, period.temperature_c));
+                }
+            }
+        }
+
+        // once started, pre-heating carries on until the period starts.  Otherwise as the room warmed up the lead
+        // would shrink, and it would go back to the setback
+        if let Some(preheat) = self.preheat {
+            if unix_secs < preheat.period_starts_unix && next.map_or(false, |(_, starts, _)| starts == preheat.period_starts_unix) {
+                return Some(preheat.temperature_c);
+            }
+            self.preheat = None;
+        }
+        if let (Some((until, starts, temperature_c)), true, Some(room_c), Some(rate)) = (next, self.config.preheat && heating, room_c, warmup_c_per_min) {
+            if temperature_c > room_c && rate > 0.0 {
+                let lead_mins = ((temperature_c - room_c) / rate).min(self.config.max_preheat_mins as f32);
+                if (until as f32) / 60.0 <= lead_mins {
+                    self.preheat = Some(Preheat { temperature_c, period_starts_unix: starts, lead_mins });
+                    return Some(temperature_c);
+                }
+            }
+        }
+        Some(self.config.setback_c)
+    }
+
+    /// Like target, but only returns it when it changes, so the bus doesn't get a packet every loop
+    pub fn evaluate(&mut self, unix_secs: u64, heating: bool, room_c: Option<f32>, warmup_c_per_min: Option<f32>) -> Option<f32> {
+        let target_c = self.target(unix_secs, heating, room_c, warmup_c_per_min);
+        if target_c == self.target_c {
+            return None;
+        }
+        self.target_c = target_c;
+        target_c
+    }
+}
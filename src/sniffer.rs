@@ -0,0 +1,171 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Packet, PACKET_SIZE_MAX};
+use crate::bus_trace::decode;
+
+const FRAMES_KEPT: usize = 200;
+// fewer go to flash, as NVS is small
+const FLASH_FRAMES_KEPT: usize = 32;
+// a partial packet with nothing more after this long is given up on, as at 2400 baud a packet takes under 100 ms
+const PARTIAL_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SnifferConfig {
+    pub enabled: bool,  // takes effect at the next boot, and means never transmitting to the heat pump
+    pub flash_log: bool,
+}
+impl SnifferConfig {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            flash_log: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SniffedFrame {
+    pub seq: u64,
+    pub ms_since_start: u64,
+    pub from: String,  // "controller", "unit", or "unknown" for junk and unrecognized types
+    pub hex: String,
+    pub decode: String,
+    pub checksum_ok: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Sniffer {
+    // Listens in on the bus between the heat pump and some other controller (e.g. a Kumo or PAR remote) without
+    // ever sending, to help work out packet types we don't understand yet
+    pub config: SnifferConfig,
+    #[serde(skip)]
+    pub frames: VecDeque<SniffedFrame>,
+    #[serde(skip)]
+    pub previous_log: Vec<SniffedFrame>,  // what was in flash at boot, i.e. from before the last reset
+    #[serde(skip)]
+    next_seq: u64,
+    #[serde(skip)]
+    flushed_seq: u64,
+    #[serde(skip)]
+    partial: Vec<u8>,
+    #[serde(skip)]
+    last_byte: Option<Instant>,
+    #[serde(skip)]
+    start: Instant,
+}
+
+/// Which side of the bus a packet type comes from.  The unit's replies have 0x20 added to the request type
+fn direction(packet_type: u8) -> &'static str {
+    match packet_type {
+        0x40..=0x5f => "controller",
+        0x60..=0x7f => "unit",
+        _ => "unknown",
+    }
+}
+
+impl Sniffer {
+    pub fn new() -> Self {
+        Self {
+            config: SnifferConfig::new(),
+            frames: VecDeque::new(),
+            previous_log: Vec::new(),
+            next_seq: 0,
+            flushed_seq: 0,
+            partial: Vec::new(),
+            last_byte: None,
+            start: Instant::now(),
+        }
+    }
+
+    fn push(&mut self, bytes: &[u8], packet_ok: Option<bool>) {
+        let frame = SniffedFrame {
+            seq: self.next_seq,
+            ms_since_start: self.start.elapsed().as_millis() as u64,
+            from: match packet_ok {
+                Some(_) => direction(bytes[1]),
+                None => "unknown",
+            }.to_string(),
+            hex: bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" "),
+            decode: match packet_ok {
+                Some(_) => decode(bytes),
+                None => "junk".to_string(),
+            },
+            checksum_ok: packet_ok.unwrap_or(false),
+        };
+        if self.frames.len() >= FRAMES_KEPT {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+        self.next_seq += 1;
+    }
+
+    /// Splits whatever came off the bus into packets.  Bytes can be fed in any size chunks, including none
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if !bytes.is_empty() {
+            self.last_byte = Some(Instant::now());
+            self.partial.extend_from_slice(bytes);
+        } else if !self.partial.is_empty() && self.last_byte.map_or(false, |t| t.elapsed() > PARTIAL_TIMEOUT) {
+            let junk = std::mem::take(&mut self.partial);
+            self.push(&junk, None);
+        }
+
+        loop {
+            // anything before a sync byte is junk
+            match self.partial.iter().position(|b| *b == 0xfc) {
+                Some(0) => {}
+                Some(i) => {
+                    let junk: Vec<u8> = self.partial.drain(..i).collect();
+                    self.push(&junk, None);
+                }
+                None => { return; }
+            }
+            if self.partial.len() < 5 {
+                return;
+            }
+            let size = 6 + self.partial[4] as usize;
+            if size > PACKET_SIZE_MAX {
+                // not a real length, so the sync byte was too
+                let junk: Vec<u8> = self.partial.drain(..1).collect();
+                self.push(&junk, None);
+                continue;
+            }
+            if self.partial.len() < size {
+                return;
+            }
+            let packet: Vec<u8> = self.partial.drain(..size).collect();
+            let ok = Packet::from_bytes(&packet).is_ok();
+            self.push(&packet, Some(ok));
+        }
+    }
+
+    /// The frames after seq as json lines, and the seq to ask from next time
+    pub fn lines_since(&self, seq: u64) -> (Vec<String>, u64) {
+        let lines = self.frames.iter()
+            .filter(|f| f.seq >= seq)
+            .map(|f| serde_json::to_string(f).unwrap())
+            .collect();
+        (lines, self.next_seq)
+    }
+
+    pub fn oldest_seq(&self) -> u64 {
+        self.frames.front().map_or(self.next_seq, |f| f.seq)
+    }
+
+    /// The frames to write to flash if there are any new ones since the last time, as json lines
+    pub fn take_flash_log(&mut self) -> Option<String> {
+        if !self.config.flash_log || self.next_seq == self.flushed_seq {
+            return None;
+        }
+        self.flushed_seq = self.next_seq;
+        let skip = self.frames.len().saturating_sub(FLASH_FRAMES_KEPT);
+        let lines: Vec<String> = self.frames.iter().skip(skip).map(|f| serde_json::to_string(f).unwrap()).collect();
+        Some(lines.join("\n"))
+    }
+
+    pub fn load_previous_log(&mut self, log: &str) {
+        self.previous_log = log.lines().filter_map(|l| serde_json::from_str(l).ok()).collect();
+    }
+}
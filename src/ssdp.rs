@@ -0,0 +1,147 @@
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::HTTP_PORT;
+
+const SSDP_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 255, 250);
+const SSDP_PORT: u16 = 1900;
+const DEVICE_TYPE: &str = "urn:schemas-upnp-org:device:Basic:1";
+// NOTIFYs are repeated well within the max-age so control points don't forget about us
+const MAX_AGE_SECS: u64 = 1800;
+const NOTIFY_INTERVAL: Duration = Duration::from_secs(600);
+
+/// A UPnP-style uuid that stays the same for a given controller
+pub fn uuid(mac: &str) -> String {
+    format!("uuid:6d697473-7562-6973-6869-{}", mac)
+}
+
+/// The device description served at /description.xml
+pub fn description_xml(mac: &str, friendly_name: &str) -> String {
+    format!(concat!(
+        "<?xml version=\"1.0\"?>\r\n",
+        "<root xmlns=\"urn:schemas-upnp-org:device-1-0\">\r\n",
+        "<specVersion><major>1</major><minor>0</minor></specVersion>\r\n",
+        "<device>\r\n",
+        "<deviceType>{}</deviceType>\r\n",
+        "<friendlyName>{}</friendlyName>\r\n",
+        "<manufacturer>eteq</manufacturer>\r\n",
+        "<modelName>Mitsubishi heatpump controller</modelName>\r\n",
+        "<modelNumber>{}</modelNumber>\r\n",
+        "<serialNumber>{}</serialNumber>\r\n",
+        "<UDN>{}</UDN>\r\n",
+        "<presentationURL>/index.html</presentationURL>\r\n",
+        "</device>\r\n",
+        "</root>\r\n"),
+        DEVICE_TYPE, xml_escape(friendly_name), env!("CARGO_PKG_VERSION"), mac, uuid(mac))
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Answers SSDP searches and announces the controller, so UPnP tools (and Windows network discovery) can find it
+pub struct Ssdp {
+    socket: UdpSocket,
+    uuid: String,
+    last_notify: Option<Instant>,
+}
+
+impl Ssdp {
+    pub fn new(mac: &str) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, SSDP_PORT))?;
+        socket.join_multicast_v4(&SSDP_GROUP, &Ipv4Addr::UNSPECIFIED)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, uuid: uuid(mac), last_notify: None })
+    }
+
+    /// Called from the main loop: never blocks, and problems are only logged since discovery is a nicety
+    pub fn poll(&mut self) {
+        let mut buf = [0u8; 1024];
+        while let Ok((n, src)) = self.socket.recv_from(&mut buf) {
+            let request = String::from_utf8_lossy(&buf[..n]);
+            if let Some(st) = search_target(&request) {
+                if let Some(target) = self.answers(&st) {
+                    if let Err(e) = self.respond(src, &target) {
+                        info!("SSDP response to {} failed: {}", src, e);
+                    }
+                }
+            }
+        }
+
+        if self.last_notify.map_or(true, |t| t.elapsed() >= NOTIFY_INTERVAL) {
+            self.last_notify = Some(Instant::now());
+            if let Err(e) = self.notify() {
+                info!("SSDP notify failed: {}", e);
+            }
+        }
+    }
+
+    /// The search target to answer with if we match st
+    fn answers(&self, st: &str) -> Option<String> {
+        match st {
+            "ssdp:all" | "upnp:rootdevice" => Some("upnp:rootdevice".to_string()),
+            _ if st == DEVICE_TYPE || st == self.uuid => Some(st.to_string()),
+            _ => None,
+        }
+    }
+
+    fn usn(&self, target: &str) -> String {
+        if target == self.uuid { self.uuid.clone() } else { format!("{}::{}", self.uuid, target) }
+    }
+
+    /// Our address as seen from dest, which is the one to put in LOCATION
+    fn local_ip(dest: SocketAddr) -> anyhow::Result<(UdpSocket, std::net::IpAddr)> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))?;
+        socket.connect(dest)?;
+        let ip = socket.local_addr()?.ip();
+        Ok((socket, ip))
+    }
+
+    fn respond(&self, dest: SocketAddr, target: &str) -> anyhow::Result<()> {
+        let (socket, ip) = Self::local_ip(dest)?;
+        let message = format!(concat!(
+            "HTTP/1.1 200 OK\r\n",
+            "CACHE-CONTROL: max-age={}\r\n",
+            "EXT:\r\n",
+            "LOCATION: http://{}:{}/description.xml\r\n",
+            "SERVER: esp-idf/1.0 UPnP/1.0 heatpump-controller/{}\r\n",
+            "ST: {}\r\n",
+            "USN: {}\r\n\r\n"),
+            MAX_AGE_SECS, ip, HTTP_PORT, env!("CARGO_PKG_VERSION"), target, self.usn(target));
+        socket.send(message.as_bytes())?;
+        Ok(())
+    }
+
+    fn notify(&self) -> anyhow::Result<()> {
+        let dest = SocketAddr::V4(SocketAddrV4::new(SSDP_GROUP, SSDP_PORT));
+        let (socket, ip) = Self::local_ip(dest)?;
+        for target in ["upnp:rootdevice", DEVICE_TYPE, self.uuid.as_str()] {
+            let message = format!(concat!(
+                "NOTIFY * HTTP/1.1\r\n",
+                "HOST: 239.255.255.250:1900\r\n",
+                "CACHE-CONTROL: max-age={}\r\n",
+                "LOCATION: http://{}:{}/description.xml\r\n",
+                "NT: {}\r\n",
+                "NTS: ssdp:alive\r\n",
+                "SERVER: esp-idf/1.0 UPnP/1.0 heatpump-controller/{}\r\n",
+                "USN: {}\r\n\r\n"),
+                MAX_AGE_SECS, ip, HTTP_PORT, target, env!("CARGO_PKG_VERSION"), self.usn(target));
+            socket.send(message.as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// The ST header of an M-SEARCH, or None if this isn't one
+fn search_target(request: &str) -> Option<String> {
+    let mut lines = request.lines();
+    if !lines.next()?.starts_with("M-SEARCH") {
+        return None;
+    }
+    lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim().eq_ignore_ascii_case("ST") { Some(value.trim().to_string()) } else { None }
+    })
+}
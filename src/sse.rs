@@ -0,0 +1,121 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Ipv4Addr, SocketAddrV4, TcpListener, TcpStream};
+use std::time::{Duration, Instant};
+
+use log::info;
+
+use crate::HTTP_PORT;
+
+// the http server handles one request at a time, so an event stream, which never finishes, can't go through it.
+// This is two above the http port, as one above is the UDP discovery port
+pub const SSE_PORT: u16 = HTTP_PORT + 2;
+const SSE_MAX_CLIENTS: usize = 4;
+// a comment line this often keeps proxies and the browser from giving up on a quiet stream
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+// how long a new connection has to send its request line
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const REQUEST_MAX_LEN: usize = 1024;
+// each write to a client holds up the main loop, so one that's stopped reading is dropped after this
+const WRITE_TIMEOUT: Duration = Duration::from_millis(500);
+
+struct Client {
+    stream: TcpStream,
+    request: Vec<u8>,
+    connected: Instant,
+    streaming: bool,  // once the request has been read and answered with the event stream headers
+}
+
+/// Server-sent events at /events on SSE_PORT: a "status" event each time the status changes, and a heartbeat
+/// otherwise.  Like the discovery responder it's polled from the main loop, which it only holds up for a write
+pub struct SseServer {
+    listener: TcpListener,
+    clients: Vec<Client>,
+    last_etag: Option<String>,
+    last_sent: Instant,
+}
+
+impl SseServer {
+    pub fn new() -> anyhow::Result<Self> {
+        let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, SSE_PORT))?;
+        listener.set_nonblocking(true)?;
+        Ok(Self { listener, clients: Vec::new(), last_etag: None, last_sent: Instant::now() })
+    }
+
+    /// Whether anyone is listening, so the status only gets worked out when it's needed
+    pub fn has_listeners(&self) -> bool {
+        self.clients.iter().any(|c| c.streaming)
+    }
+
+    /// Takes new connections and reads their requests
+    pub fn poll(&mut self) {
+        while let Ok((stream, addr)) = self.listener.accept() {
+            if self.clients.len() >= SSE_MAX_CLIENTS {
+                info!("Turning away event stream client {}, already at {}", addr, SSE_MAX_CLIENTS);
+                let mut stream = stream;
+                let _ = stream.write_all(b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+                continue;
+            }
+            if stream.set_nonblocking(true).is_ok() {
+                self.clients.push(Client { stream, request: Vec::new(), connected: Instant::now(), streaming: false });
+            }
+        }
+
+        let mut new_listener = false;
+        self.clients.retain_mut(|client| {
+            if client.streaming {
+                return true;
+            }
+            let mut buf = [0u8; 256];
+            loop {
+                match client.stream.read(&mut buf) {
+                    Ok(0) => { return false; }
+                    Ok(n) => { client.request.extend_from_slice(&buf[..n]); }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => { break; }
+                    Err(_) => { return false; }
+                }
+            }
+            if !client.request.windows(4).any(|w| w == b"\r\n\r\n") {
+                return client.request.len() < REQUEST_MAX_LEN && client.connected.elapsed() < REQUEST_TIMEOUT;
+            }
+
+            let request_line = String::from_utf8_lossy(&client.request).lines().next().unwrap_or("").to_string();
+            let path = request_line.split(' ').nth(1).unwrap_or("");
+            if !request_line.starts_with("GET ") || path.split('?').next() != Some("/events") {
+                let _ = client.stream.write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n");
+                return false;
+            }
+            // the page comes from the http port, so this is cross-origin to it
+            let headers = b"HTTP/1.1 200 OK\r\nContent-Type: text/event-stream\r\nCache-Control: no-cache\r\n\
+                            Connection: keep-alive\r\nAccess-Control-Allow-Origin: *\r\n\r\n";
+            // blocking from here on, so an event bigger than the send buffer goes out whole, but not for long
+            let ready = client.stream.set_nonblocking(false)
+                .and_then(|_| client.stream.set_write_timeout(Some(WRITE_TIMEOUT)))
+                .and_then(|_| client.stream.write_all(headers));
+            if ready.is_err() {
+                return false;
+            }
+            client.streaming = true;
+            new_listener = true;
+            true
+        });
+        if new_listener {
+            // so whoever just connected gets the status straight away
+            self.last_etag = None;
+        }
+    }
+
+    /// Sends the status if it's changed since last time (going by its ETag), or a heartbeat if it's been a while
+    pub fn send_status(&mut self, status: &serde_json::Value, etag: &str) {
+        let event = if self.last_etag.as_deref() != Some(etag) {
+            self.last_etag = Some(etag.to_string());
+            format!("event: status\ndata: {}\n\n", status)
+        } else if self.last_sent.elapsed() >= HEARTBEAT_INTERVAL {
+            ": heartbeat\n\n".to_string()
+        } else {
+            return;
+        };
+        self.last_sent = Instant::now();
+        // a client that can't keep up is dropped rather than waited on any longer, and EventSource reconnects by itself
+        self.clients.retain_mut(|client| !client.streaming || client.stream.write_all(event.as_bytes()).is_ok());
+    }
+}
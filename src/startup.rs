@@ -0,0 +1,30 @@
+#![allow(dead_code)]
+
+/// The steps the firmware goes through while booting, each shown as a distinct LED color so a stuck boot
+/// can be localized without a serial cable.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StartupPhase {
+    Nvs,
+    Uart,
+    WifiScan,
+    WifiConnect,
+    Ip,
+    Http,
+    HeatPumpConnect,
+}
+
+impl StartupPhase {
+    /// The (r, g, b) color for this phase at the given brightness
+    pub fn color(&self, brightness: u8) -> (u8, u8, u8) {
+        let b = brightness;
+        match self {
+            StartupPhase::Nvs => (b, 0, 0),                  // red
+            StartupPhase::Uart => (b, b/4, 0),               // reddish-orange
+            StartupPhase::WifiScan => (b, b/2, 0),           // orange
+            StartupPhase::WifiConnect => (b, b, 0),          // yellow
+            StartupPhase::Ip => (0, b, b),                   // cyan
+            StartupPhase::Http => (0, 0, b),                 // blue
+            StartupPhase::HeatPumpConnect => (b, 0, b),      // magenta, same as disconnected in the main loop
+        }
+    }
+}
@@ -0,0 +1,71 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::{Packet, StatusPacketType, PACKET_SIZE_MAX};
+
+// every info request type that fits in the low bits, known or not
+const PROBE_TYPES: std::ops::RangeInclusive<u8> = 0x00..=0x1f;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ProbeResult {
+    pub known: bool,  // already one of the StatusPacketTypes
+    pub answered: bool,
+    pub response_type: Option<u8>,
+    pub hex: Option<String>,
+    pub data: Option<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StatusProbe {
+    // Asks the unit for every info packet type in turn to find ones it answers that we don't decode yet, which can
+    // differ between models.  One type is asked for each time around the loop so the normal polling carries on
+    pub results: BTreeMap<u8, ProbeResult>,
+    pub running: bool,
+    pub secs_since_start: Option<f32>,
+    #[serde(skip)]
+    next_type: Option<u8>,
+    #[serde(skip)]
+    started: Option<Instant>,
+}
+
+impl StatusProbe {
+    pub fn new() -> Self {
+        Self {
+            results: BTreeMap::new(),
+            running: false,
+            secs_since_start: None,
+            next_type: None,
+            started: None,
+        }
+    }
+
+    /// Starts over from the first type, throwing out any earlier results
+    pub fn start(&mut self) {
+        self.results.clear();
+        self.running = true;
+        self.next_type = Some(*PROBE_TYPES.start());
+        self.started = Some(Instant::now());
+    }
+
+    pub fn next_type(&self) -> Option<u8> {
+        self.next_type
+    }
+
+    pub fn record(&mut self, info_type: u8, response: Option<&Packet>) {
+        self.results.insert(info_type, ProbeResult {
+            known: StatusPacketType::from_repr(info_type as usize).is_some(),
+            answered: response.is_some(),
+            response_type: response.map(|p| p.packet_type),
+            hex: response.map(|p| p.to_bytes(&mut [0u8; PACKET_SIZE_MAX]).iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(" ")),
+            data: response.map(|p| p.data.to_vec()),
+        });
+        self.next_type = if info_type < *PROBE_TYPES.end() { Some(info_type + 1) } else { None };
+        self.running = self.next_type.is_some();
+    }
+
+    pub fn update_secs(&mut self) {
+        self.secs_since_start = self.started.map(|t| t.elapsed().as_secs_f32());
+    }
+}
@@ -0,0 +1,54 @@
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Language {
+    En,
+    De,
+    Fr,
+}
+
+// the values the API uses for each group, in the same order as the labels below
+const GROUPS: [(&str, &[&str]); 4] = [
+    ("mode", &["Off", "Heat", "Dry", "Cool", "Fan", "Auto"]),
+    ("fan_speed", &["Auto", "Quiet", "Low", "Med", "High", "VeryHigh"]),
+    ("vane", &["Auto", "Horizontal", "MidHorizontal", "Midpoint", "MidVertical", "Vertical", "Swing"]),
+    ("widevane", &["FarLeft", "Left", "Mid", "Right", "FarRight", "Split", "Swing", "Unknown"]),
+];
+
+fn labels(language: Language) -> [&'static [&'static str]; 4] {
+    match language {
+        Language::En => [
+            &["Off", "Heat", "Dry", "Cool", "Fan", "Auto"],
+            &["Auto", "Quiet", "Low", "Medium", "High", "Very high"],
+            &["Auto", "Horizontal", "Mid-horizontal", "Midpoint", "Mid-vertical", "Vertical", "Swing"],
+            &["Far left", "Left", "Middle", "Right", "Far right", "Split", "Swing", "Unknown"],
+        ],
+        Language::De => [
+            &["Aus", "Heizen", "Entfeuchten", "Kühlen", "Lüften", "Automatik"],
+            &["Auto", "Leise", "Niedrig", "Mittel", "Hoch", "Sehr hoch"],
+            &["Auto", "Horizontal", "Halb horizontal", "Mitte", "Halb vertikal", "Vertikal", "Schwenken"],
+            &["Ganz links", "Links", "Mitte", "Rechts", "Ganz rechts", "Geteilt", "Schwenken", "Unbekannt"],
+        ],
+        Language::Fr => [
+            &["Arrêt", "Chauffage", "Déshumidification", "Climatisation", "Ventilation", "Auto"],
+            &["Auto", "Silencieux", "Faible", "Moyen", "Fort", "Très fort"],
+            &["Auto", "Horizontal", "Mi-horizontal", "Milieu", "Mi-vertical", "Vertical", "Balayage"],
+            &["Tout à gauche", "Gauche", "Milieu", "Droite", "Tout à droite", "Divisé", "Balayage", "Inconnu"],
+        ],
+    }
+}
+
+/// The labels for every enum value the API takes, keyed by group and then by the API value, e.g.
+/// {"language": "en", "mode": {"Heat": "Heat", ...}, ...}
+pub fn table(language: Language) -> serde_json::Value {
+    let mut jval = json!({"language": language});
+    for ((group, values), group_labels) in GROUPS.iter().zip(labels(language)) {
+        jval[*group] = values.iter().zip(group_labels.iter())
+            .map(|(value, label)| (value.to_string(), json!(label)))
+            .collect::<serde_json::Map<_, _>>()
+            .into();
+    }
+    jval
+}
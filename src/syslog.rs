@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::Mutex;
+
+use log::{Level, Log, Metadata, Record};
+
+use esp_idf_svc::log::EspLogger;
+
+const SYSLOG_DEFAULT_PORT: u16 = 514;
+const SYSLOG_FACILITY_LOCAL0: u8 = 16;
+const RECENT_LINES_MAX: usize = 100;
+
+struct SyslogSink {
+    socket: UdpSocket,
+    server: SocketAddr,
+    hostname: String,
+}
+
+struct RecentLines {
+    lines: VecDeque<String>,
+    next_seq: u64,  // the sequence number the next line pushed will get
+}
+
+/// Logs everything to the regular esp logger, and also to a remote syslog server over UDP once one is set.
+/// The last few lines are kept in memory so they can be streamed to the web UI.
+pub struct SyslogLogger {
+    esp: EspLogger,
+    sink: Mutex<Option<SyslogSink>>,
+    recent: Mutex<RecentLines>,
+}
+
+pub static LOGGER: SyslogLogger = SyslogLogger {
+    esp: EspLogger::new(),
+    sink: Mutex::new(None),
+    recent: Mutex::new(RecentLines { lines: VecDeque::new(), next_seq: 0 }),
+};
+
+impl SyslogLogger {
+    /// Use in place of EspLogger::initialize_default()
+    pub fn initialize(&'static self) {
+        log::set_logger(self).unwrap();
+        self.esp.initialize();
+    }
+
+    /// Starts (or with None, stops) sending to a syslog server given as "host" or "host:port"
+    pub fn set_server(&self, server: Option<&str>, hostname: &str) -> anyhow::Result<()> {
+        let sink = match server {
+            Some(s) if !s.is_empty() => {
+                let addr = if s.contains(':') {
+                    s.to_socket_addrs()?.next()
+                } else {
+                    (s, SYSLOG_DEFAULT_PORT).to_socket_addrs()?.next()
+                };
+                let server = addr.ok_or(anyhow::anyhow!("could not resolve syslog server {}", s))?;
+                Some(SyslogSink {
+                    socket: UdpSocket::bind("0.0.0.0:0")?,
+                    server,
+                    hostname: hostname.to_string(),
+                })
+            }
+            _ => None,
+        };
+        *self.sink.lock().unwrap() = sink;
+        Ok(())
+    }
+
+    /// The sequence number of the oldest line still kept, i.e. where a new reader should start
+    pub fn oldest_seq(&self) -> u64 {
+        let recent = self.recent.lock().unwrap();
+        recent.next_seq - recent.lines.len() as u64
+    }
+
+    /// The lines from sequence number `seq` on (or from the oldest kept, if those have been dropped),
+    /// along with the sequence number to ask for next time
+    pub fn lines_since(&self, seq: u64) -> (Vec<String>, u64) {
+        let recent = self.recent.lock().unwrap();
+        let oldest = recent.next_seq - recent.lines.len() as u64;
+        let skip = seq.saturating_sub(oldest) as usize;
+        (recent.lines.iter().skip(skip).cloned().collect(), recent.next_seq)
+    }
+}
+
+impl Log for SyslogLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.esp.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.esp.log(record);
+
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        // try_lock so a log line from inside the send (or while a reader holds the lines) can't deadlock
+        if let Ok(mut recent) = self.recent.try_lock() {
+            if recent.lines.len() >= RECENT_LINES_MAX {
+                recent.lines.pop_front();
+            }
+            recent.lines.push_back(format!("{} {}: {}", record.level(), record.target(), record.args()));
+            recent.next_seq += 1;
+        }
+        if let Ok(sink) = self.sink.try_lock() {
+            if let Some(sink) = sink.as_ref() {
+                let severity = match record.level() {
+                    Level::Error => 3,
+                    Level::Warn => 4,
+                    Level::Info => 6,
+                    Level::Debug | Level::Trace => 7,
+                };
+                let message = format!("<{}>{} {}: {}", SYSLOG_FACILITY_LOCAL0 * 8 + severity,
+                                      sink.hostname, record.target(), record.args());
+                // nowhere sensible to report a failure to, so just drop the line
+                let _ = sink.socket.send_to(message.as_bytes(), sink.server);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        self.esp.flush();
+    }
+}
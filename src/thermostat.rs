@@ -0,0 +1,129 @@
+use std::time::{Duration, Instant};
+
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::{HeatPumpMode, HeatPumpSetting};
+
+// how far past the target the setpoint gets pushed when nudging the unit's own thermostat
+const SETPOINT_NUDGE_C: f32 = 2.0;
+
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ThermostatAction {
+    Power,  // turn the unit on and off
+    Setpoint,  // leave it on but push the unit's setpoint above/below the target
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ThermostatConfig {
+    pub enabled: bool,
+    pub target_c: f32,
+    pub hysteresis_c: f32,
+    pub action: ThermostatAction,
+    pub max_sensor_age_secs: u64,  // stop regulating if the remote temperature is older than this
+}
+impl ThermostatConfig {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            target_c: 20.0,
+            hysteresis_c: 0.5,
+            action: ThermostatAction::Power,
+            max_sensor_age_secs: 600,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.hysteresis_c < 0.0 {
+            return Err("hysteresis_c cannot be negative".to_string());
+        }
+        if self.max_sensor_age_secs == 0 {
+            return Err("max_sensor_age_secs must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Thermostat {
+    // Regulates the heat pump from a temperature sensor that isn't the unit's own
+    pub config: ThermostatConfig,
+    pub remote_temperature_c: Option<f32>,
+    pub calling: Option<bool>,  // whether we are currently asking for heating/cooling, None if not decided yet
+    #[serde(skip)]
+    pub remote_updated: Option<Instant>,
+}
+
+impl Thermostat {
+    pub fn new() -> Self {
+        Self {
+            config: ThermostatConfig::new(),
+            remote_temperature_c: None,
+            calling: None,
+            remote_updated: None,
+        }
+    }
+
+    pub fn set_remote_temperature(&mut self, temperature_c: f32) {
+        self.remote_temperature_c = Some(temperature_c);
+        self.remote_updated = Some(Instant::now());
+    }
+
+    pub fn sensor_age(&self) -> Option<Duration> {
+        self.remote_updated.map(|t| t.elapsed())
+    }
+
+    /// Decides whether the heat pump needs to be changed given the current mode. Only returns a setting when the
+    /// call for heating/cooling flips, so the bus doesn't get a packet every loop.
+    pub fn evaluate(&mut self, mode: HeatPumpMode) -> Option<HeatPumpSetting> {
+        if !self.config.enabled {
+            self.calling = None;
+            return None;
+        }
+
+        let temperature_c = match (self.remote_temperature_c, self.sensor_age()) {
+            (Some(t), Some(age)) if age <= Duration::from_secs(self.config.max_sensor_age_secs) => t,
+            _ => {
+                if self.calling.is_some() {
+                    info!("remote temperature is missing or stale, thermostat is not regulating");
+                    self.calling = None;
+                }
+                return None;
+            }
+        };
+
+        let low = self.config.target_c - self.config.hysteresis_c;
+        let high = self.config.target_c + self.config.hysteresis_c;
+        let calling = match mode {
+            HeatPumpMode::Heat => {
+                if temperature_c < low { true } else if temperature_c > high { false } else { return None; }
+            }
+            HeatPumpMode::Cool | HeatPumpMode::Dry => {
+                if temperature_c > high { true } else if temperature_c < low { false } else { return None; }
+            }
+            // no clear direction to regulate in for the other modes
+            _ => { return None; }
+        };
+
+        if self.calling == Some(calling) {
+            return None;
+        }
+        info!("thermostat at {} C (target {} C) for {:?}, calling: {}", temperature_c, self.config.target_c, mode, calling);
+        self.calling = Some(calling);
+
+        let mut setting = HeatPumpSetting::new();
+        match self.config.action {
+            ThermostatAction::Power => {
+                setting.poweron = Some(calling);
+            }
+            ThermostatAction::Setpoint => {
+                let heating = matches!(mode, HeatPumpMode::Heat);
+                // push the setpoint past the target in the direction we want the unit to work (or not)
+                let nudge = if calling == heating { SETPOINT_NUDGE_C } else { -SETPOINT_NUDGE_C };
+                setting.desired_temperature_c = Some(self.config.target_c + nudge);
+            }
+        }
+        Some(setting)
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::{Duration, Instant};
+
+#[cfg(target_os = "espidf")]
+use esp_idf_hal as hal;
+
+#[cfg(target_os = "espidf")]
+use hal::uart;
+
+use crate::packet::{Packet, PacketError, PacketParser};
+
+/// What the heat pump logic needs from the line to the unit.  The UartDriver is the real one; having it behind a trait
+/// means the connect, poll and set code can be run against something else, e.g. canned bytes
+pub trait Transport {
+    fn write(&self, bytes: &[u8]) -> anyhow::Result<usize>;
+
+    /// Waits up to `timeout` for anything to come in, returning how many bytes were read (0 if none came)
+    fn read(&self, buf: &mut [u8], timeout: Duration) -> anyhow::Result<usize>;
+
+    /// Throws away whatever has come in but not been read yet, so a reply isn't mixed up with leftovers
+    fn discard_input(&self) -> anyhow::Result<()>;
+}
+
+// only the firmware has a uart; the host tests bring their own Transport
+#[cfg(target_os = "espidf")]
+impl Transport for uart::UartDriver<'_> {
+    fn write(&self, bytes: &[u8]) -> anyhow::Result<usize> {
+        Ok(uart::UartDriver::write(self, bytes)?)
+    }
+
+    fn read(&self, buf: &mut [u8], timeout: Duration) -> anyhow::Result<usize> {
+        let ticks: u32 = hal::delay::TickType::from(timeout).into();
+        Ok(uart::UartDriver::read(self, buf, ticks)?)
+    }
+
+    fn discard_input(&self) -> anyhow::Result<()> {
+        while self.remaining_read()? > 0 {
+            uart::UartDriver::read(self, &mut [0u8; 1], 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Everything that came back after a request
+pub struct Reply {
+    pub bytes: Vec<u8>,  // all that was read, for the bus trace
+    pub skipped: usize,  // how many of those came before a finished packet's sync byte
+    pub latency: Option<Duration>,  // until the first byte, if any came
+    pub packet: anyhow::Result<Option<Packet>>,  // Ok(None) if nothing came at all
+}
+
+/// Waits up to `wait` for a reply to start, then reads it until the packet is finished, the line has been quiet for
+/// `byte_gap`, or `read_limit` has gone by.  The waiting is a blocking read, so the task sleeps until the uart driver
+/// has bytes for it rather than checking back every few ms
+pub fn read_reply(uart: &dyn Transport, wait: Duration, byte_gap: Duration, read_limit: Duration) -> anyhow::Result<Reply> {
+    let wait_start = Instant::now();
+    let mut read_start = None;
+    let mut latency = None;
+    let mut parser = PacketParser::new();
+    let mut bytes_read: Vec<u8> = Vec::new();
+    let mut rbuf = [0u8; 32];
+
+    let parsed = loop {
+        // never more than the parser wants, so whatever follows the packet stays in the uart for next time
+        let wanted = parser.wanted().min(rbuf.len());
+        let nread = uart.read(&mut rbuf[..wanted], if bytes_read.is_empty() { wait } else { byte_gap })?;
+        if nread == 0 || read_start.is_some_and(|t: Instant| t.elapsed() > read_limit) {
+            break None;
+        }
+        if read_start.is_none() {
+            latency = Some(wait_start.elapsed());
+            read_start = Some(Instant::now());
+        }
+        bytes_read.extend_from_slice(&rbuf[..nread]);
+        if let Some(parsed) = rbuf[..nread].iter().filter_map(|b| parser.feed(*b)).last() {
+            break Some(parsed);
+        }
+    };
+
+    let (skipped, packet) = match parsed {
+        Some(parsed) => (parser.skipped(), parsed.map(Some)),
+        None if bytes_read.is_empty() => (0, Ok(None)),
+        // the line went quiet partway through, or there was never a sync byte
+        None if parser.in_packet() => (0, Err(PacketError::TooShort.into())),
+        None => (0, Err(PacketError::NoSync.into())),
+    };
+    Ok(Reply { bytes: bytes_read, skipped, latency, packet })
+}
@@ -0,0 +1,54 @@
+use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+
+use log::info;
+use serde_json::json;
+
+use crate::{HeatPumpStatus, HTTP_PORT};
+
+// one above the http port, so it's easy to remember and to open in a firewall alongside it
+pub const DISCOVERY_PORT: u16 = HTTP_PORT + 1;
+const DISCOVERY_QUERY: &str = "eteq-mheatpump-discover";
+
+/// Answers broadcast queries on DISCOVERY_PORT, for networks where mDNS doesn't get through.  The query is the
+/// JSON {"query": "eteq-mheatpump-discover"}, and the reply goes straight back to whoever asked
+pub struct UdpDiscovery {
+    socket: UdpSocket,
+    mac: String,
+}
+
+impl UdpDiscovery {
+    pub fn new(mac: &str) -> anyhow::Result<Self> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, mac: mac.to_string() })
+    }
+
+    /// Called from the main loop: never blocks, and only logs problems
+    pub fn poll(&self, state: &HeatPumpStatus) {
+        let mut buf = [0u8; 256];
+        while let Ok((n, src)) = self.socket.recv_from(&mut buf) {
+            let is_query = serde_json::from_slice::<serde_json::Value>(&buf[..n])
+                .map_or(false, |q| q["query"] == DISCOVERY_QUERY);
+            if !is_query {
+                continue;
+            }
+            // our address as seen from whoever asked
+            let ip = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+                .and_then(|s| { s.connect(src)?; s.local_addr() })
+                .map(|a| a.ip().to_string())
+                .ok();
+            let reply = json!({
+                "mac": self.mac,
+                "ip": ip,
+                "port": HTTP_PORT,
+                "hostname": ["heatpump-controller-", self.mac.as_str()].concat(),
+                "location": state.controller_location,
+                "connected": state.connected,
+                "version": env!("CARGO_PKG_VERSION"),
+            });
+            if let Err(e) = self.socket.send_to(reply.to_string().as_bytes(), src) {
+                info!("discovery reply to {} failed: {}", src, e);
+            }
+        }
+    }
+}
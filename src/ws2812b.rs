@@ -9,16 +9,21 @@ use esp_idf_hal as hal;
 use hal::rmt::*;
 
 pub struct Ws2812B<'a> {
-    tx: TxRmtDriver<'a>
+    tx: TxRmtDriver<'a>,
+    last_color: Option<u32>,
 }
 
 impl<'b> Ws2812B<'b> {
     pub fn new(tx: TxRmtDriver<'b>) -> Self {
-        Self { tx }
+        Self { tx, last_color: None }
     }
 
+    /// Sends the color to the LED, unless it's already showing it since the transmit blocks
     pub fn set(&mut self, rgb: Rgb) -> Result<()> {
         let color: u32 = rgb.to_grb();
+        if self.last_color == Some(color) {
+            return Ok(());
+        }
         let ticks_hz = self.tx.counter_clock()?;
         let (t0h, t0l, t1h, t1l) = (
             Pulse::new_with_duration(ticks_hz, PinState::High, &Duration::from_nanos(400))?,
@@ -34,6 +39,7 @@ impl<'b> Ws2812B<'b> {
             signal.set(23 - i as usize, &(high_pulse, low_pulse))?;
         }
         self.tx.start_blocking(&signal)?;
+        self.last_color = Some(color);
         Ok(())
     }
 }